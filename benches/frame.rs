@@ -0,0 +1,46 @@
+use std::hint::black_box;
+use std::io::{self, Write};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use rust_threading::frame;
+
+/// Counts writes so the benchmark has something that behaves like a socket: every call is a "syscall",
+/// and vectored calls take every buffer at once.
+#[derive(Default)]
+struct Syscalls(usize);
+
+impl Write for Syscalls {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += 1;
+        Ok(black_box(buf).len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        self.0 += 1;
+        Ok(black_box(bufs).iter().map(|buf| buf.len()).sum())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("framing");
+    for size in [64, 1024, 64 * 1024] {
+        let payload = vec![b'a'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encode_then_write", size), &payload, |b, payload| {
+            let mut sink = Syscalls::default();
+            b.iter(|| sink.write_all(&frame::encode_frame(payload).unwrap()).unwrap());
+        });
+        group.bench_with_input(BenchmarkId::new("write_vectored", size), &payload, |b, payload| {
+            let mut sink = Syscalls::default();
+            b.iter(|| frame::write_frame(&mut sink, payload).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, framing);
+criterion_main!(benches);
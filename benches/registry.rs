@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use parking_lot::Mutex;
+use rust_threading::outbound::{Frame, Outbound, Queue};
+use rust_threading::registry::Registry;
+use rust_threading::user::User;
+
+/// Messages each client sends someone else before disconnecting.
+const MESSAGES: usize = 50;
+/// Broadcasts going out while they're at it.
+const BROADCASTS: usize = 200;
+
+/// How the registry used to be: everyone behind one lock, with claims comparing against everyone.
+#[derive(Default)]
+struct OneLock(Mutex<BTreeMap<User, Outbound>>);
+
+/// What a simulated client needs from whichever registry it's using.
+trait Users: Send + Sync + 'static {
+    fn claim(&self, user: &User, outbound: Outbound) -> bool;
+    fn send_to(&self, user: &User, frame: Frame);
+    fn send_to_all(&self, frame: &Frame);
+    fn release(&self, user: &User);
+}
+
+impl Users for OneLock {
+    fn claim(&self, user: &User, outbound: Outbound) -> bool {
+        let mut users = self.0.lock();
+        if users.contains_key(user) || users.keys().any(|other| other.looks_like(user)) {
+            return false;
+        }
+        users.insert(user.clone(), outbound);
+        true
+    }
+
+    fn send_to(&self, user: &User, frame: Frame) {
+        if let Some(outbound) = self.0.lock().get(user) {
+            let _ = outbound.send(frame);
+        }
+    }
+
+    fn send_to_all(&self, frame: &Frame) {
+        let full: Vec<_> =
+            self.0.lock().iter().filter(|(_, outbound)| outbound.send(frame.clone()).is_err()).map(|(user, _)| user.clone()).collect();
+        drop(full);
+    }
+
+    fn release(&self, user: &User) {
+        self.0.lock().remove(user);
+    }
+}
+
+impl Users for Registry<Outbound> {
+    fn claim(&self, user: &User, outbound: Outbound) -> bool {
+        self.claim_nick(user, outbound).is_ok()
+    }
+
+    fn send_to(&self, user: &User, frame: Frame) {
+        let _ = Registry::send_to(self, user, frame);
+    }
+
+    fn send_to_all(&self, frame: &Frame) {
+        Registry::send_to_all(self, frame, None);
+    }
+
+    fn release(&self, user: &User) {
+        Registry::release(self, user);
+    }
+}
+
+/// `clients` threads all connecting, then each sending `MESSAGES` to the next one along and disconnecting
+/// while another broadcasts. Queues are long enough for everything they'll get, so nobody needs reading from.
+fn simulate<U: Users + Default>(clients: usize) -> Duration {
+    let users = Arc::new(U::default());
+    let start = Arc::new(Barrier::new(clients + 2));
+    let connected = Arc::new(Barrier::new(clients + 1));
+    let frame: Frame = Frame::from(&b"hello"[..]);
+
+    let mut handles: Vec<_> = (0..clients)
+        .map(|i| {
+            let (users, start, connected, frame) = (users.clone(), start.clone(), connected.clone(), frame.clone());
+            thread::spawn(move || {
+                let (me, next) = (User::new(format!("user{i}")), User::new(format!("user{}", (i + 1) % clients)));
+                let (outbound, queue): (Outbound, Queue) = Outbound::new(MESSAGES + BROADCASTS);
+                start.wait();
+                assert!(users.claim(&me, outbound));
+                connected.wait();
+                for _ in 0..MESSAGES {
+                    users.send_to(&next, frame.clone());
+                }
+                users.release(&me);
+                drop(queue);
+            })
+        })
+        .collect();
+    handles.push({
+        let (users, start) = (users.clone(), start.clone());
+        thread::spawn(move || {
+            start.wait();
+            connected.wait();
+            for _ in 0..BROADCASTS {
+                users.send_to_all(&frame);
+            }
+        })
+    });
+
+    let started = Instant::now();
+    start.wait();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    started.elapsed()
+}
+
+fn registry(c: &mut Criterion) {
+    let mut group = c.benchmark_group("registry");
+    group.sample_size(20);
+    for clients in [100, 300] {
+        group.bench_with_input(BenchmarkId::new("one_lock", clients), &clients, |b, &clients| {
+            b.iter_custom(|iters| (0..iters).map(|_| simulate::<OneLock>(clients)).sum());
+        });
+        group.bench_with_input(BenchmarkId::new("sharded", clients), &clients, |b, &clients| {
+            b.iter_custom(|iters| (0..iters).map(|_| simulate::<Registry<Outbound>>(clients)).sum());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, registry);
+criterion_main!(benches);
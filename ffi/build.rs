@@ -0,0 +1,12 @@
+use std::env;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = env::var("CARGO_MANIFEST_DIR").expect("Cargo always sets CARGO_MANIFEST_DIR");
+    cbindgen::generate(&crate_dir)
+        .expect("Couldn't generate the C header")
+        .write_to_file(Path::new(&crate_dir).join("include/chat_client.h"));
+}
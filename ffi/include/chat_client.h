@@ -0,0 +1,67 @@
+/* Generated by cbindgen from ffi/src/lib.rs on every build, don't edit it by hand. */
+
+#ifndef CHAT_CLIENT_H
+#define CHAT_CLIENT_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A connection to a chat server, from `chat_connect`. Opaque to C.
+typedef struct ChatClient ChatClient;
+
+// Connects to the server at `host` and `port` and logs in as `nick`, with the server's `password` if it
+// needs one, which can be null otherwise. Blocks until the server's let us in.
+//
+// Returns null if it couldn't, see `chat_last_error`. Otherwise the client has to go back to
+// `chat_disconnect` once it's done with.
+//
+// # Safety
+//
+// `host` and `nick` have to be valid NUL-terminated strings, and `password` too if it isn't null.
+struct ChatClient *chat_connect(const char *host,
+                                uint16_t port,
+                                const char *nick,
+                                const char *password);
+
+// Sends a line of chat, or a command like `/join #rust`. Returns 0 if it went, or -1 if it didn't, see
+// `chat_last_error`.
+//
+// # Safety
+//
+// `client` has to be from `chat_connect` and not disconnected yet, and `text` a valid NUL-terminated string.
+int chat_send(struct ChatClient *client,
+              const char *text);
+
+// Waits up to `timeout_ms` for the next thing from the server, answering pings along the way. Returns 1 and
+// points `event` at its JSON if there was one, 0 if there wasn't, or -1 if the connection's broken, see
+// `chat_last_error`. Whatever `event` points at has to go back to `chat_free_string`.
+//
+// # Safety
+//
+// `client` has to be from `chat_connect` and not disconnected yet, and `event` somewhere to write a pointer.
+int chat_poll(struct ChatClient *client,
+              uint32_t timeout_ms,
+              char **event);
+
+// Hangs up and frees `client`. Null is fine, and does nothing.
+//
+// # Safety
+//
+// `client` has to be null or from `chat_connect`, and not used again after this.
+void chat_disconnect(struct ChatClient *client);
+
+// Why the last call on this thread that failed did, or null if none has. It stays valid until another call
+// on this thread fails.
+const char *chat_last_error(void);
+
+// Frees a string from `chat_poll`. Null is fine, and does nothing.
+//
+// # Safety
+//
+// `s` has to be null or from `chat_poll`, and not used again after this.
+void chat_free_string(char *s);
+
+#endif  /* CHAT_CLIENT_H */
@@ -0,0 +1,332 @@
+//! The chat client as a C library, for games or anything else that can call C, like Python through `ctypes`.
+//! It's the same `protocol::client::Connection` the native and browser clients use, over a blocking TCP
+//! socket. The header, `include/chat_client.h`, gets regenerated by cbindgen on every build.
+//!
+//! ```python
+//! chat = ctypes.CDLL("libchat_client.so")
+//! chat.chat_connect.restype = ctypes.c_void_p
+//! client = ctypes.c_void_p(chat.chat_connect(b"localhost", 7878, b"alice", None))
+//! chat.chat_send(client, b"hello")
+//! event = ctypes.c_char_p()
+//! if chat.chat_poll(client, 1000, ctypes.byref(event)) == 1:
+//!     print(event.value)
+//!     chat.chat_free_string(event)
+//! ```
+//!
+//! Events come out as the JSON of a `protocol::client::Event`, and have to go back to `chat_free_string`.
+//! Anything that fails says why through `chat_last_error`.
+//!
+//! Python bots are better off with the `python` feature, a proper `chat_client` module built with maturin.
+
+#[cfg(feature = "python")]
+mod python;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::ptr;
+use std::time::Duration;
+use protocol::client::{Connection, ConnectionError, Event};
+use protocol::response::{self, AuthResponse, Login};
+use protocol::user::{User, UserError};
+use thiserror::Error;
+
+/// Optional features the client asks for in the handshake.
+const FEATURES: &[&str] = &[response::PASSWORD];
+
+/// A connection to a chat server, from `chat_connect`. Opaque to C.
+#[derive(Debug)]
+pub struct ChatClient {
+    stream: TcpStream,
+    connection: Connection,
+    /// What's come in that hasn't been polled for yet.
+    events: VecDeque<Event>,
+}
+
+#[derive(Error, Debug)]
+enum ChatError {
+    #[error("Failed to read/write from stream: `{0}`")]
+    IO(#[from] std::io::Error),
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
+    #[error("Bad nick: `{0}`")]
+    User(#[from] UserError),
+    #[error("Authorization failed: `{0}`")]
+    Auth(AuthResponse),
+    #[error("Server won't talk to this client: `{0}`")]
+    Incompatible(String),
+    #[error("Server closed the connection")]
+    Closed,
+    #[error("No {0} given, or it isn't UTF-8")]
+    BadArgument(&'static str),
+    #[error("No client given")]
+    NoClient,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+impl ChatClient {
+    fn connect(host: &str, port: u16, login: Login) -> Result<Self, ChatError> {
+        login.user.validate()?;
+        let mut client = Self {
+            stream: TcpStream::connect((host, port))?,
+            connection: Connection::new(FEATURES),
+            events: VecDeque::new(),
+        };
+        client.flush()?;
+
+        loop {
+            match client.next_event()? {
+                Event::Accepted(_) => {
+                    client.connection.log_in(&login)?;
+                    client.flush()?;
+                }
+                Event::LoggedIn => return Ok(client),
+                Event::Refused(resp) => return Err(ChatError::Auth(resp)),
+                Event::Incompatible(reason) => return Err(ChatError::Incompatible(reason)),
+                Event::Message(_) => unreachable!("Nothing but answers comes before logging in"),
+            }
+        }
+    }
+
+    /// Blocks until there's an event.
+    fn next_event(&mut self) -> Result<Event, ChatError> {
+        self.stream.set_read_timeout(None)?;
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            self.read()?;
+        }
+    }
+
+    /// The next event if there is one within `timeout`.
+    fn poll(&mut self, timeout: Duration) -> Result<Option<Event>, ChatError> {
+        if self.events.is_empty() {
+            // A zero timeout means none at all to the socket, so it's always at least a millisecond
+            self.stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+            match self.read() {
+                Err(ChatError::IO(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                res => res?,
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+
+    /// Reads whatever's arrived, queueing the events it adds up to and answering pings.
+    fn read(&mut self) -> Result<(), ChatError> {
+        let mut buf = [0; 4096];
+        let n = self.stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(ChatError::Closed);
+        }
+        self.events.extend(self.connection.receive(&buf[..n])?);
+        self.flush()
+    }
+
+    fn send(&mut self, text: &str) -> Result<(), ChatError> {
+        self.connection.send(text)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), ChatError> {
+        Ok(self.stream.write_all(&self.connection.take_outgoing())?)
+    }
+}
+
+/// Remembers `e` for `chat_last_error`, returning `fallback`.
+fn fail<T>(e: ChatError, fallback: T) -> T {
+    // Error messages never have a NUL in them, but there's no sense panicking over it if one does
+    let message = CString::new(e.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    fallback
+}
+
+/// The string at `s`, or `None` if it's null or isn't UTF-8.
+///
+/// # Safety
+///
+/// `s` has to be null or a valid NUL-terminated string.
+unsafe fn string<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Connects to the server at `host` and `port` and logs in as `nick`, with the server's `password` if it
+/// needs one, which can be null otherwise. Blocks until the server's let us in.
+///
+/// Returns null if it couldn't, see `chat_last_error`. Otherwise the client has to go back to
+/// `chat_disconnect` once it's done with.
+///
+/// # Safety
+///
+/// `host` and `nick` have to be valid NUL-terminated strings, and `password` too if it isn't null.
+#[no_mangle]
+pub unsafe extern "C" fn chat_connect(
+    host: *const c_char,
+    port: u16,
+    nick: *const c_char,
+    password: *const c_char,
+) -> *mut ChatClient {
+    let Some(host) = string(host) else {
+        return fail(ChatError::BadArgument("host"), ptr::null_mut());
+    };
+    let Some(nick) = string(nick) else {
+        return fail(ChatError::BadArgument("nick"), ptr::null_mut());
+    };
+    let password = match password.is_null() {
+        true => None,
+        false => match string(password) {
+            Some(password) => Some(password.to_string()),
+            None => return fail(ChatError::BadArgument("password"), ptr::null_mut()),
+        },
+    };
+
+    match ChatClient::connect(host, port, Login::new(User::new(nick), password)) {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => fail(e, ptr::null_mut()),
+    }
+}
+
+/// Sends a line of chat, or a command like `/join #rust`. Returns 0 if it went, or -1 if it didn't, see
+/// `chat_last_error`.
+///
+/// # Safety
+///
+/// `client` has to be from `chat_connect` and not disconnected yet, and `text` a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn chat_send(client: *mut ChatClient, text: *const c_char) -> c_int {
+    let Some(client) = client.as_mut() else {
+        return fail(ChatError::NoClient, -1);
+    };
+    let Some(text) = string(text) else {
+        return fail(ChatError::BadArgument("text"), -1);
+    };
+
+    match client.send(text) {
+        Ok(()) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Waits up to `timeout_ms` for the next thing from the server, answering pings along the way. Returns 1 and
+/// points `event` at its JSON if there was one, 0 if there wasn't, or -1 if the connection's broken, see
+/// `chat_last_error`. Whatever `event` points at has to go back to `chat_free_string`.
+///
+/// # Safety
+///
+/// `client` has to be from `chat_connect` and not disconnected yet, and `event` somewhere to write a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn chat_poll(client: *mut ChatClient, timeout_ms: u32, event: *mut *mut c_char) -> c_int {
+    let Some(client) = client.as_mut() else {
+        return fail(ChatError::NoClient, -1);
+    };
+    if event.is_null() {
+        return fail(ChatError::BadArgument("event"), -1);
+    }
+
+    match client.poll(Duration::from_millis(timeout_ms.into())) {
+        Ok(Some(next)) => {
+            // JSON escapes control characters, so there's never a NUL in it
+            let json = serde_json::to_string(&next).expect("Events always serialize");
+            *event = CString::new(json).expect("JSON has no NULs").into_raw();
+            1
+        }
+        Ok(None) => 0,
+        Err(e) => fail(e, -1),
+    }
+}
+
+/// Hangs up and frees `client`. Null is fine, and does nothing.
+///
+/// # Safety
+///
+/// `client` has to be null or from `chat_connect`, and not used again after this.
+#[no_mangle]
+pub unsafe extern "C" fn chat_disconnect(client: *mut ChatClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Why the last call on this thread that failed did, or null if none has. It stays valid until another call
+/// on this thread fails.
+#[no_mangle]
+pub extern "C" fn chat_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Frees a string from `chat_poll`. Null is fine, and does nothing.
+///
+/// # Safety
+///
+/// `s` has to be null or from `chat_poll`, and not used again after this.
+#[no_mangle]
+pub unsafe extern "C" fn chat_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use protocol::frame::{read_message, write_message, FrameLimits};
+    use protocol::response::{Capabilities, Handshake, ServerMessage, PROTOCOL_VERSION};
+    use super::*;
+
+    #[test]
+    fn connect_poll_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let limits = FrameLimits::default();
+            let _: Handshake = read_message(&mut stream, &limits).unwrap();
+            let accepted = Capabilities::Accepted { version: PROTOCOL_VERSION, features: Vec::new(), time_ms: None };
+            write_message(&mut stream, &accepted).unwrap();
+            let login: Login = read_message(&mut stream, &limits).unwrap();
+            write_message(&mut stream, &AuthResponse::Success).unwrap();
+            write_message(&mut stream, &ServerMessage::Ping { token: 3 }).unwrap();
+            write_message(&mut stream, &ServerMessage::notice("hi")).unwrap();
+
+            let lines: Vec<_> = BufReader::new(stream).lines().take(2).map(Result::unwrap).collect();
+            (login, lines)
+        });
+
+        unsafe {
+            let client = chat_connect(c"127.0.0.1".as_ptr(), port, c"alice".as_ptr(), ptr::null());
+            assert!(!client.is_null());
+
+            let mut event = ptr::null_mut();
+            assert_eq!(1, chat_poll(client, 1000, &mut event));
+            assert_eq!(r#"{"Message":{"Notice":{"text":"hi"}}}"#, CStr::from_ptr(event).to_str().unwrap());
+            chat_free_string(event);
+
+            assert_eq!(0, chat_send(client, c"hello".as_ptr()));
+            chat_disconnect(client);
+        }
+
+        let (login, lines) = server.join().unwrap();
+        assert_eq!("alice", login.user.name);
+        assert_eq!(vec!["/pong 3", "hello"], lines);
+    }
+
+    #[test]
+    fn failures_say_why() {
+        unsafe {
+            assert!(chat_connect(c"127.0.0.1".as_ptr(), 1, c"not a nick!".as_ptr(), ptr::null()).is_null());
+            assert!(CStr::from_ptr(chat_last_error()).to_str().unwrap().starts_with("Bad nick"));
+            assert_eq!(-1, chat_send(ptr::null_mut(), c"hello".as_ptr()));
+            assert_eq!("No client given", CStr::from_ptr(chat_last_error()).to_str().unwrap());
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! The `chat_client` Python module, the same client as the C API but without the pointers. Build it with
+//! `maturin develop` in `ffi/`.
+//!
+//! ```python
+//! import chat_client
+//!
+//! class PingBot:
+//!     def on_connect(self, client):
+//!         client.send("/join #bots")
+//!
+//!     def on_incoming(self, client, sender, text):
+//!         if text == "!ping":
+//!             client.send(f"pong, {sender}")
+//!
+//! chat_client.Client("localhost", 7878, "pingbot").run(PingBot())
+//! ```
+//!
+//! A bot can have any of `on_connect(client)`, `on_incoming(client, sender, text)` for chat and DMs, and
+//! `on_event(client, event)` for everything else. Events are the same as the C API's JSON, as dicts.
+
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+use protocol::client::Event;
+use protocol::response::{Login, ServerMessage};
+use protocol::user::User;
+use pyo3::exceptions::{PyConnectionError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::call::PyCallArgs;
+use crate::{ChatClient, ChatError};
+
+/// How long to wait at a time before checking for Ctrl-C.
+const SIGNAL_CHECK: Duration = Duration::from_millis(100);
+
+/// A connection to a chat server, logged in as soon as it's made.
+#[pyclass(name = "Client", module = "chat_client")]
+struct PyClient(ChatClient);
+
+impl From<ChatError> for PyErr {
+    fn from(e: ChatError) -> Self {
+        match e {
+            ChatError::User(_) | ChatError::BadArgument(_) => PyValueError::new_err(e.to_string()),
+            _ => PyConnectionError::new_err(e.to_string()),
+        }
+    }
+}
+
+#[pymethods]
+impl PyClient {
+    /// Connects to the server at `host` and `port` and logs in as `nick`, with the server's `password` if it
+    /// needs one.
+    #[new]
+    #[pyo3(signature = (host, port, nick, password=None))]
+    fn new(py: Python<'_>, host: &str, port: u16, nick: &str, password: Option<String>) -> PyResult<Self> {
+        let login = Login::new(User::new(nick), password);
+        Ok(Self(py.detach(|| ChatClient::connect(host, port, login))?))
+    }
+
+    /// Sends a line of chat, or a command like `/join #rust`.
+    fn send(&mut self, text: &str) -> PyResult<()> {
+        Ok(self.0.send(text)?)
+    }
+
+    /// The next event from the server, waiting up to `timeout` seconds for one, or forever if it's `None`.
+    /// `None` if there wasn't one in time.
+    #[pyo3(signature = (timeout=None))]
+    fn poll(&mut self, py: Python<'_>, timeout: Option<f64>) -> PyResult<Option<Py<PyAny>>> {
+        let timeout = timeout.map(Duration::try_from_secs_f64).transpose().map_err(|e| PyValueError::new_err(e.to_string()))?;
+        match self.wait(py, timeout)?? {
+            Some(event) => Ok(Some(to_python(py, &event)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Hands everything from the server to `bot`'s hooks until the server hangs up.
+    fn run(slf: &Bound<'_, Self>, bot: &Bound<'_, PyAny>) -> PyResult<()> {
+        let py = slf.py();
+        hook(bot, "on_connect", (slf,))?;
+        loop {
+            // Not borrowed while the hooks run, so they can send
+            let event = match slf.borrow_mut().wait(py, None)? {
+                Ok(event) => event.expect("Waiting forever always ends up with something"),
+                Err(ChatError::Closed) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            match event {
+                Event::Message(ServerMessage::Chat { from, text, .. } | ServerMessage::Private { from, text, .. }) => {
+                    hook(bot, "on_incoming", (slf, from.name, text))?;
+                }
+                event => hook(bot, "on_event", (slf, to_python(py, &event)?))?,
+            }
+        }
+    }
+
+    /// Hangs up. Anything after this raises `ConnectionError`.
+    fn close(&mut self) {
+        let _ = self.0.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl PyClient {
+    /// Polls for up to `timeout`, or forever, a little at a time so Ctrl-C still works. The outer error is
+    /// Ctrl-C, or whatever else Python had to say meanwhile.
+    fn wait(&mut self, py: Python<'_>, timeout: Option<Duration>) -> PyResult<Result<Option<Event>, ChatError>> {
+        let started = Instant::now();
+        loop {
+            let left = timeout.map_or(SIGNAL_CHECK, |timeout| timeout.saturating_sub(started.elapsed()));
+            let client = &mut self.0;
+            match py.detach(|| client.poll(left.min(SIGNAL_CHECK))) {
+                Ok(None) => {}
+                polled => return Ok(polled),
+            }
+            py.check_signals()?;
+            if left.is_zero() {
+                return Ok(Ok(None));
+            }
+        }
+    }
+}
+
+/// `event` as a Python object, the same shape as its JSON.
+fn to_python(py: Python<'_>, event: &Event) -> PyResult<Py<PyAny>> {
+    let json = serde_json::to_string(event).expect("Events always serialize");
+    Ok(py.import("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+/// Calls `bot.name(*args)`, if the bot has it.
+fn hook<'py>(bot: &Bound<'py, PyAny>, name: &str, args: impl PyCallArgs<'py>) -> PyResult<()> {
+    if bot.hasattr(name)? {
+        bot.call_method1(name, args)?;
+    }
+    Ok(())
+}
+
+#[pymodule]
+fn chat_client(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyClient>()
+}
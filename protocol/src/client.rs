@@ -0,0 +1,221 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::frame::{decode_message, encode_message, frame_len, FrameError, FrameLimits, PREFIX_LEN};
+use crate::response::{AuthResponse, Capabilities, Handshake, Login, ServerMessage};
+
+/// A client's side of a connection, without any I/O: what the server sends goes in through `receive` (or
+/// `handle`, a frame at a time), and what to send it comes out of `take_outgoing`. It keeps track of how far
+/// through the handshake and login it's got and answers pings itself, so every client, native or in a
+/// browser, talks to the server the same way.
+#[derive(Debug)]
+pub struct Connection {
+    state: State,
+    limits: FrameLimits,
+    /// What's come in that isn't a whole frame yet.
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Handshaking,
+    Accepted,
+    LoggingIn,
+    Chatting,
+    Closed,
+}
+
+/// Something the server said that the client needs to know about.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The server answered the handshake, so it's time to `log_in`.
+    Accepted(Capabilities),
+    /// The server won't talk to this client at all, and why.
+    Incompatible(String),
+    /// The server turned us away, either before the handshake or after logging in.
+    Refused(AuthResponse),
+    LoggedIn,
+    Message(ServerMessage),
+}
+
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    #[error("Bad frame: `{0}`")]
+    Frame(#[from] FrameError),
+    #[error("Can't {0} until the server's let us in")]
+    TooSoon(&'static str),
+    #[error("Connection is closed")]
+    Closed,
+}
+
+/// What a handshake gets back: `Capabilities`, or an `AuthResponse` from a server turning everyone away
+/// before it's even read the handshake, like when it's full.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Answer {
+    Capabilities(Capabilities),
+    Refused(AuthResponse),
+}
+
+impl Connection {
+    /// A connection that's about to send its handshake, offering `features`.
+    pub fn new(features: &[&str]) -> Self {
+        let mut connection = Self {
+            state: State::Handshaking,
+            limits: FrameLimits::default(),
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        };
+        connection.queue(&Handshake::new(features));
+        connection
+    }
+
+    pub fn with_limits(mut self, limits: FrameLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sends `login` once the server's accepted the handshake.
+    pub fn log_in(&mut self, login: &Login) -> Result<(), ConnectionError> {
+        match self.state {
+            State::Accepted => {
+                self.queue(login);
+                self.state = State::LoggingIn;
+                Ok(())
+            }
+            State::Closed => Err(ConnectionError::Closed),
+            _ => Err(ConnectionError::TooSoon("log in")),
+        }
+    }
+
+    /// Sends a line of chat, or a command.
+    pub fn send(&mut self, text: &str) -> Result<(), ConnectionError> {
+        match self.state {
+            State::Chatting => {
+                self.outgoing.extend(text.trim_end().as_bytes());
+                self.outgoing.push(b'\n');
+                Ok(())
+            }
+            State::Closed => Err(ConnectionError::Closed),
+            _ => Err(ConnectionError::TooSoon("chat")),
+        }
+    }
+
+    /// Takes in whatever's just arrived, however it's been split up, returning what happened in every frame
+    /// that's now complete.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Vec<Event>, ConnectionError> {
+        self.incoming.extend(bytes);
+
+        let mut events = Vec::new();
+        while let Some(len) = frame_len(&self.incoming, &self.limits)? {
+            let frame: Vec<u8> = self.incoming.drain(..len).collect();
+            events.extend(self.handle(&frame[PREFIX_LEN..])?);
+        }
+        Ok(events)
+    }
+
+    /// Takes in one frame's payload. `None` if it was something handled here, like a ping.
+    pub fn handle(&mut self, payload: &[u8]) -> Result<Option<Event>, ConnectionError> {
+        let event = match self.state {
+            State::Handshaking => match decode_message(payload, &self.limits)? {
+                Answer::Capabilities(Capabilities::Rejected(reason)) => Event::Incompatible(reason),
+                Answer::Capabilities(capabilities) => Event::Accepted(capabilities),
+                Answer::Refused(resp) => Event::Refused(resp),
+            },
+            // Someone turned away for being full might not wait for the login to say so
+            State::Accepted | State::LoggingIn => match decode_message(payload, &self.limits)? {
+                AuthResponse::Success => Event::LoggedIn,
+                resp => Event::Refused(resp),
+            },
+            State::Chatting => match decode_message(payload, &self.limits)? {
+                ServerMessage::Ping { token } => {
+                    self.outgoing.extend(format!("/pong {token}\n").as_bytes());
+                    return Ok(None);
+                }
+                msg => Event::Message(msg),
+            },
+            State::Closed => return Err(ConnectionError::Closed),
+        };
+
+        self.state = match &event {
+            Event::Accepted(_) => State::Accepted,
+            Event::LoggedIn | Event::Message(_) => State::Chatting,
+            Event::Incompatible(_) | Event::Refused(_) => State::Closed,
+        };
+        Ok(Some(event))
+    }
+
+    /// Everything waiting to go to the server, in order.
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.outgoing)
+    }
+
+    /// Whether the server's let us in and we're chatting.
+    pub fn is_chatting(&self) -> bool {
+        self.state == State::Chatting
+    }
+
+    fn queue<T: Serialize>(&mut self, msg: &T) {
+        self.outgoing.extend(encode_message(msg).expect("Handshakes and logins always serialize"));
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::response::PROTOCOL_VERSION;
+    use crate::user::User;
+    use super::*;
+
+    fn accepted() -> Vec<u8> {
+        encode_message(&Capabilities::Accepted { version: PROTOCOL_VERSION, features: Vec::new(), time_ms: None }).unwrap()
+    }
+
+    #[test]
+    fn handshake_then_login_then_chat() {
+        let mut connection = Connection::new(&[]);
+        assert_eq!(encode_message(&Handshake::new(&[])).unwrap(), connection.take_outgoing());
+        assert!(matches!(connection.send("hi"), Err(ConnectionError::TooSoon(_))));
+
+        let events = connection.receive(&accepted()).unwrap();
+        assert!(matches!(events.as_slice(), [Event::Accepted(_)]));
+
+        let login = Login::new(User::new("alice"), None);
+        connection.log_in(&login).unwrap();
+        assert_eq!(encode_message(&login).unwrap(), connection.take_outgoing());
+
+        // The answer and the first message, split somewhere awkward
+        let notice = ServerMessage::notice("hello");
+        let bytes = [encode_message(&AuthResponse::Success).unwrap(), encode_message(&notice).unwrap()].concat();
+        let (first, rest) = bytes.split_at(7);
+        assert!(connection.receive(first).unwrap().is_empty());
+        assert_eq!(vec![Event::LoggedIn, Event::Message(notice)], connection.receive(rest).unwrap());
+
+        connection.send("hi there  \n").unwrap();
+        assert_eq!(b"hi there\n", connection.take_outgoing().as_slice());
+    }
+
+    #[test]
+    fn answers_pings_itself() {
+        let mut connection = Connection::new(&[]);
+        connection.receive(&accepted()).unwrap();
+        connection.log_in(&Login::new(User::new("alice"), None)).unwrap();
+        connection.receive(&encode_message(&AuthResponse::Success).unwrap()).unwrap();
+        connection.take_outgoing();
+
+        let events = connection.receive(&encode_message(&ServerMessage::Ping { token: 9 }).unwrap()).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(b"/pong 9\n", connection.take_outgoing().as_slice());
+    }
+
+    #[test]
+    fn turned_away_before_the_handshake() {
+        let mut connection = Connection::new(&[]);
+        let events = connection.receive(&encode_message(&AuthResponse::ServerFull).unwrap()).unwrap();
+
+        assert_eq!(vec![Event::Refused(AuthResponse::ServerFull)], events);
+        assert!(matches!(connection.log_in(&Login::new(User::new("alice"), None)), Err(ConnectionError::Closed)));
+    }
+}
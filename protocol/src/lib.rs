@@ -0,0 +1,18 @@
+//! What goes over the wire between the chat server and its clients: `frame` is how messages are packed into
+//! length-prefixed JSON frames, `response` is what's in them, and `user` is who's talking. `client` is a
+//! client's half of the conversation with no I/O of its own, for every client to drive however it connects.
+//! `testing` has streams for tests, with the `test-support` feature. Everything else is up to whoever's on either end.
+//!
+//! Builds without `std`, for clients on microcontrollers or in WASM. Encoding and decoding frames only needs
+//! `alloc`, and reading and writing them straight off streams comes with the `std` feature.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod client;
+pub mod frame;
+pub mod response;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+pub mod user;
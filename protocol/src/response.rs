@@ -0,0 +1,305 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::user::User;
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest one a server still lets in. Clients from before there were handshakes count as version 0.
+pub const OLDEST_SUPPORTED: u32 = 1;
+
+/// The handshake feature for a server that needs a password, so the client knows to ask for one.
+pub const PASSWORD: &str = "password";
+
+/// What a client sends first, before its `Login`: the newest protocol version it speaks, and the optional
+/// features it can use.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// The server's answer to a `Handshake`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Capabilities {
+    /// The version to speak, and which of the client's features the server's going to use. `time_ms` is when
+    /// the server answered, in milliseconds since the Unix epoch by its clock, so the client can tell how far
+    /// off its own is.
+    Accepted {
+        version: u32,
+        features: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        time_ms: Option<u64>,
+    },
+    /// Why they can't talk, before the client bothers sending its `User`.
+    Rejected(String),
+}
+
+impl Handshake {
+    /// This build's handshake, offering `features`.
+    pub fn new(features: &[&str]) -> Self {
+        Self { version: PROTOCOL_VERSION, features: features.iter().map(|f| f.to_string()).collect() }
+    }
+
+    /// The answer from a server that can use `supported`: the newest version both sides speak, and the
+    /// features both sides know.
+    pub fn answer(&self, supported: &[&str]) -> Capabilities {
+        if self.version < OLDEST_SUPPORTED {
+            return Capabilities::Rejected(format!(
+                "Protocol version {} is too old, this server needs {OLDEST_SUPPORTED} or newer",
+                self.version
+            ));
+        }
+
+        let features = self.features.iter().filter(|f| supported.contains(&f.as_str())).cloned().collect();
+        Capabilities::Accepted { version: self.version.min(PROTOCOL_VERSION), features, time_ms: None }
+    }
+}
+
+impl Capabilities {
+    pub fn has(&self, feature: &str) -> bool {
+        matches!(self, Capabilities::Accepted { features, .. } if features.iter().any(|f| f == feature))
+    }
+
+    /// Says the server's clock reads `now`, if these let the client in.
+    #[cfg(feature = "std")]
+    pub fn stamped(mut self, now: SystemTime) -> Self {
+        if let Capabilities::Accepted { time_ms, .. } = &mut self {
+            // Only fails if the clock is set before 1970, which there's no sense passing on
+            *time_ms = now.duration_since(UNIX_EPOCH).ok().map(|since| since.as_millis() as u64);
+        }
+        self
+    }
+}
+
+/// What a client sends after the handshake: who it is, the server's password if it has one, and its nick's
+/// if that's registered. Without passwords it's just the `User`, which is what it used to be.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_password: Option<String>,
+}
+
+impl Login {
+    pub fn new(user: User, password: Option<String>) -> Self {
+        Self { user, password, account_password: None }
+    }
+
+    /// Logs in to the account `user`'s nick is registered to with `account_password`.
+    pub fn identified(mut self, account_password: Option<String>) -> Self {
+        self.account_password = account_password;
+        self
+    }
+
+    /// Whether this gets past `password`, if the server has one.
+    pub fn knows(&self, password: Option<&str>) -> bool {
+        password.is_none_or(|password| same_secret(self.password.as_deref().unwrap_or_default(), password))
+    }
+}
+
+/// Whether `given` is `secret`. Compared in constant time, so how long it takes doesn't give away how much of it
+/// was right.
+pub fn same_secret(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && given.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(Serialize, Deserialize, Debug, Error, PartialEq, Eq)]
+pub enum AuthResponse {
+    // We don't construct this as an error ever
+    #[error("")]
+    Success,
+    #[error("{0}")]
+    Error(String),
+    #[error("Wrong password, or none given for a server that needs one")]
+    BadPassword,
+    #[error("Server is full, try again later")]
+    ServerFull,
+    /// Too many are connecting at once, like everyone coming back after a restart. Told when to try again,
+    /// jittered so they don't all come back at once.
+    #[error("Too many are connecting at once, try again in {retry_after_ms} ms")]
+    Busy { retry_after_ms: u64 },
+}
+
+impl AuthResponse {
+    /// What a client that went straight to its `User` gets told. It's from before there were handshakes, so
+    /// this is the only thing it'd understand.
+    pub fn needs_handshake() -> Self {
+        AuthResponse::Error(format!("This server needs a newer client, one that speaks protocol version {OLDEST_SUPPORTED} or newer"))
+    }
+}
+
+/// Anything the server sends to an authorized client, one per frame.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    Chat {
+        from: User,
+        text: String,
+        /// When the server read the message, in milliseconds since the Unix epoch. Lets clients work out
+        /// end-to-end delivery latency. Zero if the server didn't say.
+        #[serde(default)]
+        received_ms: u64,
+        /// Where it was said, or `None` for the lobby everyone who hasn't joined a channel is in.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+    },
+    /// Said to just `to`, with `/msg`.
+    Private { from: User, to: User, text: String },
+    /// Something from the server itself rather than another user, like an operator's wallops. When it's
+    /// telling the client to hold off, like for sending too fast, `retry_after_ms` is how long for.
+    Notice {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// `user` connecting or disconnecting, sent to everyone else. Not chat, so clients can show it quieter.
+    Presence { user: User, change: PresenceChange },
+    /// The answer to `/names`: who's connected to this server, or just who's in `channel` if the client asked
+    /// about one, by nick.
+    Names {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        users: Vec<Member>,
+    },
+    /// The answer to `/list`: every channel on this server, with how many are in it.
+    Channels { channels: BTreeMap<String, usize> },
+    /// `message` numbered within its buffer (a channel, the lobby, someone's DMs, or notices), so a client
+    /// can say where it got up to. Only bouncers send these, see `backfill`.
+    Sequenced { buffer: String, seq: u64, message: Box<ServerMessage> },
+    /// Checking the connection's still there after it's been quiet for a while. Clients answer with
+    /// `/pong <token>` straight away, or get disconnected.
+    Ping { token: u64 },
+    /// The server throwing this client off, for being kicked, banned or `/kill`ed, with why. It hangs up
+    /// straight after, so this is how a client tells that apart from the connection just dropping.
+    Disconnected { reason: String },
+}
+
+/// Someone in a `ServerMessage::Names`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub user: User,
+    /// How long since they last said anything, in seconds, if the server keeps track.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+}
+
+/// What a `ServerMessage::Presence` says happened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PresenceChange {
+    Joined,
+    /// With why, if it's more than them hanging up.
+    Quit { reason: Option<String> },
+}
+
+impl Display for PresenceChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PresenceChange::Joined => write!(f, "has joined"),
+            PresenceChange::Quit { reason: None } => write!(f, "has quit"),
+            PresenceChange::Quit { reason: Some(reason) } => write!(f, "has quit ({reason})"),
+        }
+    }
+}
+
+/// What goes between the old and new nick in the notice that someone's changed it.
+const RENAMED: &str = " is now known as ";
+
+impl ServerMessage {
+    /// A notice that isn't asking anything of the client.
+    pub fn notice(text: impl Into<String>) -> Self {
+        ServerMessage::Notice { text: text.into(), retry_after_ms: None }
+    }
+
+    /// The notice telling everyone `from` goes by `to` now.
+    pub fn renamed(from: &User, to: &User) -> Self {
+        ServerMessage::notice(format!("{from}{RENAMED}{to}"))
+    }
+
+    /// The old and new nick, if this is a `renamed` notice.
+    pub fn as_renamed(&self) -> Option<(&str, &str)> {
+        match self {
+            ServerMessage::Notice { text, .. } => text.split_once(RENAMED),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn handshake_answers() {
+        let handshake = Handshake { version: PROTOCOL_VERSION + 1, features: vec!["backfill".into(), "nope".into()] };
+        let answer = handshake.answer(&["backfill", "other"]);
+        let features = vec!["backfill".into()];
+        assert_eq!(Capabilities::Accepted { version: PROTOCOL_VERSION, features, time_ms: None }, answer);
+        assert!(answer.has("backfill") && !answer.has("other"));
+
+        let old = Handshake { version: OLDEST_SUPPORTED - 1, features: vec!["backfill".into()] };
+        assert!(matches!(old.answer(&["backfill"]), Capabilities::Rejected(_)));
+        assert!(!old.answer(&["backfill"]).has("backfill"));
+        assert_eq!(old.answer(&[]), old.answer(&[]).stamped(SystemTime::now()));
+        let stamped = handshake.answer(&[]).stamped(UNIX_EPOCH + Duration::from_millis(1_234));
+        assert!(matches!(stamped, Capabilities::Accepted { time_ms: Some(1_234), .. }));
+
+        // Clients that don't know about features yet can leave them out
+        let bare: Handshake = serde_json::from_str(r#"{"version":1}"#).unwrap();
+        assert_eq!(Handshake::new(&[]), bare);
+    }
+
+    #[test]
+    fn logins() {
+        let user = User::new("alice").with_display_name("Alice");
+        let login = Login::new(user.clone(), Some("hunter2".to_string()));
+        let json = serde_json::to_string(&login).unwrap();
+        assert_eq!(r#"{"name":"alice","display_name":"Alice","password":"hunter2"}"#, json);
+
+        // A plain `User` is a login with no password, and the other way round
+        let bare: Login = serde_json::from_str(&serde_json::to_string(&user).unwrap()).unwrap();
+        assert_eq!(Login::new(user.clone(), None), bare);
+        assert_eq!(user, serde_json::from_str::<User>(&json).unwrap());
+
+        assert!(login.knows(Some("hunter2")) && login.knows(None));
+        assert!(!login.knows(Some("hunter3")) && !login.knows(Some("hunter")));
+        assert!(!bare.knows(Some("hunter2")) && bare.knows(None));
+
+        let identified = Login::new(user, None).identified(Some("sesame".to_string()));
+        let json = serde_json::to_string(&identified).unwrap();
+        assert_eq!(r#"{"name":"alice","display_name":"Alice","account_password":"sesame"}"#, json);
+        assert_eq!(identified, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn presence() {
+        let quit = ServerMessage::Presence { user: User::new("alice"), change: PresenceChange::Quit { reason: None } };
+        let json = serde_json::to_string(&quit).unwrap();
+        assert_eq!(r#"{"Presence":{"user":{"name":"alice"},"change":{"Quit":{"reason":null}}}}"#, json);
+        assert_eq!(quit, serde_json::from_str(&json).unwrap());
+
+        assert_eq!("has joined", PresenceChange::Joined.to_string());
+        let reset = PresenceChange::Quit { reason: Some("connection reset".to_string()) };
+        assert_eq!("has quit (connection reset)", reset.to_string());
+    }
+
+    #[test]
+    fn names() {
+        let alice = Member { user: User::new("alice"), idle_secs: Some(90) };
+        let names = ServerMessage::Names { channel: None, users: vec![alice, Member { user: User::new("bob"), idle_secs: None }] };
+        let json = serde_json::to_string(&names).unwrap();
+        assert_eq!(r#"{"Names":{"users":[{"user":{"name":"alice"},"idle_secs":90},{"user":{"name":"bob"}}]}}"#, json);
+        assert_eq!(names, serde_json::from_str(&json).unwrap());
+    }
+}
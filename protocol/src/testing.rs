@@ -0,0 +1,26 @@
+//! Streams for testing anything that reads frames, here and in the crates built on this one.
+
+#![cfg(feature = "std")]
+
+use std::io::{Cursor, Read, Write};
+
+/// A stream that only ever hands out a single byte per `read`, like a really bad network would.
+#[derive(Debug, Clone)]
+pub struct Trickle(pub Cursor<Vec<u8>>);
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let end = buf.len().min(1);
+        self.0.read(&mut buf[..end])
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
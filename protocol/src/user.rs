@@ -0,0 +1,176 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use unicode_security::confusable_detection::skeleton;
+
+/// Longest a nick can be, in bytes. They're ASCII anyway.
+pub const MAX_NICK_LEN: usize = 30;
+/// Longest a display name can be, in characters.
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UserError {
+    #[error("Nick has to be 1-{MAX_NICK_LEN} letters, digits or any of _-[]\\`^{{}}|, not starting with a digit or -")]
+    BadNick,
+    #[error("Display name has to be 1-{MAX_DISPLAY_NAME_LEN} characters with no control characters")]
+    BadDisplayName,
+}
+
+/// Someone connected to the server. `name` is their login nick, which is what everything on the server
+/// goes by, so two users with the same nick are the same user whatever they're displayed as.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    /// What clients should show instead of the nick, if anything. Can be any old Unicode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+impl User {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            display_name: None,
+        }
+    }
+
+    pub fn with_display_name(mut self, display_name: impl Into<String>) -> Self {
+        self.display_name = Some(display_name.into());
+        self
+    }
+
+    /// What to show this user as.
+    pub fn shown(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Whether one of us could pass for the other. Nicks and display names are compared with case and
+    /// lookalike characters folded away, so `aIice`, `ALICE` and a Cyrillic `аlice` all look like `alice`.
+    pub fn looks_like(&self, other: &User) -> bool {
+        let theirs: Vec<_> = other.folded_names().collect();
+        self.folded_names().any(|name| theirs.contains(&name))
+    }
+
+    /// Every name this user goes by, folded the way `looks_like` compares them. Two users look alike if they
+    /// share any of these, so they can be looked up instead of compared one by one.
+    pub fn folded_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.names().flat_map(folds)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        core::iter::once(self.name.as_str()).chain(self.display_name.as_deref())
+    }
+
+    /// Nicks stick to the usual IRC charset so they're easy to type and tell apart. Display names are
+    /// where people get to be creative.
+    pub fn validate(&self) -> Result<(), UserError> {
+        let nick = &self.name;
+        if nick.is_empty()
+            || nick.len() > MAX_NICK_LEN
+            || !nick.chars().all(is_nick_char)
+            || nick.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        {
+            return Err(UserError::BadNick);
+        }
+
+        if let Some(display_name) = &self.display_name {
+            let len = display_name.chars().count();
+            if len == 0 || len > MAX_DISPLAY_NAME_LEN || display_name.chars().any(char::is_control) {
+                return Err(UserError::BadDisplayName);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// UTS #39 skeletons of `name`, ignoring case. Lowercasing first and last both lose something (`I` looks
+/// like `l` but `i` doesn't), so names that share either one look alike.
+fn folds(name: &str) -> [String; 2] {
+    let lowered_first = skeleton(&name.to_lowercase()).collect();
+    let lowered_last = skeleton(name).collect::<String>().to_lowercase();
+    [lowered_first, skeleton(&lowered_last).collect()]
+}
+
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for User {}
+
+impl PartialOrd for User {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for User {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name.cmp(&other.name)
+    }
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Whether `c` can be in a nick.
+pub fn is_nick_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-[]\\`^{}|".contains(c)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nicks_are_restricted() {
+        for nick in ["alice", "Bob_99", "[away]", "a-b"] {
+            assert_eq!(Ok(()), User::new(nick).validate(), "{nick}");
+        }
+        for nick in ["", "9lives", "-dash", "has space", "ålice", "nick\n", &"a".repeat(MAX_NICK_LEN + 1)] {
+            assert_eq!(Err(UserError::BadNick), User::new(nick).validate(), "{nick:?}");
+        }
+    }
+
+    #[test]
+    fn display_names_can_be_unicode() {
+        assert_eq!(Ok(()), User::new("alice").with_display_name("Älice 🌸").validate());
+        assert_eq!(Err(UserError::BadDisplayName), User::new("alice").with_display_name("").validate());
+        assert_eq!(Err(UserError::BadDisplayName), User::new("alice").with_display_name("a\u{7}").validate());
+        assert_eq!(Err(UserError::BadDisplayName), User::new("alice").with_display_name("é".repeat(33)).validate());
+    }
+
+    #[test]
+    fn lookalikes() {
+        let alice = User::new("alice");
+        for other in ["ALICE", "aIice", "a1ice", "alIce"] {
+            assert!(User::new(other).looks_like(&alice), "{other}");
+        }
+        assert!(User::new("bob").with_display_name("\u{430}lice").looks_like(&alice));
+        assert!(alice.looks_like(&User::new("bob").with_display_name("Alice")));
+
+        assert!(!User::new("alicia").looks_like(&alice));
+        assert!(!User::new("bob").with_display_name("Bobby").looks_like(&alice));
+    }
+
+    #[test]
+    fn display_name_doesnt_change_who_you_are() {
+        let alice = User::new("alice").with_display_name("Alice");
+        assert_eq!(User::new("alice"), alice);
+        assert_eq!("Alice", alice.shown());
+        assert_eq!("alice", User::new("alice").shown());
+
+        // And stays off the wire unless it's set
+        assert_eq!(r#"{"name":"alice"}"#, serde_json::to_string(&User::new("alice")).unwrap());
+        let parsed: User = serde_json::from_str(r#"{"name":"alice","display_name":"Alice"}"#).unwrap();
+        assert_eq!(Some("Alice"), parsed.display_name.as_deref());
+    }
+}
@@ -0,0 +1,255 @@
+use std::io::{Error, ErrorKind, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Hard I/O budget for a single connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoLimits {
+    pub bytes_per_sec: u64,
+    pub lines_per_sec: u64,
+    /// Seconds in a row a connection may go over budget before it's cut off.
+    pub max_strikes: u32,
+}
+
+impl Default for IoLimits {
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: 64 * 1024,
+            lines_per_sec: 50,
+            max_strikes: 5,
+        }
+    }
+}
+
+/// What to do with a connection after checking its usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Ok,
+    /// Over budget for this second, so stop reading from it for this long.
+    Throttle(Duration),
+    /// Over budget for too long, cut it off.
+    Disconnect,
+}
+
+/// Counts what a connection sends per one-second window, plus running totals.
+#[derive(Debug, Clone)]
+pub struct IoMeter {
+    limits: IoLimits,
+    window_start: Instant,
+    window_bytes: u64,
+    window_lines: u64,
+    // Whether the current window already counted as a strike
+    window_struck: bool,
+    strikes: u32,
+    pub total_bytes: u64,
+    pub total_lines: u64,
+}
+
+impl IoMeter {
+    pub fn new(limits: IoLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            window_start: now,
+            window_bytes: 0,
+            window_lines: 0,
+            window_struck: false,
+            strikes: 0,
+            total_bytes: 0,
+            total_lines: 0,
+        }
+    }
+
+    pub fn record_bytes(&mut self, n: u64, now: Instant) {
+        self.roll(now);
+        self.window_bytes += n;
+        self.total_bytes += n;
+    }
+
+    pub fn record_line(&mut self, now: Instant) {
+        self.roll(now);
+        self.window_lines += 1;
+        self.total_lines += 1;
+    }
+
+    /// Seconds in a row it's gone over its limits, and how many of those it gets before being cut off.
+    pub fn strikes(&self) -> (u32, u32) {
+        (self.strikes, self.limits.max_strikes)
+    }
+
+    pub fn verdict(&mut self, now: Instant) -> Verdict {
+        self.roll(now);
+
+        let over = self.window_bytes > self.limits.bytes_per_sec || self.window_lines > self.limits.lines_per_sec;
+        if !over {
+            return Verdict::Ok;
+        }
+
+        if !self.window_struck {
+            self.window_struck = true;
+            self.strikes += 1;
+        }
+
+        if self.strikes > self.limits.max_strikes {
+            Verdict::Disconnect
+        } else {
+            Verdict::Throttle((self.window_start + WINDOW).saturating_duration_since(now))
+        }
+    }
+
+    /// Starts a new window if the current one is over. Going a whole window without getting struck
+    /// forgives every strike so far.
+    fn roll(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.window_start) < WINDOW {
+            return;
+        }
+
+        if !self.window_struck {
+            self.strikes = 0;
+        }
+        self.window_start = now;
+        self.window_bytes = 0;
+        self.window_lines = 0;
+        self.window_struck = false;
+    }
+}
+
+/// Wraps a reader so every byte read from it counts against its connection's `IoMeter`. Reads stall while
+/// the connection is over budget and fail outright once it's been over budget for too long.
+#[derive(Debug)]
+pub struct Metered<R> {
+    inner: R,
+    meter: Arc<Mutex<IoMeter>>,
+}
+
+impl<R> Metered<R> {
+    pub fn new(inner: R, meter: Arc<Mutex<IoMeter>>) -> Self {
+        Self { inner, meter }
+    }
+}
+
+/// Applies a verdict by sleeping through a throttle, or failing on a disconnect.
+pub fn enforce(meter: &Mutex<IoMeter>) -> std::io::Result<()> {
+    // Don't hold the lock while sleeping
+    let verdict = meter.lock().verdict(Instant::now());
+    match verdict {
+        Verdict::Ok => Ok(()),
+        Verdict::Throttle(pause) => {
+            thread::sleep(pause);
+            Ok(())
+        }
+        Verdict::Disconnect => Err(over_budget()),
+    }
+}
+
+/// What reading a connection comes to once it's been over its I/O budget for too long, however it's read.
+pub fn over_budget() -> Error {
+    Error::new(ErrorKind::QuotaExceeded, "Connection went over its I/O budget for too long")
+}
+
+impl<R: Read> Read for Metered<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        enforce(&self.meter)?;
+        let n = self.inner.read(buf)?;
+        self.meter.lock().record_bytes(n as u64, Instant::now());
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    const LIMITS: IoLimits = IoLimits { bytes_per_sec: 100, lines_per_sec: 2, max_strikes: 2 };
+
+    #[test]
+    fn under_budget_is_fine() {
+        let start = Instant::now();
+        let mut meter = IoMeter::new(LIMITS, start);
+        meter.record_bytes(100, start);
+        meter.record_line(start);
+        meter.record_line(start);
+
+        assert_eq!(Verdict::Ok, meter.verdict(start));
+    }
+
+    #[test]
+    fn over_budget_throttles_until_window_ends() {
+        let start = Instant::now();
+        let mut meter = IoMeter::new(LIMITS, start);
+        meter.record_bytes(101, start);
+
+        let now = start + Duration::from_millis(300);
+        assert_eq!(Verdict::Throttle(Duration::from_millis(700)), meter.verdict(now));
+        // Still the same window, so still the same strike
+        assert_eq!(Verdict::Throttle(Duration::from_millis(700)), meter.verdict(now));
+        assert_eq!(1, meter.strikes);
+
+        let next_window = start + Duration::from_secs(1);
+        assert_eq!(Verdict::Ok, meter.verdict(next_window));
+    }
+
+    #[test]
+    fn too_many_lines_counts_too() {
+        let start = Instant::now();
+        let mut meter = IoMeter::new(LIMITS, start);
+        (0..3).for_each(|_| meter.record_line(start));
+
+        assert!(matches!(meter.verdict(start), Verdict::Throttle(_)));
+    }
+
+    #[test]
+    fn repeat_offenders_get_disconnected() {
+        let mut now = Instant::now();
+        let mut meter = IoMeter::new(LIMITS, now);
+
+        for _ in 0..LIMITS.max_strikes {
+            meter.record_bytes(1000, now);
+            assert!(matches!(meter.verdict(now), Verdict::Throttle(_)));
+            now += WINDOW;
+        }
+
+        meter.record_bytes(1000, now);
+        assert_eq!(Verdict::Disconnect, meter.verdict(now));
+        assert_eq!(3000, meter.total_bytes);
+    }
+
+    #[test]
+    fn clean_window_forgives_strikes() {
+        let mut now = Instant::now();
+        let mut meter = IoMeter::new(LIMITS, now);
+
+        meter.record_bytes(1000, now);
+        meter.verdict(now);
+        now += WINDOW;
+        meter.verdict(now);
+        now += WINDOW;
+        meter.verdict(now);
+
+        assert_eq!(0, meter.strikes);
+    }
+
+    #[test]
+    fn metered_reader_counts_bytes() {
+        let meter = Arc::new(Mutex::new(IoMeter::new(IoLimits::default(), Instant::now())));
+        let mut reader = Metered::new(Cursor::new(vec![0; 64]), meter.clone());
+
+        let mut sink = Vec::new();
+        reader.read_to_end(&mut sink).unwrap();
+        assert_eq!(64, meter.lock().total_bytes);
+    }
+
+    #[test]
+    fn metered_reader_cuts_off_abuse() {
+        let limits = IoLimits { bytes_per_sec: 10, lines_per_sec: 10, max_strikes: 0 };
+        let meter = Arc::new(Mutex::new(IoMeter::new(limits, Instant::now())));
+        let mut reader = Metered::new(Cursor::new(vec![0; 64]), meter.clone());
+
+        let mut sink = Vec::new();
+        assert!(reader.read_to_end(&mut sink).is_err());
+    }
+}
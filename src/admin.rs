@@ -0,0 +1,251 @@
+//! The admin socket, a Unix socket for operators to keep an eye on the server over without joining as a user.
+//! Each connection sends one command on a line and gets lines back:
+//!
+//! - `tail [--channel #x]` follows live chat, everywhere or in just that channel.
+//! - `tail --level info|warn` follows the server's logs at that level or above instead.
+//! - `inspect <nick>` shows how someone's connection is doing.
+//!
+//! `tail` keeps going until the admin hangs up. `--mode admin` talks to it, as does anything else that can talk
+//! to a Unix socket, like `socat`.
+//!
+//! Unix only, there's no named-pipe version for Windows since nothing here is built or tested there. The admin
+//! HTTP API in `control` works everywhere, so that's what's left on Windows.
+
+use std::io::{copy, stdout, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+use crate::outbound::Outbound;
+use crate::registry::Registry;
+use crate::tail::{self, Follow};
+use crate::user::User;
+
+/// How often to check on an admin who's following something quiet, in case they've hung up.
+const CHECK_IN: Duration = Duration::from_secs(1);
+
+/// What an admin can ask for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AdminCommand {
+    Tail(Follow),
+    Inspect(String),
+}
+
+impl AdminCommand {
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("tail") => {
+                let (mut channel, mut level) = (None, None);
+                while let Some(option) = words.next() {
+                    let value = words.next().ok_or_else(|| format!("{option} needs a value"))?;
+                    match option {
+                        "--channel" => channel = Some(value.to_string()),
+                        "--level" => level = Some(value.parse()?),
+                        _ => return Err(format!("tail doesn't take {option}")),
+                    }
+                }
+                match (channel, level) {
+                    (Some(_), Some(_)) => Err("tail follows chat with --channel or logs with --level, not both".to_string()),
+                    (channel, None) => Ok(AdminCommand::Tail(Follow::Chat { channel })),
+                    (None, Some(level)) => Ok(AdminCommand::Tail(Follow::Logs(level))),
+                }
+            }
+            Some("inspect") => match (words.next(), words.next()) {
+                (Some(nick), None) => Ok(AdminCommand::Inspect(nick.to_string())),
+                _ => Err("inspect takes the nick to look at".to_string()),
+            },
+            Some(other) => Err(format!("No such command `{other}`, try tail or inspect")),
+            None => Err("No command given, try tail or inspect".to_string()),
+        }
+    }
+}
+
+/// Takes admins on a Unix socket, which is removed again once this is dropped.
+#[derive(Debug)]
+pub struct AdminSocket {
+    path: PathBuf,
+}
+
+impl AdminSocket {
+    /// Starts taking admins on `path`, cleaning up after a server that's gone if there was one. `users` is who
+    /// there is to inspect.
+    pub(crate) fn listen(path: &Path, users: Arc<Registry<Outbound>>) -> std::io::Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(ErrorKind::AddrInUse, "There's already a server on that admin socket"));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        thread::spawn(move || {
+            for admin in listener.incoming() {
+                match admin {
+                    Ok(admin) => {
+                        let users = users.clone();
+                        thread::spawn(move || serve(admin, &users));
+                    }
+                    Err(e) => warn!("[ADMIN] Failed to accept an admin: {e:?}"),
+                }
+            }
+        });
+
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Does what `admin` asks.
+fn serve(mut admin: UnixStream, users: &Registry<Outbound>) {
+    let Ok(reader) = admin.try_clone() else {
+        return;
+    };
+    let mut line = String::new();
+    if BufReader::new(reader).read_line(&mut line).is_err() {
+        return;
+    }
+
+    let command = match AdminCommand::parse(&line) {
+        Ok(command) => command,
+        Err(e) => {
+            let _ = writeln!(admin, "error: {e}");
+            return;
+        }
+    };
+    match command {
+        AdminCommand::Tail(what) => {
+            let lines = tail::follow(what);
+            loop {
+                match lines.recv_timeout(CHECK_IN) {
+                    Ok(line) => {
+                        if writeln!(admin, "{line}").is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) if !hung_up(&admin) => {}
+                    Err(_) => return,
+                }
+            }
+        }
+        AdminCommand::Inspect(nick) => {
+            let _ = writeln!(admin, "{}", inspect(users, &nick));
+        }
+    }
+}
+
+/// Everything there is to know about `nick`'s connection.
+fn inspect(users: &Registry<Outbound>, nick: &str) -> String {
+    let Some(outbound) = users.get(&User::new(nick)) else {
+        return format!("error: Nobody's connected here as {nick}");
+    };
+    match outbound.diagnostics() {
+        Some(diagnostics) => diagnostics.report(nick, outbound.waiting(), outbound.bytes_written(), SystemTime::now()),
+        None => format!("error: {nick} is here, but there's nothing to say about their connection"),
+    }
+}
+
+/// Whether `admin` has gone. They've nothing more to say after their command, so anything they do is dropped.
+fn hung_up(admin: &UnixStream) -> bool {
+    if admin.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let gone = match (&*admin).read(&mut [0; 64]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+    gone || admin.set_nonblocking(false).is_err()
+}
+
+/// Sends `command` to the server's admin socket at `path` and prints what comes back, until the server's
+/// done answering or this gets interrupted.
+pub fn run(path: &Path, command: &str) -> std::io::Result<()> {
+    let mut server = UnixStream::connect(path)?;
+    writeln!(server, "{command}")?;
+    copy(&mut server, &mut stdout().lock())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Instant;
+    use tracing_subscriber::layer::SubscriberExt;
+    use crate::accounting::{IoLimits, IoMeter};
+    use crate::diagnostics::Diagnostics;
+    use crate::tail::{Level, Tail};
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let parse = AdminCommand::parse;
+        assert_eq!(Ok(AdminCommand::Tail(Follow::Chat { channel: None })), parse("tail\n"));
+        assert_eq!(Ok(AdminCommand::Tail(Follow::Chat { channel: Some("#rust".to_string()) })), parse("tail --channel #rust"));
+        assert_eq!(Ok(AdminCommand::Tail(Follow::Logs(Level::Warn))), parse("tail --level warn"));
+        assert!(parse("tail --level").is_err());
+        assert!(parse("tail --level loud").is_err());
+        assert!(parse("tail --channel #rust --level info").is_err());
+        assert!(parse("tail --follow yes").is_err());
+        assert_eq!(Ok(AdminCommand::Inspect("alice".to_string())), parse("inspect alice\n"));
+        assert!(parse("inspect").is_err());
+        assert!(parse("inspect alice bob").is_err());
+        assert!(parse("reboot").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn inspects() {
+        let users: Registry<Outbound> = Default::default();
+        let meter = Arc::new(parking_lot::Mutex::new(IoMeter::new(IoLimits::default(), Instant::now())));
+        let diagnostics = Arc::new(Diagnostics::new(IpAddr::from([10, 0, 0, 7]), meter));
+        diagnostics.violation("Sent a line over 16 bytes");
+        let (outbound, _queue) = Outbound::new(4);
+        users.claim_nick(&User::new("alice"), outbound.with_diagnostics(diagnostics)).unwrap();
+        users.claim_nick(&User::new("bob"), Outbound::new(4).0).unwrap();
+
+        let report = inspect(&users, "alice");
+        assert!(report.starts_with("alice, from 10.0.0.7\n"), "{report}");
+        assert!(report.contains("Send queue: 0 of 4 frames") && report.ends_with("just now: Sent a line over 16 bytes"), "{report}");
+        assert!(inspect(&users, "bob").starts_with("error: bob is here, but"));
+        assert_eq!("error: Nobody's connected here as carol", inspect(&users, "carol"));
+    }
+
+    #[test]
+    fn tails_over_the_socket() {
+        let _logging = tracing::subscriber::set_default(tracing_subscriber::registry().with(Tail));
+        let path = std::env::temp_dir().join(format!("basic-irc-admin-{}.sock", std::process::id()));
+        let socket = AdminSocket::listen(&path, Default::default()).unwrap();
+        assert_eq!(ErrorKind::AddrInUse, AdminSocket::listen(&path, Default::default()).unwrap_err().kind());
+
+        let mut admin = UnixStream::connect(&path).unwrap();
+        writeln!(admin, "tail --level warn").unwrap();
+        let mut lines = BufReader::new(admin.try_clone().unwrap()).lines();
+        // Logging until it's picked up, since it's only following once the command's been read. Other tests
+        // log warnings too, so there might be some of theirs first.
+        admin.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        loop {
+            warn!("[TEST] admin test");
+            if lines.next().and_then(Result::ok).is_some_and(|line| line.contains("admin test")) {
+                break;
+            }
+        }
+
+        let mut nonsense = UnixStream::connect(&path).unwrap();
+        writeln!(nonsense, "reboot").unwrap();
+        let mut answer = String::new();
+        nonsense.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("error: No such command `reboot`"), "{answer}");
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -1,17 +1,46 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use clap::Parser;
+use ed25519_dalek::VerifyingKey;
 use thiserror::Error;
+use rust_threading::exit::Exit;
+use rust_threading::flood::FloodLimits;
+use rust_threading::keepalive::Keepalive;
+use rust_threading::server::Protocol;
+use rust_threading::output::Output;
+use rust_threading::signing::parse_trusted;
+use rust_threading::tail::LogFormat;
+use tracing_subscriber::filter::LevelFilter;
 
 #[derive(clap::ValueEnum, Debug, Clone)]
 pub enum Mode {
     Client,
     Server,
+    /// Stays connected to --upstream as --name, for that user's clients to attach to.
+    Bouncer,
+    /// Sends what's after the options to a server's --admin-socket, like `tail --level warn`, and prints what comes back.
+    Admin,
+    /// Checks this machine can run a server with these options: enough open files for everyone who can connect, stores that open, and somewhere to listen.
+    Doctor,
+}
+
+/// What the server runs on.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    /// A pool of threads, one per connection being served.
+    Threads,
+    /// Every connection read and written on one event loop thread, over non-blocking sockets.
+    Mio,
+    /// Tasks on a tokio runtime, two per connection.
+    Tokio,
 }
 
 #[derive(Error, Debug)]
 pub enum ArgError {
     #[error("Invalid input: `{0}`")]
     InvalidInput(String),
-    #[error("No input given -- please pass 'client' or 'server'")]
+    #[error("No input given -- please pass 'client', 'server', 'bouncer', 'admin' or 'doctor'")]
     NoInput,
 }
 
@@ -23,6 +52,12 @@ impl TryFrom<String> for Mode {
             Ok(Mode::Client)
         } else if value == "server" {
             Ok(Mode::Server)
+        } else if value == "bouncer" {
+            Ok(Mode::Bouncer)
+        } else if value == "admin" {
+            Ok(Mode::Admin)
+        } else if value == "doctor" {
+            Ok(Mode::Doctor)
         } else if value.is_empty() {
             Err(ArgError::NoInput)
         } else {
@@ -32,12 +67,235 @@ impl TryFrom<String> for Mode {
 }
 
 #[derive(Parser, Debug)]
-#[command(about, about = "Does a TCP server/client thing.")]
+#[command(about, about = "Does a TCP server/client thing.", after_help = Exit::help(), args_override_self = true)]
 pub struct Args {
     #[arg(short, long, help = "Mode to start the app in.")]
     pub mode: Mode,
     #[arg(short, long, help = "Port to use. Default will bind any available port", default_value_t = 0)]
     pub port: u16,
-    #[arg(short, long, help = "Username to use for the client. Will prompt if not given.")]
+    #[arg(long, visible_alias = "bind", help = "IP or host name the server listens on, or the client connects to, optionally with a port that wins over --port, e.g. example.com:6667 or [::1]:6667. :: listens on every interface, IPv4 and IPv6.", default_value = "127.0.0.1")]
+    pub host: String,
+    #[arg(long, help = "Unix only. Unix socket the server listens on instead of --host and --port, or the client connects to, for when everyone's on the same machine. The server removes it again when it stops. Server threads runtime only, and nothing on it is TLS.")]
+    pub unix: Option<PathBuf>,
+    #[arg(long, help = "TOML file of settings named like these options, e.g. max_line_len = 4096 or oper = [\"alice\"]. BASIC_IRC_MAX_LINE_LEN and so on in the environment win over it, and options given here win over both, except lists, which add up. ${NAME} in a value is environment variable NAME, e.g. password = \"${CHAT_PASSWORD}\".")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Write a config with every setting in it, commented out at its default, to this file and exit. Won't overwrite one that's there.")]
+    pub init_config: Option<PathBuf>,
+    #[arg(long, help = "Print every setting as it ends up after --config, the environment and these options, with where each came from, and exit.")]
+    pub show_config: bool,
+    #[arg(long, help = "Server only. How the server runs. mio reads and writes every connection on one thread, and tokio runs each connection as tasks. Otherwise both serve everyone the same as threads does, with the same commands, channels, history and limits. They don't do TLS, WebSocket, --unix or --protocol irc, and ignore the worker, acceptor and writer options.", default_value = "threads")]
+    pub runtime: Runtime,
+    #[arg(long, help = "Server only. Check the config and options, say what would go wrong starting the server with them, and exit without starting it.")]
+    pub check_config: bool,
+    #[arg(long, help = "Server and bouncer only. Least serious logs written to stderr: error, warn, info, debug, trace or off. Anyone tailing them on the --admin-socket gets everything down to info regardless.", default_value = "info")]
+    pub log_level: LevelFilter,
+    #[arg(long, help = "Server and bouncer only. How logs are written to stderr. json is an object a line, with the connection each came from as its span, for shipping to a collector.", default_value = "text")]
+    pub log_format: LogFormat,
+    #[arg(long, help = "Server only. What clients speak. irc is plain RFC 2812 for any IRC client, native is what the bundled client speaks.", default_value = "native")]
+    pub protocol: Protocol,
+    #[arg(long, help = "Talk TLS. The server needs --tls-cert and --tls-key, the client checks the server's certificate is for --host.")]
+    pub tls: bool,
+    #[arg(long, help = "Server only. PEM file with the certificate chain to use with --tls.")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, visible_alias = "tls-key-file", help = "Server only. PEM file with the private key for --tls-cert. Refused if anyone can read it.")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, help = "Client only. PEM file with the CA(s) to trust with --tls instead of the usual public ones, e.g. for a self-signed server.")]
+    pub tls_ca: Option<PathBuf>,
+    #[arg(long, help = "Server only. Advertise the server on the LAN over mDNS.")]
+    pub mdns: bool,
+    #[arg(long, help = "Server only. Ask the router to forward the port over UPnP so the server is reachable from the internet.")]
+    pub upnp: bool,
+    #[arg(long, help = "Server only. STUN server (host:port) to ask for our public IP at startup, e.g. stun.l.google.com:19302.")]
+    pub stun_server: Option<String>,
+    #[arg(long, help = "Server only. Name of this server in a cluster, has to be unique across it.")]
+    pub node_name: Option<String>,
+    #[arg(long, help = "Server only. Address other servers in the cluster link to.")]
+    pub cluster_listen: Option<SocketAddr>,
+    #[arg(long, help = "Server only. Another server in the cluster to link to. Can be given more than once. Their clocks have to agree with this one's to within 30 seconds.")]
+    pub peer: Vec<SocketAddr>,
+    #[arg(long, help = "Server only. File with this node's signing key, made if it doesn't exist. A throwaway key is used if not given.")]
+    pub node_key: Option<PathBuf>,
+    #[arg(long, value_parser = parse_trusted, help = "Server only. Another node's public key as node=<hex>, printed when it starts. Gossip from other nodes has to be signed by one, unless --trust-any-node. Can be given more than once.")]
+    pub trust: Vec<(String, VerifyingKey)>,
+    #[arg(long, help = "Server only. Take gossip from nodes without a --trust key at their word, so anyone who can reach --cluster-listen can speak for any node.")]
+    pub trust_any_node: bool,
+    #[arg(long, help = "Server only. Upstream server to mirror, read-only, to this server's clients, who can /join its channels to follow them. Logs in upstream with --name, or 'mirror', and follows every channel there as <name>-channels.")]
+    pub mirror: Option<SocketAddr>,
+    #[arg(long, help = "Bouncer only. Chat server to stay connected to, as host:port.")]
+    pub upstream: Option<String>,
+    #[arg(long, help = "Bouncer only. Messages kept for when a client attaches while none are, past which the oldest get forgotten.", default_value_t = 1000)]
+    pub backlog: usize,
+    #[arg(long, help = "Server only. Nick that gets to use operator commands like /kick, /ban, /unban, /bans, /kill and /wallops, once whoever's using it shows it's them: by the nick being registered in --accounts, or by giving /oper the --oper-password. Needs one of those. Can be given more than once.")]
+    pub oper: Vec<String>,
+    #[arg(long, help = "Server only. Password /oper takes to make an --oper nick an operator. Best kept in --oper-password-file.")]
+    pub oper_password: Option<String>,
+    #[arg(long, conflicts_with = "oper_password", help = "Server only. File with --oper-password in it. Refused if anyone can read it.")]
+    pub oper_password_file: Option<PathBuf>,
+    #[arg(long, help = "Server only. What the server calls itself, e.g. in the MOTD.", default_value = "basic-irc")]
+    pub server_name: String,
+    #[arg(long, help = "Server only. File with a message of the day sent to everyone when they connect. {user}, {server_name}, {online_count} and {uptime} get filled in, here and in the maintenance message.")]
+    pub motd: Option<PathBuf>,
+    #[arg(long, help = "Server only. What new connections get told while an operator has the server in /maintenance, unless they give a message.", default_value = rust_threading::maintenance::DEFAULT_MESSAGE)]
+    pub maintenance_message: String,
+    #[arg(long, help = "Client only. Look for servers on the LAN over mDNS and pick one instead of using --port.")]
+    pub discover: bool,
+    #[arg(long, help = "Client only. Seconds to spend looking for servers with --discover.", default_value_t = 3)]
+    pub discover_secs: u64,
+    #[arg(long, help = "Client only. Lua script returning a table of hooks: on_connect(nick), on_outgoing(line) and on_incoming(from, text). Can be given more than once, they run in order.")]
+    pub plugin: Vec<PathBuf>,
+    #[arg(long, help = "Client only. Rule acting on what comes in, as `[away] <private|mention|message> [text] => <action>`. The action is a line to send, with {from}, {channel} and {text} filled in, like `away private => /msg {from} Away, back soon`, or ! and a shell command, which gets them in $CHAT_FROM, $CHAT_CHANNEL and $CHAT_TEXT. Can be given more than once.")]
+    pub trigger: Vec<String>,
+    #[arg(long, help = "Client only. Seconds before a --trigger fires again for the same sender, so two clients can't keep answering each other.", default_value_t = 30)]
+    pub trigger_cooldown_secs: u64,
+    #[arg(long, help = "Client only. File to keep the session in between runs: what's been shown, the /awaylog and the channels joined. Made if it isn't there.")]
+    pub session: Option<PathBuf>,
+    #[arg(long, help = "Client only. Stay connected without a terminal, taking one over this Unix socket instead. Run it in the background, attach with --attach, and /quit to disconnect.")]
+    pub daemon: Option<PathBuf>,
+    #[arg(long, help = "Client only. Attach this terminal to a client started with --daemon on this socket. Ctrl-D detaches, leaving it connected.")]
+    pub attach: Option<PathBuf>,
+    #[arg(long, help = "Client only. How to show what happens. json is an object a line on stdout, with an `event` like message, join, error or disconnected, for jq or anything else reading it. There's no prompt, and stdin still gets sent.", default_value = "text")]
+    pub output: Output,
+    #[arg(long, help = "Client only. Keep count of lines sent, messages received and how long they took to get here, shown with /stats and when the client exits.")]
+    pub stats: bool,
+    #[arg(long, help = "Client only. Bytes up and down after which to warn, for metered connections. How much is used shows in the prompt, and when the client exits.")]
+    pub bandwidth_cap: Option<u64>,
+    #[arg(long, help = "Password clients need to connect to the server, or for the client to log in with. The client asks for one if the server needs it and this isn't given. Best kept in --password-file.")]
+    pub password: Option<String>,
+    #[arg(long, conflicts_with = "password", help = "File with --password in it, so it's not on the command line or in a config. Refused if anyone can read it.")]
+    pub password_file: Option<PathBuf>,
+    #[arg(long, help = "Client only. Password for your nick, if it's been /register-ed. Best kept in --account-password-file.")]
+    pub account_password: Option<String>,
+    #[arg(long, conflicts_with = "account_password", help = "Client only. File with --account-password in it. Refused if anyone can read it.")]
+    pub account_password_file: Option<PathBuf>,
+    #[arg(long, help = "Server only. Messages kept per channel, and for the lobby, to show people when they connect or join. 0 keeps none.", default_value_t = 50)]
+    pub history: usize,
+    #[arg(long, help = "Server only. File to keep that history in, so it's still there after a restart. Made if it isn't there.")]
+    pub history_file: Option<PathBuf>,
+    #[arg(long, help = "Server only. SQLite database of registered nicks, made if it isn't there. Without it nobody can /register.")]
+    pub accounts: Option<PathBuf>,
+    #[arg(long, help = "Server only. File to keep /ban-s in, one `<mask> <why>` per line, so they're still there after a restart. Made if it isn't there.")]
+    pub bans_file: Option<PathBuf>,
+    #[arg(long, help = "Server only. Port to serve Prometheus metrics on at /metrics, on the same address as the server: users and channel members online, messages broadcast, bytes in/out and auth failures. Off without it.")]
+    pub metrics_port: Option<u16>,
+    #[arg(long, help = "Server only, threads runtime only. Port to also take WebSocket connections on, for browsers, on the same address as the server. Binary messages carry the native protocol and text messages are chat lines. With --tls they're wss://. Off without it.")]
+    pub ws_port: Option<u16>,
+    #[arg(long, help = "Server only. Port for the admin HTTP API, on the same address as the server: GET /users, DELETE /users/<nick> to kick, POST /notice and GET /stats. Off without it, and needs --admin-token.")]
+    pub admin_port: Option<u16>,
+    #[arg(long, help = "Server only. Token the admin HTTP API wants as `Authorization: Bearer <token>`. Best set in the config from an environment variable, like ${CHAT_ADMIN_TOKEN}, rather than written in it.")]
+    pub admin_token: Option<String>,
+    #[arg(long, help = "Server only, and Unix only. Socket to take admin commands on, like `tail` to follow chat or logs and `inspect` to look at someone's connection. With --mode admin, the socket to send the command to. Elsewhere, there's --admin-port.")]
+    pub admin_socket: Option<PathBuf>,
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, help = "Admin only. Command to send, `tail [--channel #x]` to follow chat, `tail --level info|warn` to follow logs, or `inspect <nick>` to look at someone's connection.")]
+    pub admin_command: Vec<String>,
+    #[arg(short, long, help = "Username to use for the client, or for a mirror upstream. Will prompt if not given.")]
     pub name: Option<String>,
-}
\ No newline at end of file
+    #[arg(long, help = "Client only. What others see you as instead of --name. Unlike nicks, anything goes, up to 32 characters.")]
+    pub display_name: Option<String>,
+    #[arg(long, help = "Server only. Bytes of chat the server may have read but not yet sent on, at once. Send queues and history are capped by --send-queue-len and --history instead.", default_value_t = 64 * 1024 * 1024)]
+    pub memory_budget: usize,
+    #[arg(long, help = "Server only. Longest line in bytes a client may send; longer lines are dropped.", default_value_t = 64 * 1024)]
+    pub max_line_len: usize,
+    #[arg(long, help = "Server only. Longest message in characters as people see them; longer ones get cut short.", default_value_t = 512)]
+    pub max_message_len: usize,
+    #[arg(long, help = "Server only. Worker threads for connections. Each client holds one for as long as it's connected, so this is how many can chat at once. Each also gets a writer thread, so there are at most --workers plus --max-clients threads for clients.", default_value_t = 64)]
+    pub workers: usize,
+    #[arg(long, help = "Server only. Connections that may wait for a free worker before new ones are turned away, which only happens with --max-clients over --workers. They wait until somebody leaves.", default_value_t = 128)]
+    pub accept_queue: usize,
+    #[arg(long, help = "Server only. Most clients connected at once, counting ones waiting for a worker or still being written to after leaving. Anyone past it is told the server's full and hung up on. With threads, one per worker if not given, so nobody's left waiting for one. With mio and tokio, no limit if not given.")]
+    pub max_clients: Option<usize>,
+    #[arg(long, help = "Server only. New connections let in per second, so everyone reconnecting at once after a restart is spread out. Anyone past it is told when to try again, jittered, and hung up on. 0 doesn't limit them.", default_value_t = 100)]
+    pub max_accepts_per_sec: u64,
+    #[arg(long, help = "Server only. Connections let in all at once before --max-accepts-per-sec kicks in.", default_value_t = 200)]
+    pub accept_burst: u64,
+    #[arg(long, help = "Server only. Threads accepting connections, load-balanced by the kernel with SO_REUSEPORT. Linux only.", default_value_t = 1)]
+    pub acceptors: usize,
+    #[arg(long, help = "Server only. Bytes per second a client may send before being throttled, then disconnected.", default_value_t = 64 * 1024)]
+    pub max_bytes_per_sec: u64,
+    #[arg(long, help = "Server only. Lines per second a client may send before being throttled, then disconnected.", default_value_t = 50)]
+    pub max_lines_per_sec: u64,
+    #[arg(long, help = "Server only. Messages and commands per second a client may keep up; faster ones get dropped, and it gets told to slow down. 0 doesn't limit them.", default_value_t = 5)]
+    pub max_messages_per_sec: u64,
+    #[arg(long, help = "Server only. Messages a client may send all at once before --max-messages-per-sec kicks in.", default_value_t = 10)]
+    pub message_burst: u64,
+    #[arg(long, help = "Server only. Messages in a row that may get dropped for flooding before the client is disconnected. 0 never disconnects.", default_value_t = 20)]
+    pub max_flood_drops: u32,
+    #[arg(long, help = "Server only. Seconds a client may go without sending anything before it's pinged to check it's still there. 0 never pings.", default_value_t = 60)]
+    pub ping_interval_secs: u64,
+    #[arg(long, help = "Server only. Seconds a pinged client has to answer before it's disconnected.", default_value_t = 30)]
+    pub ping_timeout_secs: u64,
+    #[arg(long, help = "Server only. Messages that may wait to go out to one client before it's disconnected for not keeping up.", default_value_t = 256)]
+    pub send_queue_len: usize,
+    #[arg(long, help = "Server only. Bytes per second sent to each client. Unlimited if not given.")]
+    pub max_egress_bytes_per_sec: Option<u64>,
+    #[arg(long, help = "Server only. Milliseconds to wait for more messages to a client so they go out in one write.", default_value_t = 0)]
+    pub batch_window_ms: u64,
+    #[arg(long, help = "Server only. Most bytes batched into one write to a client.", default_value_t = 16 * 1024)]
+    pub batch_bytes: usize,
+}
+
+impl Args {
+    /// The host and port to use, going by --host and then --port. IPv6 addresses can be bare (`::1`) or
+    /// bracketed (`[::1]`), which they need to be to come with a port (`[::1]:6667`).
+    pub fn endpoint(&self) -> (&str, u16) {
+        let (host, port) = split_port(&self.host);
+        (host, port.unwrap_or(self.port))
+    }
+
+    /// How fast clients may say things, going by --max-messages-per-sec, --message-burst and --max-flood-drops.
+    pub fn flood(&self) -> FloodLimits {
+        FloodLimits { messages_per_sec: self.max_messages_per_sec, burst: self.message_burst, max_dropped: self.max_flood_drops }
+    }
+
+    /// When quiet clients get pinged, going by --ping-interval-secs and --ping-timeout-secs.
+    pub fn keepalive(&self) -> Keepalive {
+        let (ping_after, timeout) = (Duration::from_secs(self.ping_interval_secs), Duration::from_secs(self.ping_timeout_secs));
+        Keepalive { ping_after, timeout }
+    }
+}
+
+/// `host` without its port, and the port if it had one.
+pub fn split_port(host: &str) -> (&str, Option<u16>) {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        return match bracketed.split_once(']') {
+            Some((ip, "")) => (ip, None),
+            Some((ip, port)) => match port.strip_prefix(':').and_then(|p| p.parse().ok()) {
+                Some(port) => (ip, Some(port)),
+                None => (host, None),
+            },
+            None => (host, None),
+        };
+    }
+
+    // Only one colon, so not a bare IPv6 address
+    match host.split_once(':') {
+        Some((name, port)) if !port.contains(':') => match port.parse() {
+            Ok(port) => (name, Some(port)),
+            Err(_) => (host, None),
+        },
+        _ => (host, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(host: &str) -> (String, u16) {
+        let args = Args::parse_from(["rust-threading", "--mode", "client", "--port", "7000", "--host", host]);
+        let (host, port) = args.endpoint();
+        (host.to_string(), port)
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(("127.0.0.1".to_string(), 7000), endpoint("127.0.0.1"));
+        assert_eq!(("example.com".to_string(), 6667), endpoint("example.com:6667"));
+        assert_eq!(("::1".to_string(), 7000), endpoint("::1"));
+        assert_eq!(("::1".to_string(), 7000), endpoint("[::1]"));
+        assert_eq!(("::1".to_string(), 6667), endpoint("[::1]:6667"));
+        assert_eq!(("fe80::1%eth0".to_string(), 6667), endpoint("[fe80::1%eth0]:6667"));
+        // Left alone for resolving to fail on
+        assert_eq!(("[::1]:nope".to_string(), 7000), endpoint("[::1]:nope"));
+        assert_eq!(("example.com:nope".to_string(), 7000), endpoint("example.com:nope"));
+    }
+}
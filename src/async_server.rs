@@ -0,0 +1,452 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use socket2::SockRef;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::Notify;
+use tokio::task::block_in_place;
+use tracing::{field, info, info_span, warn, Instrument, Span};
+use crate::accounting::{IoMeter, over_budget, Verdict};
+use crate::capacity::Seat;
+use crate::diagnostics::Diagnostics;
+use crate::frame::{encode_message, FrameError, PREFIX_LEN};
+use crate::listener;
+use crate::metrics::Metrics;
+use crate::outbound::{Outbound, Queue};
+use crate::response::{AuthResponse, PresenceChange};
+use crate::scuffed_clone::HangUp;
+use crate::server::{advertise, answer_handshake, farewell, features, HELLO_LIMITS, let_in, Line, map_port, Refusal, seat};
+use crate::server::{Options, public_address, say_goodbye, ServerError, Session, Shared, SHUTDOWN_GRACE, welcome};
+use crate::shutdown::{self, Shutdown};
+use crate::transport::Listener;
+use crate::user::User;
+
+/// How long to wait between checking whether everyone's been seen off.
+const POLL: Duration = Duration::from_millis(20);
+
+/// The server as tokio tasks, two per connection: one reading it and one writing out its send queue. Only reading
+/// and writing are done differently. Everything else is the threaded server's, through `Shared` and `Session`: the
+/// same broadcaster, commands, channels, history, bans, limits, flood control and keepalive, so every option means
+/// the same here except the worker, acceptor and writer ones, which don't apply. TLS, WebSocket, Unix sockets and
+/// IRC are the threaded server's alone.
+///
+/// Clients get throttled by not being read from for a while. What blocks in the threaded server, like waiting on
+/// the broadcaster or checking an account's password, blocks in place here, so tokio moves the other tasks off.
+///
+/// There are as many connections at once as `--max-clients` lets in, or as many as connect without it.
+pub fn start(address: SocketAddr, options: Options) -> std::io::Result<()> {
+    let listener = listener::bind_one(address)?;
+    let port = listener.local_addr()?.port();
+    info!("Listening on port {port} on tokio");
+    let _advertisement = advertise(&options, port);
+    let _mapping = map_port(&options, port);
+    let metrics = Arc::new(Metrics::default());
+    *metrics.public_address.lock() = public_address(&options, port);
+
+    let shutdown = Arc::new(Shutdown::default());
+    shutdown::on_signals(shutdown.clone())?;
+    run(listener, options, metrics, shutdown)
+}
+
+fn run(listener: std::net::TcpListener, options: Options, metrics: Arc<Metrics>, shutdown: Arc<Shutdown>) -> std::io::Result<()> {
+    // Shutting down wakes up the accept by connecting to the listener
+    shutdown.watch(&Listener::from(listener.try_clone()?))?;
+    let shared = Shared::open(listener.local_addr()?, &options, (metrics, shutdown.clone()), options.max_clients)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(serve(listener, Arc::new(shared), Arc::new(options), shutdown))
+}
+
+async fn serve(
+    listener: std::net::TcpListener,
+    shared: Arc<Shared>,
+    options: Arc<Options>,
+    shutdown: Arc<Shutdown>,
+) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+
+    loop {
+        let accepted = listener.accept().await;
+        if shutdown.stopping() {
+            break;
+        }
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed on handling incoming stream: {e:?}");
+                continue;
+            }
+        };
+
+        let peer = peer.ip().to_canonical();
+        let Shared { bans, clients, accepts, .. } = &*shared;
+        match seat(peer, (bans, clients, accepts)) {
+            Ok(seat) => {
+                let span = info_span!("conn", %peer, nick = field::Empty);
+                tokio::spawn(handle_connection(stream, (peer, seat), shared.clone(), options.clone()).instrument(span));
+            }
+            Err(refusal) => drop(tokio::spawn(turn_away(stream, peer, refusal))),
+        }
+    }
+
+    info!("Not taking any more connections, saying goodbye to everyone");
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    block_in_place(|| say_goodbye(&shared.users, &shared.sender, deadline));
+    // Everyone's seat is held until they're seen off and their send queue's been written out
+    while shared.clients.connected() > 0 {
+        if Instant::now() >= deadline {
+            let left = shared.clients.connected();
+            warn!("{left} connection(s) were still being served after {}s", SHUTDOWN_GRACE.as_secs());
+            break;
+        }
+        tokio::time::sleep(POLL).await;
+    }
+    Ok(())
+}
+
+/// Tells `peer` why it's being turned away and hangs up.
+async fn turn_away(mut stream: TcpStream, peer: IpAddr, refusal: Refusal) {
+    let told = match encode_message(&refusal.answer()) {
+        Ok(answer) => stream.write_all(&answer).await.map_err(FrameError::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = told {
+        warn!("Failed telling {peer} why they're turned away: {e:?}");
+    }
+}
+
+/// Wakes a connection's writer whenever there's something new on its send queue, and once more when it's dropped
+/// with the last `Outbound`, so the writer notices nothing else is coming.
+struct Wake(Arc<Notify>);
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        self.0.notify_one();
+    }
+}
+
+async fn handle_connection(stream: TcpStream, (peer, seat): (IpAddr, Seat), shared: Arc<Shared>, options: Arc<Options>) {
+    // Shutting down reading from it, like the keepalive and kicks do, looks like they hung up
+    let hang_up = match SockRef::from(&stream).try_clone() {
+        Ok(socket) => std::net::TcpStream::from(socket),
+        Err(e) => {
+            warn!("Couldn't clone the connection, dropping it: {e:?}");
+            return;
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let meter = Arc::new(Mutex::new(IoMeter::new(options.io_limits, Instant::now())));
+    let diagnostics = Arc::new(Diagnostics::new(peer, meter.clone()));
+
+    let (outbound, queue) = Outbound::new(options.send_queue_len);
+    let written = Arc::new(Notify::new());
+    let wake = Wake(written.clone());
+    let outbound = outbound
+        .with_hang_up(move || hang_up.hang_up())
+        .with_wake(move || wake.0.notify_one())
+        .with_diagnostics(diagnostics.clone());
+
+    let started = Instant::now();
+    let auth = auth(&mut reader, &mut writer, (&shared, outbound.clone()), &meter, &options).await;
+    for alarm in shared.metrics.handshakes.record(peer, started.elapsed(), auth.is_ok()) {
+        warn!("[AUTH] Warning: {alarm}");
+    }
+    let user = match auth {
+        Ok(user) => user,
+        Err(e) => {
+            warn!("Failed validating user: {e:?}");
+            return;
+        }
+    };
+    Span::current().record("nick", field::display(&user.name));
+
+    // Only started once the auth response is out, so nothing can get written ahead of it. It winds down on its
+    // own once the user is released and the queue runs dry.
+    tokio::spawn(write_queue(writer, queue, written, seat, shared.metrics.clone()).in_current_span());
+    block_in_place(|| welcome(&shared, &user, &options));
+    let (quit, user) = chat(reader, Session::new(user, &options), &shared, (&diagnostics, &outbound), &options).await;
+    block_in_place(|| farewell(&shared, &user, quit));
+
+    let meter = meter.lock();
+    info!("Disconnected after sending {} bytes in {} lines", meter.total_bytes, meter.total_lines);
+}
+
+/// Answers the handshake, then reads the hello and lets the user in with `outbound` as their send queue if
+/// `let_in` does, answering with an `AuthResponse` either way, like `do_auth_flow` does on a thread.
+async fn auth<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    writer: &mut OwnedWriteHalf,
+    (shared, outbound): (&Shared, Outbound),
+    meter: &Mutex<IoMeter>,
+    options: &Options,
+) -> Result<User, ServerError> {
+    let handshake = read_hello_frame(reader, meter, &shared.metrics).await?;
+    let (answer, shaken) = answer_handshake(&handshake, &features(options.password.is_some()));
+    writer.write_all(&encode_message(&answer)?).await?;
+    shaken?;
+
+    let hello = read_hello_frame(reader, meter, &shared.metrics).await?;
+    // Holds up this worker while an account password's checked, but only for as long as a login takes
+    let admitted = block_in_place(|| let_in(&hello, options, |user| shared.admit(user, outbound)));
+    let answer = match &admitted {
+        Ok(_) => Some(AuthResponse::Success),
+        Err(e) => e.answer(),
+    };
+    if let Some(answer) = answer {
+        if let Err(e) = writer.write_all(&encode_message(&answer)?).await {
+            if let Ok(user) = &admitted {
+                shared.users.release(user);
+            }
+            return Err(e.into());
+        }
+    }
+    admitted
+}
+
+/// Reads one frame of the handshake or hello, without decoding it.
+async fn read_hello_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    meter: &Mutex<IoMeter>,
+    metrics: &Metrics,
+) -> Result<Vec<u8>, FrameError> {
+    let mut prefix = [0; PREFIX_LEN];
+    reader.read_exact(&mut prefix).await?;
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > HELLO_LIMITS.max_len {
+        return Err(FrameError::TooLarge { len, max: HELLO_LIMITS.max_len });
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame).await?;
+    meter.lock().record_bytes((PREFIX_LEN + len) as u64, Instant::now());
+    metrics.bytes_in.fetch_add((PREFIX_LEN + len) as u64, Ordering::Relaxed);
+    Ok(frame)
+}
+
+/// Hands every line read to the `session` until they go, like `handle_chat` does on a thread. Ends with why they
+/// went and who they were by then.
+async fn chat<R: AsyncRead + Unpin>(
+    mut reader: BufReader<R>,
+    mut session: Session,
+    shared: &Shared,
+    (diagnostics, outbound): (&Diagnostics, &Outbound),
+    options: &Options,
+) -> (PresenceChange, User) {
+    let meter = diagnostics.meter();
+    let mut buffer = Vec::with_capacity(4096.min(options.max_line_len + 1));
+
+    loop {
+        let line = match next_line(&mut reader, &mut buffer, options.max_line_len, (meter, &shared.metrics)).await {
+            // Lines count against the budget too, not just bytes, so check before doing anything with it
+            Ok(Some(line)) => {
+                meter.lock().record_line(Instant::now());
+                pace(meter).await.map(|()| Some(line))
+            }
+            read => read,
+        };
+        let sinks = (&shared.sender, &shared.memory, &*shared.metrics);
+        if let Err(quit) = block_in_place(|| session.heard(line, sinks, (diagnostics, outbound), options)) {
+            return (quit, session.user().clone());
+        }
+    }
+}
+
+/// Reads the next line with trailing whitespace trimmed into `buffer`, or `None` once they've hung up, the same as
+/// `LineReader` does on a thread. Every byte counts against `meter`, which holds them to it as they're read.
+async fn next_line<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    buffer: &mut Vec<u8>,
+    max_line_len: usize,
+    (meter, metrics): (&Mutex<IoMeter>, &Metrics),
+) -> std::io::Result<Option<Line>> {
+    // One over the max so we can tell a line that's exactly the max apart from one that's too long
+    let read_limit = max_line_len as u64 + 1;
+    let n = read_chunk(reader, buffer, read_limit, (meter, metrics)).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n as u64 == read_limit && buffer.last() != Some(&b'\n') {
+        // Throw away the rest of it, a bounded chunk at a time
+        while read_chunk(reader, buffer, read_limit, (meter, metrics)).await? > 0 && buffer.last() != Some(&b'\n') {}
+        return Ok(Some(Line::TooLong));
+    }
+    Ok(Some(Line::Text(String::from_utf8_lossy(buffer).trim_end().to_string())))
+}
+
+/// Reads into `buffer` up to the next line feed, but no more than `limit` bytes, once `meter` lets it.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    buffer: &mut Vec<u8>,
+    limit: u64,
+    (meter, metrics): (&Mutex<IoMeter>, &Metrics),
+) -> std::io::Result<usize> {
+    buffer.clear();
+    pace(meter).await?;
+    let n = reader.take(limit).read_until(b'\n', buffer).await?;
+    meter.lock().record_bytes(n as u64, Instant::now());
+    metrics.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    Ok(n)
+}
+
+/// `enforce` without holding up the thread: waits out a throttle, or fails once they've been over budget too long.
+async fn pace(meter: &Mutex<IoMeter>) -> std::io::Result<()> {
+    let verdict = meter.lock().verdict(Instant::now());
+    match verdict {
+        Verdict::Ok => Ok(()),
+        // Not reading from them is all the throttling they need, TCP does the rest
+        Verdict::Throttle(pause) => {
+            tokio::time::sleep(pause).await;
+            Ok(())
+        }
+        Verdict::Disconnect => Err(over_budget()),
+    }
+}
+
+/// Writes out everything put on the connection's send queue until nothing else can be, like `spawn_writer` does
+/// on a thread. The connection keeps its `_seat` until then.
+async fn write_queue(mut writer: OwnedWriteHalf, queue: Queue, written: Arc<Notify>, _seat: Seat, metrics: Arc<Metrics>) {
+    loop {
+        match queue.try_next() {
+            Ok(frame) => {
+                if let Err(e) = writer.write_all(&frame).await {
+                    warn!("Failed writing: {e:?}");
+                    return;
+                }
+                queue.wrote(frame.len());
+                metrics.bytes_out.fetch_add(frame.len() as u64, Ordering::Relaxed);
+            }
+            Err(TryRecvError::Empty) => written.notified().await,
+            Err(TryRecvError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::thread;
+    use crate::frame::{FrameLimits, read_message};
+    use crate::response::ServerMessage;
+    use crate::testing::log_in;
+    use super::*;
+
+    fn spawn_server(options: Options) -> SocketAddr {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        // Never stops, it goes away with the test process
+        thread::spawn(move || run(listener, options, Arc::default(), Arc::default()).unwrap());
+        address
+    }
+
+    fn connect(address: SocketAddr, name: &str) -> (TcpStream, AuthResponse) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let resp = log_in(&mut stream, &User::new(name));
+        (stream, resp)
+    }
+
+    /// The next chat message on `stream`, skipping over notices and everyone coming and going.
+    fn next_chat(stream: &mut TcpStream) -> (String, String) {
+        loop {
+            match read_message(stream, &FrameLimits::default()).unwrap() {
+                ServerMessage::Chat { from, text, .. } => return (from.name, text),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn chat_round_trip() {
+        let address = spawn_server(Options::default());
+        let (mut one, resp) = connect(address, "one");
+        assert_eq!(AuthResponse::Success, resp);
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"hello\n").unwrap();
+        assert_eq!(("one".to_string(), "hello".to_string()), next_chat(&mut two));
+
+        // Taken nicks get turned away, then hung up on
+        let (mut dupe, resp) = connect(address, "one");
+        assert_eq!(AuthResponse::Error("Name is already taken: one".to_string()), resp);
+        assert_eq!(0, dupe.read(&mut [0; 16]).unwrap());
+
+        two.write_all(b"hi\n").unwrap();
+        assert_eq!(("two".to_string(), "hi".to_string()), next_chat(&mut one));
+    }
+
+    #[test]
+    fn runs_commands() {
+        let address = spawn_server(Options::default());
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"/msg two psst\n/nick uno\nhello again\n").unwrap();
+        let ServerMessage::Private { from, text, .. } = read_message(&mut two, &FrameLimits::default()).unwrap() else {
+            panic!("Expected the PM first");
+        };
+        assert_eq!(("one", "psst"), (from.name.as_str(), text.as_str()));
+        assert_eq!(("uno".to_string(), "hello again".to_string()), next_chat(&mut two));
+    }
+
+    #[test]
+    fn oversized_lines_are_dropped() {
+        let address = spawn_server(Options { max_line_len: 16, ..Default::default() });
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(format!("{}\nafter\n", "a".repeat(100)).as_bytes()).unwrap();
+        assert_eq!(("one".to_string(), "after".to_string()), next_chat(&mut two));
+    }
+
+    #[test]
+    fn nicks_free_up_on_disconnect() {
+        let address = spawn_server(Options::default());
+        let (one, _) = connect(address, "one");
+        drop(one);
+
+        // The server notices on its own time, so give it a few goes
+        for _ in 0..50 {
+            if connect(address, "one").1 == AuthResponse::Success {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Nick never freed up");
+    }
+
+    #[test]
+    fn turns_away_past_max_clients() {
+        let address = spawn_server(Options { max_clients: Some(1), ..Default::default() });
+        let (_one, _) = connect(address, "one");
+
+        let mut two = TcpStream::connect(address).unwrap();
+        two.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(AuthResponse::ServerFull, read_message(&mut two, &FrameLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn says_goodbye_on_shutdown() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (address, shutdown) = (listener.local_addr().unwrap(), Arc::new(Shutdown::default()));
+        let stopping = shutdown.clone();
+        let server = thread::spawn(move || run(listener, Options::default(), Arc::default(), stopping));
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"bye\n").unwrap();
+        assert_eq!(("one".to_string(), "bye".to_string()), next_chat(&mut two));
+        shutdown.begin();
+        server.join().unwrap().unwrap();
+
+        // Along with anyone else leaving, they hear why before they're hung up on
+        let heard: Vec<ServerMessage> = std::iter::from_fn(|| read_message(&mut two, &FrameLimits::default()).ok()).collect();
+        assert!(heard.contains(&ServerMessage::notice("Server shutting down")), "{heard:?}");
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::response::ServerMessage;
+use crate::transcript::timestamp;
+use crate::user::{is_nick_char, User};
+
+/// Oldest entries get forgotten past this, so a weekend away doesn't eat all the memory.
+const MAX_ENTRIES: usize = 1000;
+
+/// Mentions and private messages that came in while the user was `/away`, kept for when they're back.
+/// Being away is only known to the client, the server and everyone else don't know about it.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AwayLog {
+    /// Why they're away, if they are. Empty if they didn't say.
+    reason: Option<String>,
+    entries: Vec<String>,
+    /// How many had to be forgotten for going over `MAX_ENTRIES`.
+    forgotten: usize,
+}
+
+impl AwayLog {
+    /// `/away [reason]`, returning what to tell the user.
+    pub fn away(&mut self, reason: &str) -> String {
+        self.reason = Some(reason.to_string());
+        match reason {
+            "" => "You're away. Mentions and private messages will be kept for /awaylog".to_string(),
+            _ => format!("You're away ({reason}). Mentions and private messages will be kept for /awaylog"),
+        }
+    }
+
+    /// `/back`, returning what to tell the user, including whatever they missed.
+    pub fn back(&mut self) -> String {
+        if self.reason.take().is_none() {
+            return "You weren't away".to_string();
+        }
+        format!("Welcome back! {}", self.take())
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.reason.is_some()
+    }
+
+    /// A reminder of being away and what's waiting in `/awaylog`, if there's anything to remind of.
+    pub fn status(&self) -> Option<String> {
+        let waiting = match self.entries.len() {
+            0 => String::new(),
+            n => format!(", {n} waiting in /awaylog"),
+        };
+        match &self.reason {
+            Some(reason) if reason.is_empty() => Some(format!("You're still away{waiting}")),
+            Some(reason) => Some(format!("You're still away ({reason}){waiting}")),
+            None if waiting.is_empty() => None,
+            None => Some(format!("You're back{waiting}")),
+        }
+    }
+
+    /// Keeps `msg` if the user's away and it's for them: a private message, or chat with their nick in it.
+    /// `shown` is how it showed up in the terminal.
+    pub fn note(&mut self, msg: &ServerMessage, me: &User, shown: &str) {
+        if self.reason.is_none() {
+            return;
+        }
+
+        let for_me = match msg {
+            ServerMessage::Private { .. } => true,
+            ServerMessage::Chat { from, text, .. } => from != me && mentions(text, &me.name),
+            ServerMessage::Notice { .. }
+            | ServerMessage::Presence { .. }
+            | ServerMessage::Names { .. }
+            | ServerMessage::Channels { .. }
+            | ServerMessage::Ping { .. }
+            | ServerMessage::Disconnected { .. } => false,
+            ServerMessage::Sequenced { message, .. } => return self.note(message, me, shown),
+        };
+        if !for_me {
+            return;
+        }
+
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.remove(0);
+            self.forgotten += 1;
+        }
+        self.entries.push(format!("[{}] {shown}", timestamp(SystemTime::now())));
+    }
+
+    /// `/awaylog`, everything kept so far, which then gets cleared.
+    pub fn take(&mut self) -> String {
+        let mut summary = match self.entries.len() {
+            0 => "Nothing for you while you were away".to_string(),
+            n => format!("{n} for you while you were away:\n{}", self.entries.join("\n")),
+        };
+        if self.forgotten > 0 {
+            summary.push_str(&format!("\n({} older ones didn't fit)", self.forgotten));
+        }
+
+        self.entries.clear();
+        self.forgotten = 0;
+        summary
+    }
+}
+
+/// Whether `nick` is in `text` as a word of its own, ignoring ASCII case, e.g. `bob:` but not `bobby`.
+pub(crate) fn mentions(text: &str, nick: &str) -> bool {
+    let (text, nick) = (text.to_ascii_lowercase(), nick.to_ascii_lowercase());
+    text.match_indices(&nick).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + nick.len()..].chars().next();
+        !before.is_some_and(is_nick_char) && !after.is_some_and(is_nick_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(from: &str, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new(from), text: text.to_string(), received_ms: 0, channel: None }
+    }
+
+    #[test]
+    fn mentions_are_whole_words() {
+        assert!(mentions("bob: you there?", "bob"));
+        assert!(mentions("hey BOB", "bob"));
+        assert!(mentions("bobby and bob", "bob"));
+        assert!(!mentions("bobby", "bob"));
+        assert!(!mentions("[bob]", "bob"));
+        assert!(!mentions("nothing here", "bob"));
+    }
+
+    #[test]
+    fn only_keeps_whats_for_me_while_away() {
+        let bob = User::new("bob");
+        let mut log = AwayLog::default();
+        log.note(&chat("alice", "bob?"), &bob, "before");
+        assert_eq!("You weren't away", log.back());
+
+        assert!(log.away("lunch").contains("(lunch)"));
+        log.note(&chat("alice", "bob?"), &bob, "mention");
+        log.note(&chat("alice", "hi all"), &bob, "not for me");
+        log.note(&chat("bob", "I'm bob"), &bob, "my own");
+        log.note(&ServerMessage::Private { from: User::new("alice"), to: bob.clone(), text: "psst".to_string() }, &bob, "dm");
+        log.note(&ServerMessage::notice("bob joined"), &bob, "notice");
+
+        assert_eq!(Some("You're still away (lunch), 2 waiting in /awaylog".to_string()), log.status());
+
+        let back = log.back();
+        assert!(back.starts_with("Welcome back! 2 for you while you were away:\n"));
+        assert!(back.contains("] mention\n"));
+        assert!(back.ends_with("] dm"));
+        assert_eq!("Nothing for you while you were away", log.take());
+        assert_eq!(None, log.status());
+    }
+
+    #[test]
+    fn forgets_oldest_past_the_limit() {
+        let bob = User::new("bob");
+        let mut log = AwayLog::default();
+        log.away("");
+        for i in 0..MAX_ENTRIES + 3 {
+            log.note(&chat("alice", "bob"), &bob, &i.to_string());
+        }
+
+        let summary = log.take();
+        assert!(summary.starts_with(&format!("{MAX_ENTRIES} for you")));
+        assert!(summary.ends_with("(3 older ones didn't fit)"));
+    }
+}
@@ -0,0 +1,184 @@
+//! How a client attaching to a bouncer gets caught up. The bouncer numbers everything it passes along within
+//! its buffer, and keeps the latest of it. A client that asked for `FEATURE` in its handshake says where it
+//! got up to in each buffer with a `/backfill` line before anything else, and gets sent everything after
+//! that, in order, before anything live.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use crate::channel::LOBBY;
+use crate::frame::{encode_message, FrameError};
+use crate::outbound::Frame;
+use crate::response::ServerMessage;
+
+/// The last sequence number seen in each buffer.
+pub type Seen = BTreeMap<String, u64>;
+
+/// The handshake feature for being caught up this way.
+pub const FEATURE: &str = "backfill";
+/// What a client sends first to say what it's seen, followed by `Seen` as JSON.
+pub const COMMAND: &str = "/backfill";
+/// The buffer for notices from the server (or the bouncer) itself.
+pub const STATUS: &str = "*";
+
+/// Which buffer `message` goes in for `me`: where it was said, who a private message is with, or `STATUS`.
+pub fn buffer(message: &ServerMessage, me: &str) -> String {
+    match message {
+        ServerMessage::Chat { channel, .. } => channel.as_deref().unwrap_or(LOBBY).to_string(),
+        ServerMessage::Private { from, to, .. } if from.name == me => to.name.clone(),
+        ServerMessage::Private { from, .. } => from.name.clone(),
+        ServerMessage::Notice { .. }
+        | ServerMessage::Presence { .. }
+        | ServerMessage::Names { .. }
+        | ServerMessage::Channels { .. }
+        | ServerMessage::Ping { .. }
+        | ServerMessage::Disconnected { .. } => STATUS.to_string(),
+        ServerMessage::Sequenced { buffer, .. } => buffer.clone(),
+    }
+}
+
+/// The line asking to be caught up on everything after `seen`.
+pub fn request(seen: &Seen) -> String {
+    format!("{COMMAND} {}\n", serde_json::to_string(seen).expect("Maps of strings to numbers always serialize"))
+}
+
+/// What a `/backfill` line says has been seen, or `None` if it isn't one (or makes no sense).
+pub fn parse_request(line: &str) -> Option<Seen> {
+    let seen = line.strip_prefix(COMMAND)?;
+    serde_json::from_str(seen.trim()).ok()
+}
+
+#[derive(Debug)]
+struct Entry {
+    buffer: String,
+    seq: u64,
+    frame: Frame,
+}
+
+/// The latest messages passed along, numbered, up to a limit past which the oldest get forgotten.
+#[derive(Debug, Default)]
+pub struct History {
+    limit: usize,
+    entries: VecDeque<Entry>,
+    /// The last sequence number handed out in each buffer.
+    latest: HashMap<String, u64>,
+    /// How many have ever been pushed, so a position still means the same thing after old ones are forgotten.
+    pushed: u64,
+}
+
+/// What a client missed, ready to send.
+#[derive(Debug, Default)]
+pub struct Backfill {
+    pub frames: Vec<Frame>,
+    /// How many it missed that were already forgotten.
+    pub forgotten: u64,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, ..Default::default() }
+    }
+
+    /// Numbers `message` in `buffer` and keeps it, returning it numbered and encoded.
+    pub fn push(&mut self, buffer: String, message: ServerMessage) -> Result<Frame, FrameError> {
+        let seq = self.latest.get(&buffer).map_or(1, |seq| seq + 1);
+        let message = ServerMessage::Sequenced { buffer: buffer.clone(), seq, message: Box::new(message) };
+        let frame: Frame = encode_message(&message)?.into();
+
+        self.latest.insert(buffer.clone(), seq);
+        self.pushed += 1;
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        if self.limit > 0 {
+            self.entries.push_back(Entry { buffer, seq, frame: frame.clone() });
+        }
+        Ok(frame)
+    }
+
+    /// Where the history's up to, to pass to `since` later.
+    pub fn position(&self) -> u64 {
+        self.pushed
+    }
+
+    /// The last sequence number in every buffer so far.
+    pub fn latest(&self) -> Seen {
+        self.latest.iter().map(|(buffer, seq)| (buffer.clone(), *seq)).collect()
+    }
+
+    /// Everything after `seen` that was pushed before `position`, in the order it came in.
+    pub fn since(&self, seen: &Seen, position: u64) -> Backfill {
+        let first = self.pushed - self.entries.len() as u64;
+        let mut backfill = Backfill::default();
+        let mut oldest = HashMap::new();
+
+        for (at, entry) in (first..).zip(&self.entries) {
+            oldest.entry(entry.buffer.as_str()).or_insert(entry.seq);
+            if at < position && entry.seq > seen.get(&entry.buffer).copied().unwrap_or(0) {
+                backfill.frames.push(entry.frame.clone());
+            }
+        }
+
+        // Anything between what was seen and the oldest still kept is gone
+        for (buffer, latest) in &self.latest {
+            let seen = seen.get(buffer).copied().unwrap_or(0);
+            let oldest = oldest.get(buffer.as_str()).copied().unwrap_or(latest + 1);
+            backfill.forgotten += oldest.saturating_sub(seen + 1);
+        }
+        backfill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame::{read_message, FrameLimits};
+    use crate::user::User;
+    use super::*;
+
+    fn said(channel: &str, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new("far"), text: text.to_string(), received_ms: 0, channel: Some(channel.to_string()) }
+    }
+
+    fn decode(frames: &[Frame]) -> Vec<(String, u64)> {
+        frames
+            .iter()
+            .map(|frame| match read_message(&mut &frame[..], &FrameLimits::default()).unwrap() {
+                ServerMessage::Sequenced { buffer, seq, .. } => (buffer, seq),
+                other => panic!("Didn't expect {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picks_up_after_what_was_seen() {
+        let mut history = History::new(4);
+        for (channel, text) in [("#a", "1"), ("#b", "1"), ("#a", "2"), ("#a", "3"), ("#b", "2")] {
+            history.push(channel.to_string(), said(channel, text)).unwrap();
+        }
+        assert_eq!(Seen::from([("#a".to_string(), 3), ("#b".to_string(), 2)]), history.latest());
+
+        // #a 1 didn't fit
+        let backfill = history.since(&Seen::new(), history.position());
+        assert_eq!(vec![("#b".to_string(), 1), ("#a".to_string(), 2), ("#a".to_string(), 3), ("#b".to_string(), 2)],
+            decode(&backfill.frames));
+        assert_eq!(1, backfill.forgotten);
+
+        let seen = Seen::from([("#a".to_string(), 2), ("#b".to_string(), 2)]);
+        let backfill = history.since(&seen, history.position());
+        assert_eq!(vec![("#a".to_string(), 3)], decode(&backfill.frames));
+        assert_eq!(0, backfill.forgotten);
+
+        // Not past where it's told to stop, since the rest are going out live
+        let position = history.position();
+        history.push("#a".to_string(), said("#a", "4")).unwrap();
+        assert_eq!(vec![("#a".to_string(), 3)], decode(&history.since(&seen, position).frames));
+    }
+
+    #[test]
+    fn backfill_requests() {
+        let seen = Seen::from([("#rust".to_string(), 42), (STATUS.to_string(), 7)]);
+        let line = request(&seen);
+        assert!(line.starts_with("/backfill {") && line.ends_with('\n'));
+        assert_eq!(Some(seen), parse_request(&line));
+        assert_eq!(None, parse_request("/backfill nope"));
+        assert_eq!(None, parse_request("hello"));
+    }
+}
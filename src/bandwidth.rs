@@ -0,0 +1,155 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::scuffed_clone::{HangUp, ScuffedClone};
+
+/// Bytes up and down one connection, for keeping an eye on a metered one. Counts what the client reads and
+/// writes, so anything TLS adds on top isn't in it.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    up: AtomicU64,
+    down: AtomicU64,
+    /// How much to let through before warning, if anything.
+    cap: OnceLock<u64>,
+    warned: AtomicBool,
+}
+
+impl Bandwidth {
+    /// Warns once `cap` bytes have gone up and down in total. Only the first cap given counts.
+    pub fn cap_at(&self, cap: u64) {
+        let _ = self.cap.set(cap);
+    }
+
+    pub fn is_capped(&self) -> bool {
+        self.cap.get().is_some()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.up.load(Ordering::Relaxed) + self.down.load(Ordering::Relaxed)
+    }
+
+    /// The warning to show the first time the cap's been reached, `None` any other time.
+    pub fn over_cap(&self) -> Option<String> {
+        let cap = *self.cap.get()?;
+        if self.total() < cap || self.warned.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some(format!("Heads up, {} has gone up and down, over the {} cap", size(self.total()), size(cap)))
+    }
+
+    /// What to put in front of the prompt: how much of the cap is used, if there is one.
+    pub fn prompt(&self) -> String {
+        match self.cap.get() {
+            Some(cap) => format!("[{} of {}] > ", size(self.total()), size(*cap)),
+            None => "> ".to_string(),
+        }
+    }
+}
+
+impl Display for Bandwidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Used {} up, {} down", size(self.up.load(Ordering::Relaxed)), size(self.down.load(Ordering::Relaxed)))?;
+        if let Some(cap) = self.cap.get() {
+            write!(f, ", {} of the {} cap", size(self.total()), size(*cap))?;
+        }
+        Ok(())
+    }
+}
+
+/// `bytes` the way a phone plan would put it, in powers of 1000.
+pub(crate) fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+
+    let mut scaled = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while scaled >= 1000.0 && unit < UNITS.len() - 1 {
+        scaled /= 1000.0;
+        unit += 1;
+    }
+    format!("{scaled:.1} {}", UNITS[unit])
+}
+
+/// Wraps a connection so everything read from or written to it, through any clone, counts in `Bandwidth`.
+#[derive(Debug)]
+pub struct Counted<S> {
+    inner: S,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, bandwidth: Arc<Bandwidth>) -> Self {
+        Self { inner, bandwidth }
+    }
+
+    #[cfg(test)]
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bandwidth.down.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bandwidth.up.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: ScuffedClone> ScuffedClone for Counted<S> {
+    fn scuffed_clone(&self) -> Self {
+        Self { inner: self.inner.scuffed_clone(), bandwidth: self.bandwidth.clone() }
+    }
+}
+
+impl<S: HangUp> HangUp for Counted<S> {
+    fn hang_up(&self) {
+        self.inner.hang_up();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    #[test]
+    fn counts_every_clone_and_warns_once() {
+        let bandwidth = Arc::new(Bandwidth::default());
+        bandwidth.cap_at(1500);
+        let mut conn = Counted::new(Cursor::new(vec![0; 1000]), bandwidth.clone());
+        let mut other = conn.scuffed_clone();
+
+        conn.read_exact(&mut [0; 600]).unwrap();
+        assert_eq!(None, bandwidth.over_cap());
+        assert_eq!("[600 B of 1.5 KB] > ", bandwidth.prompt());
+
+        other.write_all(&[1; 1000]).unwrap();
+        assert!(bandwidth.over_cap().unwrap().contains("1.6 KB has gone up and down"));
+        assert_eq!(None, bandwidth.over_cap());
+        assert_eq!("Used 1.0 KB up, 600 B down, 1.6 KB of the 1.5 KB cap", bandwidth.to_string());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!("999 B", size(999));
+        assert_eq!("12.3 MB", size(12_345_678));
+        assert_eq!("2000.0 TB", size(2_000_000_000_000_000));
+        assert_eq!("> ", Bandwidth::default().prompt());
+    }
+}
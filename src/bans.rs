@@ -0,0 +1,283 @@
+//! Who operators have `/ban`ned, by nick mask or by address, and turned away whenever they try to connect.
+//! Optionally kept on disk too, one ban per line as `<mask> <why>`, so a restart doesn't let everyone back in.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use parking_lot::Mutex;
+use thiserror::Error;
+use tracing::warn;
+use crate::user::{is_nick_char, User};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MaskError {
+    #[error("{0} isn't a nick, a nick mask like bob* or an address like 10.0.0.0/8")]
+    Invalid(String),
+    #[error("{0} can't have a /{1}, it's at most /{2}")]
+    Prefix(IpAddr, u8, u8),
+}
+
+/// What a ban covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mask {
+    /// Nicks matching this, in lowercase, where `*` is anything and `?` is any one character.
+    Nick(String),
+    /// Addresses in this network, kept with the host bits zeroed. A single address is its own `/32` or `/128`.
+    Address(IpAddr, u8),
+}
+
+impl Mask {
+    /// Whether it covers `user`, or anyone connecting from `peer` if it's known.
+    pub fn catches(&self, user: &User, peer: Option<IpAddr>) -> bool {
+        self.matches_nick(&user.name) || peer.is_some_and(|peer| self.matches_address(peer))
+    }
+
+    fn matches_nick(&self, nick: &str) -> bool {
+        match self {
+            Mask::Nick(mask) => glob(mask.as_bytes(), nick.to_ascii_lowercase().as_bytes()),
+            Mask::Address(..) => false,
+        }
+    }
+
+    fn matches_address(&self, peer: IpAddr) -> bool {
+        match *self {
+            Mask::Address(network, prefix) => network == masked(peer.to_canonical(), prefix),
+            Mask::Nick(_) => false,
+        }
+    }
+}
+
+impl FromStr for Mask {
+    type Err = MaskError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || MaskError::Invalid(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        if let Ok(addr) = addr.parse::<IpAddr>() {
+            let addr = addr.to_canonical();
+            let max = bits(addr);
+            return match prefix.unwrap_or(max) {
+                prefix if prefix > max => Err(MaskError::Prefix(addr, prefix, max)),
+                prefix => Ok(Mask::Address(masked(addr, prefix), prefix)),
+            };
+        }
+
+        // Anything that isn't an address is a nick, wildcards and all
+        let is_mask_char = |c: char| is_nick_char(c) || c == '*' || c == '?';
+        match !s.is_empty() && prefix.is_none() && s.chars().all(is_mask_char) {
+            true => Ok(Mask::Nick(s.to_ascii_lowercase())),
+            false => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Mask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mask::Nick(mask) => write!(f, "{mask}"),
+            Mask::Address(addr, prefix) if *prefix == bits(*addr) => write!(f, "{addr}"),
+            Mask::Address(addr, prefix) => write!(f, "{addr}/{prefix}"),
+        }
+    }
+}
+
+fn bits(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// `addr` with everything past the first `prefix` bits zeroed.
+fn masked(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => Ipv4Addr::from(u32::from(addr) & u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)).into(),
+        IpAddr::V6(addr) => Ipv6Addr::from(u128::from(addr) & u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)).into(),
+    }
+}
+
+/// Whether `text` matches `mask`, where `*` matches any run of characters and `?` any one.
+fn glob(mask: &[u8], text: &[u8]) -> bool {
+    match (mask.split_first(), text.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => glob(rest, text) || (!text.is_empty() && glob(mask, &text[1..])),
+        (Some((b'?', rest)), Some((_, text))) => glob(rest, text),
+        (Some((m, rest)), Some((t, text))) => m == t && glob(rest, text),
+        _ => false,
+    }
+}
+
+/// The bans, oldest first, each with why it's there. That's what whoever it catches gets told.
+#[derive(Debug, Default)]
+pub struct Bans {
+    banned: Mutex<Vec<(Mask, String)>>,
+    /// Where they're kept, if anywhere, which is forgotten once writing to it fails.
+    path: Mutex<Option<PathBuf>>,
+}
+
+impl Bans {
+    /// Bans kept in `path`, picking up whatever's already there.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut banned = Vec::new();
+        let mut skipped = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    let (mask, why) = line.split_once(' ').unwrap_or((&line, ""));
+                    match mask.parse() {
+                        Ok(mask) => banned.push((mask, why.trim().to_string())),
+                        Err(_) if line.trim().is_empty() => {}
+                        Err(_) => skipped += 1,
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if skipped > 0 {
+            warn!("[BANS] Skipped {skipped} lines in {} that weren't bans", path.display());
+        }
+
+        let bans = Self { banned: Mutex::new(banned), path: Mutex::new(Some(path.to_path_buf())) };
+        bans.save(&bans.banned.lock())?;
+        Ok(bans)
+    }
+
+    /// Bans `mask`, with `why` being what anyone it catches is told. Returns whether it wasn't banned already.
+    pub fn ban(&self, mask: Mask, why: String) -> bool {
+        let mut banned = self.banned.lock();
+        if banned.iter().any(|(m, _)| *m == mask) {
+            return false;
+        }
+        banned.push((mask, why));
+        self.keep(&banned);
+        true
+    }
+
+    /// Lifts the ban on exactly `mask`. Returns whether there was one.
+    pub fn unban(&self, mask: &Mask) -> bool {
+        let mut banned = self.banned.lock();
+        let before = banned.len();
+        banned.retain(|(m, _)| m != mask);
+        if banned.len() == before {
+            return false;
+        }
+        self.keep(&banned);
+        true
+    }
+
+    /// Every ban, oldest first.
+    pub fn list(&self) -> Vec<(Mask, String)> {
+        self.banned.lock().clone()
+    }
+
+    /// Why `user` is banned by nick, or `None` if they're not.
+    pub fn reason(&self, user: &User) -> Option<String> {
+        self.find(|mask| mask.matches_nick(&user.name))
+    }
+
+    /// Why anyone connecting from `peer` is banned, or `None` if they're not.
+    pub fn reason_from(&self, peer: IpAddr) -> Option<String> {
+        self.find(|mask| mask.matches_address(peer))
+    }
+
+    fn find(&self, matches: impl Fn(&Mask) -> bool) -> Option<String> {
+        self.banned.lock().iter().find(|(mask, _)| matches(mask)).map(|(_, why)| why.clone())
+    }
+
+    /// Writes `banned` to disk if there's a file, carrying on with just what's in memory if that fails.
+    fn keep(&self, banned: &[(Mask, String)]) {
+        if let Err(e) = self.save(banned) {
+            let path = self.path.lock().take().map(|path| path.display().to_string()).unwrap_or_default();
+            warn!("[BANS] Couldn't write to {path}, only keeping bans in memory from now on: {e}");
+        }
+    }
+
+    /// Writes all of `banned` to the file, swapping it in whole so a crash halfway leaves the old one.
+    fn save(&self, banned: &[(Mask, String)]) -> std::io::Result<()> {
+        let Some(path) = self.path.lock().clone() else {
+            return Ok(());
+        };
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        for (mask, why) in banned {
+            writeln!(file, "{mask} {why}")?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(s: &str) -> Mask {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_masks() {
+        assert_eq!(Mask::Nick("bob".to_string()), mask("Bob"));
+        assert_eq!(Mask::Nick("b?b*".to_string()), mask("b?b*"));
+        assert_eq!(Mask::Address(IpAddr::from([10, 1, 2, 3]), 32), mask("10.1.2.3"));
+        assert_eq!(Mask::Address(IpAddr::from([10, 0, 0, 0]), 8), mask("10.1.2.3/8"));
+        assert_eq!(Mask::Address(IpAddr::from([10, 1, 2, 3]), 32), mask("::ffff:10.1.2.3"));
+        assert_eq!("10.0.0.0/8", mask("10.9.9.9/8").to_string());
+        assert_eq!("2001:db8::/32", mask("2001:db8:1::1/32").to_string());
+        assert_eq!("::1", mask("::1/128").to_string());
+        assert_eq!(Err(MaskError::Prefix(IpAddr::from([10, 0, 0, 1]), 33, 32)), "10.0.0.1/33".parse::<Mask>());
+        for bad in ["", "bob/8", "10.0.0.1/x", "no spaces", "bob!"] {
+            assert_eq!(Err(MaskError::Invalid(bad.to_string())), bad.parse::<Mask>());
+        }
+    }
+
+    #[test]
+    fn bans_by_nick_and_address() {
+        let bans = Bans::default();
+        assert!(bans.ban(mask("Bob"), "Banned by alice: spamming".to_string()));
+        assert!(!bans.ban(mask("bob"), "again".to_string()));
+        assert!(bans.ban(mask("spam*bot?"), "Bots".to_string()));
+        assert!(bans.ban(mask("192.168.0.0/16"), "Whole office".to_string()));
+
+        assert_eq!(Some("Banned by alice: spamming".to_string()), bans.reason(&User::new("BOB")));
+        assert_eq!(Some("Bots".to_string()), bans.reason(&User::new("SpamTheBot2")));
+        assert_eq!(None, bans.reason(&User::new("spambot")));
+        assert_eq!(None, bans.reason(&User::new("carol")));
+        assert_eq!(Some("Whole office".to_string()), bans.reason_from(IpAddr::from([192, 168, 40, 2])));
+        assert_eq!(Some("Whole office".to_string()), bans.reason_from("::ffff:192.168.0.1".parse().unwrap()));
+        assert_eq!(None, bans.reason_from(IpAddr::from([192, 169, 0, 1])));
+
+        assert!(bans.unban(&mask("BOB")));
+        assert!(!bans.unban(&mask("bob")));
+        assert_eq!(None, bans.reason(&User::new("bob")));
+        assert_eq!(vec![mask("spam*bot?"), mask("192.168.0.0/16")], bans.list().into_iter().map(|(m, _)| m).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("basic-irc-bans-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let bans = Bans::open(&path).unwrap();
+        bans.ban(mask("bob"), "Banned by alice: spamming".to_string());
+        bans.ban(mask("10.0.0.0/8"), String::new());
+        bans.ban(mask("carol"), "Banned by alice".to_string());
+        bans.unban(&mask("carol"));
+        std::fs::write(&path, std::fs::read_to_string(&path).unwrap() + "!!! not a ban\n").unwrap();
+
+        let reopened = Bans::open(&path).unwrap();
+        let kept = vec![(mask("bob"), "Banned by alice: spamming".to_string()), (mask("10.0.0.0/8"), String::new())];
+        assert_eq!(kept, reopened.list());
+        // Rewritten without the junk
+        assert_eq!("bob Banned by alice: spamming\n10.0.0.0/8 \n", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+/// Explicit embeddings, overrides and isolates. Unlike the plain marks (LRM, RLM, ALM) these keep going
+/// until something closes them, which is how a RLO in someone's message ends up reversing the rest of the line.
+const CONTROLS: [char; 9] = [
+    '\u{202A}', '\u{202B}', '\u{202C}', '\u{202D}', '\u{202E}', // LRE RLE PDF LRO RLO
+    '\u{2066}', '\u{2067}', '\u{2068}', '\u{2069}', // LRI RLI FSI PDI
+];
+const FSI: char = '\u{2068}';
+const PDI: char = '\u{2069}';
+
+/// Makes text from someone else safe to put next to our own. Any explicit bidi controls get dropped and
+/// the rest gets wrapped in FSI/PDI, so RTL text still reads right but can't reorder anything around it,
+/// like the nick it's shown next to. Terminal controls like ESC and CR show up as U+FFFD instead, so nobody
+/// can move the cursor or write over what's already on screen, like to fake a line from someone else.
+pub fn isolate(text: &str) -> String {
+    let mut isolated = String::with_capacity(text.len() + 2 * FSI.len_utf8());
+    isolated.push(FSI);
+    isolated.extend(text.chars().filter(|c| !CONTROLS.contains(c)).map(|c| match c {
+        '\t' => ' ',
+        c if c.is_control() => char::REPLACEMENT_CHARACTER,
+        c => c,
+    }));
+    isolated.push(PDI);
+    isolated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_just_gets_wrapped() {
+        assert_eq!("\u{2068}hello\u{2069}", isolate("hello"));
+        assert_eq!("\u{2068}שלום\u{2069}", isolate("שלום"));
+    }
+
+    #[test]
+    fn controls_get_dropped() {
+        // RLO trying to make "gnp.exe" read as "exe.png", and a stray PDI trying to close our isolate early
+        assert_eq!("\u{2068}photo_gnp.exe\u{2069}", isolate("photo_\u{202E}gnp.exe"));
+        assert_eq!("\u{2068}hi there\u{2069}", isolate("hi\u{2069} there\u{2066}"));
+    }
+
+    #[test]
+    fn terminal_controls_get_replaced() {
+        // Going back to the start of the line, clearing it, and writing someone else's message over it
+        assert_eq!("\u{2068}\u{FFFD}\u{FFFD}[K<admin> hi\u{2069}", isolate("\r\x1b[K<admin> hi"));
+        assert_eq!("\u{2068}a b\u{FFFD}\u{FFFD}\u{2069}", isolate("a\tb\u{7F}\u{9B}"));
+    }
+
+    #[test]
+    fn marks_are_kept() {
+        assert_eq!("\u{2068}a\u{200F}b\u{2069}", isolate("a\u{200F}b"));
+    }
+}
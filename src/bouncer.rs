@@ -0,0 +1,399 @@
+use std::io::{BufRead, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use parking_lot::Mutex;
+use tracing::{field, info, info_span, warn, Span};
+use crate::backfill::{self, History, Seen};
+use crate::client::{Client, ClientError};
+use crate::frame::{encode_message, write_message, FrameError, Framed};
+use crate::listener;
+use crate::outbound::{spawn_writer, Frame, Outbound, OutboundError, WriterOptions};
+use crate::response::{AuthResponse, ServerMessage};
+use crate::server::{shake_hands, ServerError, HELLO_LIMITS};
+use crate::user::User;
+
+/// How long to wait before reconnecting upstream the first time, doubling each time after up to `MAX_RECONNECT`.
+const RECONNECT: Duration = Duration::from_secs(1);
+const MAX_RECONNECT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BouncerOptions {
+    pub upstream: SocketAddr,
+    /// Nick the bouncer logs into the upstream server with, and the only one it lets attach.
+    pub name: String,
+    /// Messages kept for attaching clients to catch up on, past which the oldest get forgotten.
+    pub backlog: usize,
+    /// Messages that may wait to go out to one attached client before new ones for it get dropped.
+    pub send_queue_len: usize,
+    /// Longest line in bytes an attached client may send upstream; longer lines are dropped.
+    pub max_line_len: usize,
+}
+
+#[derive(Debug)]
+struct AttachedClient {
+    id: u64,
+    outbound: Outbound,
+    /// What's come in since it attached, held back until it's been caught up. `None` once it's live.
+    held: Option<Vec<Frame>>,
+}
+
+/// Who's attached, and what they've all been sent.
+#[derive(Debug, Default)]
+struct Attached {
+    clients: Vec<AttachedClient>,
+    next_id: u64,
+    history: History,
+    /// Where everyone had got up to when the last client detached.
+    detached_at: Seen,
+}
+
+#[derive(Debug, Default)]
+struct Bouncer {
+    /// Where lines from attached clients go, while connected upstream.
+    upstream: Mutex<Option<TcpStream>>,
+    attached: Mutex<Attached>,
+}
+
+/// Stays logged into the upstream server as `options.name` for good, reconnecting whenever it's lost, and
+/// lets that user's clients attach on `address` like it was the server itself. Clients get caught up on
+/// what they missed when they attach, see `backfill`.
+pub fn start(address: SocketAddr, options: BouncerOptions) -> std::io::Result<()> {
+    let listener = listener::bind_one(address)?;
+    println!("Bouncing {} as {} on port {}", options.upstream, options.name, listener.local_addr()?.port());
+
+    let options = Arc::new(options);
+    let bouncer = Arc::new(Bouncer::new(options.backlog));
+    let (upstream_options, upstream_bouncer) = (options.clone(), bouncer.clone());
+    thread::Builder::new()
+        .name("upstream".to_string())
+        .spawn(move || stay_connected(&upstream_options, &upstream_bouncer))?;
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let (options, bouncer) = (options.clone(), bouncer.clone());
+                thread::spawn(move || {
+                    let _entered = info_span!("client", id = field::Empty).entered();
+                    if let Err(e) = attach(stream, &options, &bouncer) {
+                        warn!("[BOUNCER] Client went away: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("[BOUNCER] Failed to accept a client: {e:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn stay_connected(options: &BouncerOptions, bouncer: &Bouncer) {
+    let mut wait = RECONNECT;
+    loop {
+        let e = follow(options, bouncer, &mut wait).unwrap_err();
+        *bouncer.upstream.lock() = None;
+        warn!("[BOUNCER] Lost upstream {}, reconnecting in {wait:?}: {e}", options.upstream);
+        bouncer.notice(&format!("Lost the connection to {} ({e}), reconnecting", options.upstream));
+
+        thread::sleep(wait);
+        wait = (wait * 2).min(MAX_RECONNECT);
+    }
+}
+
+/// Passes along everything upstream says until it's gone, starting `wait` over once logged in.
+fn follow(options: &BouncerOptions, bouncer: &Bouncer, wait: &mut Duration) -> Result<(), ClientError> {
+    let tcp = TcpStream::connect(options.upstream)?;
+    let writer = tcp.try_clone()?;
+    let mut upstream = Client::new(User::new(options.name.clone()), tcp);
+    upstream.do_auth_flow()?;
+
+    *wait = RECONNECT;
+    *bouncer.upstream.lock() = Some(writer);
+    info!("[BOUNCER] Connected to {} as {}", options.upstream, options.name);
+    bouncer.notice(&format!("Connected to {} as {}", options.upstream, options.name));
+
+    loop {
+        let message = upstream.next_message()?;
+        bouncer.deliver(backfill::buffer(&message, &options.name), message);
+    }
+}
+
+impl Bouncer {
+    fn new(backlog: usize) -> Self {
+        let attached = Attached { history: History::new(backlog), ..Default::default() };
+        Self { attached: Mutex::new(attached), ..Default::default() }
+    }
+
+    /// Numbers `message` and sends it to everyone attached, or holds it for those still being caught up.
+    fn deliver(&self, buffer: String, message: ServerMessage) {
+        let mut attached = self.attached.lock();
+        let frame = match attached.history.push(buffer, message) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("[BOUNCER] Couldn't encode message: {e:?}");
+                return;
+            }
+        };
+
+        attached.clients.retain_mut(|client| {
+            if let Some(held) = &mut client.held {
+                held.push(frame.clone());
+                return true;
+            }
+            match client.outbound.send(frame.clone()) {
+                Ok(()) => true,
+                Err(OutboundError::Full) => {
+                    warn!("[BOUNCER] Client {} isn't keeping up, dropping a message for it", client.id);
+                    true
+                }
+                Err(OutboundError::Closed) => false,
+            }
+        });
+    }
+
+    fn notice(&self, text: &str) {
+        self.deliver(backfill::STATUS.to_string(), ServerMessage::notice(text));
+    }
+
+    /// Adds a client, held back until `catch_up`. Returns its id, where the history was at, and where it
+    /// should be caught up from if it doesn't say.
+    fn add(&self, outbound: Outbound) -> (u64, u64, Seen) {
+        let mut attached = self.attached.lock();
+        let id = attached.next_id;
+        attached.next_id += 1;
+
+        // Coming in alongside other clients there's nothing to catch up on, since it's all been seen
+        let seen = match attached.clients.is_empty() {
+            true => attached.detached_at.clone(),
+            false => attached.history.latest(),
+        };
+        attached.clients.push(AttachedClient { id, outbound, held: Some(Vec::new()) });
+        (id, attached.history.position(), seen)
+    }
+
+    /// Sends client `id` everything after `seen` up to `position`, then what was held for it, and lets it go live.
+    fn catch_up(&self, id: u64, seen: &Seen, position: u64) -> Result<(), FrameError> {
+        let mut attached = self.attached.lock();
+        let backfill = attached.history.since(seen, position);
+        let Some(client) = attached.clients.iter_mut().find(|client| client.id == id) else {
+            return Ok(());
+        };
+
+        let caught_up = match (backfill.frames.len(), backfill.forgotten) {
+            (0, 0) => None,
+            (n, 0) => Some(format!("{n} messages while you were gone:")),
+            (n, forgotten) => Some(format!("{n} messages while you were gone, and {forgotten} older ones that didn't fit:")),
+        };
+        if let Some(text) = caught_up {
+            let _ = client.outbound.send(encode_message(&ServerMessage::notice(text))?.into());
+        }
+        for frame in backfill.frames.into_iter().chain(client.held.take().unwrap_or_default()) {
+            let _ = client.outbound.send(frame);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) {
+        let mut attached = self.attached.lock();
+        attached.clients.retain(|client| client.id != id);
+        if attached.clients.is_empty() {
+            attached.detached_at = attached.history.latest();
+        }
+    }
+}
+
+/// Logs a client in like the server would, as long as it's the bouncer's user, then catches it up on what
+/// it missed and passes along what it says until it detaches.
+fn attach(mut stream: TcpStream, options: &BouncerOptions, bouncer: &Bouncer) -> Result<(), ServerError> {
+    // Lines can come in right behind the hello, so both get read through the one buffer
+    let mut reader = Framed::new(stream.try_clone()?);
+    let capabilities = shake_hands(&mut reader, &mut stream, &[backfill::FEATURE])?;
+    let hello: User = reader.read_message(&HELLO_LIMITS)?;
+    if hello.name != options.name {
+        let resp = format!("This bouncer is only for {}", options.name);
+        write_message(&mut stream, &AuthResponse::Error(resp))?;
+        return Ok(());
+    }
+    write_message(&mut stream, &AuthResponse::Success)?;
+
+    // Room for the whole backlog on top of the usual queue, since it all goes out at once
+    let (outbound, queue) = Outbound::new(options.backlog + options.send_queue_len + 1);
+    spawn_writer(stream.try_clone()?, queue, WriterOptions::default())?;
+    let (id, position, detached_at) = bouncer.add(outbound.clone());
+    Span::current().record("id", id);
+    info!("[BOUNCER] Attached");
+
+    let client = (id, position, detached_at);
+    let result = relay(&mut reader, options, bouncer, capabilities.has(backfill::FEATURE), client, &outbound);
+    bouncer.remove(id);
+    info!("[BOUNCER] Detached");
+    Ok(result?)
+}
+
+/// What the client's `/backfill` line says it's seen, leaving anything else it sent for `relay`.
+fn said_seen(reader: &mut Framed<TcpStream>, options: &BouncerOptions) -> std::io::Result<Option<Seen>> {
+    if !reader.fill_buf()?.starts_with(backfill::COMMAND.as_bytes()) {
+        return Ok(None);
+    }
+    let mut line = String::new();
+    (&mut *reader).take(options.max_line_len as u64).read_line(&mut line)?;
+    Ok(backfill::parse_request(&line))
+}
+
+/// Catches client `id` up, then passes along its lines until it's gone.
+fn relay(
+    reader: &mut Framed<TcpStream>,
+    options: &BouncerOptions,
+    bouncer: &Bouncer,
+    can_backfill: bool,
+    (id, position, detached_at): (u64, u64, Seen),
+    outbound: &Outbound,
+) -> Result<(), FrameError> {
+    // Clients that can say what they've seen do so first thing, so nothing goes out to them till then
+    let seen = match can_backfill {
+        true => said_seen(reader, options)?,
+        false => None,
+    };
+    let seen = seen.filter(|seen| !seen.is_empty()).unwrap_or(detached_at);
+    bouncer.catch_up(id, &seen, position)?;
+
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let n = (&mut *reader).take(options.max_line_len as u64 + 1).read_until(b'\n', &mut line)?;
+        if n == 0 {
+            return Ok(());
+        }
+        if line.len() > options.max_line_len {
+            // Skip the rest of it so the next line starts fresh
+            reader.skip_until(b'\n')?;
+            continue;
+        }
+
+        let sent = match bouncer.upstream.lock().as_ref() {
+            Some(mut upstream) => upstream.write_all(&line).is_ok(),
+            None => false,
+        };
+        if !sent {
+            let text = format!("Not connected to {} right now, that didn't go through", options.upstream);
+            let _ = outbound.send(encode_message(&ServerMessage::notice(text))?.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::io::BufReader;
+    use crate::frame::{read_message, FrameLimits};
+    use crate::response::{Capabilities, Handshake};
+    use crate::testing::accepted;
+    use super::*;
+
+    fn chat(text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new("far"), text: text.to_string(), received_ms: 1, channel: None }
+    }
+
+    /// Connects to the bouncer as `name` asking for `features`, returning the connection if it's let in.
+    fn connect(address: SocketAddr, name: &str, features: &[&str]) -> Result<TcpStream, AuthResponse> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write_message(&mut stream, &Handshake::new(features)).unwrap();
+        let capabilities: Capabilities = read_message(&mut stream, &FrameLimits::default()).unwrap();
+        assert_eq!(features.contains(&backfill::FEATURE), capabilities.has(backfill::FEATURE));
+        write_message(&mut stream, &User::new(name)).unwrap();
+        match read_message(&mut stream, &FrameLimits::default()).unwrap() {
+            AuthResponse::Success => Ok(stream),
+            resp => Err(resp),
+        }
+    }
+
+    /// Takes the bouncer's connection upstream, returning it and who it logged in as.
+    fn accept(upstream: &TcpListener) -> (TcpStream, User) {
+        let (mut server, _) = upstream.accept().unwrap();
+        let _: Handshake = read_message(&mut server, &FrameLimits::default()).unwrap();
+        server.write_all(&accepted()).unwrap();
+        let hello = read_message(&mut server, &FrameLimits::default()).unwrap();
+        write_message(&mut server, &AuthResponse::Success).unwrap();
+        (server, hello)
+    }
+
+    /// The next message, minus its number if it has one.
+    fn next(stream: &mut TcpStream) -> ServerMessage {
+        match read_message(stream, &FrameLimits::default()).unwrap() {
+            ServerMessage::Sequenced { message, .. } => *message,
+            message => message,
+        }
+    }
+
+    #[test]
+    fn replays_what_was_missed() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = BouncerOptions {
+            upstream: upstream.local_addr().unwrap(),
+            name: "alice".to_string(),
+            backlog: 2,
+            send_queue_len: 8,
+            max_line_len: 64,
+        };
+
+        // The bouncer's own listener, taken over from `start` so the test knows its port
+        let local = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = local.local_addr().unwrap();
+        let bouncer = Arc::new(Bouncer::new(options.backlog));
+        {
+            let (upstream_options, upstream_bouncer) = (options.clone(), bouncer.clone());
+            thread::spawn(move || stay_connected(&upstream_options, &upstream_bouncer));
+            let (options, bouncer) = (options.clone(), bouncer.clone());
+            thread::spawn(move || {
+                for stream in local.incoming() {
+                    let (options, bouncer) = (options.clone(), bouncer.clone());
+                    thread::spawn(move || attach(stream.unwrap(), &options, &bouncer));
+                }
+            });
+        }
+
+        let (mut server, hello) = accept(&upstream);
+        assert_eq!(User::new("alice"), hello);
+        for text in ["one", "two", "three"] {
+            write_message(&mut server, &chat(text)).unwrap();
+        }
+        // Nothing's attached, so it's all missed, but only the last two fit along with the connect notice
+        while bouncer.attached.lock().history.position() < 4 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(matches!(connect(address, "bob", &[]), Err(AuthResponse::Error(_))));
+        let mut client = connect(address, "alice", &[]).unwrap();
+        assert_eq!(
+            ServerMessage::notice("2 messages while you were gone, and 2 older ones that didn't fit:"),
+            next(&mut client)
+        );
+        assert_eq!(chat("two"), next(&mut client));
+        assert_eq!(chat("three"), next(&mut client));
+
+        // Live from here on, both ways
+        write_message(&mut server, &chat("four")).unwrap();
+        assert_eq!(chat("four"), next(&mut client));
+        client.write_all(b"hello upstream\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&mut server).read_line(&mut line).unwrap();
+        assert_eq!("hello upstream\n", line);
+
+        // A client that says what it's seen gets just the rest, even with someone else attached
+        let mut other = connect(address, "alice", &[backfill::FEATURE]).unwrap();
+        other.write_all(b"/backfill {\"&lobby\":2,\"*\":1}\n").unwrap();
+        assert_eq!(ServerMessage::notice("2 messages while you were gone:"), next(&mut other));
+        assert_eq!(chat("three"), next(&mut other));
+        assert_eq!(chat("four"), next(&mut other));
+        write_message(&mut server, &chat("five")).unwrap();
+        assert_eq!(chat("five"), next(&mut other));
+        assert_eq!(chat("five"), next(&mut client));
+
+        // Losing upstream gets said, and the bouncer comes back on its own
+        drop(server);
+        assert!(matches!(next(&mut client), ServerMessage::Notice { text, .. } if text.starts_with("Lost the connection")));
+        let _server = accept(&upstream);
+        assert!(matches!(next(&mut client), ServerMessage::Notice { text, .. } if text.starts_with("Connected to")));
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A pool of bytes that buffers have to reserve from before they hold onto data, so they have a ceiling instead
+/// of growing with whatever clients throw at it. Only chat lines on their way from a connection to the
+/// broadcaster reserve from it. Send queues are capped in frames, history in messages and cluster links in
+/// frames per link, none of which are charged here.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// Bytes taken out of a `MemoryBudget`. They go back into it when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Reserves `bytes` if there's room for them, otherwise counts a rejection and returns `None`.
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize) -> Option<Reservation> {
+        let reserved = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|total| *total <= self.limit)
+        });
+
+        match reserved {
+            Ok(_) => Some(Reservation { budget: self.clone(), bytes }),
+            Err(_) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// How many reservations have been turned away so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_and_release() {
+        let budget = MemoryBudget::new(10);
+
+        let first = budget.try_reserve(6).unwrap();
+        assert_eq!(6, budget.used());
+        assert!(budget.try_reserve(5).is_none());
+        assert_eq!(1, budget.rejected());
+
+        let second = budget.try_reserve(4).unwrap();
+        assert_eq!(10, budget.used());
+
+        drop(first);
+        assert_eq!(4, budget.used());
+        drop(second);
+        assert_eq!(0, budget.used());
+    }
+
+    #[test]
+    fn reserve_doesnt_overflow() {
+        let budget = MemoryBudget::new(usize::MAX);
+        let _held = budget.try_reserve(10).unwrap();
+        assert!(budget.try_reserve(usize::MAX).is_none());
+        assert_eq!(10, budget.used());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{IoSlice, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use parking_lot::Mutex;
+use crate::token_bucket::TokenBucket;
+
+/// How many clients can be connected at once. A connection takes a `Seat` as soon as it's accepted, before
+/// it waits for a worker, and holds it until its handler and its writer are both done with it. So there are
+/// never more writer threads than seats, even with some still getting the last of their queue out to a slow client.
+#[derive(Debug)]
+pub struct ClientLimit {
+    max: usize,
+    connected: AtomicUsize,
+    turned_away: AtomicU64,
+}
+
+/// One connection's place under a `ClientLimit`. It's given up when this is dropped.
+#[derive(Debug)]
+pub struct Seat {
+    limit: Arc<ClientLimit>,
+}
+
+impl ClientLimit {
+    /// At most `max` clients, or as many as can connect if `None`.
+    pub fn new(max: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max: max.unwrap_or(usize::MAX),
+            connected: AtomicUsize::new(0),
+            turned_away: AtomicU64::new(0),
+        })
+    }
+
+    /// A seat if there's one free, otherwise counts someone turned away and returns `None`.
+    pub fn try_seat(self: &Arc<Self>) -> Option<Seat> {
+        let seated = self.connected.fetch_update(Ordering::AcqRel, Ordering::Acquire, |connected| {
+            (connected < self.max).then_some(connected + 1)
+        });
+
+        match seated {
+            Ok(_) => Some(Seat { limit: self.clone() }),
+            Err(_) => {
+                self.turned_away.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Clients holding a seat right now.
+    #[cfg(any(test, feature = "tokio"))]
+    pub fn connected(&self) -> usize {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// How many have been turned away for the server being full so far.
+    pub fn turned_away(&self) -> u64 {
+        self.turned_away.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        self.limit.connected.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A connection's writer, holding onto its `Seat` for as long as it's around.
+#[derive(Debug)]
+pub struct Seated<W> {
+    writer: W,
+    _seat: Seat,
+}
+
+impl<W> Seated<W> {
+    pub fn new(writer: W, seat: Seat) -> Self {
+        Self { writer, _seat: seat }
+    }
+}
+
+impl<W: Write> Write for Seated<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// How fast new connections get let in, so a crowd reconnecting all at once, like after a restart, gets
+/// spread out instead of hammering the listener. Whoever's turned away is told when to come back.
+#[derive(Debug)]
+pub struct AcceptRate {
+    bucket: Option<Mutex<TokenBucket>>,
+    /// How long the bucket takes to fill back up, which is what comebacks get spread across.
+    spread: Duration,
+    turned_away: AtomicU64,
+}
+
+impl AcceptRate {
+    /// `per_sec` connections a second, after `burst` all at once. 0 lets them all in.
+    pub fn new(per_sec: u64, burst: u64) -> Self {
+        let bucket = (per_sec > 0).then(|| Mutex::new(TokenBucket::new(per_sec, burst.max(1), Instant::now())));
+        let spread = Duration::from_secs_f64(burst.max(1) as f64 / per_sec.max(1) as f64).max(Duration::from_secs(1));
+        Self { bucket, spread, turned_away: AtomicU64::new(0) }
+    }
+
+    /// Lets a connection in, or counts it turned away and says how long it should wait before trying again.
+    /// That's when there'd be room for it plus up to `spread` more, at random, so everyone turned away
+    /// doesn't come back at the same moment and get turned away again.
+    pub fn try_accept(&self, now: Instant) -> Result<(), Duration> {
+        let Some(bucket) = &self.bucket else {
+            return Ok(());
+        };
+        let Err(wait) = bucket.lock().try_take(1, now) else {
+            return Ok(());
+        };
+        self.turned_away.fetch_add(1, Ordering::Relaxed);
+        Err(wait + jitter(self.spread))
+    }
+
+    /// How many have been turned away for coming too fast so far.
+    pub fn turned_away(&self) -> u64 {
+        self.turned_away.load(Ordering::Relaxed)
+    }
+}
+
+/// Anywhere from nothing to `max`. Doesn't need to be any good as randomness, just different each time.
+fn jitter(max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    max.mul_f64(hasher.finish() as f64 / u64::MAX as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seats_until_full() {
+        let limit = ClientLimit::new(Some(2));
+
+        let first = limit.try_seat().unwrap();
+        let _second = limit.try_seat().unwrap();
+        assert!(limit.try_seat().is_none());
+        assert_eq!((2, 1), (limit.connected(), limit.turned_away()));
+
+        drop(first);
+        assert_eq!(1, limit.connected());
+        assert!(limit.try_seat().is_some());
+        assert_eq!(1, limit.connected());
+    }
+
+    #[test]
+    fn writers_keep_their_seat() {
+        let limit = ClientLimit::new(Some(1));
+        let mut writer = Seated::new(Vec::new(), limit.try_seat().unwrap());
+        assert!(limit.try_seat().is_none());
+
+        writer.write_all(b"still going").unwrap();
+        assert_eq!(b"still going", writer.writer.as_slice());
+        drop(writer);
+        assert_eq!(0, limit.connected());
+    }
+
+    #[test]
+    fn accepts_at_a_rate() {
+        let (rate, now) = (AcceptRate::new(10, 2), Instant::now());
+        assert_eq!((Ok(()), Ok(())), (rate.try_accept(now), rate.try_accept(now)));
+
+        // Back once there's room, up to the time to fill back up later
+        let mut waits = Vec::new();
+        for _ in 0..20 {
+            let wait = rate.try_accept(now).unwrap_err();
+            assert!(wait >= Duration::from_millis(100) && wait <= Duration::from_millis(1100), "{wait:?}");
+            waits.push(wait);
+        }
+        waits.dedup();
+        assert!(waits.len() > 1, "They're all told to come back at once: {waits:?}");
+        assert_eq!(20, rate.turned_away());
+        assert_eq!(Ok(()), rate.try_accept(now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn unlimited() {
+        let (rate, now) = (AcceptRate::new(0, 0), Instant::now());
+        assert!((0..1000).all(|_| rate.try_accept(now).is_ok()));
+    }
+}
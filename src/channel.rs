@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+use crate::user::User;
+
+const MAX_NAME_LEN: usize = 50;
+/// What the lobby's called wherever it needs a name.
+pub const LOBBY: &str = "&lobby";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    #[error("Channel names start with # and have no spaces, commas or control characters, up to {MAX_NAME_LEN} long: `{0}`")]
+    BadName(String),
+}
+
+/// Checks `name` is something that could be a channel, like `#rust`.
+pub fn validate(name: &str) -> Result<(), ChannelError> {
+    let valid = name.starts_with('#')
+        && (2..=MAX_NAME_LEN).contains(&name.chars().count())
+        && !name.chars().any(|c| c == ' ' || c == ',' || c.is_control());
+
+    if valid { Ok(()) } else { Err(ChannelError::BadName(name.to_string())) }
+}
+
+/// Who's in which channel. Channels exist as long as someone's in them, so joining one that doesn't exist
+/// makes it and the last one out turns off the lights.
+///
+/// Whatever someone says goes to the channel they joined last that they're still in. Anyone who isn't in
+/// any channel is in the lobby, where everyone else in the lobby hears them, same as before channels.
+#[derive(Debug, Default)]
+pub struct Channels {
+    members: BTreeMap<String, BTreeSet<User>>,
+    /// What each user is in, oldest join first, so the last one is where they're talking.
+    joined: BTreeMap<User, Vec<String>>,
+}
+
+impl Channels {
+    /// Puts `user` in `channel` and makes it where they're talking. Returns whether they weren't already in it.
+    pub fn join(&mut self, user: &User, channel: &str) -> bool {
+        let joined = self.joined.entry(user.clone()).or_default();
+        joined.retain(|c| c != channel);
+        joined.push(channel.to_string());
+
+        self.members.entry(channel.to_string()).or_default().insert(user.clone())
+    }
+
+    /// Takes `user` out of `channel`, returning whether they were in it.
+    pub fn part(&mut self, user: &User, channel: &str) -> bool {
+        let Some(members) = self.members.get_mut(channel) else {
+            return false;
+        };
+        if !members.remove(user) {
+            return false;
+        }
+        if members.is_empty() {
+            self.members.remove(channel);
+        }
+
+        if let Some(joined) = self.joined.get_mut(user) {
+            joined.retain(|c| c != channel);
+            if joined.is_empty() {
+                self.joined.remove(user);
+            }
+        }
+        true
+    }
+
+    /// Takes `user` out of everything, e.g. when they disconnect.
+    pub fn leave_all(&mut self, user: &User) {
+        for channel in self.joined.remove(user).unwrap_or_default() {
+            if let Some(members) = self.members.get_mut(&channel) {
+                members.remove(user);
+                if members.is_empty() {
+                    self.members.remove(&channel);
+                }
+            }
+        }
+    }
+
+    /// Moves everything `from` is in over to `to`, keeping where they're talking.
+    pub fn rename(&mut self, from: &User, to: &User) {
+        let Some(joined) = self.joined.remove(from) else {
+            return;
+        };
+        for channel in &joined {
+            if let Some(members) = self.members.get_mut(channel) {
+                members.remove(from);
+                members.insert(to.clone());
+            }
+        }
+        self.joined.insert(to.clone(), joined);
+    }
+
+    /// Where `user` is talking, or `None` for the lobby.
+    pub fn current(&self, user: &User) -> Option<&str> {
+        self.joined.get(user)?.last().map(String::as_str)
+    }
+
+    /// Whether `user` hears what's said in `channel`, where `None` is the lobby.
+    pub fn hears(&self, user: &User, channel: Option<&str>) -> bool {
+        match channel {
+            Some(channel) => self.members.get(channel).is_some_and(|members| members.contains(user)),
+            None => !self.joined.contains_key(user),
+        }
+    }
+
+    /// Every channel with how many are in it.
+    pub fn sizes(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.members.iter().map(|(channel, members)| (channel.as_str(), members.len()))
+    }
+
+    /// How many users are in any channel at all, so everyone else is in the lobby.
+    pub fn in_any(&self) -> usize {
+        self.joined.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert!(validate("#rust").is_ok());
+        assert!(validate("#日本語").is_ok());
+        for bad in ["rust", "#", "#a b", "#a,b", "#a\u{7}", &format!("#{}", "a".repeat(MAX_NAME_LEN))] {
+            assert_eq!(Err(ChannelError::BadName(bad.to_string())), validate(bad));
+        }
+    }
+
+    #[test]
+    fn join_and_part() {
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let mut channels = Channels::default();
+
+        assert!(channels.join(&alice, "#rust"));
+        assert!(!channels.join(&alice, "#rust"));
+        assert!(channels.hears(&alice, Some("#rust")));
+        assert!(!channels.hears(&alice, None));
+        assert!(!channels.hears(&bob, Some("#rust")));
+        assert!(channels.hears(&bob, None));
+
+        assert!(!channels.part(&bob, "#rust"));
+        assert!(channels.part(&alice, "#rust"));
+        assert!(!channels.part(&alice, "#rust"));
+        assert!(channels.hears(&alice, None));
+        assert!(channels.members.is_empty());
+    }
+
+    #[test]
+    fn talks_in_the_last_joined() {
+        let alice = User::new("alice");
+        let mut channels = Channels::default();
+        assert_eq!(None, channels.current(&alice));
+
+        channels.join(&alice, "#rust");
+        channels.join(&alice, "#go");
+        assert_eq!(Some("#go"), channels.current(&alice));
+        // Joining again switches back to it
+        channels.join(&alice, "#rust");
+        assert_eq!(Some("#rust"), channels.current(&alice));
+
+        channels.part(&alice, "#rust");
+        assert_eq!(Some("#go"), channels.current(&alice));
+    }
+
+    #[test]
+    fn leave_all_cleans_up() {
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let mut channels = Channels::default();
+        channels.join(&alice, "#rust");
+        channels.join(&alice, "#go");
+        channels.join(&bob, "#rust");
+
+        channels.leave_all(&alice);
+        assert_eq!(None, channels.current(&alice));
+        assert!(channels.hears(&bob, Some("#rust")));
+        assert_eq!(vec!["#rust"], channels.members.keys().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,349 @@
+//! `--check-config`: goes over what a server's been given and says what would go wrong starting it, without
+//! starting it. Each check passes, fails (the server wouldn't start, or wouldn't work) or warns (it would, but
+//! probably not how it was meant to).
+
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use clap::ValueEnum;
+use rust_threading::server::{self, Protocol};
+#[cfg(feature = "tls")]
+use rust_threading::tls;
+use rust_threading::outbound::WriterOptions;
+use rust_threading::{secret, signing};
+use rust_threading::user::User;
+use crate::args::{Args, Runtime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Everything that got checked, in order.
+#[derive(Debug, Default)]
+pub struct Report {
+    checks: Vec<(Outcome, &'static str, String)>,
+}
+
+impl Report {
+    pub fn ok(&mut self, what: &'static str, detail: impl Into<String>) {
+        self.checks.push((Outcome::Ok, what, detail.into()));
+    }
+
+    pub fn warn(&mut self, what: &'static str, detail: impl Into<String>) {
+        self.checks.push((Outcome::Warn, what, detail.into()));
+    }
+
+    pub fn fail(&mut self, what: &'static str, detail: impl Into<String>) {
+        self.checks.push((Outcome::Fail, what, detail.into()));
+    }
+
+    fn count(&self, outcome: Outcome) -> usize {
+        self.checks.iter().filter(|(o, ..)| *o == outcome).count()
+    }
+
+    /// Whether the server's good to start, warnings and all.
+    pub fn passed(&self) -> bool {
+        self.count(Outcome::Fail) == 0
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (outcome, what, detail) in &self.checks {
+            let outcome = match outcome {
+                Outcome::Ok => "ok",
+                Outcome::Warn => "warn",
+                Outcome::Fail => "FAIL",
+            };
+            writeln!(f, "{outcome:<5} {what:<13} {detail}")?;
+        }
+        write!(f, "{} problem(s), {} warning(s)", self.count(Outcome::Fail), self.count(Outcome::Warn))
+    }
+}
+
+/// Checks everything a server started with `args` would need, with `config` being the file they came from,
+/// if any.
+pub fn check(args: &Args, config: Option<&Path>) -> Report {
+    let mut report = Report::default();
+    if let Some(config) = config {
+        // It wouldn't have got this far if it didn't parse
+        report.ok("config", format!("{} parses", config.display()));
+    }
+
+    match &args.unix {
+        Some(path) => socket(&mut report, "listen", path),
+        None => listen(&mut report, args),
+    }
+    if let Some(addr) = args.cluster_listen {
+        bind(&mut report, "cluster", addr);
+    }
+    if let Some(path) = &args.node_key {
+        match path.exists() {
+            true => match signing::load_or_create(path) {
+                Ok(_) => report.ok("node key", format!("{} loads", path.display())),
+                Err(e) => report.fail("node key", format!("{}: {e}", path.display())),
+            },
+            false => file_to_make(&mut report, "node key", path),
+        }
+    }
+
+    match (args.tls, &args.tls_cert, &args.tls_key) {
+        #[cfg(feature = "tls")]
+        (true, Some(cert), Some(key)) => match tls::server_config(cert, key) {
+            Ok(_) => report.ok("tls", format!("{} and {} load", cert.display(), key.display())),
+            Err(e) => report.fail("tls", e.to_string()),
+        },
+        #[cfg(not(feature = "tls"))]
+        (true, Some(_), Some(_)) => report.fail("tls", "--tls needs the tls feature, which this was built without"),
+        (true, ..) => report.fail("tls", "--tls needs --tls-cert and --tls-key"),
+        (false, None, None) => {}
+        (false, ..) => report.warn("tls", "--tls-cert or --tls-key is given, but --tls isn't on"),
+    }
+
+    if let Some(path) = &args.password_file {
+        match secret::read(path) {
+            Ok(_) => report.ok("password", format!("{} reads, and not everyone can", path.display())),
+            Err(e) => report.fail("password", e.to_string()),
+        }
+    }
+
+    if let Some(path) = &args.motd {
+        match std::fs::read_to_string(path) {
+            Ok(_) => report.ok("motd", format!("{} reads", path.display())),
+            Err(e) => report.fail("motd", format!("Couldn't read {}: {e}", path.display())),
+        }
+    }
+    let proven = args.accounts.is_some() || args.oper_password.is_some() || args.oper_password_file.is_some();
+    for nick in &args.oper {
+        match User::new(nick.as_str()).validate() {
+            Ok(()) if proven => report.ok("oper", format!("{nick} is a valid nick")),
+            Ok(()) => {
+                report.fail("oper", format!("{nick} needs --accounts or --oper-password, or anyone connecting as it is one"))
+            }
+            Err(e) => report.fail("oper", format!("{nick}: {e}")),
+        }
+    }
+
+    if let Some(path) = &args.history_file {
+        file_to_make(&mut report, "history file", path);
+    }
+    if let Some(path) = &args.accounts {
+        file_to_make(&mut report, "accounts", path);
+    }
+    if let Some(path) = &args.bans_file {
+        file_to_make(&mut report, "bans file", path);
+    }
+    if let Some(path) = &args.admin_socket {
+        socket(&mut report, "admin socket", path);
+    }
+
+    let limits = [("workers", args.workers), ("acceptors", args.acceptors), ("send queue", args.send_queue_len)];
+    for (name, value) in limits.into_iter().chain(args.max_clients.map(|max| ("max clients", max))) {
+        if value == 0 {
+            report.fail("limits", format!("The {name} can't be 0"));
+        }
+    }
+    if args.acceptors > 1 && !cfg!(target_os = "linux") {
+        report.warn("limits", "More than one acceptor needs SO_REUSEPORT, so there's just one here");
+    }
+
+    if args.runtime != Runtime::Threads {
+        runtime(&mut report, args);
+    }
+    features(&mut report, args);
+    report
+}
+
+/// Options that need a cargo feature this was built without. The server can't do without the ones that fail,
+/// and just goes without the ones that warn.
+pub fn features(report: &mut Report, args: &Args) {
+    let needed = [
+        ("accounts", "--accounts", args.accounts.is_some(), cfg!(feature = "accounts"), Outcome::Fail),
+        ("mio", "--runtime mio", args.runtime == Runtime::Mio, cfg!(feature = "mio"), Outcome::Fail),
+        ("tokio", "--runtime tokio", args.runtime == Runtime::Tokio, cfg!(feature = "tokio"), Outcome::Fail),
+        ("irc", "--protocol irc", args.protocol == Protocol::Irc, cfg!(feature = "irc"), Outcome::Fail),
+        ("mdns", "--mdns", args.mdns, cfg!(feature = "mdns"), Outcome::Warn),
+        ("upnp", "--upnp", args.upnp, cfg!(feature = "upnp"), Outcome::Warn),
+    ];
+    for (feature, option, given, built, outcome) in needed {
+        if given && !built {
+            let detail = format!("{option} needs the {feature} feature, which this was built without");
+            report.checks.push((outcome, "features", detail));
+        }
+    }
+}
+
+/// Whether the server can listen where `args` say.
+pub fn listen(report: &mut Report, args: &Args) {
+    let (host, port) = args.endpoint();
+    match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => bind(report, "listen", addr),
+        Ok(None) => report.fail("listen", format!("{host} doesn't resolve to any address")),
+        Err(e) => report.fail("listen", format!("Couldn't resolve {host}: {e}")),
+    }
+}
+
+fn bind(report: &mut Report, what: &'static str, addr: SocketAddr) {
+    let e = match TcpListener::bind(addr) {
+        Ok(_) if addr.port() == 0 => return report.ok(what, format!("{} can listen on any free port", addr.ip())),
+        Ok(_) => return report.ok(what, format!("{addr} is free")),
+        Err(e) => e,
+    };
+    let fix = match e.kind() {
+        ErrorKind::PermissionDenied if addr.port() < 1024 => {
+            ". Ports under 1024 need root or CAP_NET_BIND_SERVICE, or pick one over 1024"
+        }
+        ErrorKind::AddrInUse => ". Something else is listening there already, `ss -ltnp` says what",
+        ErrorKind::AddrNotAvailable => ". That isn't an address of this machine, 0.0.0.0 or :: listens on all of them",
+        _ => "",
+    };
+    report.fail(what, format!("Can't listen on {addr}: {e}{fix}"));
+}
+
+/// A file that's either there already or gets made, which it can only be if its directory's there.
+fn file_to_make(report: &mut Report, what: &'static str, path: &Path) {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if path.is_dir() {
+        report.fail(what, format!("{} is a directory", path.display()));
+    } else if path.exists() {
+        report.ok(what, format!("{} is there", path.display()));
+    } else if parent.is_dir() {
+        report.ok(what, format!("{} will be made", path.display()));
+    } else {
+        report.fail(what, format!("{} can't be made, there's no {}", path.display(), parent.display()));
+    }
+}
+
+#[cfg(unix)]
+fn socket(report: &mut Report, what: &'static str, path: &Path) {
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        report.fail(what, format!("There's already a server on {}", path.display()));
+    } else {
+        file_to_make(report, what, path);
+    }
+}
+
+#[cfg(not(unix))]
+fn socket(report: &mut Report, what: &'static str, _: &Path) {
+    report.fail(what, "Needs Unix sockets, which there aren't here");
+}
+
+/// What mio and tokio would quietly ignore, or refuse.
+fn runtime(report: &mut Report, args: &Args) {
+    if args.tls {
+        report.fail("runtime", "Only the threads runtime does TLS");
+    }
+    if args.unix.is_some() {
+        report.fail("runtime", "Only the threads runtime listens on a Unix socket");
+    }
+    for option in threads_only(args) {
+        report.fail("runtime", format!("Only the threads runtime takes {option}"));
+    }
+
+    // Only tuning for how the threads runtime goes about things, so the server works the same without them
+    let runtime = args.runtime.to_possible_value().expect("None of them are skipped");
+    let (defaults, writer) = (server::Options::default(), WriterOptions::default());
+    let ignored = [
+        ("--workers", args.workers != defaults.workers),
+        ("--accept-queue", args.accept_queue != defaults.accept_queue),
+        ("--acceptors", args.acceptors != defaults.acceptors),
+        ("--max-egress-bytes-per-sec", args.max_egress_bytes_per_sec.is_some()),
+        ("--batch-window-ms and --batch-bytes", args.batch_window_ms != 0 || args.batch_bytes != writer.batch_bytes),
+    ];
+    for (option, given) in ignored {
+        if given {
+            report.warn("runtime", format!("The {} runtime ignores {option}", runtime.get_name()));
+        }
+    }
+}
+
+/// Options given for what only the threads runtime does. The others serve everyone through the same sessions and
+/// broadcaster, but only threads speaks IRC.
+pub fn threads_only(args: &Args) -> Vec<&'static str> {
+    let features = [("--protocol irc", args.protocol == Protocol::Irc)];
+    features.into_iter().filter(|(_, given)| *given).map(|(option, _)| option).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use super::*;
+
+    fn check_args(args: &[&str]) -> Report {
+        let args = Args::parse_from(["rust-threading", "--mode", "server"].iter().chain(args));
+        check(&args, None)
+    }
+
+    fn outcomes(report: &Report) -> Vec<(Outcome, &str)> {
+        report.checks.iter().map(|(outcome, what, _)| (*outcome, *what)).collect()
+    }
+
+    #[test]
+    fn defaults_pass() {
+        let report = check_args(&[]);
+        assert!(report.passed(), "{report}");
+        assert_eq!(vec![(Outcome::Ok, "listen")], outcomes(&report));
+        assert!(report.to_string().ends_with("0 problem(s), 0 warning(s)"));
+    }
+
+    #[test]
+    fn finds_problems() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+        let report = check_args(&[
+            "--port", &port, "--tls", "--tls-cert", "/nope/cert.pem", "--tls-key", "/nope/key.pem",
+            "--oper", "alice", "--oper", "no spaces", "--history-file", "/nope/history.jsonl", "--workers", "0",
+        ]);
+        assert!(!report.passed());
+        let expected = vec![
+            (Outcome::Fail, "listen"),
+            (Outcome::Fail, "tls"),
+            (Outcome::Fail, "oper"),
+            (Outcome::Fail, "oper"),
+            (Outcome::Fail, "history file"),
+            (Outcome::Fail, "limits"),
+        ];
+        assert_eq!(expected, outcomes(&report));
+        let shown = report.to_string();
+        assert!(shown.contains("FAIL  history file  /nope/history.jsonl can't be made, there's no /nope"), "{shown}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listens_on_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("basic-irc-check-{}.sock", std::process::id()));
+        let report = check_args(&["--unix", path.to_str().unwrap()]);
+        assert_eq!(vec![(Outcome::Ok, "listen")], outcomes(&report), "{report}");
+
+        let _server = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let report = check_args(&["--unix", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(report.to_string().contains("There's already a server on"), "{report}");
+    }
+
+    #[cfg(all(feature = "mio", feature = "accounts"))]
+    #[test]
+    fn other_runtimes_warn() {
+        let report = check_args(&["--runtime", "mio", "--workers", "8", "--accounts", "accounts.db"]);
+        assert!(report.passed());
+        let expected = [(Outcome::Ok, "listen"), (Outcome::Ok, "accounts"), (Outcome::Warn, "runtime")];
+        assert_eq!(Vec::from(expected), outcomes(&report));
+        assert!(report.to_string().contains("The mio runtime ignores --workers"), "{report}");
+    }
+
+    #[cfg(all(feature = "mio", feature = "tokio"))]
+    #[test]
+    fn other_runtimes_hold_everyone_to_the_same_limits() {
+        let limits = ["--max-clients", "10", "--accept-burst", "5", "--message-burst", "3", "--ping-timeout-secs", "5"];
+        for runtime in ["mio", "tokio"] {
+            let report = check_args(&[&["--runtime", runtime][..], &limits].concat());
+            assert!(report.passed(), "{report}");
+            let irc = check_args(&["--runtime", runtime, "--protocol", "irc"]);
+            assert!(irc.to_string().contains("Only the threads runtime takes --protocol irc"), "{irc}");
+        }
+    }
+}
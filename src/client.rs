@@ -1,97 +1,388 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
 use std::io::{BufRead, Read, stdin, stdout, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::thread::sleep;
-use std::time::Duration;
-use thiserror::Error;
+use std::time::{Duration, Instant, SystemTime};
 use parking_lot::Mutex;
-use crate::response::AuthResponse;
-use crate::scuffed_clone::ScuffedClone;
+use protocol::client::{Connection, ConnectionError, Event};
+use thiserror::Error;
+use crate::away::AwayLog;
+use crate::backfill::{self, Seen};
+use crate::bandwidth::{Bandwidth, Counted};
+use crate::bidi::isolate;
+use crate::clock::{self, Skew};
+use crate::command::Command;
+use crate::exit::Exit;
+use crate::frame::{FrameError, FrameLimits, Framed};
+use crate::output::{Output, Record};
+use crate::plugin::Plugins;
+use crate::response::{self, AuthResponse, Capabilities, Login, Member, ServerMessage};
+use crate::scuffed_clone::{HangUp, ScuffedClone};
 use crate::server::VALIDATE_BUFFER_SIZE;
 use crate::server_friendly_string::ServerFriendlyString;
+use crate::session::Session;
+use crate::stats::Stats;
+use crate::transcript::{timestamp, Transcript};
+use crate::trigger::{self, Fired, Triggers};
 use crate::user::User;
 
 #[derive(Error, Debug)]
 pub enum ClientError {
     #[error("Failed to read/write from stream: `{0}`")]
     IO(#[from] std::io::Error),
-    #[error("Failed serializing user info: `{0}`")]
-    Serde(#[from] serde_json::Error),
+    #[error("Bad frame: `{0}`")]
+    Frame(#[from] FrameError),
+    #[error("{0}")]
+    Connection(#[from] ConnectionError),
     #[error("Authorization failed: `{0}`")]
     Auth(#[from] AuthResponse),
+    #[error("Server won't talk to this client: `{0}`")]
+    Incompatible(String),
+    #[error("{0}")]
+    Kicked(String),
+}
+
+impl ClientError {
+    /// How long the server asked to be left alone for before trying again, if it turned us away for being
+    /// busy rather than for good.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ClientError::Auth(AuthResponse::Busy { retry_after_ms }) => Some(Duration::from_millis(*retry_after_ms)),
+            _ => None,
+        }
+    }
 }
 
+/// Optional features the client asks for in the handshake.
+const FEATURES: &[&str] = &[backfill::FEATURE, response::PASSWORD];
+
+/// Where the client gets what the user types and shows them things: the terminal it's running in, or
+/// whichever one is attached to it as a daemon.
+pub trait Console: Debug + Send + Sync {
+    /// The next line typed, newline and all, or an empty string once there won't be any more.
+    fn read_line(&self) -> std::io::Result<String>;
+    /// Shows `text` as is, escape codes and all.
+    fn write(&self, text: &str);
+
+    fn println(&self, line: &str) {
+        self.write(&format!("{line}\n"));
+    }
+}
+
+/// The terminal the client's running in.
+#[derive(Debug, Default)]
+pub struct Terminal;
+
+impl Console for Terminal {
+    fn read_line(&self) -> std::io::Result<String> {
+        let mut line = String::with_capacity(64);
+        stdin().lock().read_line(&mut line)?;
+        Ok(line)
+    }
+
+    fn write(&self, text: &str) {
+        let mut stdout = stdout().lock();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+/// How much of the last session gets shown again on starting up.
+const REPLAYED_LINES: usize = 20;
+
 #[derive(Debug)]
 pub struct Client<S: Read + Write + ScuffedClone + Send> {
-    user: User,
-    conn: S,
+    // Shared with the thread showing what comes in, which is what hears about a `/nick` going through
+    user: Arc<Mutex<User>>,
+    conn: Counted<S>,
+    // All reads go through here so frames that arrive back-to-back don't get lost between reads
+    reader: Framed<Counted<S>>,
+    // Where it's got to with the server, which is the same for every client
+    connection: Connection,
+    // Counted for every clone of the connection
+    bandwidth: Arc<Bandwidth>,
+    // Shared with the thread showing what comes in
+    transcript: Arc<Mutex<Transcript>>,
+    plugins: Arc<Mutex<Plugins>>,
+    triggers: Arc<Mutex<Triggers>>,
+    away: Arc<Mutex<AwayLog>>,
+    // Where it's got up to in each buffer, when talking to a bouncer
+    seen: Arc<Mutex<Seen>>,
+    // Until when the server's asked for nothing more to be sent, set by the receiving thread
+    held_until: Arc<Mutex<Option<Instant>>>,
+    // Shared with the receiving thread too, with `--stats`
+    stats: Option<Arc<Mutex<Stats>>>,
+    // What the server said it'd do in the handshake, once there's been one
+    capabilities: Option<Capabilities>,
+    // Asked for when logging in if the server needs one and it isn't given
+    password: Option<String>,
+    // For a nick that's been `/register`-ed
+    account_password: Option<String>,
+    console: Arc<dyn Console>,
+    output: Output,
+    // Where to save the session on the way out, minus what's shared above, which lives there until then
+    session: Option<(PathBuf, Session)>,
 }
 
 impl<S: Read + Write + ScuffedClone + Send> Client<S>
 {
     pub fn new(user: User, conn: S) -> Self {
+        let bandwidth = Arc::new(Bandwidth::default());
+        let conn = Counted::new(conn, bandwidth.clone());
         Self {
-            user,
+            user: Arc::new(Mutex::new(user)),
+            reader: Framed::new(conn.scuffed_clone()),
             conn,
+            connection: Connection::new(FEATURES),
+            bandwidth,
+            transcript: Default::default(),
+            plugins: Default::default(),
+            triggers: Default::default(),
+            away: Default::default(),
+            seen: Default::default(),
+            held_until: Default::default(),
+            stats: None,
+            capabilities: None,
+            password: None,
+            account_password: None,
+            console: Arc::new(Terminal),
+            output: Output::Text,
+            session: None,
         }
     }
 
-    /// Performs the authorization flow for a connecting user. In addition to the `Result`, this function
-    /// reads an `AuthResponse` from the server indicating success or failure.
-    fn do_auth_flow(&mut self) -> Result<(), ClientError> {
-        let user_str = serde_json::to_vec(&self.user)?;
-        self.conn.write_all(&user_str)?;
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = Arc::new(Mutex::new(plugins));
+        self
+    }
+
+    /// Acts on what comes in by `triggers`, like answering while `/away`.
+    pub fn with_triggers(mut self, triggers: Triggers) -> Self {
+        self.triggers = Arc::new(Mutex::new(triggers));
+        self
+    }
+
+    pub fn with_console(mut self, console: Arc<dyn Console>) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Shows everything as JSON instead, with `Output::Json`.
+    pub fn with_output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
 
-        let mut buf = [0; VALIDATE_BUFFER_SIZE * 2];
-        let n = self.conn.read(&mut buf)?;
-        // Don't read the null bytes
-        let resp: AuthResponse = serde_json::from_slice(&buf[..n])?;
+    /// Keeps count of what's sent and received, for `/stats` and a summary on the way out.
+    pub fn with_stats(mut self) -> Self {
+        self.stats = Some(Default::default());
+        self
+    }
 
-        match &resp {
-            AuthResponse::Success => Ok(()),
-            AuthResponse::Error(_) => Err(ClientError::Auth(resp)),
+    /// Logs in with `password`, for servers that need one.
+    pub fn with_password(mut self, password: String) -> Self {
+        self.password = Some(password);
+        self
+    }
+
+    /// Identifies with `account_password`, for nicks that have been `/register`-ed.
+    pub fn with_account_password(mut self, account_password: String) -> Self {
+        self.account_password = Some(account_password);
+        self
+    }
+
+    /// Warns once `cap` bytes have gone up and down, showing how much is used in the prompt until then.
+    pub fn with_bandwidth_cap(self, cap: u64) -> Self {
+        self.bandwidth.cap_at(cap);
+        self
+    }
+
+    /// Picks up where `session` left off, and saves it back to `path` when done.
+    pub fn with_session(mut self, path: PathBuf, mut session: Session) -> Self {
+        self.transcript = Arc::new(Mutex::new(std::mem::take(&mut session.transcript)));
+        self.away = Arc::new(Mutex::new(std::mem::take(&mut session.away)));
+        self.seen = Arc::new(Mutex::new(std::mem::take(&mut session.seen)));
+        self.session = Some((path, session));
+        self
+    }
+
+    /// Starts over on a new `conn`, after the last one turned us away, keeping everything else.
+    pub fn reconnect(&mut self, conn: S) {
+        self.conn = Counted::new(conn, self.bandwidth.clone());
+        self.reader = Framed::new(self.conn.scuffed_clone());
+        self.connection = Connection::new(FEATURES);
+        self.capabilities = None;
+    }
+
+    /// Performs the authorization flow for a connecting user: the handshake, and then the user, asking for a
+    /// password if the server needs one. In addition to the `Result`, this function reads an `AuthResponse`
+    /// from the server indicating success or failure.
+    pub(crate) fn do_auth_flow(&mut self) -> Result<(), ClientError> {
+        let limits = FrameLimits { max_len: VALIDATE_BUFFER_SIZE * 2, ..Default::default() };
+        let sent = self.conn.write_all(&self.connection.take_outgoing());
+        // A server turning us away, e.g. for being full, says why and hangs up without waiting for the handshake
+        let answer = self.next_event(&limits);
+        if let Ok(Event::Refused(resp)) = answer {
+            return Err(ClientError::Auth(resp));
         }
+        sent?;
+        match answer? {
+            Event::Accepted(capabilities) => self.capabilities = Some(capabilities),
+            Event::Incompatible(reason) => return Err(ClientError::Incompatible(reason)),
+            Event::Refused(resp) => return Err(ClientError::Auth(resp)),
+            event => unreachable!("Nothing but an answer comes before the handshake's answered, got {event:?}"),
+        }
+
+        if self.password.is_none() && self.capabilities.as_ref().is_some_and(|c| c.has(response::PASSWORD)) {
+            match self.output {
+                Output::Text => self.console.write("This server needs a password: "),
+                Output::Json => emit(&*self.console, Record::Info { text: "This server needs a password" }),
+            }
+            self.password = Some(self.console.read_line()?.trim_end_matches(['\r', '\n']).to_string());
+        }
+        let login = Login::new(self.user.lock().clone(), self.password.clone()).identified(self.account_password.clone());
+        self.connection.log_in(&login)?;
+        self.conn.write_all(&self.connection.take_outgoing())?;
+
+        match self.next_event(&limits)? {
+            Event::LoggedIn => Ok(()),
+            Event::Refused(resp) => Err(ClientError::Auth(resp)),
+            event => unreachable!("Nothing but an answer comes before the login's answered, got {event:?}"),
+        }
+    }
+
+    /// Blocks until the next frame that's more than a ping, sending whatever the connection has to say back.
+    fn next_event(&mut self, limits: &FrameLimits) -> Result<Event, ClientError> {
+        next_event((&mut self.reader, &mut self.conn), &mut self.connection, limits)
+    }
+
+    /// Blocks until the server sends something that plugins don't drop.
+    pub(crate) fn next_message(&mut self) -> Result<ServerMessage, ClientError> {
+        let conn = (&mut self.reader, &mut self.conn);
+        read_incoming(conn, &mut self.connection, &self.plugins, &self.seen, self.stats.as_deref())
     }
 
-    pub fn start(&mut self) -> Result<(), ClientError> {
+    /// Logs in and chats until the user's done, which is `Ok`, or the server's done with us, which is why.
+    pub fn start(&mut self) -> Result<(), ClientError>
+    where
+        S: HangUp,
+    {
         self.do_auth_flow()?;
+        // A bouncer waits to hear what's been seen before it sends anything
+        if self.capabilities.as_ref().is_some_and(|c| c.has(backfill::FEATURE)) {
+            self.conn.write_all(backfill::request(&self.seen.lock()).as_bytes())?;
+        }
+        let channels = self.resume();
+        self.transcript.lock().event(&format!("Connected as {}", self.user.lock().shown()));
+        if self.output == Output::Json {
+            emit(&*self.console, Record::Connected { nick: &self.user.lock().name });
+        }
+
+        // Whatever came in right behind the auth response is already in the reader, so the receiver takes it
+        let mut incoming = std::mem::replace(&mut self.reader, Framed::new(self.conn.scuffed_clone()));
+        let mut connection = std::mem::replace(&mut self.connection, Connection::new(FEATURES));
+        let mut answering = self.conn.scuffed_clone();
+        let connected = AtomicBool::new(true);
+        let (transcript, away, plugins, seen) = (self.transcript.clone(), self.away.clone(), self.plugins.clone(), self.seen.clone());
+        let (me, stats, bandwidth) = (self.user.clone(), self.stats.clone(), self.bandwidth.clone());
+        let (held_until, triggers) = (self.held_until.clone(), self.triggers.clone());
+
+        let ended = thread::scope(|scope| {
+            let console = self.console.clone();
+            let shared = Shared {
+                me: &me,
+                transcript: &transcript,
+                away: &away,
+                plugins: &plugins,
+                seen: &seen,
+                stats: stats.as_deref(),
+                bandwidth: &bandwidth,
+                held_until: &held_until,
+                triggers: &triggers,
+                skew: self.skew(),
+                output: self.output,
+            };
+            let connected = &connected;
+            let conn = (&mut incoming, &mut answering);
+            let receiver = scope.spawn(move || receive(conn, &mut connection, shared, &*console, connected));
+
+            // Back into the channels from last time before the plugins get a go
+            let lines = self.plugins.lock().connected(&self.user.lock());
+            for line in channels.into_iter().map(|channel| format!("/join {channel}")).chain(lines) {
+                self.send(line);
+            }
+            self.chat(connected);
+
+            // Reads on every clone of the connection see the end of it, so the receiver stops too
+            connected.store(false, Ordering::Relaxed);
+            self.conn.hang_up();
+            receiver.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        });
+
+        self.save_session();
+        if self.stats.is_some() || self.bandwidth.is_capped() {
+            let summary = self.summary();
+            self.tell(&summary, Record::Info { text: &summary });
+        }
+        ended
+    }
 
-        // Concurrency is hard so I'll do it stupidly. Yes that's a Mutex for a stream that will
-        // _always_ exclusively hold it. I'm stupid.
-        // TODO: This DOES NOT work as expected. A thread scope will block waiting for the threads to
-        //       return, so it's not suitable for what I'm trying to do. I'll need to be able to use
-        //       a regular `thread::spawn` call, which requires a 'static lifetime on `S`. God help me.
-        //let arc_conn = Arc::new(Mutex::new(self.conn.scuffed_clone()));
-        //thread::scope(|scope| {
-        //    let rx_conn = arc_conn.clone();
-        //    scope.spawn(move || {
-        //        let mut rx_conn = rx_conn.lock();
-        //        let mut buf = Vec::with_capacity(512);
-        //        let mut last_pos = 0;
-        //        loop {
-        //            match rx_conn.read(&mut buf) {
-        //                // lol busy wait
-        //                Ok(0) => { sleep(Duration::from_secs(1)); }
-        //                Ok(n) => {
-        //                    let mut stdout = stdout().lock();
-
-        //                    let _ = stdout.write(&buf[last_pos..last_pos + n]);
-        //                    if let Err(e) = stdout.flush() {
-        //                        eprintln!("Error flushing stdout: {e:?}");
-        //                    };
-
-        //                    last_pos += n;
-        //                }
-        //                Err(e) => { eprintln!("Error on receiving message: {e:?}"); }
-        //            }
-        //        }
-        //    });
-        //});
+    /// How far off the server's clock is from this one, going by the handshake.
+    fn skew(&self) -> Skew {
+        let time_ms = match &self.capabilities {
+            Some(Capabilities::Accepted { time_ms, .. }) => *time_ms,
+            _ => None,
+        };
+        Skew::new(time_ms, SystemTime::now())
+    }
+
+    /// Shows the end of the last session, if there was one, returning the channels to join again. There's
+    /// no history on the server to fill in what was missed in between, so that's left as a gap, unless it's
+    /// a bouncer being caught up from.
+    fn resume(&mut self) -> Vec<String> {
+        let Some((_, Session { saved_at: Some(saved_at), channels, .. })) = &self.session else {
+            return Vec::new();
+        };
+
+        let mut transcript = self.transcript.lock();
+        for line in transcript.tail(REPLAYED_LINES) {
+            self.tell(&line, Record::Info { text: &line });
+        }
+        let ended = format!("--- Last session ended {} ---", timestamp(*saved_at));
+        self.tell(&ended, Record::Info { text: &ended });
+        transcript.event(&format!("Resumed the session that ended {}", timestamp(*saved_at)));
+        if let Some(status) = self.away.lock().status() {
+            self.tell(&status, Record::Info { text: &status });
+        }
+        channels.clone()
+    }
 
+    fn save_session(&mut self) {
+        let Some((path, session)) = &mut self.session else {
+            return;
+        };
+
+        session.transcript = self.transcript.lock().clone();
+        session.away = self.away.lock().clone();
+        session.seen = self.seen.lock().clone();
+        if let Err(e) = session.save(path) {
+            eprintln!("[SESSION] Couldn't save the session: {e}");
+        }
+    }
+
+    /// Sends what the user types until they're done or the server's gone.
+    fn chat(&mut self, connected: &AtomicBool) {
         loop {
-            let msg = match get_input(b"> ", stdin().lock(), stdout().lock()) {
+            // Nothing to prompt for in JSON, it'd just get in the way of whatever's reading it
+            if self.output == Output::Text {
+                self.console.write(&self.bandwidth.prompt());
+            }
+            let msg = match self.console.read_line() {
                 Ok(m) => {
-                    if m.is_empty() {
+                    if m.is_empty() || !connected.load(Ordering::Relaxed) {
                         break;
                     }
 
@@ -103,18 +394,404 @@ impl<S: Read + Write + ScuffedClone + Send> Client<S>
                 }
             };
 
-            if let Err(e) = self.conn.write_all(msg.0.as_bytes()) {
-                eprintln!("Couldn't write message; skipping: {e:?}");
+            let text = msg.to_string();
+            if let Some(reply) = self.local_command(&text) {
+                self.tell(&reply, Record::Info { text: &reply });
                 continue;
             }
 
-            println!("<{}> {}", self.user.name, msg);
+            let outgoing = self.plugins.lock().outgoing(text);
+            if let Some(text) = outgoing {
+                self.send(text);
+            }
+        }
+    }
+
+    /// Sends a line to the server and shows it, unless it's a command the server would only say is wrong.
+    fn send(&mut self, text: String) {
+        let command = Command::parse(&text);
+        if let Some(Err(e)) = &command {
+            let e = e.to_string();
+            self.tell(&e, Record::Error { error: &e });
+            return;
         }
 
-        Ok(())
+        let (console, output) = (&*self.console, self.output);
+        let tell = |text: &str, record: Record| match output {
+            Output::Text => console.println(text),
+            Output::Json => emit(console, record),
+        };
+        let outgoing = (&*self.held_until, self.stats.as_deref(), &*self.bandwidth);
+        if let Err(e) = write_line(&text, &mut self.conn, outgoing, &tell) {
+            match self.output {
+                Output::Text => eprintln!("Couldn't write message; skipping: {e:?}"),
+                Output::Json => emit(&*self.console, Record::Error { error: &format!("Couldn't write message: {e}") }),
+            }
+            return;
+        }
+
+        match (&mut self.session, &command) {
+            (Some((_, session)), Some(Ok(Command::Join { channel }))) => session.joined(channel),
+            (Some((_, session)), Some(Ok(Command::Part { channel }))) => session.parted(channel),
+            _ => {}
+        }
+
+        let (me, now) = (self.user.lock().clone(), SystemTime::now());
+        match command {
+            Some(Ok(Command::Msg { nick, text })) => {
+                let shown = format!("{} -> *{}* {}", clock::stamp(now, now), isolate(&nick), isolate(&text));
+                self.tell(&shown, Record::Sent { to: Some(&nick), text: &text });
+                self.transcript.lock().chat(&me, &format!("-> {nick}: {text}"));
+            }
+            // The password stays off the screen and out of the transcript
+            Some(Ok(Command::Register { .. } | Command::Oper { .. })) => {}
+            _ => {
+                let shown = format!("{} {}", clock::stamp(now, now), chat_line(&me, &text));
+                self.tell(&shown, Record::Sent { to: None, text: &text });
+                self.transcript.lock().chat(&me, &text);
+            }
+        }
+    }
+
+    /// Shows `text`, or `record` with `--output json`.
+    fn tell(&self, text: &str, record: Record) {
+        match self.output {
+            Output::Text => self.console.println(text),
+            Output::Json => emit(&*self.console, record),
+        }
+    }
+
+    /// Commands the client handles itself instead of sending them to the server, returning what to tell
+    /// the user. `None` if it's not one of them.
+    fn local_command(&self, text: &str) -> Option<String> {
+        let (command, args) = text.split_once(' ').unwrap_or((text, ""));
+        match command {
+            "/export" => Some(self.export(args.trim())),
+            "/find" => Some(self.find(args.trim())),
+            "/away" => Some(self.away.lock().away(args.trim())),
+            "/back" => Some(self.away.lock().back()),
+            "/awaylog" => Some(self.away.lock().take()),
+            "/stats" => Some(match &self.stats {
+                Some(_) => self.summary(),
+                None => format!("{}\nNot keeping count of anything else, start the client with --stats for that", self.bandwidth),
+            }),
+            _ => None,
+        }
     }
+
+    /// What's been counted with `--stats`, and the bandwidth used.
+    fn summary(&self) -> String {
+        match &self.stats {
+            Some(stats) => format!("{}\n  {}", stats.lock(), self.bandwidth),
+            None => self.bandwidth.to_string(),
+        }
+    }
+
+    /// `/find <text>`, listing every line in the transcript with `text` in it.
+    fn find(&self, needle: &str) -> String {
+        if needle.is_empty() {
+            return "Usage: /find <text>".to_string();
+        }
+
+        let found = self.transcript.lock().find(needle);
+        match found.len() {
+            0 => format!("Nothing matching {needle:?}"),
+            n => format!("{}\n{n} found", found.join("\n")),
+        }
+    }
+
+    /// `/export [#chan] <path>`, returning what to tell the user.
+    fn export(&self, args: &str) -> String {
+        let path = match args.split_once(' ') {
+            _ if args.is_empty() => return "Usage: /export [#chan] <path>".to_string(),
+            Some((chan, _)) if chan.starts_with('#') => return format!("There are no channels yet, leave out {chan}"),
+            _ => Path::new(args),
+        };
+
+        match self.transcript.lock().export(path) {
+            Ok(format) => format!("Exported to {} as {format:?}", path.display()),
+            Err(e) => format!("Couldn't export to {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Reads frames until one's more than a ping, handing each to `connection` and sending back whatever it has
+/// to say, like answers to pings.
+fn next_event<R: Read, W: Write>(
+    (reader, conn): (&mut Framed<R>, &mut W),
+    connection: &mut Connection,
+    limits: &FrameLimits,
+) -> Result<Event, ClientError> {
+    loop {
+        let event = connection.handle(&reader.read_frame(limits)?);
+        let outgoing = connection.take_outgoing();
+        if !outgoing.is_empty() {
+            conn.write_all(&outgoing)?;
+        }
+        if let Some(event) = event? {
+            return Ok(event);
+        }
+    }
+}
+
+/// Reads the next message that plugins don't drop, noting how far it's got if it's numbered. Pings get
+/// answered over `conn` rather than read.
+fn read_incoming<R: Read, W: Write>(
+    conn: (&mut Framed<R>, &mut W),
+    connection: &mut Connection,
+    plugins: &Mutex<Plugins>,
+    seen: &Mutex<Seen>,
+    stats: Option<&Mutex<Stats>>,
+) -> Result<ServerMessage, ClientError> {
+    let (reader, conn) = conn;
+    loop {
+        let Event::Message(mut msg) = next_event((reader, conn), connection, &FrameLimits::default())? else {
+            return Err(ConnectionError::Closed.into());
+        };
+        if let Some(stats) = stats {
+            stats.lock().received(&msg, SystemTime::now());
+        }
+        if let ServerMessage::Sequenced { buffer, seq, message } = msg {
+            seen.lock().insert(buffer, seq);
+            msg = *message;
+        }
+        let (ServerMessage::Chat { from, text, .. } | ServerMessage::Private { from, text, .. }) = &mut msg else {
+            return Ok(msg);
+        };
+        if let Some(filtered) = plugins.lock().incoming(from, std::mem::take(text)) {
+            *text = filtered;
+            return Ok(msg);
+        }
+    }
+}
+
+/// What the thread showing incoming messages shares with the client.
+#[derive(Clone, Copy)]
+struct Shared<'a> {
+    me: &'a Mutex<User>,
+    transcript: &'a Mutex<Transcript>,
+    away: &'a Mutex<AwayLog>,
+    plugins: &'a Mutex<Plugins>,
+    seen: &'a Mutex<Seen>,
+    stats: Option<&'a Mutex<Stats>>,
+    bandwidth: &'a Bandwidth,
+    held_until: &'a Mutex<Option<Instant>>,
+    triggers: &'a Mutex<Triggers>,
+    skew: Skew,
+    output: Output,
 }
 
+/// Shows everything the server sends while the user types, until the server's gone, stamped with when it
+/// was said, and answers pings and whatever sets off triggers over `conn`. What's for the user goes in the
+/// away log too.
+fn receive<R: Read, W: Write>(
+    (reader, conn): (&mut Framed<R>, &mut W),
+    connection: &mut Connection,
+    shared: Shared,
+    console: &dyn Console,
+    connected: &AtomicBool,
+) -> Result<(), ClientError> {
+    // What the server said before it hung up, if it was throwing us off
+    let mut thrown_off = None;
+    loop {
+        match read_incoming((reader, conn), connection, shared.plugins, shared.seen, shared.stats) {
+            Ok(msg) => {
+                let me = {
+                    let mut me = shared.me.lock();
+                    match msg.as_renamed() {
+                        Some((old, new)) if old == me.name => me.name = new.to_string(),
+                        _ => {}
+                    }
+                    me.clone()
+                };
+                match &msg {
+                    ServerMessage::Disconnected { reason } => thrown_off = Some(reason.clone()),
+                    ServerMessage::Notice { retry_after_ms: Some(ms), .. } => {
+                        *shared.held_until.lock() = Some(Instant::now() + Duration::from_millis(*ms));
+                    }
+                    _ => {}
+                }
+                let now = SystemTime::now();
+                let at = shared.skew.said_at(&msg).unwrap_or(now);
+                let shown = show(&msg, &me, at, &mut shared.transcript.lock());
+                let away = {
+                    let mut away = shared.away.lock();
+                    away.note(&msg, &me, &shown);
+                    away.is_away()
+                };
+
+                let warning = shared.bandwidth.over_cap();
+                if shared.output == Output::Json {
+                    let latency = shared.skew.said_at(&msg).and_then(|said| now.duration_since(said).ok());
+                    console.println(&Record::from_message(&msg, latency).line(at));
+                    if let Some(warning) = warning {
+                        emit(console, Record::Info { text: &warning });
+                    }
+                } else {
+                    // Clear the prompt, show the message, then put the prompt back under it
+                    let warning = warning.map(|warning| format!("\n{warning}")).unwrap_or_default();
+                    console.write(&format!("\r\x1b[K{} {shown}{warning}\n{}", clock::stamp(at, now), shared.bandwidth.prompt()));
+                }
+                // After what set them off is shown
+                let fired = shared.triggers.lock().fire(&msg, &me, away, Instant::now());
+                for fired in fired {
+                    act(fired, conn, console, shared);
+                }
+            }
+            Err(e) => {
+                // Nothing to say if it's us who hung up
+                if !connected.swap(false, Ordering::Relaxed) {
+                    return Ok(());
+                }
+                let e = thrown_off.map_or(e, ClientError::Kicked);
+                if shared.output == Output::Json {
+                    emit(console, Record::Disconnected { reason: &e.to_string(), exit_code: Exit::from(&e).code() });
+                } else {
+                    console.println(&format!("\r\x1b[KLost the connection to the server ({e}), press Enter to quit"));
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Writes `text` to the server over `conn` the same way for what's typed and what triggers send: once
+/// whatever the server last asked to be left alone for is over, like after sending too fast, so it doesn't
+/// get dropped too, and counted in `stats` and against the bandwidth cap. Anything to say about either goes
+/// to `tell`.
+fn write_line<W: Write>(
+    text: &str,
+    conn: &mut W,
+    (held_until, stats, bandwidth): (&Mutex<Option<Instant>>, Option<&Mutex<Stats>>, &Bandwidth),
+    tell: &dyn Fn(&str, Record),
+) -> std::io::Result<()> {
+    let until = held_until.lock().take();
+    let wait = until.map_or(Duration::ZERO, |until| until.saturating_duration_since(Instant::now()));
+    if !wait.is_zero() {
+        let text = format!("Holding off for {} ms, the server asked us to slow down", wait.as_millis());
+        tell(&text, Record::Info { text: &text });
+        thread::sleep(wait);
+    }
+
+    let line = ServerFriendlyString::from(text).0;
+    conn.write_all(line.as_bytes())?;
+    if let Some(stats) = stats {
+        stats.lock().sent(&line);
+    }
+    if let Some(warning) = bandwidth.over_cap() {
+        tell(&warning, Record::Info { text: &warning });
+    }
+    Ok(())
+}
+
+/// Does what a trigger `fired` for, sending over `conn` like anything typed and saying what it did on
+/// `console` and in the transcript.
+fn act<W: Write>(fired: Fired, conn: &mut W, console: &dyn Console, shared: Shared) {
+    let tell = |text: &str, record: Record| match shared.output {
+        Output::Text => console.write(&format!("\r\x1b[K{text}\n{}", shared.bandwidth.prompt())),
+        Output::Json => emit(console, record),
+    };
+    let done = match fired {
+        Fired::Send(line) => match Command::parse(&line) {
+            Some(Err(e)) => Err(format!("Trigger couldn't send {line:?}: {e}")),
+            _ => match write_line(&line, conn, (shared.held_until, shared.stats, shared.bandwidth), &tell) {
+                Ok(()) => Ok(format!("Trigger sent {line:?}")),
+                Err(e) => Err(format!("Trigger couldn't send {line:?}: {e}")),
+            },
+        },
+        Fired::Run { command, env } => match trigger::run(&command, env) {
+            Ok(()) => Ok(format!("Trigger ran {command:?}")),
+            Err(e) => Err(format!("Trigger couldn't run {command:?}: {e}")),
+        },
+    };
+    shared.transcript.lock().event(done.as_ref().unwrap_or_else(|e| e));
+    match (shared.output, done) {
+        (Output::Text, Ok(text) | Err(text)) => {
+            console.write(&format!("\r\x1b[K* {}\n{}", isolate(&text), shared.bandwidth.prompt()));
+        }
+        (Output::Json, Ok(text)) => emit(console, Record::Info { text: &text }),
+        (Output::Json, Err(error)) => emit(console, Record::Error { error: &error }),
+    }
+}
+
+/// Writes `record` as it is now, for `--output json`.
+fn emit(console: &dyn Console, record: Record) {
+    console.println(&record.line(SystemTime::now()));
+}
+
+/// How a message from the server shows up in the terminal, noting it in the transcript on the way as said
+/// at `at`.
+fn show(msg: &ServerMessage, me: &User, at: SystemTime, transcript: &mut Transcript) -> String {
+    match msg {
+        ServerMessage::Chat { from, text, channel: None, .. } => {
+            transcript.chat_at(at, from, text);
+            chat_line(from, text)
+        }
+        ServerMessage::Chat { from, text, channel: Some(channel), .. } => {
+            transcript.chat_at(at, from, &format!("[{channel}] {text}"));
+            format!("[{}] {}", isolate(channel), chat_line(from, text))
+        }
+        ServerMessage::Private { from, text, .. } => {
+            transcript.chat_at(at, from, &format!("-> {}: {text}", me.name));
+            format!("*{}* {}", isolate(from.shown()), isolate(text))
+        }
+        ServerMessage::Notice { text, .. } | ServerMessage::Disconnected { reason: text } => {
+            transcript.event_at(at, text);
+            text.lines().map(|line| format!("* {}", isolate(line))).collect::<Vec<_>>().join("\n")
+        }
+        ServerMessage::Presence { user, change } => {
+            transcript.event_at(at, &format!("{} {change}", user.shown()));
+            // Dimmed, so it doesn't get in the way of the conversation
+            format!("\x1b[2m* {} {}\x1b[22m", isolate(user.shown()), isolate(&change.to_string()))
+        }
+        ServerMessage::Names { channel, users } => {
+            let text = names(channel.as_deref(), users);
+            transcript.event_at(at, &text);
+            format!("* {}", isolate(&text))
+        }
+        ServerMessage::Channels { channels } => {
+            let text = list(channels);
+            transcript.event_at(at, &text);
+            format!("* {}", isolate(&text))
+        }
+        ServerMessage::Sequenced { message, .. } => show(message, me, at, transcript),
+        ServerMessage::Ping { .. } => unreachable!("read_incoming answers pings itself"),
+    }
+}
+
+/// Like `3 in #rust: alice, bob (idle 5m), zed`. Anyone who's said something in the last minute isn't idle.
+fn names(channel: Option<&str>, users: &[Member]) -> String {
+    let names = users
+        .iter()
+        .map(|member| match member.idle_secs.filter(|secs| *secs >= 60) {
+            Some(secs) => format!("{} (idle {})", member.user.shown(), clock::span(Duration::from_secs(secs))),
+            None => member.user.shown().to_string(),
+        })
+        .collect::<Vec<_>>();
+    let (count, names) = match users.len() {
+        0 => ("Nobody".to_string(), String::new()),
+        n => (n.to_string(), format!(": {}", names.join(", "))),
+    };
+    match channel {
+        Some(channel) => format!("{count} in {channel}{names}"),
+        None => format!("{count} connected{names}"),
+    }
+}
+
+/// Like `2 channel(s): #go (1), #rust (3)`.
+fn list(channels: &BTreeMap<String, usize>) -> String {
+    if channels.is_empty() {
+        return "No channels".to_string();
+    }
+    let channels = channels.iter().map(|(channel, members)| format!("{channel} ({members})")).collect::<Vec<_>>();
+    format!("{} channel(s): {}", channels.len(), channels.join(", "))
+}
+
+/// How a chat message shows up in the terminal. Both the name and the text are isolated, so neither can
+/// flip the other around with bidi controls.
+fn chat_line(from: &User, text: &str) -> String {
+    format!("<{}> {}", isolate(from.shown()), isolate(text))
+}
 
 /// Reads input using a given prompt up to the first newline.
 pub fn get_input<I, O>(prompt: &[u8], mut input: I, mut output: O) -> Result<String, std::io::Error>
@@ -134,7 +811,11 @@ where
 
 #[cfg(test)]
 mod tests {
-    use std::io::{Cursor, Seek, SeekFrom};
+    use std::io::Cursor;
+    use std::time::Duration;
+    use crate::frame::{encode_frame, encode_message};
+    use crate::response::{Handshake, PresenceChange, ServerMessage};
+    use crate::testing::{accepted, logged_in, Duplex};
     use super::*;
 
     #[test]
@@ -163,33 +844,319 @@ mod tests {
         assert_eq!(b"> ", &output[..]);
     }
 
+    #[test]
+    fn test_chat_line_isolates_name_and_text() {
+        let from = User::new("mallory").with_display_name("\u{202E}nimda");
+        assert_eq!("<\u{2068}nimda\u{2069}> \u{2068}hi\u{2069}", chat_line(&from, "hi"));
+    }
+
+    #[test]
+    fn show_incoming() {
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let (mut transcript, now) = (Transcript::default(), SystemTime::now());
+        let chat = |channel: Option<&str>| ServerMessage::Chat {
+            from: alice.clone(),
+            text: "hi".to_string(),
+            received_ms: 0,
+            channel: channel.map(str::to_string),
+        };
+
+        assert_eq!(chat_line(&alice, "hi"), show(&chat(None), &bob, now, &mut transcript));
+        let in_channel = format!("[{}] {}", isolate("#rust"), chat_line(&alice, "hi"));
+        assert_eq!(in_channel, show(&chat(Some("#rust")), &bob, now, &mut transcript));
+        let private = ServerMessage::Private { from: alice.clone(), to: bob.clone(), text: "psst".to_string() };
+        assert_eq!(format!("*{}* {}", isolate("alice"), isolate("psst")), show(&private, &bob, now, &mut transcript));
+        let notice = ServerMessage::notice("one\ntwo");
+        assert_eq!(format!("* {}\n* {}", isolate("one"), isolate("two")), show(&notice, &bob, now, &mut transcript));
+
+        let joined = ServerMessage::Presence { user: alice.clone(), change: PresenceChange::Joined };
+        let shown = format!("\x1b[2m* {} {}\x1b[22m", isolate("alice"), isolate("has joined"));
+        assert_eq!(shown, show(&joined, &bob, now, &mut transcript));
+
+        // Nothing anyone else sends gets to move the cursor or clear the line
+        let text = "\r\x1b[K<admin> hi".to_string();
+        let forged = ServerMessage::Chat { from: alice.clone(), text, received_ms: 0, channel: None };
+        let shown = show(&forged, &bob, now, &mut transcript);
+        assert!(!shown.contains(['\r', '\x1b']), "{shown:?}");
+        assert_eq!("<\u{2068}alice\u{2069}> \u{2068}\u{FFFD}\u{FFFD}[K<admin> hi\u{2069}", shown);
+
+        assert_eq!(1, transcript.find("[#rust] hi").len());
+        assert_eq!(1, transcript.find("-> bob: psst").len());
+        assert_eq!(1, transcript.find("alice has joined").len());
+
+        let idle = Member { user: User::new("bob").with_display_name("Bob"), idle_secs: Some(330) };
+        let users = vec![Member { user: alice, idle_secs: Some(5) }, idle];
+        let names = ServerMessage::Names { channel: Some("#rust".to_string()), users };
+        assert_eq!(format!("* {}", isolate("2 in #rust: alice, Bob (idle 5m)")), show(&names, &bob, now, &mut transcript));
+        let nobody = ServerMessage::Names { channel: Some("#empty".to_string()), users: vec![] };
+        assert_eq!(format!("* {}", isolate("Nobody in #empty")), show(&nobody, &bob, now, &mut transcript));
+        let channels = ServerMessage::Channels { channels: [("#rust".to_string(), 3), ("#go".to_string(), 1)].into() };
+        assert_eq!(format!("* {}", isolate("2 channel(s): #go (1), #rust (3)")), show(&channels, &bob, now, &mut transcript));
+        let none = ServerMessage::Channels { channels: Default::default() };
+        assert_eq!(format!("* {}", isolate("No channels")), show(&none, &bob, now, &mut transcript));
+    }
+
+    #[test]
+    fn receive_until_the_server_is_gone() {
+        let me = User::new("bob");
+        let mut input = Vec::new();
+        for text in ["one", "two"] {
+            let msg = ServerMessage::Chat { from: User::new("alice"), text: text.to_string(), received_ms: 0, channel: None };
+            input.extend(encode_frame(&serde_json::to_vec(&msg).unwrap()).unwrap());
+        }
+        // What a bouncer sends is numbered
+        let numbered = ServerMessage::Sequenced {
+            buffer: "#rust".to_string(),
+            seq: 7,
+            message: Box::new(ServerMessage::notice("from alice's bouncer")),
+        };
+        input.extend(encode_frame(&serde_json::to_vec(&numbered).unwrap()).unwrap());
+        for (from, to) in [("alice", "alicia"), ("bob", "bobby")] {
+            let renamed = ServerMessage::renamed(&User::new(from), &User::new(to));
+            input.extend(encode_frame(&serde_json::to_vec(&renamed).unwrap()).unwrap());
+        }
+        input.extend(encode_frame(&serde_json::to_vec(&ServerMessage::Ping { token: 9 }).unwrap()).unwrap());
+        let (me, transcript, seen, connected) = (Mutex::new(me), Mutex::new(Transcript::default()), Mutex::default(), AtomicBool::new(true));
+
+        let (away, plugins, bandwidth, triggers) = (Mutex::default(), Mutex::default(), Bandwidth::default(), Mutex::default());
+        let shared = Shared {
+            me: &me,
+            transcript: &transcript,
+            away: &away,
+            plugins: &plugins,
+            seen: &seen,
+            stats: None,
+            bandwidth: &bandwidth,
+            held_until: &Mutex::default(),
+            triggers: &triggers,
+            skew: Skew::default(),
+            output: Output::Text,
+        };
+        let mut answers = Vec::new();
+        let mut connection = logged_in(&me.lock());
+        let ended = receive((&mut Framed::new(Cursor::new(input)), &mut answers), &mut connection, shared, &Terminal, &connected);
+        assert_eq!(Exit::Lost, Exit::from(&ended.unwrap_err()));
+        assert!(!connected.load(Ordering::Relaxed));
+        assert_eq!(b"/pong 9\n", answers.as_slice());
+        assert_eq!(4, transcript.lock().find("alice").len());
+        assert_eq!("bobby", me.lock().name);
+        assert_eq!(Seen::from([("#rust".to_string(), 7)]), *seen.lock());
+    }
+
+    /// Everything the client shows, kept instead of shown.
+    #[derive(Debug, Default)]
+    struct Captured(Mutex<String>);
+
+    impl Console for Captured {
+        fn read_line(&self) -> std::io::Result<String> {
+            Ok(String::new())
+        }
+
+        fn write(&self, text: &str) {
+            self.0.lock().push_str(text);
+        }
+    }
+
+    #[test]
+    fn receive_as_json() {
+        let me = Mutex::new(User::new("bob"));
+        let mut input = Vec::new();
+        let joined = ServerMessage::Presence { user: User::new("alice"), change: PresenceChange::Joined };
+        let chat = ServerMessage::Chat { from: User::new("alice"), text: "hi".to_string(), received_ms: 0, channel: None };
+        let slow_down = ServerMessage::Notice { text: "Slow down".to_string(), retry_after_ms: Some(60_000) };
+        let kicked = ServerMessage::Disconnected { reason: "Bob was kicked by alice: spam".to_string() };
+        for msg in [joined, chat, slow_down, kicked] {
+            input.extend(encode_frame(&serde_json::to_vec(&msg).unwrap()).unwrap());
+        }
+        let (transcript, seen, connected) = (Mutex::default(), Mutex::default(), AtomicBool::new(true));
+        let (away, plugins, bandwidth, held_until) = (Mutex::default(), Mutex::default(), Bandwidth::default(), Mutex::default());
+        let shared = Shared {
+            me: &me,
+            transcript: &transcript,
+            away: &away,
+            plugins: &plugins,
+            seen: &seen,
+            stats: None,
+            bandwidth: &bandwidth,
+            held_until: &held_until,
+            triggers: &Mutex::default(),
+            skew: Skew::default(),
+            output: Output::Json,
+        };
+
+        let console = Captured::default();
+        let mut connection = logged_in(&me.lock());
+        let conn = (&mut Framed::new(Cursor::new(input)), &mut Vec::new());
+        let ended = receive(conn, &mut connection, shared, &console, &connected);
+        assert!(matches!(ended, Err(ClientError::Kicked(notice)) if notice == "Bob was kicked by alice: spam"));
+        let events: Vec<serde_json::Value> = console.0.lock().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let kinds: Vec<_> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+        assert_eq!(vec!["join", "message", "notice", "notice", "disconnected"], kinds);
+        assert_eq!(("alice", "hi"), (events[1]["from"].as_str().unwrap(), events[1]["text"].as_str().unwrap()));
+        assert_eq!(60_000, events[2]["retry_after_ms"]);
+        assert!(events[3].get("retry_after_ms").is_none());
+        assert!(held_until.lock().is_some_and(|until| until > Instant::now() + Duration::from_secs(50)));
+        assert_eq!(5, events[4]["exit_code"]);
+    }
+
+    #[test]
+    fn triggers_answer_over_the_connection() {
+        let me = Mutex::new(User::new("bob"));
+        let psst = ServerMessage::Private { from: User::new("alice"), to: User::new("bob"), text: "psst".to_string() };
+        let input = encode_frame(&serde_json::to_vec(&psst).unwrap()).unwrap();
+        let rules = ["private => /msg {from} Not now".to_string()];
+        let triggers = Mutex::new(Triggers::parse(&rules, Duration::from_secs(30)).unwrap());
+        let (transcript, connected) = (Mutex::new(Transcript::default()), AtomicBool::new(true));
+
+        let (away, plugins, seen) = (Mutex::default(), Mutex::default(), Mutex::default());
+        // Answers wait out the server asking us to slow down and count as sent, same as anything typed
+        let (stats, held_until) = (Mutex::default(), Mutex::new(Some(Instant::now() + Duration::from_millis(20))));
+        let shared = Shared {
+            me: &me,
+            transcript: &transcript,
+            away: &away,
+            plugins: &plugins,
+            seen: &seen,
+            stats: Some(&stats),
+            bandwidth: &Bandwidth::default(),
+            held_until: &held_until,
+            triggers: &triggers,
+            skew: Skew::default(),
+            output: Output::Json,
+        };
+
+        let (console, mut answers) = (Captured::default(), Vec::new());
+        let mut connection = logged_in(&me.lock());
+        let _ = receive((&mut Framed::new(Cursor::new(input)), &mut answers), &mut connection, shared, &console, &connected);
+        assert_eq!(b"/msg alice Not now\n", answers.as_slice());
+        let shown = console.0.lock().clone();
+        assert!(shown.lines().nth(1).is_some_and(|line| line.contains("Holding off for")), "{shown}");
+        assert!(shown.lines().nth(2).is_some_and(|line| line.contains("Trigger sent")), "{shown}");
+        assert_eq!(1, transcript.lock().find("Trigger sent").len());
+        assert!(held_until.lock().is_none());
+        assert!(stats.lock().to_string().contains("Sent 1 lines"));
+    }
+
+    #[test]
+    fn test_client_export() {
+        let user = User::new("hello");
+        let client = Client::new(user.clone(), Duplex::new(Vec::new()));
+        client.transcript.lock().chat(&user, "hi");
+
+        assert_eq!(Some("Usage: /export [#chan] <path>".to_string()), client.local_command("/export"));
+        assert!(client.export("#general log.txt").starts_with("There are no channels yet"));
+
+        let path = std::env::temp_dir().join(format!("basic-irc-export-{}.md", std::process::id()));
+        assert!(client.local_command(&format!("/export {}", path.display())).unwrap().ends_with("as Markdown"));
+        let exported = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(exported.ends_with("**hello**: hi\n"));
+    }
+
+    #[test]
+    fn test_client_find() {
+        let user = User::new("hello");
+        let client = Client::new(user.clone(), Duplex::new(Vec::new()));
+        client.transcript.lock().chat(&user, "hi");
+        client.transcript.lock().chat(&user, "bye");
+
+        assert!(client.local_command("/find hi").unwrap().ends_with("\n1 found"));
+        assert_eq!(Some("Nothing matching \"nope\"".to_string()), client.local_command("/find nope"));
+        assert_eq!(None, client.local_command("/finder"));
+        assert_eq!(None, client.local_command("/kill someone"));
+    }
+
+    #[test]
+    fn session_keeps_track_of_channels() {
+        let path = std::env::temp_dir().join("unused-session.json");
+        let mut client = Client::new(User::new("bob"), Duplex::new(Vec::new())).with_session(path, Session::default());
+        for line in ["/join #rust", "/join #go", "/part #rust", "/join", "hi"] {
+            client.send(line.to_string());
+        }
+
+        let (_, session) = client.session.as_ref().unwrap();
+        assert_eq!(vec!["#go"], session.channels);
+    }
+
     #[test]
     fn test_client_do_auth_flow_success() {
         let user = User::new(String::from("hello"));
-        let user_json = serde_json::to_vec(&user).unwrap();
-
-        // Set a response where it _would_ be before the client does any writes
-        let mut cursor: Cursor<Vec<u8>> = Default::default();
-        cursor.seek(SeekFrom::Start(user_json.len() as u64)).unwrap();
-        let _ = cursor.write(&serde_json::to_vec(&AuthResponse::Success).unwrap()).unwrap();
-        cursor.seek(SeekFrom::Start(0)).unwrap();
+        let sent = [encode_message(&Handshake::new(FEATURES)).unwrap(), encode_message(&user).unwrap()].concat();
+        let resp = [accepted(), encode_message(&AuthResponse::Success).unwrap()].concat();
 
-        let mut client = Client::new(user, cursor);
+        let mut client = Client::new(user, Duplex::new(resp));
         assert!(client.do_auth_flow().is_ok());
+        assert_eq!(sent, client.conn.get_ref().output);
+    }
+
+    #[test]
+    fn test_client_do_auth_flow_incompatible() {
+        let resp = encode_message(&Capabilities::Rejected("too old".to_string())).unwrap();
+        let mut client = Client::new(User::new("hello"), Duplex::new(resp));
+        assert!(matches!(client.do_auth_flow(), Err(ClientError::Incompatible(reason)) if reason == "too old"));
+        // Never got as far as saying who it is
+        assert_eq!(encode_message(&Handshake::new(FEATURES)).unwrap(), client.conn.get_ref().output);
     }
 
     #[test]
     fn test_client_do_auth_flow_failure() {
         let user = User::new(String::from("hello"));
-        let user_json = serde_json::to_vec(&user).unwrap();
+        let resp = encode_frame(&serde_json::to_vec(&AuthResponse::Error("".to_string())).unwrap()).unwrap();
 
-        // Set a response where it _would_ be before the client does any writes
-        let mut cursor: Cursor<Vec<u8>> = Default::default();
-        cursor.seek(SeekFrom::Start(user_json.len() as u64)).unwrap();
-        let _ = cursor.write(&serde_json::to_vec(&AuthResponse::Error("".to_string())).unwrap()).unwrap();
-        cursor.seek(SeekFrom::Start(0)).unwrap();
-
-        let mut client = Client::new(user, cursor);
+        let mut client = Client::new(user, Duplex::new(resp));
         assert!(client.do_auth_flow().is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn busy_then_reconnected() {
+        let user = User::new("hello");
+        let busy = encode_message(&AuthResponse::Busy { retry_after_ms: 250 }).unwrap();
+        let mut client = Client::new(user.clone(), Duplex::new(busy));
+        let e = client.do_auth_flow().unwrap_err();
+        assert_eq!(Some(Duration::from_millis(250)), e.retry_after());
+        assert_eq!(None, ClientError::Auth(AuthResponse::ServerFull).retry_after());
+
+        // Starts the handshake over from scratch on the new connection
+        let sent = [encode_message(&Handshake::new(FEATURES)).unwrap(), encode_message(&user).unwrap()].concat();
+        client.reconnect(Duplex::new([accepted(), encode_message(&AuthResponse::Success).unwrap()].concat()));
+        assert!(client.do_auth_flow().is_ok());
+        assert_eq!(sent, client.conn.get_ref().output);
+    }
+
+    #[test]
+    fn test_client_do_auth_flow_coalesced_with_chat() {
+        let user = User::new(String::from("hello"));
+        let chat = ServerMessage::Chat { from: User::new("other"), text: "hi".to_string(), received_ms: 0, channel: None };
+
+        // The auth response and a chat message show up in the same read
+        let mut input = accepted();
+        input.extend(encode_frame(&serde_json::to_vec(&AuthResponse::Success).unwrap()).unwrap());
+        input.extend(encode_frame(&serde_json::to_vec(&chat).unwrap()).unwrap());
+
+        let mut client = Client::new(user, Duplex::new(input));
+        assert!(client.do_auth_flow().is_ok());
+
+        let msg: ServerMessage = client.reader.read_message(&FrameLimits::default()).unwrap();
+        assert_eq!(chat, msg);
+    }
+
+    #[cfg(feature = "plugins")]
+    #[test]
+    fn plugins_filter_incoming() {
+        let chat = |from: &str, text: &str| {
+            ServerMessage::Chat { from: User::new(from), text: text.to_string(), received_ms: 0, channel: None }
+        };
+        let mut input = Vec::new();
+        for msg in [chat("spammer", "buy now"), chat("alice", "hi")] {
+            input.extend(encode_frame(&serde_json::to_vec(&msg).unwrap()).unwrap());
+        }
+
+        let mut plugins = Plugins::default();
+        let source = "return { on_incoming = function(from, text) if from == 'spammer' then return false end return text:upper() end }";
+        plugins.add("filter.lua".to_string(), source).unwrap();
+        let mut client = Client::new(User::new("bob"), Duplex::new(input)).with_plugins(plugins);
+        client.connection = logged_in(&User::new("bob"));
+
+        assert_eq!(chat("alice", "HI"), client.next_message().unwrap());
+    }
+}
@@ -0,0 +1,117 @@
+//! When the client shows something was said. Messages are stamped by the server's clock, which the client's
+//! own might not agree with, so the server says what time it makes it in the handshake and the client goes by
+//! the difference. Anything older than a minute is history being replayed and shows how long ago it was,
+//! anything else is live and shows the time.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::response::ServerMessage;
+
+/// Said longer ago than this and it's history, not something that just came in.
+const LIVE: Duration = Duration::from_secs(60);
+
+/// How far the server's clock is ahead of this one's.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Skew {
+    ahead_ms: i64,
+}
+
+impl Skew {
+    /// From the time the server gave in its handshake, if it gave one, and the time it got here. The trip
+    /// over is counted as skew too, but that's well under what anyone would notice.
+    pub fn new(server_ms: Option<u64>, now: SystemTime) -> Self {
+        let Some(server_ms) = server_ms else {
+            return Self::default();
+        };
+        Self { ahead_ms: server_ms as i64 - millis(now) as i64 }
+    }
+
+    /// A time stamped by the server, by this clock.
+    pub fn local(&self, server_ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis((server_ms as i64 - self.ahead_ms).max(0) as u64)
+    }
+
+    /// When `msg` was said by this clock, if the server stamped it, which it only does for chat.
+    pub fn said_at(&self, msg: &ServerMessage) -> Option<SystemTime> {
+        match msg {
+            ServerMessage::Chat { received_ms, .. } if *received_ms > 0 => Some(self.local(*received_ms)),
+            ServerMessage::Sequenced { message, .. } => self.said_at(message),
+            _ => None,
+        }
+    }
+}
+
+/// What to put in front of something said at `at`: how long ago if it's history, or the time (UTC) if not.
+pub fn stamp(at: SystemTime, now: SystemTime) -> String {
+    match now.duration_since(at) {
+        Ok(age) if age >= LIVE => format!("[{}]", ago(age)),
+        _ => format!("[{}]", clock_time(at)),
+    }
+}
+
+/// Like `2m ago`, in the biggest unit that fits.
+pub(crate) fn ago(age: Duration) -> String {
+    match age.as_secs() {
+        secs if secs < 60 => "just now".to_string(),
+        _ => format!("{} ago", span(age)),
+    }
+}
+
+/// Like `2m`, in the biggest unit that fits, rounded down. Anything under a minute is `0m`.
+pub(crate) fn span(length: Duration) -> String {
+    match length.as_secs() {
+        secs if secs < 60 * 60 => format!("{}m", secs / 60),
+        secs if secs < 24 * 60 * 60 => format!("{}h", secs / (60 * 60)),
+        secs => format!("{}d", secs / (24 * 60 * 60)),
+    }
+}
+
+/// Like `13:37`, in UTC.
+fn clock_time(at: SystemTime) -> String {
+    let secs = millis(at) / 1000;
+    format!("{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60)
+}
+
+fn millis(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::user::User;
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn goes_by_the_servers_clock() {
+        // This clock's five minutes behind
+        let skew = Skew::new(Some(1_000_300_000), at(1_000_000));
+        assert_eq!(at(1_000_000), skew.local(1_000_300_000));
+        assert_eq!(Skew::default(), Skew::new(None, at(1_000_000)));
+
+        let chat = |received_ms| {
+            ServerMessage::Chat { from: User::new("alice"), text: "hi".to_string(), received_ms, channel: None }
+        };
+        let replayed = ServerMessage::Sequenced { buffer: "&lobby".to_string(), seq: 1, message: Box::new(chat(1_000_120_000)) };
+        // Said three minutes ago by the server, so not two minutes in the future
+        assert_eq!(Some(at(999_820)), skew.said_at(&replayed));
+        assert_eq!(None, skew.said_at(&chat(0)));
+        assert_eq!(None, skew.said_at(&ServerMessage::notice("hi")));
+    }
+
+    #[test]
+    fn stamps() {
+        let now = at(1_709_300_220);
+        assert_eq!("[13:37]", stamp(now, now));
+        assert_eq!("[13:36]", stamp(at(1_709_300_170), now));
+        assert_eq!("[2m ago]", stamp(at(1_709_300_100), now));
+        assert_eq!("[3h ago]", stamp(at(1_709_289_000), now));
+        assert_eq!("[2d ago]", stamp(at(1_709_100_000), now));
+        // A little ahead is still live
+        assert_eq!("[13:37]", stamp(at(1_709_300_225), now));
+        assert_eq!("just now", ago(Duration::from_secs(59)));
+        assert_eq!("1h", span(Duration::from_secs(3_700)));
+    }
+}
@@ -0,0 +1,911 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ed25519_dalek::{Signature, Signer, SigningKey, SIGNATURE_LENGTH};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use crate::frame::{encode_message, FrameLimits, read_message};
+use crate::listener;
+use crate::outbound::{Frame, Outbound, OutboundError, spawn_writer, WriterOptions};
+use crate::registry::Registry;
+use crate::response::ServerMessage;
+use crate::signing::{encode_public, TrustedKeys};
+use crate::user::User;
+
+/// How often every node tells the others who's on it. Doubles as the heartbeat.
+const HEARTBEAT: Duration = Duration::from_secs(1);
+/// A node nobody's heard from in this long is assumed dead, and its users gone with it.
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+const REDIAL: Duration = Duration::from_secs(2);
+const LINK_QUEUE_LEN: usize = 1024;
+/// Chat and kill ids remembered per cluster, so relayed messages that loop back around get dropped. Ids are
+/// only unique within a node's boot, so they're remembered along with it.
+const SEEN_LEN: usize = 4096;
+/// How far off gossip's `sent_ms` can be from our clock before it's dropped as a replay, either way for clocks
+/// that don't quite agree.
+const MAX_GOSSIP_AGE: Duration = Duration::from_secs(30);
+const LINK_LIMITS: FrameLimits = FrameLimits { max_len: 1024 * 1024, max_depth: 8 };
+
+/// Where this node sits in the cluster.
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    /// Has to be unique across the cluster.
+    pub node: String,
+    /// Where other nodes link to us, if anywhere.
+    pub listen: Option<SocketAddr>,
+    /// Nodes to link to, retried for as long as the server is up.
+    pub peers: Vec<SocketAddr>,
+    /// What we sign everything we gossip with.
+    pub key: SigningKey,
+    /// Other nodes' public keys. Only gossip signed by the node it claims to be from gets through.
+    pub trusted: TrustedKeys,
+    /// Whether nodes not in `trusted` are taken at their word anyway. Off unless the operator says so, so a
+    /// cluster nobody gave keys to hears from no one.
+    pub trust_any: bool,
+}
+
+/// What nodes say to each other over a link, one per frame. Anything that gets relayed is signed by the
+/// node it's from, so the nodes in between can't make things up on its behalf, and carries the `epoch` it
+/// booted at, since its ids and `seq`s start over every time it does. They're stamped with when they were
+/// sent, in milliseconds since the Unix epoch, so an old one can't be replayed later.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Gossip {
+    Hello { node: String },
+    /// Everyone connected to `node`. Only the highest `seq` from each node counts, which is what lets these
+    /// get relayed around the cluster without looping forever.
+    Presence { node: String, epoch: u64, seq: u64, sent_ms: u64, users: Vec<Member>, signature: Signature },
+    /// A message someone on `origin` sent, relayed to every node.
+    Chat { origin: String, epoch: u64, id: u64, sent_ms: u64, message: ServerMessage, signature: Signature },
+    /// An operator on `origin` killed `target`, who gets `message` on their way out from whichever node
+    /// they're on.
+    Kill { origin: String, epoch: u64, id: u64, sent_ms: u64, target: User, message: ServerMessage, signature: Signature },
+}
+
+/// Someone connected to a node, and since when in milliseconds since the Unix epoch. When a nick turns up
+/// on two nodes, whoever's had it longer keeps it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub user: User,
+    pub since_ms: u64,
+}
+
+impl Gossip {
+    /// The node vouching for this and its signature, for anything that gets relayed.
+    fn signer(&self) -> Option<(&str, &Signature)> {
+        match self {
+            Gossip::Hello { .. } => None,
+            Gossip::Presence { node, signature, .. } => Some((node, signature)),
+            Gossip::Chat { origin, signature, .. } | Gossip::Kill { origin, signature, .. } => Some((origin, signature)),
+        }
+    }
+
+    /// When it was sent, for anything that gets relayed.
+    fn sent_ms(&self) -> Option<u64> {
+        match self {
+            Gossip::Hello { .. } => None,
+            Gossip::Presence { sent_ms, .. } | Gossip::Chat { sent_ms, .. } | Gossip::Kill { sent_ms, .. } => Some(*sent_ms),
+        }
+    }
+
+    /// What actually gets signed. Tagged with the kind of gossip, so a signature can't be lifted onto
+    /// something else that happens to encode the same.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let bytes = match self {
+            Gossip::Hello { node } => serde_json::to_vec(&("hello", node)),
+            Gossip::Presence { node, epoch, seq, sent_ms, users, .. } => {
+                serde_json::to_vec(&("presence", node, epoch, seq, sent_ms, users))
+            }
+            Gossip::Chat { origin, epoch, id, sent_ms, message, .. } => {
+                serde_json::to_vec(&("chat", origin, epoch, id, sent_ms, message))
+            }
+            Gossip::Kill { origin, epoch, id, sent_ms, target, message, .. } => {
+                serde_json::to_vec(&("kill", origin, epoch, id, sent_ms, target, message))
+            }
+        };
+        bytes.expect("Plain data always serializes")
+    }
+}
+
+#[derive(Debug)]
+struct NodeState {
+    epoch: u64,
+    seq: u64,
+    /// Everyone on the node and when they signed on.
+    users: BTreeMap<User, u64>,
+    last_heard: Instant,
+}
+
+#[derive(Debug)]
+struct Link {
+    /// Who's on the other end, once they've said hello.
+    node: Option<String>,
+    outbound: Outbound,
+}
+
+#[derive(Debug, Default)]
+struct ClusterState {
+    links: BTreeMap<usize, Link>,
+    next_link: usize,
+    nodes: BTreeMap<String, NodeState>,
+    /// Nodes that went quiet, so we can tell when they come back.
+    split: BTreeSet<String>,
+    /// When each of our own users signed on.
+    local_since: BTreeMap<User, u64>,
+    /// Origin, epoch and id of the chat and kills already heard.
+    seen: HashSet<(String, u64, u64)>,
+    seen_order: VecDeque<(String, u64, u64)>,
+    next_id: u64,
+    presence_seq: u64,
+}
+
+impl ClusterState {
+    /// Remembers a chat id from `origin`'s boot at `epoch`, returning whether it's new.
+    fn see(&mut self, origin: &str, epoch: u64, id: u64) -> bool {
+        let key = (origin.to_string(), epoch, id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > SEEN_LEN {
+            let oldest = self.seen_order.pop_front().expect("Just pushed");
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+
+    /// Whether `origin` has booted again since `epoch`, making anything from then a replay.
+    fn is_before_restart(&self, origin: &str, epoch: u64) -> bool {
+        self.nodes.get(origin).is_some_and(|known| known.epoch > epoch)
+    }
+}
+
+/// Membership and relaying for a cluster of servers. Nodes link up over plain TCP and gossip: every node
+/// relays what it hears to all its other links, so a message gets everywhere as long as the nodes are
+/// connected somehow, and losing one node or link doesn't cut anyone else off.
+///
+/// Presence is eventually consistent. Two people grabbing the same nick on different nodes at the same
+/// moment, or while the nodes are split, will both get it until the nodes hear about each other. Then
+/// whoever signed on last gets disconnected, or both if it's a tie, like nick collisions on real IRC networks.
+#[derive(Debug)]
+pub struct Cluster {
+    node: String,
+    /// When this node booted, see `boot_epoch`.
+    epoch: u64,
+    key: SigningKey,
+    trusted: TrustedKeys,
+    trust_any: bool,
+    users: Arc<Registry<Outbound>>,
+    state: Mutex<ClusterState>,
+}
+
+impl Cluster {
+    pub fn new(
+        node: impl Into<String>,
+        key: SigningKey,
+        (trusted, trust_any): (TrustedKeys, bool),
+        users: Arc<Registry<Outbound>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            node: node.into(),
+            epoch: boot_epoch(),
+            key,
+            trusted,
+            trust_any,
+            users,
+            state: Default::default(),
+        })
+    }
+
+    /// Starts listening for and dialing out to other nodes, and heartbeating to all of them.
+    pub fn start(options: &ClusterOptions, users: Arc<Registry<Outbound>>) -> std::io::Result<Arc<Self>> {
+        let trust = (options.trusted.clone(), options.trust_any);
+        let cluster = Self::new(options.node.clone(), options.key.clone(), trust, users);
+        info!("[CLUSTER] {} signs with {}", cluster.node, encode_public(&cluster.key.verifying_key()));
+        if cluster.trust_any {
+            warn!("[CLUSTER] Taking nodes without a trusted key at their word");
+        }
+
+        if let Some(listen) = options.listen {
+            let listener = listener::bind_one(listen)?;
+            info!("[CLUSTER] {} taking links on {}", cluster.node, listener.local_addr()?);
+            let cluster = cluster.clone();
+            thread::Builder::new().name("cluster-listener".to_string()).spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let cluster = cluster.clone();
+                            thread::spawn(move || cluster.run_link(stream));
+                        }
+                        Err(e) => warn!("[CLUSTER] Failed accepting a link: {e:?}"),
+                    }
+                }
+            })?;
+        }
+
+        for &peer in &options.peers {
+            let cluster = cluster.clone();
+            thread::Builder::new().name(format!("cluster-dial-{peer}")).spawn(move || loop {
+                match TcpStream::connect(peer) {
+                    Ok(stream) => cluster.run_link(stream),
+                    Err(e) => warn!("[CLUSTER] Couldn't link to {peer}, retrying: {e}"),
+                }
+                thread::sleep(REDIAL);
+            })?;
+        }
+
+        {
+            let cluster = cluster.clone();
+            thread::Builder::new().name("cluster-heartbeat".to_string()).spawn(move || loop {
+                thread::sleep(HEARTBEAT);
+                cluster.heartbeat(Instant::now());
+            })?;
+        }
+
+        Ok(cluster)
+    }
+
+    /// Whether someone on another live node has `user`'s nick, or one that looks like it.
+    pub fn is_taken_remotely(&self, user: &User) -> bool {
+        self.state.lock().nodes.values().any(|node| node.users.keys().any(|other| other == user || other.looks_like(user)))
+    }
+
+    /// Notes when one of our users signed on, for settling nick collisions with other nodes.
+    pub fn signed_on(&self, user: &User, at: SystemTime) {
+        self.state.lock().local_since.insert(user.clone(), unix_ms(at));
+    }
+
+    /// Sends a message from one of our users to the rest of the cluster.
+    pub fn publish(&self, message: &ServerMessage) {
+        let message = message.clone();
+        self.send_new(|origin, epoch, id, sent_ms| Gossip::Chat { origin, epoch, id, sent_ms, message, signature: unsigned() });
+    }
+
+    /// Kills `target` on whichever other node they're on, sending them `message` first.
+    pub fn kill(&self, target: &User, message: &ServerMessage) {
+        let (target, message) = (target.clone(), message.clone());
+        self.send_new(|origin, epoch, id, sent_ms| {
+            Gossip::Kill { origin, epoch, id, sent_ms, target, message, signature: unsigned() }
+        });
+    }
+
+    /// Signs and sends gossip that starts with us, under a fresh id.
+    fn send_new(&self, gossip: impl FnOnce(String, u64, u64, u64) -> Gossip) {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.see(&self.node, self.epoch, id);
+
+        let gossip = self.sign(gossip(self.node.clone(), self.epoch, id, unix_ms(SystemTime::now())));
+        send_to_links(&state, &gossip, None);
+    }
+
+    /// Talks to one other node until the link drops.
+    fn run_link(self: &Arc<Self>, stream: TcpStream) {
+        let peer = stream.peer_addr().map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("[CLUSTER] Couldn't set up link to {peer}: {e:?}");
+                return;
+            }
+        };
+
+        let (outbound, queue) = Outbound::new(LINK_QUEUE_LEN);
+        if let Err(e) = spawn_writer(writer, queue, WriterOptions::default()) {
+            warn!("[CLUSTER] Couldn't start a writer for {peer}: {e:?}");
+            return;
+        }
+
+        let link = self.add_link(outbound);
+        let mut reader = BufReader::new(stream);
+        loop {
+            match read_message::<_, Gossip>(&mut reader, &LINK_LIMITS) {
+                Ok(gossip) => self.handle(link, gossip, Instant::now()),
+                Err(e) => {
+                    warn!("[CLUSTER] Link to {peer} dropped: {e}");
+                    break;
+                }
+            }
+        }
+
+        self.state.lock().links.remove(&link);
+    }
+
+    /// Registers a link and introduces ourselves over it.
+    fn add_link(&self, outbound: Outbound) -> usize {
+        let mut state = self.state.lock();
+        let link = state.next_link;
+        state.next_link += 1;
+
+        let presence = self.presence(&mut state);
+        for gossip in [Gossip::Hello { node: self.node.clone() }, presence] {
+            send(&outbound, &gossip);
+        }
+        state.links.insert(link, Link { node: None, outbound });
+        link
+    }
+
+    fn handle(&self, from_link: usize, gossip: Gossip, now: Instant) {
+        if !self.is_authentic(&gossip) {
+            let (node, _) = gossip.signer().expect("Only relayed gossip gets checked");
+            warn!("[CLUSTER] Dropping gossip claiming to be from {node} that it didn't sign");
+            return;
+        }
+        let now_ms = unix_ms(SystemTime::now());
+        if gossip.sent_ms().is_some_and(|sent_ms| sent_ms.abs_diff(now_ms) > MAX_GOSSIP_AGE.as_millis() as u64) {
+            let (node, _) = gossip.signer().expect("Everything stamped is signed");
+            warn!("[CLUSTER] Dropping gossip from {node} sent too long ago, or from too far in the future");
+            return;
+        }
+
+        let mut state = self.state.lock();
+
+        match &gossip {
+            Gossip::Hello { node } => {
+                if *node == self.node {
+                    warn!("[CLUSTER] Linked to ourselves, dropping the link");
+                    state.links.remove(&from_link);
+                    return;
+                }
+                info!("[CLUSTER] Linked to {node}");
+                if let Some(link) = state.links.get_mut(&from_link) {
+                    link.node = Some(node.clone());
+                }
+            }
+            Gossip::Presence { node, epoch, seq, users, .. } => {
+                if *node == self.node {
+                    return;
+                }
+                if state.nodes.get(node).is_some_and(|known| (known.epoch, known.seq) >= (*epoch, *seq)) {
+                    // Old news, or from before it restarted, and whoever sent it has already had it from us
+                    return;
+                }
+
+                let mut notice = None;
+                if !state.nodes.contains_key(node) {
+                    info!("[CLUSTER] {node} joined with {} user(s)", users.len());
+                    if state.split.remove(node) {
+                        notice = Some(format!("*** Netjoin: {} <-> {node}, back with {} user(s)", self.node, users.len()));
+                    }
+                }
+
+                // Ties go against both sides, since the other node is making the same call about its users
+                let collisions: Vec<_> = users
+                    .iter()
+                    .filter(|member| state.local_since.get(&member.user).is_some_and(|since| *since >= member.since_ms))
+                    .map(|member| member.user.clone())
+                    .collect();
+
+                let users = users.iter().map(|member| (member.user.clone(), member.since_ms)).collect();
+                state.nodes.insert(node.clone(), NodeState { epoch: *epoch, seq: *seq, users, last_heard: now });
+                send_to_links(&state, &gossip, Some(from_link));
+                drop(state);
+
+                if let Some(notice) = notice {
+                    self.deliver_locally(&ServerMessage::notice(notice));
+                }
+                for user in collisions {
+                    let message = ServerMessage::notice(format!("Nick collision: {user} has been on {node} for longer"));
+                    if let Ok(frame) = encode_message(&message) {
+                        if self.users.kill(&user, frame.into()) {
+                            warn!("[CLUSTER] {user} collided with the one on {node}, disconnecting ours");
+                        }
+                    }
+                }
+            }
+            Gossip::Chat { origin, epoch, id, message, .. } => {
+                if state.is_before_restart(origin, *epoch) || !state.see(origin, *epoch, *id) {
+                    return;
+                }
+
+                send_to_links(&state, &gossip, Some(from_link));
+                // Don't hold the cluster lock while taking the registry's
+                drop(state);
+                self.deliver_locally(message);
+            }
+            Gossip::Kill { origin, epoch, id, target, message, .. } => {
+                if state.is_before_restart(origin, *epoch) || !state.see(origin, *epoch, *id) {
+                    return;
+                }
+
+                send_to_links(&state, &gossip, Some(from_link));
+                drop(state);
+                if let Ok(frame) = encode_message(message) {
+                    if self.users.kill(target, frame.into()) {
+                        info!("[CLUSTER] Killed {target} on behalf of {origin}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands a message from another node to every one of our users who'd hear it.
+    fn deliver_locally(&self, message: &ServerMessage) {
+        let frame: Frame = match encode_message(message) {
+            Ok(frame) => frame.into(),
+            Err(e) => {
+                warn!("[CLUSTER] Failed encoding relayed message: {e:?}");
+                return;
+            }
+        };
+
+        let full = match message {
+            ServerMessage::Chat { channel, .. } => self.users.send_to_channel(&frame, channel.as_deref(), None),
+            ServerMessage::Private { to, .. } => match self.users.send_to(to, frame) {
+                Err(OutboundError::Full) => vec![to.clone()],
+                _ => vec![],
+            },
+            ServerMessage::Notice { .. } | ServerMessage::Presence { .. } => self.users.send_to_all(&frame, None),
+            ServerMessage::Sequenced { message, .. } => return self.deliver_locally(message),
+            // Only ever an answer for someone on the server that made it, so never relayed. Kills come with their own
+            ServerMessage::Names { .. }
+            | ServerMessage::Channels { .. }
+            | ServerMessage::Ping { .. }
+            | ServerMessage::Disconnected { .. } => vec![],
+        };
+        for user in full {
+            warn!("[CLUSTER] {user} isn't keeping up, disconnecting them");
+        }
+    }
+
+    /// Tells every link who's here, and forgets nodes that have gone quiet, letting our users know they split.
+    fn heartbeat(&self, now: Instant) {
+        let mut state = self.state.lock();
+        let presence = self.presence(&mut state);
+        send_to_links(&state, &presence, None);
+
+        let (alive, dead) = std::mem::take(&mut state.nodes)
+            .into_iter()
+            .partition(|(_, known)| now.saturating_duration_since(known.last_heard) < NODE_TIMEOUT);
+        state.nodes = alive;
+        let dead: BTreeMap<_, _> = dead;
+        state.split.extend(dead.keys().cloned());
+        drop(state);
+
+        for (node, known) in dead {
+            warn!("[CLUSTER] Haven't heard from {node} in {NODE_TIMEOUT:?}, dropping its {} user(s)", known.users.len());
+            let users: Vec<_> = known.users.keys().map(|user| user.name.as_str()).collect();
+            let text = format!("*** Netsplit: {} <-> {node}, lost {}", self.node, users.join(", "));
+            self.deliver_locally(&ServerMessage::notice(text));
+        }
+    }
+
+    fn presence(&self, state: &mut ClusterState) -> Gossip {
+        state.presence_seq += 1;
+
+        // Anyone we weren't told about signed on just now, as far as we know
+        let now = unix_ms(SystemTime::now());
+        let mut local = BTreeSet::new();
+        self.users.for_each(|user, _| {
+            local.insert(user.clone());
+        });
+        state.local_since.retain(|user, _| local.contains(user));
+        let users = local
+            .into_iter()
+            .map(|user| {
+                let since_ms = *state.local_since.entry(user.clone()).or_insert(now);
+                Member { user, since_ms }
+            })
+            .collect();
+
+        self.sign(Gossip::Presence {
+            node: self.node.clone(),
+            epoch: self.epoch,
+            seq: state.presence_seq,
+            sent_ms: now,
+            users,
+            signature: unsigned(),
+        })
+    }
+
+    fn sign(&self, mut gossip: Gossip) -> Gossip {
+        let signed = self.key.sign(&gossip.signed_bytes());
+        if let Gossip::Presence { signature, .. } | Gossip::Chat { signature, .. } | Gossip::Kill { signature, .. } = &mut gossip {
+            *signature = signed;
+        }
+        gossip
+    }
+
+    /// Whether relayed gossip was signed by the node it says it's from. Nodes we don't have a key for are
+    /// only taken at their word if the operator's said to.
+    fn is_authentic(&self, gossip: &Gossip) -> bool {
+        let Some((node, signature)) = gossip.signer() else {
+            return true;
+        };
+
+        let own = self.key.verifying_key();
+        let key = if node == self.node { Some(&own) } else { self.trusted.get(node) };
+        match key {
+            Some(key) => key.verify_strict(&gossip.signed_bytes(), signature).is_ok(),
+            None => self.trust_any,
+        }
+    }
+}
+
+/// When this node booted, in milliseconds since the Unix epoch. Ids and presence `seq`s start over on every
+/// boot, so they're told apart by this, and a later boot's presence wins. Nodes made in the same millisecond,
+/// like in tests, still get different ones.
+fn boot_epoch() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = unix_ms(SystemTime::now());
+    let last = LAST.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)));
+    now.max(last.expect("Always Some") + 1)
+}
+
+fn unix_ms(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Stand-in until gossip gets signed.
+fn unsigned() -> Signature {
+    Signature::from_bytes(&[0; SIGNATURE_LENGTH])
+}
+
+fn send(outbound: &Outbound, gossip: &Gossip) {
+    match encode_message(gossip) {
+        Ok(frame) => {
+            if let Err(e) = outbound.send(frame.into()) {
+                warn!("[CLUSTER] Dropping gossip for a link: {e}");
+            }
+        }
+        Err(e) => warn!("[CLUSTER] Failed encoding gossip: {e:?}"),
+    }
+}
+
+/// Sends `gossip` over every link except `except`, i.e. the one it came in on.
+fn send_to_links(state: &ClusterState, gossip: &Gossip, except: Option<usize>) {
+    for (_, link) in state.links.iter().filter(|(id, _)| Some(**id) != except) {
+        send(&link.outbound, gossip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    use crate::outbound::Queue;
+    use crate::signing::generate;
+    use super::*;
+
+    fn chat(from: &str, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new(from), text: text.to_string(), received_ms: 0, channel: None }
+    }
+
+    fn received(queue: &Receiver<Frame>) -> Vec<Gossip> {
+        queue.try_iter().map(|frame| read_message(&mut Cursor::new(frame.to_vec()), &LINK_LIMITS).unwrap()).collect()
+    }
+
+    fn now_ms() -> u64 {
+        unix_ms(SystemTime::now())
+    }
+
+    /// Chat `origin` sent at `sent_ms`, from its boot at `epoch`.
+    fn stamped(origin: &str, (epoch, sent_ms): (u64, u64), id: u64, message: ServerMessage) -> Gossip {
+        Gossip::Chat { origin: origin.to_string(), epoch, id, sent_ms, message, signature: unsigned() }
+    }
+
+    /// Chat `origin` sent just now, from its boot at epoch 1.
+    fn relayed(origin: &str, id: u64, message: ServerMessage) -> Gossip {
+        stamped(origin, (1, now_ms()), id, message)
+    }
+
+    fn presence_of(node: &str, seq: u64, users: Vec<Member>) -> Gossip {
+        Gossip::Presence { node: node.to_string(), epoch: 1, seq, sent_ms: now_ms(), users, signature: unsigned() }
+    }
+
+    fn member(name: &str, since_ms: u64) -> Member {
+        Member { user: User::new(name), since_ms }
+    }
+
+    fn node(name: &str, trusted: TrustedKeys) -> Arc<Cluster> {
+        // Without keys, tests take everyone at their word, like `--trust-any-node`
+        let trust_any = trusted.is_empty();
+        Cluster::new(name, generate().unwrap(), (trusted, trust_any), Default::default())
+    }
+
+    /// A cluster with two fake links, with whatever was said to them on connect already drained.
+    fn cluster_with_links(trusted: TrustedKeys) -> (Arc<Cluster>, [(usize, Queue); 2]) {
+        let cluster = node("me", trusted);
+        let links = [(), ()].map(|_| {
+            let (outbound, queue) = Outbound::new(LINK_QUEUE_LEN);
+            let link = cluster.add_link(outbound);
+            received(&queue);
+            (link, queue)
+        });
+        (cluster, links)
+    }
+
+    #[test]
+    fn new_links_get_hello_and_presence() {
+        let users: Arc<Registry<Outbound>> = Default::default();
+        users.claim_nick(&User::new("local"), Outbound::new(1).0).unwrap();
+        let cluster = Cluster::new("me", generate().unwrap(), Default::default(), users);
+        cluster.signed_on(&User::new("local"), UNIX_EPOCH + Duration::from_millis(1));
+
+        let (outbound, queue) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.add_link(outbound);
+        let received = received(&queue);
+        let Gossip::Presence { sent_ms, .. } = received[1] else { panic!("No presence in {received:?}") };
+        let (epoch, users) = (cluster.epoch, vec![member("local", 1)]);
+        let presence = Gossip::Presence { node: "me".to_string(), epoch, seq: 1, sent_ms, users, signature: unsigned() };
+        assert_eq!(vec![Gossip::Hello { node: "me".to_string() }, cluster.sign(presence)], received);
+    }
+
+    #[test]
+    fn presence_is_relayed_once() {
+        let (cluster, [(a, a_queue), (_, b_queue)]) = cluster_with_links(Default::default());
+        let presence = presence_of("other", 3, vec![member("far", 0)]);
+        let now = Instant::now();
+
+        cluster.handle(a, presence.clone(), now);
+        assert!(cluster.is_taken_remotely(&User::new("far")));
+        assert!(received(&a_queue).is_empty());
+        assert_eq!(vec![presence.clone()], received(&b_queue));
+
+        // Hearing it again, or anything older, goes nowhere
+        cluster.handle(a, presence, now);
+        cluster.handle(a, presence_of("other", 2, vec![]), now);
+        assert!(received(&b_queue).is_empty());
+        assert!(cluster.is_taken_remotely(&User::new("far")));
+    }
+
+    #[test]
+    fn chat_is_relayed_and_delivered_once() {
+        let (cluster, [(a, _), (_, b_queue)]) = cluster_with_links(Default::default());
+        let (outbound, local) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("local"), outbound).unwrap();
+
+        let gossip = relayed("other", 7, chat("far", "hi"));
+        cluster.handle(a, gossip.clone(), Instant::now());
+        cluster.handle(a, gossip.clone(), Instant::now());
+
+        assert_eq!(vec![gossip], received(&b_queue));
+        let delivered: Vec<ServerMessage> = local
+            .try_iter()
+            .map(|frame| read_message(&mut Cursor::new(frame.to_vec()), &FrameLimits::default()).unwrap())
+            .collect();
+        assert_eq!(vec![chat("far", "hi")], delivered);
+    }
+
+    #[test]
+    fn restarted_nodes_are_heard_from_again() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let (outbound, local) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("local"), outbound).unwrap();
+
+        // The same node before and after restarting, starting its ids and presence over from scratch
+        let key = generate().unwrap();
+        for (users, text) in [(vec!["far"], "before"), (vec![], "after")] {
+            let other = Cluster::new("other", key.clone(), (Default::default(), true), Default::default());
+            for user in users {
+                other.users.claim_nick(&User::new(user), Outbound::new(1).0).unwrap();
+            }
+            let (outbound, queue) = Outbound::new(LINK_QUEUE_LEN);
+            other.add_link(outbound);
+            other.publish(&chat("far", text));
+            for gossip in received(&queue) {
+                cluster.handle(a, gossip, Instant::now());
+            }
+        }
+
+        let delivered: Vec<ServerMessage> = local
+            .try_iter()
+            .map(|frame| read_message(&mut Cursor::new(frame.to_vec()), &FrameLimits::default()).unwrap())
+            .collect();
+        assert_eq!(vec![chat("far", "before"), chat("far", "after")], delivered);
+        assert!(!cluster.is_taken_remotely(&User::new("far")));
+    }
+
+    #[test]
+    fn channel_chat_only_reaches_local_members() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let (member, member_queue) = Outbound::new(LINK_QUEUE_LEN);
+        let (lobby, lobby_queue) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("member"), member).unwrap();
+        cluster.users.claim_nick(&User::new("lobby"), lobby).unwrap();
+        cluster.users.channels().join(&User::new("member"), "#rust");
+
+        let ServerMessage::Chat { from, text, received_ms, .. } = chat("far", "hi") else { unreachable!() };
+        let message = ServerMessage::Chat { from, text, received_ms, channel: Some("#rust".to_string()) };
+        cluster.handle(a, relayed("other", 1, message), Instant::now());
+
+        assert_eq!(1, member_queue.try_iter().count());
+        assert_eq!(0, lobby_queue.try_iter().count());
+    }
+
+    #[test]
+    fn private_messages_only_reach_their_target() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let (target, target_queue) = Outbound::new(LINK_QUEUE_LEN);
+        let (other, other_queue) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("target"), target).unwrap();
+        cluster.users.claim_nick(&User::new("other"), other).unwrap();
+
+        let message = ServerMessage::Private { from: User::new("far"), to: User::new("target"), text: "psst".to_string() };
+        cluster.handle(a, relayed("other", 1, message), Instant::now());
+
+        assert_eq!(1, target_queue.try_iter().count());
+        assert_eq!(0, other_queue.try_iter().count());
+    }
+
+    #[test]
+    fn own_chat_coming_back_is_ignored() {
+        let (cluster, [(a, a_queue), (_, b_queue)]) = cluster_with_links(Default::default());
+        cluster.publish(&chat("local", "hi"));
+
+        let published = received(&a_queue);
+        assert_eq!(published, received(&b_queue));
+        cluster.handle(a, published[0].clone(), Instant::now());
+        assert!(received(&b_queue).is_empty());
+    }
+
+    #[test]
+    fn quiet_nodes_are_dropped() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let start = Instant::now();
+        cluster.handle(a, presence_of("other", 1, vec![member("far", 0)]), start);
+
+        cluster.heartbeat(start + HEARTBEAT);
+        assert!(cluster.is_taken_remotely(&User::new("far")));
+        cluster.heartbeat(start + NODE_TIMEOUT);
+        assert!(!cluster.is_taken_remotely(&User::new("far")));
+    }
+
+    #[test]
+    fn nodes_link_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (one, two) = (node("one", Default::default()), node("two", Default::default()));
+        two.users.claim_nick(&User::new("far"), Outbound::new(LINK_QUEUE_LEN).0).unwrap();
+
+        {
+            let one = one.clone();
+            thread::spawn(move || one.run_link(listener.accept().unwrap().0));
+        }
+        {
+            let two = two.clone();
+            thread::spawn(move || two.run_link(TcpStream::connect(address).unwrap()));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !one.is_taken_remotely(&User::new("far")) {
+            assert!(Instant::now() < deadline, "Presence never made it across");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn only_trusted_signatures_get_through() {
+        let other = node("other", Default::default());
+        let trusted = TrustedKeys::from([("other".to_string(), other.key.verifying_key())]);
+        let (cluster, [(a, _), (_, b_queue)]) = cluster_with_links(trusted);
+
+        let signed = other.sign(relayed("other", 1, chat("far", "hi")));
+        cluster.handle(a, signed.clone(), Instant::now());
+        assert_eq!(vec![signed.clone()], received(&b_queue));
+
+        // A peer tampering with it, signing it with someone else's key, or making up a node all get dropped
+        let Gossip::Chat { signature, .. } = signed else { unreachable!() };
+        let Gossip::Chat { sent_ms, .. } = signed else { unreachable!() };
+        let (origin, message) = ("other".to_string(), chat("far", "bye"));
+        let tampered = Gossip::Chat { origin, epoch: 1, id: 2, sent_ms, message, signature };
+        let forged = node("other", Default::default())
+            .sign(relayed("other", 3, chat("far", "bye")));
+        let unknown = node("stranger", Default::default())
+            .sign(relayed("stranger", 4, chat("far", "bye")));
+        for gossip in [tampered, forged, unknown] {
+            cluster.handle(a, gossip, Instant::now());
+        }
+        assert!(received(&b_queue).is_empty());
+    }
+
+    #[test]
+    fn strangers_arent_heard_without_opting_in() {
+        let cluster = Cluster::new("me", generate().unwrap(), Default::default(), Default::default());
+        let (outbound, queue) = Outbound::new(LINK_QUEUE_LEN);
+        let link = cluster.add_link(outbound);
+        received(&queue);
+
+        let other = node("other", Default::default());
+        cluster.handle(link, other.sign(presence_of("other", 1, vec![member("far", 0)])), Instant::now());
+        assert!(!cluster.is_taken_remotely(&User::new("far")));
+    }
+
+    #[test]
+    fn replays_are_dropped() {
+        let (cluster, [(a, _), (_, b_queue)]) = cluster_with_links(Default::default());
+        let age = MAX_GOSSIP_AGE.as_millis() as u64 + 1000;
+        for sent_ms in [now_ms() - age, now_ms() + age] {
+            cluster.handle(a, stamped("other", (2, sent_ms), 1, chat("far", "hi")), Instant::now());
+        }
+        assert!(received(&b_queue).is_empty());
+
+        // Or from before the node last restarted
+        let mut restarted = presence_of("other", 1, vec![]);
+        if let Gossip::Presence { epoch, .. } = &mut restarted {
+            *epoch = 2;
+        }
+        cluster.handle(a, restarted, Instant::now());
+        assert_eq!(1, received(&b_queue).len());
+        cluster.handle(a, relayed("other", 1, chat("far", "from before")), Instant::now());
+        assert!(received(&b_queue).is_empty());
+    }
+
+    #[test]
+    fn own_node_cant_be_spoofed() {
+        let (cluster, [(a, _), (_, b_queue)]) = cluster_with_links(Default::default());
+        let spoofed = relayed("me", 99, chat("local", "hi"));
+
+        cluster.handle(a, spoofed, Instant::now());
+        assert!(received(&b_queue).is_empty());
+    }
+
+    #[test]
+    fn kills_are_relayed_and_carried_out() {
+        let (cluster, [(a, _), (_, b_queue)]) = cluster_with_links(Default::default());
+        let (outbound, local) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("local"), outbound).unwrap();
+
+        let message = ServerMessage::Disconnected { reason: "Killed by oper: bye".to_string() };
+        let (target, signature) = (User::new("local"), unsigned());
+        let (origin, sent_ms) = ("other".to_string(), now_ms());
+        let kill = Gossip::Kill { origin, epoch: 1, id: 1, sent_ms, target, message: message.clone(), signature };
+        cluster.handle(a, kill.clone(), Instant::now());
+
+        assert_eq!(vec![kill], received(&b_queue));
+        let delivered: ServerMessage = read_message(&mut Cursor::new(local.try_recv().unwrap().to_vec()), &FrameLimits::default()).unwrap();
+        assert_eq!(message, delivered);
+    }
+
+    fn notices(queue: &Receiver<Frame>) -> Vec<String> {
+        queue
+            .try_iter()
+            .map(|frame| read_message(&mut Cursor::new(frame.to_vec()), &FrameLimits::default()).unwrap())
+            .filter_map(|message| match message {
+                ServerMessage::Notice { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn netsplit_and_netjoin_are_announced() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let (outbound, local) = Outbound::new(LINK_QUEUE_LEN);
+        cluster.users.claim_nick(&User::new("local"), outbound).unwrap();
+        let presence = |seq| presence_of("other", seq, vec![member("far", 0)]);
+
+        let start = Instant::now();
+        cluster.handle(a, presence(1), start);
+        cluster.heartbeat(start + NODE_TIMEOUT);
+        assert_eq!(vec!["*** Netsplit: me <-> other, lost far".to_string()], notices(&local));
+
+        cluster.handle(a, presence(1), start + NODE_TIMEOUT);
+        assert_eq!(vec!["*** Netjoin: me <-> other, back with 1 user(s)".to_string()], notices(&local));
+    }
+
+    #[test]
+    fn nick_collisions_go_to_whoever_was_there_first() {
+        let (cluster, [(a, _), _]) = cluster_with_links(Default::default());
+        let (older, older_queue) = Outbound::new(LINK_QUEUE_LEN);
+        let (newer, newer_queue) = Outbound::new(LINK_QUEUE_LEN);
+        for (name, outbound, since) in [("older", older, 100), ("newer", newer, 300)] {
+            cluster.users.claim_nick(&User::new(name), outbound).unwrap();
+            cluster.signed_on(&User::new(name), UNIX_EPOCH + Duration::from_millis(since));
+        }
+
+        // The other node got both nicks while we were split, with its "newer" signing on before ours
+        let users = vec![member("older", 200), member("newer", 200)];
+        cluster.handle(a, presence_of("other", 1, users), Instant::now());
+
+        assert!(notices(&older_queue).is_empty());
+        assert_eq!(vec!["Nick collision: newer has been on other for longer".to_string()], notices(&newer_queue));
+    }
+}
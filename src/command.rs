@@ -0,0 +1,226 @@
+use thiserror::Error;
+use crate::bans::{Mask, MaskError};
+use crate::channel::{self, ChannelError};
+
+/// Something a client asked the server to do instead of saying it to everyone. Any line starting with a
+/// `/` is one of these.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Disconnect `nick` wherever they are on the network. Operators only.
+    Kill { nick: String, reason: String },
+    /// Disconnect `nick` from this server, telling everyone why. Operators only.
+    Kick { nick: String, reason: Option<String> },
+    /// Kick whoever `mask` catches and keep them from connecting again. Operators only.
+    Ban { mask: Mask, reason: Option<String> },
+    /// Lift the ban on `mask`. Operators only.
+    Unban { mask: Mask },
+    /// List the bans. Operators only.
+    Bans,
+    /// Tell everyone on the network something. Operators only.
+    Wallops { text: String },
+    /// Turn new connections to this server away, with `message` if given, or let them back in. Operators only.
+    Maintenance { on: bool, message: Option<String> },
+    /// Join `channel`, or switch back to it if already in it, and talk there from now on.
+    Join { channel: String },
+    /// Leave `channel`.
+    Part { channel: String },
+    /// Say `text` to just `nick`.
+    Msg { nick: String, text: String },
+    /// Say `text` in `channel`, or the lobby if `None`, without switching to it. Have to be in it already.
+    Say { channel: Option<String>, text: String },
+    /// Go by `nick` from now on.
+    Nick { nick: String },
+    /// Register the nick in use with `password`, so nobody else can connect as it without it.
+    Register { password: String },
+    /// Become an operator, if the nick in use is one, by giving the operator password.
+    Oper { password: String },
+    /// List who's connected to this server, or just who's in `channel` if given, which can be `&lobby`.
+    Names { channel: Option<String> },
+    /// List the channels on this server and how many are in each.
+    List,
+    /// The answer to a `ServerMessage::Ping`, so the server knows the connection's still alive. Clients send
+    /// these themselves, nobody needs to type it.
+    Pong { token: String },
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Unknown command: /{0}")]
+    Unknown(String),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+    #[error("{0}")]
+    Channel(#[from] ChannelError),
+    #[error("{0}")]
+    Mask(#[from] MaskError),
+}
+
+impl Command {
+    /// Whether only operators may use it. Anything that affects everyone is.
+    pub fn operators_only(&self) -> bool {
+        !matches!(
+            self,
+            Command::Join { .. }
+                | Command::Part { .. }
+                | Command::Msg { .. }
+                | Command::Say { .. }
+                | Command::Nick { .. }
+                | Command::Register { .. }
+                | Command::Oper { .. }
+                | Command::Names { .. }
+                | Command::List
+                | Command::Pong { .. }
+        )
+    }
+
+    /// `None` if `line` is plain chat rather than a command.
+    pub fn parse(line: &str) -> Option<Result<Self, CommandError>> {
+        let line = line.strip_prefix('/')?;
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        Some(match name.to_ascii_lowercase().as_str() {
+            "kill" => match rest.split_once(' ') {
+                Some((nick, reason)) if !reason.trim().is_empty() => {
+                    Ok(Command::Kill { nick: nick.to_string(), reason: reason.trim().to_string() })
+                }
+                _ => Err(CommandError::Usage("/kill <nick> <reason>")),
+            },
+            "kick" => nick_and_reason(rest)
+                .map(|(nick, reason)| Command::Kick { nick, reason })
+                .ok_or(CommandError::Usage("/kick <nick> [reason]")),
+            "ban" => match nick_and_reason(rest) {
+                Some((mask, reason)) => mask.parse().map(|mask| Command::Ban { mask, reason }).map_err(CommandError::from),
+                None => Err(CommandError::Usage("/ban <nick|mask|address[/bits]> [reason]")),
+            },
+            "unban" if !rest.is_empty() && !rest.contains(' ') => {
+                rest.parse().map(|mask| Command::Unban { mask }).map_err(CommandError::from)
+            }
+            "unban" => Err(CommandError::Usage("/unban <nick|mask|address[/bits]>")),
+            "bans" if rest.is_empty() => Ok(Command::Bans),
+            "bans" => Err(CommandError::Usage("/bans")),
+            "wallops" if !rest.is_empty() => Ok(Command::Wallops { text: rest.to_string() }),
+            "wallops" => Err(CommandError::Usage("/wallops <text>")),
+            "maintenance" => match rest.split_once(' ').unwrap_or((rest, "")) {
+                ("on", message) => {
+                    let message = Some(message.trim().to_string()).filter(|m| !m.is_empty());
+                    Ok(Command::Maintenance { on: true, message })
+                }
+                ("off", "") => Ok(Command::Maintenance { on: false, message: None }),
+                _ => Err(CommandError::Usage("/maintenance on [message] | /maintenance off")),
+            },
+            "msg" => match rest.split_once(' ') {
+                Some((nick, text)) if !text.trim().is_empty() => {
+                    Ok(Command::Msg { nick: nick.to_string(), text: text.trim().to_string() })
+                }
+                _ => Err(CommandError::Usage("/msg <nick> <text>")),
+            },
+            "say" => match rest.split_once(' ') {
+                Some((channel::LOBBY, text)) if !text.trim().is_empty() => {
+                    Ok(Command::Say { channel: None, text: text.trim().to_string() })
+                }
+                Some((channel, text)) if !text.trim().is_empty() => channel::validate(channel)
+                    .map(|()| Command::Say { channel: Some(channel.to_string()), text: text.trim().to_string() })
+                    .map_err(CommandError::from),
+                _ => Err(CommandError::Usage("/say <#channel|&lobby> <text>")),
+            },
+            "join" => channel_arg(rest, "/join <#channel>").map(|channel| Command::Join { channel }),
+            "part" => channel_arg(rest, "/part <#channel>").map(|channel| Command::Part { channel }),
+            "nick" if !rest.is_empty() && !rest.contains(' ') => Ok(Command::Nick { nick: rest.to_string() }),
+            "nick" => Err(CommandError::Usage("/nick <newname>")),
+            "register" if !rest.is_empty() => Ok(Command::Register { password: rest.to_string() }),
+            "register" => Err(CommandError::Usage("/register <password>")),
+            "oper" if !rest.is_empty() => Ok(Command::Oper { password: rest.to_string() }),
+            "oper" => Err(CommandError::Usage("/oper <password>")),
+            "names" | "who" if rest.is_empty() => Ok(Command::Names { channel: None }),
+            "names" | "who" if rest == channel::LOBBY => Ok(Command::Names { channel: Some(rest.to_string()) }),
+            "names" | "who" => {
+                channel_arg(rest, "/names [#channel|&lobby]").map(|channel| Command::Names { channel: Some(channel) })
+            }
+            "list" if rest.is_empty() => Ok(Command::List),
+            "list" => Err(CommandError::Usage("/list")),
+            "pong" if !rest.is_empty() => Ok(Command::Pong { token: rest.to_string() }),
+            "pong" => Err(CommandError::Usage("/pong <token>")),
+            _ => Err(CommandError::Unknown(name.to_string())),
+        })
+    }
+}
+
+fn nick_and_reason(rest: &str) -> Option<(String, Option<String>)> {
+    let (nick, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    let reason = Some(reason.trim().to_string()).filter(|reason| !reason.is_empty());
+    (!nick.is_empty()).then(|| (nick.to_string(), reason))
+}
+
+fn channel_arg(rest: &str, usage: &'static str) -> Result<String, CommandError> {
+    if rest.is_empty() || rest.contains(' ') {
+        return Err(CommandError::Usage(usage));
+    }
+
+    channel::validate(rest)?;
+    Ok(rest.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(None, Command::parse("just chatting"));
+        assert_eq!(
+            Some(Ok(Command::Kill { nick: "bob".to_string(), reason: "being rude".to_string() })),
+            Command::parse("/KILL bob being rude")
+        );
+        assert_eq!(Some(Ok(Command::Wallops { text: "restarting soon".to_string() })), Command::parse("/wallops restarting soon"));
+        assert_eq!(
+            Some(Ok(Command::Maintenance { on: true, message: Some("back at 5".to_string()) })),
+            Command::parse("/maintenance on back at 5")
+        );
+        assert_eq!(Some(Ok(Command::Maintenance { on: true, message: None })), Command::parse("/maintenance on"));
+        assert_eq!(Some(Ok(Command::Maintenance { on: false, message: None })), Command::parse("/maintenance off"));
+        assert_eq!(Some(Ok(Command::Join { channel: "#rust".to_string() })), Command::parse("/join #rust"));
+        assert_eq!(Some(Ok(Command::Say { channel: None, text: "hi".to_string() })), Command::parse("/say &lobby hi"));
+        assert_eq!(
+            Some(Ok(Command::Say { channel: Some("#rust".to_string()), text: "/not a command".to_string() })),
+            Command::parse("/say #rust /not a command")
+        );
+        assert_eq!(Some(Ok(Command::Msg { nick: "bob".to_string(), text: "psst  hi".to_string() })), Command::parse("/msg bob psst  hi"));
+        assert_eq!(Some(Ok(Command::Part { channel: "#rust".to_string() })), Command::parse("/PART  #rust "));
+        assert_eq!(Some(Ok(Command::Nick { nick: "bob2".to_string() })), Command::parse("/nick bob2"));
+        let register = Command::Register { password: "correct horse".to_string() };
+        assert_eq!(Some(Ok(register)), Command::parse("/register correct horse"));
+        assert_eq!(Some(Ok(Command::Oper { password: "hunter2".to_string() })), Command::parse("/oper hunter2"));
+        assert_eq!(Some(Ok(Command::Kick { nick: "bob".to_string(), reason: None })), Command::parse("/kick bob"));
+        let ban = Command::Ban { mask: Mask::Nick("bob".to_string()), reason: Some("spamming".to_string()) };
+        assert_eq!(Some(Ok(ban)), Command::parse("/BAN Bob  spamming "));
+        let unban = Command::Unban { mask: Mask::Address([10, 0, 0, 0].into(), 8) };
+        assert_eq!(Some(Ok(unban)), Command::parse("/unban 10.0.0.0/8"));
+        assert_eq!(Some(Ok(Command::Bans)), Command::parse("/bans"));
+        assert_eq!(Some(Ok(Command::Names { channel: None })), Command::parse("/who"));
+        assert_eq!(Some(Ok(Command::Names { channel: Some("#rust".to_string()) })), Command::parse("/names #rust"));
+        assert_eq!(Some(Ok(Command::Names { channel: Some("&lobby".to_string()) })), Command::parse("/NAMES &lobby"));
+        assert_eq!(Some(Ok(Command::List)), Command::parse("/list"));
+        assert_eq!(Some(Err(CommandError::Usage("/list"))), Command::parse("/list #rust"));
+        assert_eq!(Some(Ok(Command::Pong { token: "42".to_string() })), Command::parse("/pong 42"));
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert_eq!(Some(Err(CommandError::Usage("/kill <nick> <reason>"))), Command::parse("/kill bob"));
+        assert_eq!(Some(Err(CommandError::Usage("/wallops <text>"))), Command::parse("/wallops  "));
+        assert_eq!(Some(Err(CommandError::Usage("/kick <nick> [reason]"))), Command::parse("/kick"));
+        assert_eq!(Some(Err(CommandError::Usage("/ban <nick|mask|address[/bits]> [reason]"))), Command::parse("/ban "));
+        assert!(matches!(Command::parse("/ban bob! spam"), Some(Err(CommandError::Mask(_)))));
+        assert_eq!(Some(Err(CommandError::Usage("/unban <nick|mask|address[/bits]>"))), Command::parse("/unban a b"));
+        assert_eq!(Some(Err(CommandError::Unknown("nope".to_string()))), Command::parse("/nope"));
+        assert!(matches!(Command::parse("/maintenance maybe"), Some(Err(CommandError::Usage(_)))));
+        assert_eq!(Some(Err(CommandError::Usage("/join <#channel>"))), Command::parse("/join #a #b"));
+        assert_eq!(Some(Err(CommandError::Usage("/msg <nick> <text>"))), Command::parse("/msg bob"));
+        assert!(matches!(Command::parse("/part rust"), Some(Err(CommandError::Channel(_)))));
+        assert_eq!(Some(Err(CommandError::Usage("/nick <newname>"))), Command::parse("/nick two words"));
+        assert_eq!(Some(Err(CommandError::Usage("/register <password>"))), Command::parse("/register"));
+        assert_eq!(Some(Err(CommandError::Usage("/oper <password>"))), Command::parse("/oper"));
+        assert_eq!(Some(Err(CommandError::Usage("/names [#channel|&lobby]"))), Command::parse("/who #a #b"));
+    }
+}
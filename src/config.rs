@@ -0,0 +1,415 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use thiserror::Error;
+use toml::{Table, Value};
+
+/// What environment variables for settings start with, e.g. `BASIC_IRC_MAX_LINE_LEN` for `max_line_len`.
+pub const ENV_PREFIX: &str = "BASIC_IRC_";
+
+/// Options that are about the config rather than settings in it, so they can't go in one.
+const NOT_SETTINGS: [&str; 4] = ["config", "init_config", "show_config", "check_config"];
+/// Settings that are shown hidden by `effective`.
+const SECRETS: [&str; 3] = ["password", "account_password", "admin_token"];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Couldn't read config {0}: `{1}`")]
+    Read(String, std::io::Error),
+    #[error("Config isn't valid TOML: `{0}`")]
+    Parse(#[from] toml::de::Error),
+    #[error("No such setting: `{0}`")]
+    Unknown(String),
+    #[error("Setting `{0}` has to be {1}")]
+    BadValue(String, &'static str),
+    #[error("Environment variable `{0}` isn't a setting, or has the wrong kind of value for it")]
+    Env(String),
+    #[error("Setting `{0}` needs environment variable `{1}`, which isn't set")]
+    MissingVar(String, String),
+}
+
+/// Where `--config` says the config is, if it's given. Found by hand since the rest of the command line
+/// might not parse until the config's filled it in, e.g. when the config's what sets `mode`.
+pub fn path(args: &[OsString]) -> Option<PathBuf> {
+    value_of(args, "--config")
+}
+
+/// Where `--init-config` says to write a config, if it's given. Found by hand too, since it doesn't need a
+/// `mode` or anything else.
+pub fn init_path(args: &[OsString]) -> Option<PathBuf> {
+    value_of(args, "--init-config")
+}
+
+fn value_of(args: &[OsString], flag: &str) -> Option<PathBuf> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().map(|path| PathBuf::from(path.as_ref()));
+        }
+        if let Some(path) = arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Reads the config at `path` and turns it into flags for `command`, to go _before_ the ones given on the
+/// command line so those win. Settings are named like the flags, e.g. `max_line_len = 4096` is
+/// `--max-line-len 4096`, so anything that can be a flag can be in the config. `${NAME}` in a value is
+/// environment variable `NAME`, so secrets don't have to be written in it.
+pub fn load(path: &Path, command: &Command) -> Result<Vec<OsString>, ConfigError> {
+    let config = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.display().to_string(), e))?;
+    let mut config = config.parse()?;
+    expand(&mut config, |name| std::env::var(name).ok())?;
+    to_flags(&config, command)
+}
+
+/// Replaces every `${NAME}` in the strings in `config` with what `var` says `NAME` is.
+fn expand(config: &mut Table, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+    for (key, value) in config.iter_mut() {
+        let values = match value {
+            Value::Array(values) => values.iter_mut().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            if let Value::String(s) = value {
+                *s = expand_str(s, &var).map_err(|name| ConfigError::MissingVar(key.clone(), name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `s` with every `${NAME}` in it replaced, or the first `NAME` that `var` doesn't know. A `${` that's never
+/// closed is left as it is.
+fn expand_str(s: &str, var: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some((before, after)) = rest.split_once("${") {
+        let Some((name, after)) = after.split_once('}') else {
+            break;
+        };
+        expanded.push_str(before);
+        expanded.push_str(&var(name).ok_or_else(|| name.to_string())?);
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Turns `vars` named `ENV_PREFIX` and then a setting into flags for `command`, to go between the config's
+/// and the command line's. Lists are comma-separated, and switches are `true` or `false`.
+pub fn from_env(vars: impl Iterator<Item = (OsString, OsString)>, command: &Command) -> Result<Vec<OsString>, ConfigError> {
+    let mut settings = Table::new();
+    for (name, value) in vars {
+        let Some(key) = name.to_str().and_then(|name| name.strip_prefix(ENV_PREFIX)) else {
+            continue;
+        };
+        let key = key.to_ascii_lowercase();
+        let value = value.to_string_lossy().into_owned();
+        let value = match setting(command, &key) {
+            Some(arg) if !arg.get_action().takes_values() => match value.as_str() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                _ => Value::String(value),
+            },
+            Some(arg) if matches!(arg.get_action(), ArgAction::Append) => {
+                Value::Array(value.split(',').map(|value| Value::String(value.trim().to_string())).collect())
+            }
+            _ => Value::String(value),
+        };
+        settings.insert(key, value);
+    }
+    to_flags(&settings, command).map_err(|e| match e {
+        ConfigError::Unknown(key) | ConfigError::BadValue(key, _) => {
+            ConfigError::Env(format!("{ENV_PREFIX}{}", key.to_uppercase()))
+        }
+        e => e,
+    })
+}
+
+/// The argument for setting `key`, if there is one. Settings can go by any of the option's long names, e.g.
+/// `tls_key_file` for `--tls-key`.
+fn setting<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    let key = key.replace('-', "_");
+    command.get_arguments().find(|arg| {
+        let aliases = arg.get_all_aliases().unwrap_or_default();
+        let named = arg.get_id() == key.as_str() || aliases.iter().any(|alias| alias.replace('-', "_") == key);
+        named && arg.get_long().is_some() && !NOT_SETTINGS.contains(&arg.get_id().as_str())
+    })
+}
+
+fn to_flags(config: &Table, command: &Command) -> Result<Vec<OsString>, ConfigError> {
+    let mut flags = Vec::new();
+
+    for (key, value) in config {
+        let arg = setting(command, key).ok_or_else(|| ConfigError::Unknown(key.clone()))?;
+        let flag = format!("--{}", arg.get_long().expect("Checked above"));
+
+        if !arg.get_action().takes_values() {
+            match value {
+                Value::Boolean(true) => flags.push(flag.into()),
+                Value::Boolean(false) => {}
+                _ => return Err(ConfigError::BadValue(key.clone(), "true or false")),
+            }
+            continue;
+        }
+
+        // Lists are for flags that can be given more than once, e.g. `peer = ["10.0.0.2:7000", ...]`
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                Value::Integer(n) => n.to_string(),
+                Value::Float(n) => n.to_string(),
+                Value::Boolean(b) => b.to_string(),
+                _ => return Err(ConfigError::BadValue(key.clone(), "a string, number, true/false or a list of them")),
+            };
+            flags.push(flag.clone().into());
+            flags.push(value.into());
+        }
+    }
+
+    Ok(flags)
+}
+
+/// A config with every setting in it commented out at its default, under what it's for, for `--init-config`.
+pub fn commented_defaults(command: &Command) -> String {
+    let mut config = format!(
+        "# Settings for {}, named like its options: max_line_len here is --max-line-len there. Uncomment one to\n\
+        # change it. {ENV_PREFIX}MAX_LINE_LEN and so on win over what's here, and the command line wins over both.\n",
+        command.get_name(),
+    );
+    for arg in settings(command) {
+        let mut about = arg.get_help().map(ToString::to_string).unwrap_or_default();
+        let choices = arg.get_possible_values().iter().map(|value| value.get_name().to_string()).collect::<Vec<_>>();
+        if !choices.is_empty() && arg.get_action().takes_values() {
+            let _ = write!(about, " One of {}.", choices.join(", "));
+        }
+
+        config.push('\n');
+        for line in wrap(&about, 100) {
+            let _ = writeln!(config, "# {line}");
+        }
+        let default = match arg.get_default_values() {
+            _ if !arg.get_action().takes_values() => "false".to_string(),
+            _ if matches!(arg.get_action(), ArgAction::Append) => "[]".to_string(),
+            [default] => to_value(arg, &default.to_string_lossy()).to_string(),
+            _ => String::new(),
+        };
+        let _ = writeln!(config, "{}", format!("# {} = {default}", arg.get_id()).trim_end());
+    }
+    config
+}
+
+/// What `matches` ended up with for each setting, as a config, noting which of `layers` (a name, and the
+/// flags that came from there) gave it last, or that it's the default. Passwords are hidden, and settings
+/// with no value and no default are left out.
+pub fn effective(command: &Command, matches: &ArgMatches, layers: &[(&str, &[OsString])]) -> String {
+    let layers = layers.iter().map(|(name, flags)| (*name, given(command, flags))).collect::<Vec<_>>();
+    let mut config = String::new();
+    for arg in settings(command) {
+        let id = arg.get_id().as_str();
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        let mut values = raw.map(|value| to_value(arg, &value.to_string_lossy())).collect::<Vec<_>>();
+        let value = match arg.get_action() {
+            _ if SECRETS.contains(&id) => Value::String("<hidden>".to_string()),
+            ArgAction::Append => Value::Array(values),
+            _ => match values.pop() {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        // Lists add up, so they can come from more than one place
+        let from = layers.iter().filter(|(_, ids)| ids.contains(id)).map(|(name, _)| *name).collect::<Vec<_>>();
+        let from = match (from.last(), arg.get_action()) {
+            (None, _) => "default".to_string(),
+            (Some(_), ArgAction::Append) => from.join(" + "),
+            (Some(last), _) => last.to_string(),
+        };
+        let _ = writeln!(config, "{id} = {value}  # {from}");
+    }
+    config
+}
+
+/// Every option `command` has that can be a setting, in the order they're declared.
+fn settings(command: &Command) -> impl Iterator<Item = &Arg> {
+    command.get_arguments().filter(|arg| setting(command, arg.get_id().as_str()).is_some())
+}
+
+/// The ids of the options given in `flags`, long or short.
+fn given(command: &Command, flags: &[OsString]) -> BTreeSet<String> {
+    let find = |flag: &str| match flag.strip_prefix("--") {
+        Some(long) => {
+            let long = long.split('=').next().unwrap_or_default();
+            command.get_arguments().find(|arg| arg.get_long() == Some(long))
+        }
+        None => {
+            let short = flag.strip_prefix('-').and_then(|short| short.chars().next());
+            command.get_arguments().find(|arg| short.is_some() && arg.get_short() == short)
+        }
+    };
+    flags.iter().filter_map(|flag| find(&flag.to_string_lossy())).map(|arg| arg.get_id().to_string()).collect()
+}
+
+/// How `raw` looks in a config for `arg`.
+fn to_value(arg: &Arg, raw: &str) -> Value {
+    match raw.parse() {
+        Ok(b) if !arg.get_action().takes_values() => Value::Boolean(b),
+        _ => raw.parse().map_or_else(|_| Value::String(raw.to_string()), Value::Integer),
+    }
+}
+
+/// `text` split into lines of at most `width` characters, unless a word's longer than that.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::<String>::new();
+    for word in text.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + word.len() <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+    use crate::args::{Args, Mode};
+    use super::*;
+
+    fn parse(config: &str, cli: &[&str]) -> Result<Args, ConfigError> {
+        let flags = to_flags(&config.parse()?, &Args::command())?;
+        let args = std::iter::once(OsString::from("rust-threading")).chain(flags).chain(cli.iter().map(OsString::from));
+        Ok(Args::try_parse_from(args).unwrap())
+    }
+
+    #[test]
+    fn finds_the_path() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(Some(PathBuf::from("a.toml")), path(&args(&["x", "--mode", "server", "--config", "a.toml"])));
+        assert_eq!(Some(PathBuf::from("b.toml")), path(&args(&["x", "--config=b.toml"])));
+        assert_eq!(None, path(&args(&["x", "--mode", "server"])));
+    }
+
+    #[test]
+    fn settings_become_flags() {
+        let config = r#"
+            mode = "server"
+            port = 7000
+            server-name = "test server"
+            mdns = true
+            upnp = false
+            oper = ["alice", "bob"]
+        "#;
+
+        let args = parse(config, &[]).unwrap();
+        assert!(matches!(args.mode, Mode::Server));
+        assert_eq!(7000, args.port);
+        assert_eq!("test server", args.server_name);
+        assert!(args.mdns);
+        assert!(!args.upnp);
+        assert_eq!(vec!["alice", "bob"], args.oper);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let config = "mode = \"server\"\nport = 7000\nmax_line_len = 10\noper = [\"alice\"]";
+        let args = parse(config, &["--port", "8000", "--mode", "client", "--oper", "bob"]).unwrap();
+        assert_eq!(8000, args.port);
+        assert!(matches!(args.mode, Mode::Client));
+        assert_eq!(10, args.max_line_len);
+        // Lists add up instead
+        assert_eq!(vec!["alice", "bob"], args.oper);
+    }
+
+    #[test]
+    fn bad_settings() {
+        assert!(matches!(parse("nope = 1", &[]), Err(ConfigError::Unknown(key)) if key == "nope"));
+        assert!(matches!(parse("config = \"other.toml\"", &[]), Err(ConfigError::Unknown(_))));
+        assert!(matches!(parse("mdns = \"yes\"", &[]), Err(ConfigError::BadValue(..))));
+        assert!(matches!(parse("[port]\nx = 1", &[]), Err(ConfigError::BadValue(..))));
+        assert!(matches!(parse("port = ", &[]), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn expands_environment_variables() {
+        let var = |name: &str| (name == "SECRET").then(|| "hunter2".to_string());
+        let mut config = "password = \"${SECRET}\"\noper = [\"${SECRET}-${SECRET}\", \"${\"]\nport = 7000".parse().unwrap();
+        expand(&mut config, var).unwrap();
+        let args = parse(&config.to_string(), &["--mode", "server"]).unwrap();
+        assert_eq!(Some("hunter2".to_string()), args.password);
+        assert_eq!(vec!["hunter2-hunter2", "${"], args.oper);
+
+        let mut config = "motd = \"${NOPE}\"".parse().unwrap();
+        let missing = expand(&mut config, var);
+        assert!(matches!(missing, Err(ConfigError::MissingVar(key, name)) if key == "motd" && name == "NOPE"));
+    }
+
+    #[test]
+    fn settings_go_by_aliases_too() {
+        let args = parse("tls_key_file = \"key.pem\"\nbind = \"0.0.0.0\"", &["--mode", "server"]).unwrap();
+        assert_eq!(Some(PathBuf::from("key.pem")), args.tls_key);
+        assert_eq!("0.0.0.0", args.host);
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Result<Vec<OsString>, ConfigError> {
+        from_env(vars.iter().map(|(name, value)| (name.into(), value.into())), &Args::command())
+    }
+
+    #[test]
+    fn environment_settings() {
+        let vars = [("BASIC_IRC_PORT", "7100"), ("BASIC_IRC_OPER", "bob, carol"), ("BASIC_IRC_MDNS", "true"), ("HOME", "/")];
+        let flags = [OsString::from("rust-threading")].into_iter().chain(env(&vars).unwrap());
+        let args = Args::parse_from(flags.chain(["-m", "server"].map(OsString::from)));
+        assert_eq!(7100, args.port);
+        assert_eq!(vec!["bob", "carol"], args.oper);
+        assert!(args.mdns);
+
+        assert!(matches!(env(&[("BASIC_IRC_NOPE", "1")]), Err(ConfigError::Env(name)) if name == "BASIC_IRC_NOPE"));
+        assert!(matches!(env(&[("BASIC_IRC_MDNS", "yes")]), Err(ConfigError::Env(_))));
+        assert!(matches!(env(&[("BASIC_IRC_CONFIG", "other.toml")]), Err(ConfigError::Env(_))));
+    }
+
+    #[test]
+    fn commented_defaults_are_the_defaults() {
+        let config = commented_defaults(&Args::command());
+        assert!(config.parse::<Table>().unwrap().is_empty());
+        assert!(config.contains("\n# Server only. How the server runs."));
+        assert!(config.contains("\n# max_line_len = 65536\n"));
+
+        // Everything with a default, uncommented, changes nothing
+        let uncommented = config
+            .lines()
+            .filter_map(|line| line.strip_prefix("# ").filter(|line| line.contains(" = ")))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let args = parse(&uncommented, &["--mode", "server"]).unwrap();
+        assert_eq!(format!("{:?}", Args::parse_from(["rust-threading", "--mode", "server"])), format!("{args:?}"));
+    }
+
+    #[test]
+    fn shows_where_settings_came_from() {
+        let config = "port = 7000\noper = [\"alice\"]\npassword = \"hunter2\"".parse().unwrap();
+        let from_config = to_flags(&config, &Args::command()).unwrap();
+        let cli = ["-m", "server", "--oper=bob"].map(OsString::from);
+        let flags = [&[OsString::from("rust-threading")], &from_config[..], &cli].concat();
+        let matches = Args::command().get_matches_from(flags);
+
+        let shown = effective(&Args::command(), &matches, &[("config", &from_config), ("command line", &cli)]);
+        assert!(shown.starts_with("mode = \"server\"  # command line\nport = 7000  # config\nhost = \"127.0.0.1\"  # default\n"));
+        assert!(shown.contains("\noper = [\"alice\", \"bob\"]  # config + command line\n"), "{shown}");
+        assert!(shown.contains("\npassword = \"<hidden>\"  # config\n") && !shown.contains("hunter2"));
+        assert!(!shown.contains("tls_cert"));
+    }
+}
@@ -0,0 +1,266 @@
+//! The admin HTTP API, for operators to manage the server from scripts and dashboards without joining as a
+//! user. It's off unless there's an `--admin-port`, and every request needs `Authorization: Bearer <token>`
+//! with the `--admin-token`, which is best set in the config from the environment. Everything answers JSON.
+//!
+//! - `GET /users` lists who's connected, with where they're talking and where they're from.
+//! - `DELETE /users/<nick>` kicks them, with the body as the reason if there is one.
+//! - `POST /notice` sends the body to everyone as a notice from the server.
+//! - `GET /stats` has the server's counts, like `/metrics` but as one JSON object.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use serde::Serialize;
+use tracing::{info, warn};
+use crate::diagnostics::Diagnostics;
+use crate::frame::encode_message;
+use crate::http::{self, Deadline, Request, Status};
+use crate::metrics::Metrics;
+use crate::outbound::Outbound;
+use crate::registry::Registry;
+use crate::response::{same_secret, ServerMessage};
+use crate::server;
+use crate::template::ServerInfo;
+use crate::user::User;
+
+/// How long an admin gets to send their whole request before they're hung up on, however slowly it trickles in.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Someone in `GET /users`.
+#[derive(Serialize, Debug)]
+struct Connected {
+    nick: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    /// Where they're talking, or `None` for the lobby.
+    channel: Option<String>,
+    /// Where they're connected from, if the server knows.
+    peer: Option<IpAddr>,
+    /// Frames waiting to go out to them.
+    queued: usize,
+}
+
+/// `GET /stats`.
+#[derive(Serialize, Debug)]
+struct Stats {
+    server: String,
+    uptime_secs: u64,
+    users: usize,
+    channels: BTreeMap<String, usize>,
+    messages_broadcast: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    auth_failures: u64,
+    dropped_frames: u64,
+    flood_drops: u64,
+    ping_timeouts: u64,
+    /// Where the server's reachable from outside, as a STUN server saw it, if it asked one.
+    public_address: Option<SocketAddr>,
+}
+
+/// What comes back when something didn't work.
+#[derive(Serialize, Debug)]
+struct Problem {
+    error: String,
+}
+
+/// Everything the API looks at and acts on.
+type State = (Arc<Registry<Outbound>>, Arc<Metrics>, Arc<ServerInfo>);
+
+/// Starts taking admin requests on `address` that come with `token`, for as long as the server's up. Returns
+/// where it's listening.
+pub(crate) fn listen(address: SocketAddr, token: String, state: State) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    info!("[ADMIN] Taking admin requests on http://{address}");
+
+    thread::Builder::new().name("control".to_string()).spawn(move || {
+        for admin in listener.incoming() {
+            let served = admin.and_then(|admin| serve(admin, &token, &state));
+            if let Err(e) = served {
+                warn!("[ADMIN] Failed serving an admin request: {e:?}");
+            }
+        }
+    })?;
+    Ok(address)
+}
+
+fn serve(admin: TcpStream, token: &str, state: &State) -> std::io::Result<()> {
+    let (status, body) = match http::read_request(Deadline::new(&admin, REQUEST_DEADLINE)) {
+        Ok(request) if authorized(&request, token) => route(&request, state),
+        Ok(_) => problem(Status::Unauthorized, "Needs `Authorization: Bearer <token>` with the admin token"),
+        Err(e) => problem(Status::BadRequest, &e.to_string()),
+    };
+    http::respond(&admin, status, "application/json", &body)
+}
+
+/// Whether `request` has the token, compared the same way as the server's password.
+fn authorized(request: &Request, token: &str) -> bool {
+    let given = request.header("authorization").and_then(|auth| auth.strip_prefix("Bearer ")).unwrap_or_default();
+    same_secret(given, token)
+}
+
+fn route(request: &Request, (users, metrics, info): &State) -> (Status, String) {
+    if let Some(nick) = request.path.strip_prefix("/users/") {
+        return match (request.method.as_str(), http::percent_decode(nick)) {
+            ("DELETE", Some(nick)) => kick(users, &nick, request.body.trim()),
+            ("DELETE", None) => problem(Status::BadRequest, "That nick isn't percent-encoded UTF-8"),
+            _ => problem(Status::MethodNotAllowed, "Users can only be kicked, with DELETE"),
+        };
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/users") => (Status::Ok, json(&connected(users))),
+        ("GET", "/stats") => (Status::Ok, json(&stats(users, metrics, info))),
+        ("POST", "/notice") => notice(users, request.body.trim()),
+        (_, "/users" | "/stats" | "/notice") => problem(Status::MethodNotAllowed, "Not with that method"),
+        _ => problem(Status::NotFound, "No such endpoint, try /users, /stats or /notice"),
+    }
+}
+
+fn connected(users: &Registry<Outbound>) -> Vec<Connected> {
+    let mut connected = Vec::new();
+    users.for_each(|user, outbound| {
+        connected.push(Connected {
+            nick: user.name.clone(),
+            display_name: user.display_name.clone(),
+            channel: None,
+            peer: outbound.diagnostics().map(Diagnostics::peer),
+            queued: outbound.waiting().0,
+        });
+    });
+    // Filled in after, since channels can't be locked while the registry is
+    let channels = users.channels();
+    for user in &mut connected {
+        user.channel = channels.current(&User::new(user.nick.as_str())).map(str::to_string);
+    }
+    connected.sort_by(|a, b| a.nick.cmp(&b.nick));
+    connected
+}
+
+fn stats(users: &Registry<Outbound>, metrics: &Metrics, info: &ServerInfo) -> Stats {
+    let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let online = users.len();
+    Stats {
+        server: info.name.clone(),
+        uptime_secs: info.started.elapsed().as_secs(),
+        users: online,
+        channels: users.channels().sizes().map(|(channel, members)| (channel.to_string(), members)).collect(),
+        messages_broadcast: count(&metrics.messages_broadcast),
+        bytes_in: count(&metrics.bytes_in),
+        bytes_out: count(&metrics.bytes_out),
+        auth_failures: metrics.handshakes.failed(),
+        dropped_frames: count(&metrics.dropped_frames),
+        flood_drops: count(&metrics.flood_drops),
+        ping_timeouts: count(&metrics.ping_timeouts),
+        public_address: *metrics.public_address.lock(),
+    }
+}
+
+/// `POST /notice`, sending `text` to everyone here.
+fn notice(users: &Registry<Outbound>, text: &str) -> (Status, String) {
+    if text.is_empty() {
+        return problem(Status::BadRequest, "The notice goes in the body");
+    }
+    let frame = match encode_message(&ServerMessage::notice(text)) {
+        Ok(frame) => frame.into(),
+        Err(e) => return problem(Status::BadRequest, &e.to_string()),
+    };
+
+    let online = users.len();
+    users.send_to_all(&frame, None);
+    info!("[ADMIN] Sent a notice to {online} user(s): {text:?}");
+    (Status::Ok, json(&BTreeMap::from([("sent_to", online)])))
+}
+
+/// `DELETE /users/<nick>`, kicking them with `reason` if it isn't empty.
+fn kick(users: &Registry<Outbound>, nick: &str, reason: &str) -> (Status, String) {
+    let target = User::new(nick);
+    let reason = (!reason.is_empty()).then_some(reason);
+    if !server::kick(users, &target, "kicked by an admin", reason) {
+        return problem(Status::NotFound, &format!("Nobody's connected here as {nick}"));
+    }
+    info!("[ADMIN] Kicked {target}: {reason:?}");
+    (Status::Ok, json(&BTreeMap::from([("kicked", nick)])))
+}
+
+fn problem(status: Status, error: &str) -> (Status, String) {
+    (status, json(&Problem { error: error.to_string() }))
+}
+
+fn json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).expect("Answers always serialize") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use crate::outbound::Queue;
+    use super::*;
+
+    const TOKEN: &str = "s3cret";
+
+    fn request(address: SocketAddr, head: &str, body: &str) -> (String, serde_json::Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{head}\r\nContent-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn needs_the_token() {
+        let address = listen("127.0.0.1:0".parse().unwrap(), TOKEN.to_string(), Default::default()).unwrap();
+        for auth in ["", "\r\nAuthorization: Bearer s3cre", "\r\nAuthorization: Bearer s3cret!", "\r\nAuthorization: s3cret"] {
+            let (status, body) = request(address, &format!("GET /stats HTTP/1.1{auth}"), "");
+            assert_eq!("HTTP/1.1 401 Unauthorized", status, "{auth:?}");
+            assert!(body["error"].as_str().unwrap().contains("Bearer"));
+        }
+    }
+
+    #[test]
+    fn lists_kicks_and_notices() {
+        let users: Arc<Registry<Outbound>> = Default::default();
+        let mut queues: Vec<Queue> = Vec::new();
+        for nick in ["bob", "alice"] {
+            let (outbound, queue) = Outbound::new(8);
+            users.claim_nick(&User::new(nick), outbound).unwrap();
+            queues.push(queue);
+        }
+        users.channels().join(&User::new("alice"), "#rust");
+        let metrics: Arc<Metrics> = Default::default();
+        *metrics.public_address.lock() = Some("203.0.113.7:6667".parse().unwrap());
+        let state = (users.clone(), metrics, Arc::new(ServerInfo { name: "test".to_string(), ..Default::default() }));
+        let address = listen("127.0.0.1:0".parse().unwrap(), TOKEN.to_string(), state).unwrap();
+        let authed = |head: &str| format!("{head} HTTP/1.1\r\nAuthorization: Bearer {TOKEN}");
+
+        let (status, listed) = request(address, &authed("GET /users"), "");
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert_eq!(
+            serde_json::json!([
+                { "nick": "alice", "channel": "#rust", "peer": null, "queued": 0 },
+                { "nick": "bob", "channel": null, "peer": null, "queued": 0 },
+            ]),
+            listed
+        );
+
+        let (_, stats) = request(address, &authed("GET /stats"), "");
+        assert_eq!((2, 1), (stats["users"].as_u64().unwrap(), stats["channels"]["#rust"].as_u64().unwrap()));
+        assert_eq!(("test", "203.0.113.7:6667"), (stats["server"].as_str().unwrap(), stats["public_address"].as_str().unwrap()));
+
+        let (status, sent) = request(address, &authed("POST /notice"), "Restarting in 5 minutes\n");
+        assert_eq!(("HTTP/1.1 200 OK", 2), (status.as_str(), sent["sent_to"].as_u64().unwrap()));
+        assert!(queues.iter().all(|queue| queue.try_recv().is_ok()));
+        assert_eq!("HTTP/1.1 400 Bad Request", request(address, &authed("POST /notice"), "").0);
+
+        let (status, kicked) = request(address, &authed("DELETE /users/b%6Fb"), "spamming");
+        assert_eq!(("HTTP/1.1 200 OK", "bob"), (status.as_str(), kicked["kicked"].as_str().unwrap()));
+        assert_eq!("HTTP/1.1 400 Bad Request", request(address, &authed("DELETE /users/%FF"), "").0);
+        assert_eq!("HTTP/1.1 404 Not Found", request(address, &authed("DELETE /users/carol"), "").0);
+        assert_eq!("HTTP/1.1 405 Method Not Allowed", request(address, &authed("POST /users"), "").0);
+        assert_eq!("HTTP/1.1 404 Not Found", request(address, &authed("GET /reboot"), "").0);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use parking_lot::Mutex;
+use crate::client::Console;
+
+/// Output kept for whoever attaches next, past which the oldest gets forgotten.
+const MAX_BACKLOG: usize = 64 * 1024;
+/// What a terminal says first to attach. Anything else connecting, like another daemon checking whether
+/// this one's still around, leaves the attached terminal alone.
+const HELLO: &[u8] = b"attach\n";
+
+/// The terminal attached to a daemon, if any, and what it missed while there wasn't one.
+#[derive(Debug, Default)]
+struct Attached {
+    ui: Option<UnixStream>,
+    /// Bumped with each attach, so a terminal that's been taken over doesn't detach its replacement.
+    generation: u64,
+    backlog: VecDeque<u8>,
+}
+
+/// A `Console` for a client running as a daemon, which terminals attach to over a Unix socket with
+/// `attach`. Closing the terminal just detaches it, leaving the client connected, until someone types
+/// `/quit`. Attaching from a second terminal takes over from the first, like `tmux attach -d`.
+#[derive(Debug)]
+pub struct Daemon {
+    socket: PathBuf,
+    attached: Arc<Mutex<Attached>>,
+    lines: Mutex<Receiver<String>>,
+}
+
+impl Daemon {
+    /// Starts taking terminals on `socket`, cleaning up after a daemon that's gone if there was one.
+    pub fn listen(socket: &Path) -> std::io::Result<Self> {
+        if socket.exists() {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(std::io::Error::new(ErrorKind::AddrInUse, "There's already a daemon on that socket"));
+            }
+            std::fs::remove_file(socket)?;
+        }
+
+        let listener = UnixListener::bind(socket)?;
+        let attached = Arc::new(Mutex::new(Attached::default()));
+        let (sender, lines) = channel();
+
+        let accepting = attached.clone();
+        thread::spawn(move || {
+            for ui in listener.incoming() {
+                match ui {
+                    Ok(ui) => {
+                        let (attached, sender) = (accepting.clone(), sender.clone());
+                        thread::spawn(move || attach(ui, &attached, sender));
+                    }
+                    Err(e) => eprintln!("[DAEMON] Failed to accept a terminal: {e:?}"),
+                }
+            }
+        });
+
+        Ok(Self { socket: socket.to_path_buf(), attached, lines: Mutex::new(lines) })
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.socket);
+    }
+}
+
+/// Hands the console over to `ui` once it says hello, catching it up on what it missed, and passes on
+/// what it types.
+fn attach(ui: UnixStream, attached: &Arc<Mutex<Attached>>, lines: Sender<String>) {
+    let mut hello = [0; HELLO.len()];
+    if (&ui).read_exact(&mut hello).is_err() || hello != HELLO {
+        return;
+    }
+
+    let (reader, generation) = {
+        let mut attached = attached.lock();
+        if let Some(old) = attached.ui.take() {
+            let _ = (&old).write_all(b"\r\n* Attached from somewhere else\r\n");
+            let _ = old.shutdown(Shutdown::Both);
+        }
+
+        // Ending on the prompt unless what it missed already does
+        let mut backlog = attached.backlog.drain(..).collect::<Vec<_>>();
+        if !backlog.ends_with(b"> ") {
+            backlog.extend(b"> ");
+        }
+        if (&ui).write_all(&backlog).is_err() {
+            return;
+        }
+        let Ok(reader) = ui.try_clone() else {
+            return;
+        };
+        attached.generation += 1;
+        attached.ui = Some(ui);
+        (reader, attached.generation)
+    };
+
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(n) if n > 0 => {
+                if lines.send(line).is_err() {
+                    return;
+                }
+            }
+            // Gone, so stop showing it things unless someone's already taken over
+            _ => {
+                let mut attached = attached.lock();
+                if attached.generation == generation {
+                    attached.ui = None;
+                }
+                return;
+            }
+        }
+    }
+}
+
+impl Console for Daemon {
+    /// Waits for a line from whichever terminal is attached, however long it takes for one to attach.
+    fn read_line(&self) -> std::io::Result<String> {
+        let line = self.lines.lock().recv().unwrap_or_default();
+        match line.trim() {
+            "/quit" => Ok(String::new()),
+            _ => Ok(line),
+        }
+    }
+
+    fn write(&self, text: &str) {
+        let mut attached = self.attached.lock();
+        if let Some(ui) = &attached.ui {
+            if (&*ui).write_all(text.as_bytes()).is_ok() {
+                return;
+            }
+            attached.ui = None;
+        }
+
+        attached.backlog.extend(text.as_bytes());
+        let over = attached.backlog.len().saturating_sub(MAX_BACKLOG);
+        attached.backlog.drain(..over);
+    }
+}
+
+/// Attaches this terminal to the daemon on `socket` until it's closed with Ctrl-D, or the daemon quits.
+pub fn attach_terminal(socket: &Path) -> std::io::Result<()> {
+    let daemon = UnixStream::connect(socket)?;
+    (&daemon).write_all(HELLO)?;
+    let mut from_daemon = daemon.try_clone()?;
+    thread::spawn(move || {
+        let _ = std::io::copy(&mut from_daemon, &mut std::io::stdout());
+        // The daemon's gone or someone else attached, neither of which leaves anything to type to
+        std::process::exit(0);
+    });
+
+    let mut stdin = std::io::stdin().lock();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if stdin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        (&daemon).write_all(&line)?;
+    }
+    println!("\nDetached, the client's still connected");
+    daemon.shutdown(Shutdown::Both)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn attach(socket: &Path) -> UnixStream {
+        let mut ui = UnixStream::connect(socket).unwrap();
+        ui.write_all(HELLO).unwrap();
+        ui
+    }
+
+    fn read_until(ui: &mut UnixStream, end: &str) -> String {
+        let mut read = Vec::new();
+        let mut buf = [0; 256];
+        while !String::from_utf8_lossy(&read).ends_with(end) {
+            let n = ui.read(&mut buf).unwrap();
+            assert_ne!(0, n, "Got {:?} without {end:?}", String::from_utf8_lossy(&read));
+            read.extend(&buf[..n]);
+        }
+        String::from_utf8(read).unwrap()
+    }
+
+    #[test]
+    fn attach_detach_and_take_over() {
+        let socket = std::env::temp_dir().join(format!("basic-irc-daemon-{}.sock", std::process::id()));
+        let daemon = Daemon::listen(&socket).unwrap();
+        assert!(Daemon::listen(&socket).is_err());
+
+        // Missed while nobody's attached, then caught up on when someone is
+        daemon.println("missed");
+        let mut first = attach(&socket);
+        assert_eq!("missed\n> ", read_until(&mut first, "> "));
+        first.write_all(b"hello\n").unwrap();
+        assert_eq!("hello\n", daemon.read_line().unwrap());
+
+        let mut second = attach(&socket);
+        assert_eq!("> ", read_until(&mut second, "> "));
+        assert!(read_until(&mut first, "else\r\n").contains("Attached from somewhere else"));
+        daemon.println("for the second one");
+        assert_eq!("for the second one\n", read_until(&mut second, "\n"));
+
+        // Detaching and coming back
+        drop(second);
+        thread::sleep(Duration::from_millis(50));
+        daemon.println("while detached");
+        let mut third = attach(&socket);
+        assert_eq!("while detached\n> ", read_until(&mut third, "> "));
+        third.write_all(b"/quit\n").unwrap();
+        assert_eq!("", daemon.read_line().unwrap());
+        drop(daemon);
+        assert!(!socket.exists());
+    }
+}
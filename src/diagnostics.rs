@@ -0,0 +1,162 @@
+//! What there is to know about one connection, for `inspect` on the admin socket: where it's from, how long
+//! it's been around and how it's been behaving. Each connection keeps its own up to date, and it's found by
+//! nick through the `Outbound` in the registry.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use parking_lot::Mutex;
+use crate::accounting::IoMeter;
+use crate::bandwidth::size;
+use crate::clock::ago;
+
+/// Violations kept per connection, past which the oldest get forgotten.
+const MAX_VIOLATIONS: usize = 10;
+
+#[derive(Debug)]
+pub struct Diagnostics {
+    peer: IpAddr,
+    connected_at: SystemTime,
+    /// What it's read and how it's doing against its limits, which is also what enforces them.
+    meter: Arc<Mutex<IoMeter>>,
+    last_said: Mutex<SystemTime>,
+    /// When anything at all last came in, chat or not, for `keepalive` to tell whether it's still there.
+    last_heard: Mutex<SystemTime>,
+    /// When it was pinged, if it has been since then.
+    pinged_at: Mutex<Option<SystemTime>>,
+    /// Whether it's been cut off for not answering.
+    timed_out: AtomicBool,
+    violations: Mutex<VecDeque<(SystemTime, String)>>,
+}
+
+impl Diagnostics {
+    pub fn new(peer: IpAddr, meter: Arc<Mutex<IoMeter>>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            peer,
+            connected_at: now,
+            meter,
+            last_said: Mutex::new(now),
+            last_heard: Mutex::new(now),
+            pinged_at: Mutex::new(None),
+            timed_out: AtomicBool::new(false),
+            violations: Default::default(),
+        }
+    }
+
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+
+    pub fn meter(&self) -> &Mutex<IoMeter> {
+        &self.meter
+    }
+
+    /// Notes that it's said something, so it isn't idle.
+    pub fn said_something(&self) {
+        *self.last_said.lock() = SystemTime::now();
+    }
+
+    /// How long it's been since it last said anything, as of `now`.
+    pub fn idle(&self, now: SystemTime) -> Duration {
+        now.duration_since(*self.last_said.lock()).unwrap_or_default()
+    }
+
+    /// Notes that something came in from it, so it's still there.
+    pub fn heard_from(&self) {
+        *self.last_heard.lock() = SystemTime::now();
+        *self.pinged_at.lock() = None;
+    }
+
+    /// How long it's been since anything came in from it, as of `now`.
+    pub fn quiet(&self, now: SystemTime) -> Duration {
+        now.duration_since(*self.last_heard.lock()).unwrap_or_default()
+    }
+
+    /// Notes that it's being pinged at `now`, unless it already has been since it was last heard from, in
+    /// which case it's how long ago that was.
+    pub fn ping(&self, now: SystemTime) -> Option<Duration> {
+        let mut pinged_at = self.pinged_at.lock();
+        match *pinged_at {
+            Some(at) => Some(now.duration_since(at).unwrap_or_default()),
+            None => {
+                *pinged_at = Some(now);
+                None
+            }
+        }
+    }
+
+    /// Notes that it's being cut off for not answering a ping.
+    pub fn time_out(&self) {
+        self.timed_out.store(true, Ordering::Relaxed);
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Notes that it did something it shouldn't have.
+    pub fn violation(&self, what: impl Into<String>) {
+        let mut violations = self.violations.lock();
+        if violations.len() == MAX_VIOLATIONS {
+            violations.pop_front();
+        }
+        violations.push_back((SystemTime::now(), what.into()));
+    }
+
+    /// All of it, for `nick`, with how its send queue's doing as `(waiting, capacity)` and the bytes that have
+    /// gone out to it.
+    pub fn report(&self, nick: &str, (waiting, capacity): (usize, usize), bytes_out: u64, now: SystemTime) -> String {
+        let since = |at: SystemTime| ago(now.duration_since(at).unwrap_or_default());
+        let (bytes_in, lines_in, (strikes, max_strikes)) = {
+            let meter = self.meter.lock();
+            (meter.total_bytes, meter.total_lines, meter.strikes())
+        };
+
+        let mut report = format!("{nick}, from {}\n", self.peer);
+        let _ = writeln!(report, "  Connected {}, last said anything {}", since(self.connected_at), since(*self.last_said.lock()));
+        let _ = writeln!(report, "  Sent {} in {lines_in} lines, got {}", size(bytes_in), size(bytes_out));
+        let _ = writeln!(report, "  Send queue: {waiting} of {capacity} frames waiting");
+        let _ = write!(report, "  Rate limits: {strikes} of {max_strikes} strikes");
+
+        let violations = self.violations.lock();
+        report.push_str(if violations.is_empty() { "\n  No recent violations" } else { "\n  Recent violations:" });
+        for (at, what) in violations.iter().rev() {
+            let _ = write!(report, "\n    {}: {what}", since(*at));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::accounting::IoLimits;
+    use super::*;
+
+    #[test]
+    fn reports() {
+        let meter = Arc::new(Mutex::new(IoMeter::new(IoLimits::default(), Instant::now())));
+        let diagnostics = Diagnostics::new(IpAddr::from([127, 0, 0, 1]), meter.clone());
+        meter.lock().record_bytes(1500, Instant::now());
+        meter.lock().record_line(Instant::now());
+        let later = SystemTime::now() + Duration::from_secs(150);
+        assert_eq!(
+            "alice, from 127.0.0.1\n  Connected 2m ago, last said anything 2m ago\n  Sent 1.5 KB in 1 lines, got 20 B\n  \
+            Send queue: 3 of 8 frames waiting\n  Rate limits: 0 of 5 strikes\n  No recent violations",
+            diagnostics.report("alice", (3, 8), 20, later),
+        );
+        assert_eq!(150, diagnostics.idle(later).as_secs());
+
+        for i in 0..=MAX_VIOLATIONS {
+            diagnostics.violation(format!("Did a bad thing {i}"));
+        }
+        let report = diagnostics.report("alice", (0, 8), 0, SystemTime::now());
+        // Newest first, and only so many
+        assert!(report.contains("violations:\n    just now: Did a bad thing 10\n    just now: Did a bad thing 9\n"), "{report}");
+        assert!(report.ends_with("just now: Did a bad thing 1"), "{report}");
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use thiserror::Error;
+use tracing::{info, warn};
+use crate::client::get_input;
+
+/// What servers advertise themselves as over mDNS.
+pub const SERVICE_TYPE: &str = "_basicirc._tcp.local.";
+
+#[derive(Error, Debug)]
+pub enum DiscoveryError {
+    #[error("mDNS failed: `{0}`")]
+    Mdns(#[from] mdns_sd::Error),
+    #[error("Failed to read/write from terminal: `{0}`")]
+    IO(#[from] std::io::Error),
+}
+
+/// A server found on the LAN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovered {
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+/// Keeps the server advertised on the LAN until dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("[MDNS] Failed shutting down: {e:?}");
+        }
+    }
+}
+
+/// Advertises a server listening on `port` on every interface this machine has.
+pub fn advertise(port: u16) -> Result<Advertisement, DiscoveryError> {
+    let daemon = ServiceDaemon::new()?;
+    let name = format!("basic-irc-{port}");
+    let info = ServiceInfo::new(SERVICE_TYPE, &name, &format!("{name}.local."), "", port, None)?
+        .enable_addr_auto();
+    daemon.register(info)?;
+
+    info!("[MDNS] Advertising {name} as {SERVICE_TYPE}");
+    Ok(Advertisement { daemon })
+}
+
+/// Listens for servers on the LAN for `wait`, returning whatever turned up sorted by name.
+pub fn discover(wait: Duration) -> Result<Vec<Discovered>, DiscoveryError> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + wait;
+
+    // Servers get resolved again every time they re-announce, so keep one of each
+    let mut found = BTreeMap::new();
+    while let Ok(event) = events.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(info) = event {
+            let name = info.get_fullname().trim_end_matches(SERVICE_TYPE).trim_end_matches('.').to_string();
+            // IPv4 first, it's what everyone's LAN actually works with
+            let ip = info
+                .get_addresses()
+                .iter()
+                .min_by_key(|ip| matches!(ip, IpAddr::V6(_)))
+                .copied();
+
+            if let Some(ip) = ip {
+                found.insert(name.clone(), Discovered { name, address: SocketAddr::new(ip, info.get_port()) });
+            }
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(found.into_values().collect())
+}
+
+/// Lists `servers` and has the user pick one. `None` if they didn't pick anything sensible.
+pub fn pick<I, O>(servers: &[Discovered], input: I, mut output: O) -> Result<Option<SocketAddr>, DiscoveryError>
+where
+    I: BufRead,
+    O: Write,
+{
+    for (i, server) in servers.iter().enumerate() {
+        writeln!(output, "{}) {} at {}", i + 1, server.name, server.address)?;
+    }
+
+    let choice = get_input(b"Pick a server: ", input, output)?;
+    Ok(choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| servers.get(i))
+        .map(|server| server.address))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    fn servers() -> Vec<Discovered> {
+        ["10.0.0.2:6667", "10.0.0.3:7000"]
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| Discovered { name: format!("basic-irc-{i}"), address: addr.parse().unwrap() })
+            .collect()
+    }
+
+    #[test]
+    fn pick_lists_and_picks() {
+        let mut output = Vec::new();
+        let picked = pick(&servers(), Cursor::new("2\n"), &mut output).unwrap();
+
+        assert_eq!(Some("10.0.0.3:7000".parse().unwrap()), picked);
+        let listed = String::from_utf8(output).unwrap();
+        assert!(listed.starts_with("1) basic-irc-0 at 10.0.0.2:6667\n2) basic-irc-1 at 10.0.0.3:7000\n"));
+    }
+
+    #[test]
+    fn pick_out_of_range() {
+        for choice in ["0\n", "3\n", "nope\n", ""] {
+            assert_eq!(None, pick(&servers(), Cursor::new(choice), Vec::new()).unwrap());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! `--mode doctor`: checks the machine a server's about to run on rather than its config, the things that
+//! only go wrong once it's busy or has been running a while. Whether it can have enough files open for
+//! everyone who can connect, whether its stores open and can be written, whether it can listen where it's
+//! told to, and whether it was built with what it's been asked to do. Anything wrong comes with what to do
+//! about it.
+
+use std::fs::OpenOptions;
+use std::path::Path;
+use clap::ValueEnum;
+use rust_threading::storage::Accounts;
+use crate::args::{Args, Runtime};
+use crate::check::{self, Report};
+
+/// Files each connection being served holds open: its socket, and the clones of it that reading, writing
+/// and hanging up each keep.
+const FILES_PER_CONNECTION: u64 = 4;
+/// Kept spare for everything else, like listeners, stores, cluster links and the admin socket.
+const SPARE_FILES: u64 = 64;
+
+/// Everything wrong with running a server with `args` here.
+pub fn examine(args: &Args) -> Report {
+    let mut report = Report::default();
+    check::features(&mut report, args);
+    open_files(&mut report, args, max_open_files());
+
+    if let Some(path) = args.accounts.as_ref().filter(|_| cfg!(feature = "accounts")) {
+        accounts(&mut report, path);
+    }
+    for (what, path) in [("history file", &args.history_file), ("bans file", &args.bans_file)] {
+        if let Some(path) = path {
+            store(&mut report, what, path);
+        }
+    }
+
+    check::listen(&mut report, args);
+    report
+}
+
+/// The soft and hard limits on open files, where there's any telling. Unlimited is `u64::MAX`.
+fn max_open_files() -> Option<(u64, u64)> {
+    parse_limits(&std::fs::read_to_string("/proc/self/limits").ok()?)
+}
+
+fn parse_limits(limits: &str) -> Option<(u64, u64)> {
+    let line = limits.lines().find_map(|line| line.strip_prefix("Max open files"))?;
+    let mut limits = line.split_whitespace().map(|limit| match limit {
+        "unlimited" => Some(u64::MAX),
+        limit => limit.parse().ok(),
+    });
+    Some((limits.next()??, limits.next()??))
+}
+
+/// Whether the server can have a file open for everyone who can connect, going by `limits`.
+fn open_files(report: &mut Report, args: &Args, limits: Option<(u64, u64)>) {
+    let (needed, who) = match (args.runtime, args.max_clients) {
+        (Runtime::Threads, _) => {
+            // Anyone past --max-clients is hung up on right away, so they never get as far as holding anything
+            let max = args.max_clients.unwrap_or(args.workers);
+            let (serving, waiting) = (args.workers.min(max), args.accept_queue.min(max.saturating_sub(args.workers)));
+            let needed = serving as u64 * FILES_PER_CONNECTION + waiting as u64 + args.acceptors as u64 + SPARE_FILES;
+            (needed, format!("{serving} connections being served and {waiting} waiting"))
+        }
+        // Just a socket each, and the clone of it hanging up goes through
+        (Runtime::Mio | Runtime::Tokio, Some(max)) => (max as u64 * 2 + SPARE_FILES, format!("{max} clients")),
+        // Nothing caps connections on these, so it's whatever the limit allows
+        (Runtime::Mio | Runtime::Tokio, _) => {
+            let allowed = limits.map(|(soft, _)| soft.saturating_sub(SPARE_FILES)).unwrap_or_default();
+            let runtime = args.runtime.to_possible_value().expect("None of them are skipped");
+            let detail = format!("The {} runtime takes connections until it runs out, about {allowed} here", runtime.get_name());
+            return report.ok("open files", detail);
+        }
+    };
+
+    let raise = format!("LimitNOFILE={needed} in its systemd unit, or lower --workers or --accept-queue");
+    let shortfall = match limits {
+        Some((soft, _)) if soft >= needed => {
+            return report.ok("open files", format!("{soft} can be open, {needed} are needed for {who}"));
+        }
+        None => format!("Couldn't tell how many files can be open here, {needed} are needed for {who}"),
+        Some((soft, hard)) if hard >= needed => {
+            let fix = format!("Start the server after `ulimit -n {needed}`, or with {raise}");
+            format!("Only {soft} can be open, {needed} are needed for {who}. {fix}")
+        }
+        Some((soft, hard)) => {
+            let fix = format!("Raise the hard limit in /etc/security/limits.conf or with {raise}");
+            format!("Only {soft} can be open, and no more than {hard} without root, {needed} are needed for {who}. {fix}")
+        }
+    };
+    report.warn("open files", shortfall);
+}
+
+/// Whether the accounts database opens, or can be made.
+fn accounts(report: &mut Report, path: &Path) {
+    if !path.exists() {
+        return can_make(report, "accounts", path);
+    }
+    match Accounts::open(path).and_then(|accounts| accounts.count()) {
+        Ok(count) => report.ok("accounts", format!("{} opens, with {count} registered nick(s)", path.display())),
+        Err(e) => {
+            let fix = "If it isn't an accounts database, move it aside and a new one gets made";
+            report.fail("accounts", format!("{} doesn't open: {e}. {fix}", path.display()));
+        }
+    }
+}
+
+/// Whether a store the server keeps appending to can be read and written, or made.
+fn store(report: &mut Report, what: &'static str, path: &Path) {
+    if !path.exists() {
+        return can_make(report, what, path);
+    }
+    match OpenOptions::new().read(true).append(true).open(path) {
+        Ok(_) => report.ok(what, format!("{} can be read and written", path.display())),
+        Err(e) => {
+            let fix = "It needs to belong to whoever runs the server";
+            report.fail(what, format!("Can't read and write {}: {e}. {fix}", path.display()));
+        }
+    }
+}
+
+/// Whether `path` can be made, by making something next to it and getting rid of it again.
+fn can_make(report: &mut Report, what: &'static str, path: &Path) {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let probe = parent.join(format!(".{name}.doctor-{}", std::process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            report.ok(what, format!("{} isn't there yet, but can be made", path.display()));
+        }
+        Err(e) => {
+            let fix = "Make the directory, or let whoever runs the server write to it";
+            report.fail(what, format!("{} can't be made in {}: {e}. {fix}", path.display(), parent.display()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use super::*;
+
+    fn doctor_args(args: &[&str]) -> Args {
+        Args::parse_from(["rust-threading", "--mode", "doctor"].iter().chain(args))
+    }
+
+    #[test]
+    fn reads_limits() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+            Max processes             63304                63304                processes \n\
+            Max open files            1024                 524288               files     \n";
+        assert_eq!(Some((1024, 524288)), parse_limits(limits));
+        assert_eq!(Some((u64::MAX, u64::MAX)), parse_limits("Max open files unlimited unlimited files"));
+        assert_eq!(None, parse_limits("Max processes 1 1 processes"));
+    }
+
+    #[test]
+    fn says_how_to_get_enough_files() {
+        let args = doctor_args(&["--workers", "100", "--accept-queue", "100", "--max-clients", "200"]);
+        let needed = 100 * FILES_PER_CONNECTION + 100 + 1 + SPARE_FILES;
+        let mut report = Report::default();
+        open_files(&mut report, &args, Some((needed, needed)));
+        open_files(&mut report, &args, Some((256, 4096)));
+        open_files(&mut report, &args, Some((256, 256)));
+        let shown = report.to_string();
+        assert!(shown.starts_with(&format!("ok    open files    {needed} can be open")), "{shown}");
+        assert!(shown.contains(&format!("Start the server after `ulimit -n {needed}`")), "{shown}");
+        assert!(shown.contains("no more than 256 without root"), "{shown}");
+        assert!(shown.ends_with("0 problem(s), 2 warning(s)"), "{shown}");
+
+        let capped = doctor_args(&["--workers", "100", "--accept-queue", "100", "--max-clients", "120"]);
+        let needed = 100 * FILES_PER_CONNECTION + 20 + 1 + SPARE_FILES;
+        let mut report = Report::default();
+        open_files(&mut report, &capped, Some((needed, needed)));
+        assert!(report.to_string().contains("for 100 connections being served and 20 waiting"), "{report}");
+
+        // Without --max-clients, nobody's let in to wait for a worker
+        let mut report = Report::default();
+        open_files(&mut report, &doctor_args(&["--workers", "100", "--accept-queue", "100"]), None);
+        assert!(report.to_string().contains("for 100 connections being served and 0 waiting"), "{report}");
+    }
+
+    #[test]
+    fn checks_stores() {
+        let dir = std::env::temp_dir().join(format!("basic-irc-doctor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let history = dir.join("history.jsonl");
+        std::fs::write(&history, "").unwrap();
+
+        let mut report = Report::default();
+        store(&mut report, "history file", &history);
+        accounts(&mut report, &dir.join("accounts.db"));
+        store(&mut report, "bans file", Path::new("/nope/bans.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let shown = report.to_string();
+        assert!(shown.contains("history.jsonl can be read and written"), "{shown}");
+        assert!(shown.contains("accounts.db isn't there yet, but can be made"), "{shown}");
+        assert!(shown.contains("FAIL  bans file     /nope/bans.txt can't be made in /nope"), "{shown}");
+        assert!(shown.ends_with("1 problem(s), 0 warning(s)"), "{shown}");
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::TryRecvError;
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
+use parking_lot::Mutex;
+use crate::accounting::{IoMeter, over_budget, Verdict};
+use crate::capacity::Seat;
+use crate::diagnostics::Diagnostics;
+use crate::frame::{encode_message, frame_len, PREFIX_LEN};
+use crate::listener;
+use crate::metrics::Metrics;
+use crate::outbound::{Frame, Outbound, Queue};
+use serde::Serialize;
+use socket2::SockRef;
+use tracing::{field, info, info_span, warn, Span};
+use crate::response::{AuthResponse, PresenceChange};
+use crate::scuffed_clone::HangUp;
+use crate::server::{advertise, answer_handshake, farewell, features, HELLO_LIMITS, let_in, Line, map_port, seat, Session};
+use crate::server::{Options, public_address, say_goodbye, Shared, SHUTDOWN_GRACE, welcome};
+use crate::shutdown::{self, Shutdown};
+use crate::transport::Listener;
+
+const LISTENER: Token = Token(0);
+/// Woken whenever something's put on anyone's send queue, from whichever thread put it there.
+const WAKER: Token = Token(1);
+const READ_CHUNK: usize = 4096;
+/// How often to look in on connections that are leaving, since nothing wakes the loop once everyone's let go of
+/// their send queue.
+const LINGER_CHECK: Duration = Duration::from_millis(100);
+
+/// Where a connection is at.
+enum State {
+    /// Waiting on the `Handshake` frame.
+    Handshake,
+    /// Waiting on the hello frame, the `User`.
+    Hello,
+    Chatting(Session),
+    /// Got told why it's being turned away, and gets hung up on once that's been written.
+    Rejected,
+    /// Done reading and seen off, and gets hung up on once whatever's left on its send queue has been written.
+    Leaving,
+}
+
+struct Conn {
+    stream: TcpStream,
+    peer: IpAddr,
+    state: State,
+    accepted: Instant,
+    /// Bytes read but not handled yet, at most one partial line or hello.
+    inbox: Vec<u8>,
+    /// The rest of a line over the max length gets thrown away as it comes in.
+    skipping_line: bool,
+    /// Answers to the handshake and hello, which go out ahead of anything on the send queue.
+    greeting: VecDeque<Frame>,
+    queue: Queue,
+    /// The frame being written, and how much of it has been.
+    writing: Option<(Frame, usize)>,
+    /// The sending end of `queue`, until they're leaving.
+    outbound: Option<Outbound>,
+    diagnostics: Arc<Diagnostics>,
+    /// Not read from until then, for going over its I/O budget.
+    throttled_until: Option<Instant>,
+    /// Gets dropped at the end of this turn of the loop.
+    dead: bool,
+    /// What everything logged about it goes under, since they all share the one thread.
+    span: Span,
+    _seat: Seat,
+}
+
+/// The whole server's connections on one thread: a single mio event loop over non-blocking sockets, with no
+/// thread per connection. Only reading and writing happen here. Everything else is the threaded server's, through
+/// `Shared` and `Session`: the same broadcaster, commands, channels, history, bans, limits, flood control and
+/// keepalive, so every option means the same here except the worker, acceptor and writer ones, which don't apply.
+/// TLS, WebSocket, Unix sockets and IRC are the threaded server's alone.
+///
+/// Clients get throttled by not reading from them for a while instead of by sleeping. The loop does wait on the
+/// broadcaster, for as long as it takes to take a line and to answer a `/nick`, the same as a connection's thread
+/// would, and on checking an account's password while logging someone in.
+///
+/// There are as many connections at once as `--max-clients` lets in, or as many as connect without it. Each one's
+/// memory is bounded by its max line length plus its send queue, and one that fills its send queue gets hung up on.
+struct EventLoop {
+    poll: Poll,
+    listener: TcpListener,
+    waker: Arc<Waker>,
+    /// Connections with something new on their send queue since the loop last looked.
+    woken: Arc<Mutex<HashSet<Token>>>,
+    conns: HashMap<Token, Conn>,
+    next_token: usize,
+    shared: Shared,
+    options: Options,
+    shutdown: Arc<Shutdown>,
+}
+
+pub fn start(address: SocketAddr, options: Options) -> std::io::Result<()> {
+    let listener = listener::bind_one(address)?;
+    let port = listener.local_addr()?.port();
+    info!("Listening on port {port} on a single event loop");
+    let _advertisement = advertise(&options, port);
+    let _mapping = map_port(&options, port);
+    let metrics = Arc::new(Metrics::default());
+    *metrics.public_address.lock() = public_address(&options, port);
+    let shutdown = Arc::new(Shutdown::default());
+    shutdown::on_signals(shutdown.clone())?;
+    run(listener, options, metrics, shutdown)
+}
+
+fn run(listener: std::net::TcpListener, options: Options, metrics: Arc<Metrics>, shutdown: Arc<Shutdown>) -> std::io::Result<()> {
+    // Shutting down wakes the loop up by connecting to the listener
+    shutdown.watch(&Listener::from(listener.try_clone()?))?;
+    let shared = Shared::open(listener.local_addr()?, &options, (metrics, shutdown.clone()), options.max_clients)?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+
+    let poll = Poll::new()?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    let mut event_loop = EventLoop {
+        poll,
+        listener,
+        waker,
+        woken: Default::default(),
+        conns: HashMap::new(),
+        next_token: WAKER.0 + 1,
+        shared,
+        options,
+        shutdown,
+    };
+    event_loop.run()
+}
+
+impl EventLoop {
+    fn run(&mut self) -> std::io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        // When to stop waiting for everyone to be seen off, once shutting down
+        let mut closing: Option<Instant> = None;
+
+        loop {
+            // Only wake up on a timer if someone needs to come off throttle, is leaving or it's time to give up
+            let now = Instant::now();
+            let timeout = self
+                .conns
+                .values()
+                .filter_map(|conn| match conn.state {
+                    State::Leaving => Some(now + LINGER_CHECK),
+                    _ => conn.throttled_until,
+                })
+                .chain(closing)
+                .min()
+                .map(|until| until.saturating_duration_since(now));
+
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.wake_up(),
+                    token => {
+                        if event.is_writable() {
+                            self.flush(token);
+                        }
+                        if event.is_readable() {
+                            self.on_readable(token);
+                        }
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            let rested: Vec<_> = self
+                .conns
+                .iter()
+                .filter(|(_, conn)| conn.throttled_until.is_some_and(|until| until <= now))
+                .map(|(token, _)| *token)
+                .collect();
+            for token in rested {
+                if let Some(conn) = self.conns.get_mut(&token) {
+                    conn.throttled_until = None;
+                }
+                // Whatever got left in the inbox comes first, then anything that piled up in the kernel
+                self.process(token);
+                self.on_readable(token);
+            }
+
+            let leaving: Vec<_> = self
+                .conns
+                .iter()
+                .filter(|(_, conn)| matches!(conn.state, State::Leaving))
+                .map(|(token, _)| *token)
+                .collect();
+            for token in leaving {
+                self.flush(token);
+            }
+
+            self.reap();
+            match closing {
+                None if self.shutdown.stopping() => {
+                    info!("Not taking any more connections, saying goodbye to everyone");
+                    self.poll.registry().deregister(&mut self.listener)?;
+                    let deadline = Instant::now() + SHUTDOWN_GRACE;
+                    say_goodbye(&self.shared.users, &self.shared.sender, deadline);
+                    closing = Some(deadline);
+                }
+                Some(_) if self.conns.is_empty() => return Ok(()),
+                Some(deadline) if Instant::now() >= deadline => {
+                    let left = self.conns.len();
+                    warn!("{left} connection(s) were still being served after {}s", SHUTDOWN_GRACE.as_secs());
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            if self.shutdown.stopping() {
+                return;
+            }
+            let (mut stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed on handling incoming stream: {e:?}");
+                    return;
+                }
+            };
+
+            let (now, peer) = (Instant::now(), peer.ip().to_canonical());
+            let Shared { bans, clients, accepts, .. } = &self.shared;
+            let seat = match seat(peer, (bans, clients, accepts)) {
+                Ok(seat) => seat,
+                Err(refusal) => {
+                    // Nothing's been written to it yet, so the answer fits without waiting on it
+                    if let Ok(answer) = encode_message(&refusal.answer()) {
+                        let _ = stream.write(&answer);
+                    }
+                    continue;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                warn!("Couldn't register {peer}, dropping it: {e:?}");
+                continue;
+            }
+            // Shutting down reading from it, like the keepalive and kicks do, looks like they hung up
+            let hang_up = match SockRef::from(&stream).try_clone() {
+                Ok(socket) => std::net::TcpStream::from(socket),
+                Err(e) => {
+                    warn!("Couldn't clone {peer}, dropping it: {e:?}");
+                    continue;
+                }
+            };
+
+            let meter = Arc::new(Mutex::new(IoMeter::new(self.options.io_limits, now)));
+            let diagnostics = Arc::new(Diagnostics::new(peer, meter));
+            let (outbound, queue) = Outbound::new(self.options.send_queue_len);
+            let (waker, woken) = (self.waker.clone(), self.woken.clone());
+            let outbound = outbound
+                .with_hang_up(move || hang_up.hang_up())
+                .with_wake(move || {
+                    woken.lock().insert(token);
+                    if let Err(e) = waker.wake() {
+                        warn!("Couldn't wake the event loop: {e:?}");
+                    }
+                })
+                .with_diagnostics(diagnostics.clone());
+
+            self.conns.insert(token, Conn {
+                stream,
+                peer,
+                state: State::Handshake,
+                accepted: now,
+                inbox: Vec::new(),
+                skipping_line: false,
+                greeting: VecDeque::new(),
+                queue,
+                writing: None,
+                outbound: Some(outbound),
+                diagnostics,
+                throttled_until: None,
+                dead: false,
+                span: info_span!("conn", %peer, nick = field::Empty),
+                _seat: seat,
+            });
+        }
+    }
+
+    /// Writes out whatever's been put on anyone's send queue since the last time.
+    fn wake_up(&mut self) {
+        let woken = std::mem::take(&mut *self.woken.lock());
+        for token in woken {
+            self.flush(token);
+        }
+    }
+
+    /// Reads everything the socket has, handling it as it comes in, unless the connection's throttled.
+    fn on_readable(&mut self, token: Token) {
+        let mut chunk = [0; READ_CHUNK];
+
+        loop {
+            let Some(conn) = self.conns.get_mut(&token) else { return };
+            if conn.dead || conn.throttled_until.is_some() || matches!(conn.state, State::Rejected | State::Leaving) {
+                return;
+            }
+
+            let now = Instant::now();
+            let verdict = conn.diagnostics.meter().lock().verdict(now);
+            match verdict {
+                Verdict::Ok => {}
+                Verdict::Throttle(pause) => {
+                    conn.throttled_until = Some(now + pause);
+                    return;
+                }
+                Verdict::Disconnect => {
+                    self.hear(token, Err(over_budget()));
+                    return;
+                }
+            }
+
+            match conn.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.hear(token, Ok(None));
+                    return;
+                }
+                Ok(n) => {
+                    conn.diagnostics.meter().lock().record_bytes(n as u64, now);
+                    self.shared.metrics.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+                    conn.inbox.extend_from_slice(&chunk[..n]);
+                    self.process(token);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.hear(token, Err(e));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Handles as much of the connection's inbox as it can.
+    fn process(&mut self, token: Token) {
+        let Some(conn) = self.conns.get(&token) else { return };
+        if conn.dead {
+            return;
+        }
+
+        match conn.state {
+            State::Handshake => self.process_handshake(token),
+            State::Hello => self.process_hello(token),
+            State::Chatting(_) => self.process_lines(token),
+            State::Rejected | State::Leaving => {}
+        }
+    }
+
+    fn process_handshake(&mut self, token: Token) {
+        let conn = self.conns.get_mut(&token).expect("Only called for live connections");
+        let len = match frame_len(&conn.inbox, &HELLO_LIMITS) {
+            Ok(Some(len)) => len,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(parent: &conn.span, "Failed reading handshake: {e}");
+                conn.dead = true;
+                return;
+            }
+        };
+
+        let handshake: Vec<u8> = conn.inbox.drain(..len).collect();
+        let (answer, shaken) = answer_handshake(&handshake[PREFIX_LEN..], &features(self.options.password.is_some()));
+        conn.state = match shaken {
+            Ok(_) => State::Hello,
+            Err(e) => {
+                warn!(parent: &conn.span, "Turning away: {e}");
+                State::Rejected
+            }
+        };
+        self.greet(token, &answer);
+        // The hello might've come in right behind it
+        self.process(token);
+    }
+
+    fn process_hello(&mut self, token: Token) {
+        let conn = self.conns.get_mut(&token).expect("Only called for live connections");
+        let len = match frame_len(&conn.inbox, &HELLO_LIMITS) {
+            Ok(Some(len)) => len,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(parent: &conn.span, "Failed validating user: {e}");
+                conn.dead = true;
+                return;
+            }
+        };
+
+        let hello: Vec<u8> = conn.inbox.drain(..len).collect();
+        let (shared, outbound) = (&self.shared, conn.outbound.clone().expect("Only let go of once they're leaving"));
+        let admitted = let_in(&hello[PREFIX_LEN..], &self.options, |user| shared.admit(user, outbound));
+        for alarm in shared.metrics.handshakes.record(conn.peer, conn.accepted.elapsed(), admitted.is_ok()) {
+            warn!(parent: &conn.span, "[AUTH] Warning: {alarm}");
+        }
+
+        let user = match admitted {
+            Ok(user) => user,
+            Err(e) => {
+                warn!(parent: &conn.span, "Failed validating user: {e}");
+                match e.answer() {
+                    Some(answer) => {
+                        conn.state = State::Rejected;
+                        self.greet(token, &answer);
+                    }
+                    None => conn.dead = true,
+                }
+                return;
+            }
+        };
+
+        conn.span.record("nick", field::display(&user.name));
+        let span = conn.span.clone();
+        let _entered = span.enter();
+        // Ahead of the welcome on the send queue, since the greeting goes out first
+        self.greet(token, &AuthResponse::Success);
+        welcome(&self.shared, &user, &self.options);
+        let conn = self.conns.get_mut(&token).expect("Only called for live connections");
+        conn.state = State::Chatting(Session::new(user, &self.options));
+        // Anything that came in right behind the hello is chat
+        self.process_lines(token);
+    }
+
+    fn process_lines(&mut self, token: Token) {
+        let max_line_len = self.options.max_line_len;
+
+        loop {
+            let conn = self.conns.get_mut(&token).expect("Only called for live connections");
+            if conn.dead || conn.throttled_until.is_some() || !matches!(conn.state, State::Chatting(_)) {
+                return;
+            }
+
+            let line = match conn.inbox.iter().position(|b| *b == 0xA) {
+                // Over the limit with no end in sight, so stop holding onto it
+                None if conn.inbox.len() > max_line_len => {
+                    conn.inbox.clear();
+                    if std::mem::replace(&mut conn.skipping_line, true) {
+                        return;
+                    }
+                    Line::TooLong
+                }
+                None => return,
+                Some(end) => {
+                    let line: Vec<u8> = conn.inbox.drain(..=end).collect();
+                    if std::mem::take(&mut conn.skipping_line) {
+                        continue;
+                    }
+                    match end > max_line_len {
+                        true => Line::TooLong,
+                        false => Line::Text(String::from_utf8_lossy(&line).trim_end().to_string()),
+                    }
+                }
+            };
+
+            // Lines count against the budget too, so check before doing anything with it
+            let now = Instant::now();
+            let mut meter = conn.diagnostics.meter().lock();
+            meter.record_line(now);
+            let line = match meter.verdict(now) {
+                Verdict::Ok => Ok(Some(line)),
+                Verdict::Throttle(pause) => {
+                    conn.throttled_until = Some(now + pause);
+                    Ok(Some(line))
+                }
+                Verdict::Disconnect => Err(over_budget()),
+            };
+            drop(meter);
+            self.hear(token, line);
+        }
+    }
+
+    /// Hands what reading the connection came to over to its session, and sees them off if that's the end of them.
+    /// Anyone who isn't logged in yet is just hung up on once there's nothing more to read.
+    fn hear(&mut self, token: Token, line: std::io::Result<Option<Line>>) {
+        let conn = self.conns.get_mut(&token).expect("Only called for live connections");
+        let span = conn.span.clone();
+        let _entered = span.enter();
+        let Shared { sender, memory, metrics, .. } = &self.shared;
+
+        let (State::Chatting(session), Some(outbound)) = (&mut conn.state, &conn.outbound) else {
+            match line {
+                Err(e) if e.kind() == ErrorKind::QuotaExceeded => {
+                    metrics.io_disconnects.fetch_add(1, Ordering::Relaxed);
+                    warn!("Cutting off connection, it's been over its I/O budget for too long");
+                }
+                Err(e) => warn!("Error reading: {e:?}"),
+                Ok(_) => {}
+            }
+            conn.dead = true;
+            return;
+        };
+        let Err(quit) = session.heard(line, (sender, memory, metrics), (&conn.diagnostics, outbound), &self.options) else {
+            return;
+        };
+
+        if let State::Chatting(session) = std::mem::replace(&mut conn.state, State::Leaving) {
+            farewell(&self.shared, session.user(), quit);
+            // Their send queue winds down once the registry's let go of it too and it's been written out
+            conn.outbound = None;
+            disconnected(conn);
+        }
+        self.flush(token);
+    }
+
+    /// Queues `msg` to go out ahead of anything on the connection's send queue, for answering the handshake and
+    /// hello, and writes as much as the socket will take right now.
+    fn greet<T: Serialize + std::fmt::Debug>(&mut self, token: Token, msg: &T) {
+        let Some(conn) = self.conns.get_mut(&token) else { return };
+        match encode_message(msg) {
+            Ok(frame) => conn.greeting.push_back(frame.into()),
+            Err(e) => warn!(parent: &conn.span, "Failed encoding {msg:?}: {e:?}"),
+        }
+        self.flush(token);
+    }
+
+    /// Writes as much of the greeting and the send queue as the socket will take right now.
+    fn flush(&mut self, token: Token) {
+        let Some(conn) = self.conns.get_mut(&token) else { return };
+
+        loop {
+            let (frame, written) = match &mut conn.writing {
+                Some(writing) => writing,
+                None => {
+                    let next = match conn.greeting.pop_front() {
+                        Some(frame) => Ok(frame),
+                        None => conn.queue.try_next(),
+                    };
+                    match next {
+                        Ok(frame) => conn.writing.insert((frame, 0)),
+                        Err(TryRecvError::Empty) => break,
+                        // Only once they're leaving and nothing else has hold of their queue either
+                        Err(TryRecvError::Disconnected) => {
+                            conn.dead = true;
+                            return;
+                        }
+                    }
+                }
+            };
+
+            match conn.stream.write(&frame[*written..]) {
+                Ok(0) => {
+                    conn.dead = true;
+                    return;
+                }
+                Ok(n) => {
+                    *written += n;
+                    self.shared.metrics.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                    if *written == frame.len() {
+                        conn.queue.wrote(frame.len());
+                        conn.writing = None;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!(parent: &conn.span, "Failed writing: {e:?}");
+                    conn.dead = true;
+                    return;
+                }
+            }
+        }
+
+        if matches!(conn.state, State::Rejected) {
+            conn.dead = true;
+        }
+    }
+
+    /// Drops every dead connection, seeing off anyone who was still chatting.
+    fn reap(&mut self) {
+        let dead: Vec<_> = self.conns.iter().filter(|(_, conn)| conn.dead).map(|(token, _)| *token).collect();
+        for token in dead {
+            let mut conn = self.conns.remove(&token).expect("Just found it");
+            if let Err(e) = self.poll.registry().deregister(&mut conn.stream) {
+                warn!(parent: &conn.span, "Failed deregistering: {e:?}");
+            }
+
+            // Gone without hanging up, like when writing to them failed
+            if let State::Chatting(session) = &conn.state {
+                let _entered = conn.span.enter();
+                farewell(&self.shared, session.user(), PresenceChange::Quit { reason: None });
+                disconnected(&conn);
+            }
+        }
+    }
+}
+
+fn disconnected(conn: &Conn) {
+    let meter = conn.diagnostics.meter().lock();
+    info!(parent: &conn.span, "Disconnected after sending {} bytes in {} lines", meter.total_bytes, meter.total_lines);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpStream};
+    use std::thread;
+    use crate::frame::{FrameLimits, read_message};
+    use crate::response::ServerMessage;
+    use crate::testing::log_in;
+    use crate::user::User;
+    use super::*;
+
+    fn spawn_server(options: Options) -> SocketAddr {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        // Never stops, it goes away with the test process
+        thread::spawn(move || run(listener, options, Arc::default(), Arc::default()).unwrap());
+        address
+    }
+
+    fn connect(address: SocketAddr, name: &str) -> (TcpStream, AuthResponse) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let resp = log_in(&mut stream, &User::new(name));
+        (stream, resp)
+    }
+
+    /// The next chat message on `stream`, skipping over notices and everyone coming and going.
+    fn next_chat(stream: &mut TcpStream) -> (String, String) {
+        loop {
+            match read_message(stream, &FrameLimits::default()).unwrap() {
+                ServerMessage::Chat { from, text, .. } => return (from.name, text),
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn chat_round_trip() {
+        let address = spawn_server(Options::default());
+        let (mut one, resp) = connect(address, "one");
+        assert_eq!(AuthResponse::Success, resp);
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"hello\n").unwrap();
+        assert_eq!(("one".to_string(), "hello".to_string()), next_chat(&mut two));
+
+        // Taken nicks get turned away, then hung up on
+        let (mut dupe, resp) = connect(address, "one");
+        assert_eq!(AuthResponse::Error("Name is already taken: one".to_string()), resp);
+        assert_eq!(0, dupe.read(&mut [0; 16]).unwrap());
+
+        two.write_all(b"hi\n").unwrap();
+        assert_eq!(("two".to_string(), "hi".to_string()), next_chat(&mut one));
+    }
+
+    #[test]
+    fn runs_commands() {
+        let address = spawn_server(Options::default());
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"/msg two psst\n/nick uno\nhello again\n").unwrap();
+        let ServerMessage::Private { from, text, .. } = read_message(&mut two, &FrameLimits::default()).unwrap() else {
+            panic!("Expected the PM first");
+        };
+        assert_eq!(("one", "psst"), (from.name.as_str(), text.as_str()));
+        assert_eq!(("uno".to_string(), "hello again".to_string()), next_chat(&mut two));
+    }
+
+    #[test]
+    fn oversized_lines_are_dropped() {
+        let address = spawn_server(Options { max_line_len: 16, ..Default::default() });
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(format!("{}\nafter\n", "a".repeat(READ_CHUNK * 3)).as_bytes()).unwrap();
+        assert_eq!(("one".to_string(), "after".to_string()), next_chat(&mut two));
+    }
+
+    #[test]
+    fn turns_away_past_max_clients() {
+        let address = spawn_server(Options { max_clients: Some(1), ..Default::default() });
+        let (one, _) = connect(address, "one");
+
+        let mut two = TcpStream::connect(address).unwrap();
+        two.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(AuthResponse::ServerFull, read_message(&mut two, &FrameLimits::default()).unwrap());
+
+        // Their seat's free again once they've gone
+        drop(one);
+        let freed = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut three = TcpStream::connect(address).unwrap();
+            three.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            // Turned away straight off, or left waiting on the handshake once there's a seat
+            if three.read(&mut [0; 1]).is_err() {
+                three.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                assert_eq!(AuthResponse::Success, log_in(&mut three, &User::new("three")));
+                break;
+            }
+            assert!(Instant::now() < freed, "Never got a seat back");
+        }
+    }
+
+    #[test]
+    fn says_goodbye_on_shutdown() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (address, shutdown) = (listener.local_addr().unwrap(), Arc::new(Shutdown::default()));
+        let stopping = shutdown.clone();
+        let server = thread::spawn(move || run(listener, Options::default(), Arc::default(), stopping));
+        let (mut one, _) = connect(address, "one");
+        let (mut two, _) = connect(address, "two");
+
+        one.write_all(b"bye\n").unwrap();
+        assert_eq!(("one".to_string(), "bye".to_string()), next_chat(&mut two));
+        shutdown.begin();
+        server.join().unwrap().unwrap();
+
+        // Along with anyone else leaving, they hear why before they're hung up on
+        let heard: Vec<ServerMessage> = std::iter::from_fn(|| read_message(&mut two, &FrameLimits::default()).ok()).collect();
+        assert!(heard.contains(&ServerMessage::notice("Server shutting down")), "{heard:?}");
+        assert!(TcpStream::connect(address).is_err());
+    }
+}
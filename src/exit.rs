@@ -0,0 +1,153 @@
+//! What the process' exit code says about how it ended, so scripts wrapping the client or server can tell a
+//! wrong password from a server that's down from being kicked. Anything that isn't one of these is 1, and
+//! clap has 2 for flags it couldn't make sense of.
+
+use std::io::ErrorKind;
+use protocol::client::ConnectionError;
+use crate::client::ClientError;
+use crate::frame::FrameError;
+use crate::response::AuthResponse;
+
+/// How the process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Quit, or shut down when asked to.
+    Clean,
+    /// Anything that isn't below, like a config that doesn't make sense.
+    Failed,
+    /// The server wouldn't log us in, like for a wrong password or being full or busy.
+    AuthFailed,
+    /// Couldn't connect to the server, or the server couldn't listen on its port.
+    Unreachable,
+    /// Kicked, killed or banned by an operator, or turned away for being banned.
+    Kicked,
+    /// The other end doesn't speak the protocol, or sent something that isn't in it.
+    Protocol,
+    /// The connection dropped out from under us, like when the server goes away.
+    Lost,
+}
+
+impl Exit {
+    /// All of them, in order of their codes.
+    pub const ALL: [Exit; 7] = [
+        Exit::Clean,
+        Exit::Failed,
+        Exit::AuthFailed,
+        Exit::Unreachable,
+        Exit::Kicked,
+        Exit::Protocol,
+        Exit::Lost,
+    ];
+
+    pub fn code(self) -> u8 {
+        match self {
+            Exit::Clean => 0,
+            Exit::Failed => 1,
+            // 2 is clap's
+            Exit::AuthFailed => 3,
+            Exit::Unreachable => 4,
+            Exit::Kicked => 5,
+            Exit::Protocol => 6,
+            Exit::Lost => 7,
+        }
+    }
+
+    /// What a failed connect, bind, read or write says about how things ended.
+    pub fn of_io(e: &std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionRefused
+            | ErrorKind::TimedOut
+            | ErrorKind::AddrInUse
+            | ErrorKind::AddrNotAvailable
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable => Exit::Unreachable,
+            ErrorKind::InvalidData => Exit::Protocol,
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                Exit::Lost
+            }
+            _ => Exit::Failed,
+        }
+    }
+
+    /// The table for `--help`.
+    pub fn help() -> String {
+        let mut help = "Exit codes:".to_string();
+        for exit in Exit::ALL {
+            help += &format!("\n  {}  {}", exit.code(), exit.describe());
+            if exit == Exit::Failed {
+                help += "\n  2  The flags or config didn't make sense";
+            }
+        }
+        help
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Exit::Clean => "Quit, or shut down when asked to",
+            Exit::Failed => "Anything not listed here",
+            Exit::AuthFailed => "The server wouldn't log us in, like for a wrong password or being full or busy",
+            Exit::Unreachable => "Couldn't connect to the server, or the server couldn't listen on its port",
+            Exit::Kicked => "Kicked, killed or banned, or turned away for being banned",
+            Exit::Protocol => "The other end doesn't speak the protocol, or broke it",
+            Exit::Lost => "Lost the connection, like when the server goes away",
+        }
+    }
+}
+
+impl From<&ClientError> for Exit {
+    fn from(e: &ClientError) -> Self {
+        match e {
+            ClientError::IO(e) => Exit::of_io(e),
+            // Frames are read straight off the connection, so that's where it going away shows up
+            ClientError::Frame(FrameError::IO(e)) | ClientError::Connection(ConnectionError::Frame(FrameError::IO(e))) => {
+                Exit::of_io(e)
+            }
+            ClientError::Connection(ConnectionError::Closed) => Exit::Lost,
+            ClientError::Frame(_) | ClientError::Connection(_) | ClientError::Incompatible(_) => Exit::Protocol,
+            // Bans are the one refusal that says so up front, since they're the operator's words
+            ClientError::Auth(AuthResponse::Error(why)) if why.starts_with("Banned") => Exit::Kicked,
+            ClientError::Auth(_) => Exit::AuthFailed,
+            ClientError::Kicked(_) => Exit::Kicked,
+        }
+    }
+}
+
+impl From<Exit> for std::process::ExitCode {
+    fn from(exit: Exit) -> Self {
+        exit.code().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error;
+    use super::*;
+
+    #[test]
+    fn codes_are_distinct() {
+        let codes: Vec<_> = Exit::ALL.iter().map(|exit| exit.code()).collect();
+        assert_eq!(vec![0, 1, 3, 4, 5, 6, 7], codes);
+        let help = Exit::help();
+        assert!(Exit::ALL.iter().all(|exit| help.contains(&format!("  {}  {}", exit.code(), exit.describe()))));
+    }
+
+    #[test]
+    fn client_errors() {
+        let cases = [
+            (ClientError::Auth(AuthResponse::BadPassword), Exit::AuthFailed),
+            (ClientError::Auth(AuthResponse::ServerFull), Exit::AuthFailed),
+            (ClientError::Auth(AuthResponse::Busy { retry_after_ms: 500 }), Exit::AuthFailed),
+            (ClientError::Auth(AuthResponse::Error("Banned by alice: spam".to_string())), Exit::Kicked),
+            (ClientError::Kicked("bob was kicked by alice".to_string()), Exit::Kicked),
+            (ClientError::IO(Error::from(ErrorKind::ConnectionRefused)), Exit::Unreachable),
+            (ClientError::IO(Error::from(ErrorKind::UnexpectedEof)), Exit::Lost),
+            (ClientError::Connection(ConnectionError::Closed), Exit::Lost),
+            (ClientError::Frame(Error::from(ErrorKind::UnexpectedEof).into()), Exit::Lost),
+            (ClientError::Frame(FrameError::TooDeep(32)), Exit::Protocol),
+            (ClientError::Incompatible("too old".to_string()), Exit::Protocol),
+        ];
+        for (e, exit) in cases {
+            assert_eq!(exit, Exit::from(&e), "{e:?}");
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Prometheus metrics over HTTP, for graphing the server in Grafana or anything else that scrapes. Everything's
+//! at `GET /metrics` on `--metrics-port`. Counters only ever go up, so per-second numbers like messages
+//! broadcast come from `rate()` over them.
+
+use std::fmt::{Display, Write as _};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::budget::MemoryBudget;
+use crate::channel::LOBBY;
+use crate::http::{self, Deadline, Status};
+use crate::metrics::Metrics;
+use crate::outbound::Outbound;
+use crate::registry::Registry;
+
+/// What Prometheus' text format calls itself.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+/// How long a scraper gets to send its whole request before it's hung up on, so one that never finishes, however
+/// slowly it trickles in, can't hold up the rest.
+const REQUEST_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Starts serving metrics on `address`, for as long as the server's up. Returns where it's listening.
+pub(crate) fn listen(
+    address: SocketAddr,
+    metrics: Arc<Metrics>,
+    (users, memory): (Arc<Registry<Outbound>>, Arc<MemoryBudget>),
+) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    info!("Serving metrics on http://{address}/metrics");
+
+    // Scrapes are rare and quick, so one at a time is plenty
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for scraper in listener.incoming() {
+            let served = scraper.and_then(|scraper| serve(scraper, &metrics, (&users, &memory)));
+            if let Err(e) = served {
+                warn!("[METRICS] Failed serving a scrape: {e:?}");
+            }
+        }
+    })?;
+    Ok(address)
+}
+
+fn serve(scraper: TcpStream, metrics: &Metrics, (users, memory): (&Registry<Outbound>, &MemoryBudget)) -> std::io::Result<()> {
+    let Ok(request) = http::read_request(Deadline::new(&scraper, REQUEST_DEADLINE)) else {
+        return http::respond(&scraper, Status::BadRequest, "text/plain", "That's not a request\n");
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => http::respond(&scraper, Status::Ok, CONTENT_TYPE, &render(metrics, (users, memory))),
+        (_, "/metrics") => http::respond(&scraper, Status::MethodNotAllowed, "text/plain", "Only GET\n"),
+        _ => http::respond(&scraper, Status::NotFound, "text/plain", "Metrics are at /metrics\n"),
+    }
+}
+
+/// Everything there is to know, in Prometheus' text format.
+fn render(metrics: &Metrics, (users, memory): (&Registry<Outbound>, &MemoryBudget)) -> String {
+    let mut out = String::new();
+    let counter = |m: &AtomicU64| m.load(Ordering::Relaxed);
+
+    let online = users.len();
+    sample(&mut out, ("chat_connected_users", "gauge", "Users logged in right now."), online);
+    header(&mut out, ("chat_channel_members", "gauge", "Users in each channel, and the lobby for anyone in none."));
+    {
+        // Don't take any other registry lock while holding this one
+        let channels = users.channels();
+        let _ = writeln!(out, "chat_channel_members{{channel=\"{LOBBY}\"}} {}", online.saturating_sub(channels.in_any()));
+        for (channel, members) in channels.sizes() {
+            let _ = writeln!(out, "chat_channel_members{{channel=\"{}\"}} {members}", escape(channel));
+        }
+    }
+
+    let (broadcast, failed) = (counter(&metrics.messages_broadcast), metrics.handshakes.failed());
+    sample(&mut out, ("chat_messages_broadcast_total", "counter", "Chat messages sent on to their channel."), broadcast);
+    sample(&mut out, ("chat_received_bytes_total", "counter", "Bytes read from clients."), counter(&metrics.bytes_in));
+    sample(&mut out, ("chat_sent_bytes_total", "counter", "Bytes written out to clients."), counter(&metrics.bytes_out));
+    sample(&mut out, ("chat_auth_failures_total", "counter", "Handshakes that didn't end up logged in."), failed);
+
+    header(&mut out, ("chat_handshake_duration_seconds", "histogram", "How long handshakes take, logged in or not."));
+    metrics.handshakes.durations().expose(&mut out, "chat_handshake_duration_seconds");
+    header(&mut out, ("chat_broadcast_latency_seconds", "histogram", "How long lines take from being read to being dealt with."));
+    metrics.broadcast.latency().expose(&mut out, "chat_broadcast_latency_seconds");
+
+    let rest = [
+        ("chat_oversized_lines_total", "Lines thrown away for being too long.", &metrics.oversized_lines),
+        ("chat_io_disconnects_total", "Connections cut off for going over their I/O budget.", &metrics.io_disconnects),
+        ("chat_flood_drops_total", "Messages dropped for coming too fast.", &metrics.flood_drops),
+        ("chat_flood_disconnects_total", "Connections cut off for flooding.", &metrics.flood_disconnects),
+        ("chat_ping_timeouts_total", "Connections hung up on for not answering a ping.", &metrics.ping_timeouts),
+        ("chat_dropped_frames_total", "Frames dropped for a full send queue.", &metrics.dropped_frames),
+    ];
+    for (name, help, value) in rest {
+        sample(&mut out, (name, "counter", help), counter(value));
+    }
+    let rejections = ("chat_memory_budget_rejections_total", "counter", "Messages dropped for going over the memory budget.");
+    sample(&mut out, rejections, memory.rejected());
+
+    // Only there once a STUN server's said, the address is the label and the value's always 1
+    if let Some(address) = *metrics.public_address.lock() {
+        header(&mut out, ("chat_public_address_info", "gauge", "Where the server's reachable from outside, as STUN saw it."));
+        let _ = writeln!(out, "chat_public_address_info{{address=\"{address}\"}} 1");
+    }
+    out
+}
+
+/// The `# HELP` and `# TYPE` lines every metric starts with.
+fn header(out: &mut String, (name, kind, help): (&str, &str, &str)) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+/// A metric with just the one unlabelled value.
+fn sample(out: &mut String, (name, kind, help): (&str, &str, &str), value: impl Display) {
+    header(out, (name, kind, help));
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// `value` made safe to go between the quotes of a label.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::user::User;
+    use super::*;
+
+    fn users() -> Arc<Registry<Outbound>> {
+        let users: Arc<Registry<Outbound>> = Default::default();
+        for nick in ["alice", "bob", "carol"] {
+            users.claim_nick(&User::new(nick), Outbound::new(4).0).unwrap();
+        }
+        users.channels().join(&User::new("alice"), "#rust");
+        users.channels().join(&User::new("bob"), "#rust");
+        users.channels().join(&User::new("bob"), "#\"quoted\"");
+        users
+    }
+
+    #[test]
+    fn renders_everything() {
+        let metrics = Metrics::default();
+        metrics.messages_broadcast.fetch_add(3, Ordering::Relaxed);
+        metrics.bytes_in.fetch_add(100, Ordering::Relaxed);
+        metrics.handshakes.record(IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_millis(2), false);
+
+        let memory = MemoryBudget::new(4);
+        assert!(memory.try_reserve(5).is_none());
+
+        let rendered = render(&metrics, (&users(), &memory));
+        assert!(!rendered.contains("chat_public_address_info"));
+        *metrics.public_address.lock() = Some("203.0.113.7:6667".parse().unwrap());
+        let rendered = render(&metrics, (&users(), &memory));
+        for line in [
+            "# TYPE chat_connected_users gauge",
+            "chat_connected_users 3",
+            "chat_channel_members{channel=\"&lobby\"} 1",
+            "chat_channel_members{channel=\"#rust\"} 2",
+            "chat_channel_members{channel=\"#\\\"quoted\\\"\"} 1",
+            "chat_messages_broadcast_total 3",
+            "chat_received_bytes_total 100",
+            "chat_sent_bytes_total 0",
+            "chat_auth_failures_total 1",
+            "chat_handshake_duration_seconds_bucket{le=\"0.001\"} 0",
+            "chat_handshake_duration_seconds_bucket{le=\"0.005\"} 1",
+            "chat_handshake_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "chat_broadcast_latency_seconds_count 0",
+            "chat_ping_timeouts_total 0",
+            "chat_memory_budget_rejections_total 1",
+            "chat_public_address_info{address=\"203.0.113.7:6667\"} 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "No `{line}` in:\n{rendered}");
+        }
+    }
+
+    #[test]
+    fn serves_scrapes() {
+        let address = listen("127.0.0.1:0".parse().unwrap(), Default::default(), (users(), MemoryBudget::new(1024))).unwrap();
+        let scrape = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let metrics = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n"), "{metrics}");
+        assert!(metrics.contains("\nchat_connected_users 3\n"));
+        assert!(scrape("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(scrape("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+    }
+}
@@ -0,0 +1,107 @@
+//! Flood protection for what a connection says. The I/O limits in `accounting` keep any one client from
+//! hogging the server's reading, but well under those it can still say enough to drown everyone else out, so
+//! its messages and commands go through a token bucket too. Whatever comes faster than that gets dropped
+//! rather than broadcast, and a client that keeps it up gets cut off.
+
+use std::time::{Duration, Instant};
+use crate::token_bucket::TokenBucket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloodLimits {
+    /// Messages per second a connection may keep up. 0 doesn't limit them at all.
+    pub messages_per_sec: u64,
+    /// Messages it may send all at once before that kicks in.
+    pub burst: u64,
+    /// Messages in a row that may get dropped before it's cut off. 0 never cuts it off.
+    pub max_dropped: u32,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self { messages_per_sec: 5, burst: 10, max_dropped: 20 }
+    }
+}
+
+/// What to do with a message after checking it against the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flood {
+    Ok,
+    /// Over the limit, so drop it. `first` if it's the first in a row, which is when to warn them, and
+    /// `retry_after` is how long until the next one would get through.
+    Dropped { first: bool, retry_after: Duration },
+    /// Over the limit for too long, cut them off.
+    Disconnect,
+}
+
+/// One connection's flood limits and how it's doing against them.
+#[derive(Debug, Clone)]
+pub struct FloodGuard {
+    limits: FloodLimits,
+    bucket: TokenBucket,
+    /// Messages dropped in a row, reset by one getting through.
+    dropped: u32,
+}
+
+impl FloodGuard {
+    pub fn new(limits: FloodLimits, now: Instant) -> Self {
+        Self { limits, bucket: TokenBucket::new(limits.messages_per_sec, limits.burst, now), dropped: 0 }
+    }
+
+    /// Checks one more message, said at `now`. Dropped ones still count, so flooding on only digs deeper.
+    pub fn check(&mut self, now: Instant) -> Flood {
+        let wait = self.bucket.take(1, now);
+        if wait.is_zero() {
+            self.dropped = 0;
+            return Flood::Ok;
+        }
+
+        self.dropped += 1;
+        match self.limits.max_dropped {
+            max if max > 0 && self.dropped > max => Flood::Disconnect,
+            // Past the debt, there has to be a whole token for the next one
+            _ => Flood::Dropped { first: self.dropped == 1, retry_after: wait + self.per_message() },
+        }
+    }
+
+    fn per_message(&self) -> Duration {
+        Duration::from_secs(1).checked_div(self.limits.messages_per_sec as u32).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_then_disconnects() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(FloodLimits { messages_per_sec: 2, burst: 3, max_dropped: 2 }, now);
+        assert_eq!([Flood::Ok; 3], [(); 3].map(|()| guard.check(now)));
+        assert_eq!(Flood::Dropped { first: true, retry_after: Duration::from_secs(1) }, guard.check(now));
+        assert_eq!(Flood::Dropped { first: false, retry_after: Duration::from_millis(1500) }, guard.check(now));
+        assert_eq!(Flood::Disconnect, guard.check(now));
+    }
+
+    #[test]
+    fn slowing_down_gets_through_again() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(FloodLimits { messages_per_sec: 2, burst: 1, max_dropped: 2 }, now);
+        assert_eq!(Flood::Ok, guard.check(now));
+        let Flood::Dropped { first: true, retry_after } = guard.check(now) else { panic!("Should've been dropped") };
+        // Has to wait off the dropped one too, which is what it's told to do
+        assert_eq!(Duration::from_secs(1), retry_after);
+        assert_eq!(Flood::Ok, guard.check(now + retry_after));
+        assert!(matches!(guard.check(now + retry_after), Flood::Dropped { first: true, .. }));
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(FloodLimits { messages_per_sec: 0, burst: 0, max_dropped: 0 }, now);
+        assert!((0..1000).all(|_| guard.check(now) == Flood::Ok));
+
+        let mut guard = FloodGuard::new(FloodLimits { messages_per_sec: 1, burst: 1, max_dropped: 0 }, now);
+        guard.check(now);
+        assert!((0..1000).all(|_| guard.check(now) != Flood::Disconnect));
+    }
+}
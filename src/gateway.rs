@@ -0,0 +1,550 @@
+use std::io::{BufRead, Read, Write};
+use serde::Deserialize;
+use tracing::warn;
+use crate::channel::{self, LOBBY};
+use crate::frame::{decode_message, encode_message, FrameLimits, PREFIX_LEN};
+use crate::irc::{Message, MAX_LINE_LEN};
+use crate::outbound::Outbound;
+use crate::response::{Login, PresenceChange, ServerMessage};
+use crate::server::ServerError;
+use crate::user::User;
+
+/// Clients that haven't registered after this many lines aren't going to.
+const MAX_REGISTRATION_LINES: usize = 32;
+
+/// What gets queued for an IRC connection. Mostly the same messages everyone gets, plus IRC-only replies
+/// the gateway sends itself, like `PONG`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Outgoing {
+    Server(ServerMessage),
+    Irc(Message),
+}
+
+/// The server's name as IRC wants it, i.e. without spaces.
+pub fn host(server_name: &str) -> String {
+    server_name.replace(' ', "-")
+}
+
+/// `nick!nick@host`, what messages from a user are prefixed with.
+fn source(user: &User, host: &str) -> String {
+    format!("{0}!{0}@{host}", user.name)
+}
+
+fn reply(host: &str, command: &str, params: &[&str]) -> Message {
+    Message::new(command, params.iter().copied()).with_prefix(host)
+}
+
+/// Reads lines until the client has said who it is with `NICK` and `USER`, and any `PASS` before them,
+/// answering anything else it asks in the meantime. `admit` gets to say whether they're let in, and they get
+/// to try another nick if it's taken. `PASS` goes for the nick's account too, if it's registered. `None` if
+/// they quit or never got in.
+pub fn register<R, W, T>(
+    reader: &mut R,
+    writer: &mut W,
+    host: &str,
+    mut admit: impl FnMut(&Login) -> Result<T, ServerError>,
+) -> std::io::Result<Option<(User, T)>>
+where
+    R: BufRead,
+    W: Write,
+{
+    let (mut nick, mut has_user, mut password) = (None, false, None);
+    let mut line = Vec::with_capacity(MAX_LINE_LEN);
+
+    for _ in 0..MAX_REGISTRATION_LINES {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE_LEN as u64).read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let Ok(msg) = Message::parse(&String::from_utf8_lossy(&line)) else {
+            continue;
+        };
+
+        let answer = match (msg.command.as_str(), msg.params.as_slice()) {
+            ("CAP", [ls, ..]) if ls.eq_ignore_ascii_case("LS") => Some(reply(host, "CAP", &["*", "LS", ""])),
+            ("PASS", [given, ..]) => {
+                password = Some(given.clone());
+                None
+            }
+            ("CAP" | "PASS", _) => None,
+            ("NICK", [name, ..]) => {
+                nick = Some(name.clone());
+                None
+            }
+            ("NICK", []) => Some(reply(host, "431", &["*", "No nickname given"])),
+            ("USER", [_, _, _, _, ..]) => {
+                has_user = true;
+                None
+            }
+            ("USER", _) => Some(reply(host, "461", &["*", "USER", "Not enough parameters"])),
+            ("PING", [token, ..]) => Some(reply(host, "PONG", &[host, token])),
+            ("QUIT", _) => return Ok(None),
+            _ => Some(reply(host, "451", &["*", "You have not registered"])),
+        };
+        if let Some(answer) = answer {
+            writer.write_all(answer.to_line().as_bytes())?;
+        }
+
+        let Some(name) = nick.as_deref().filter(|_| has_user) else {
+            continue;
+        };
+        let login = Login::new(User::new(name), password.clone()).identified(password.clone());
+        let refusal = match admit(&login) {
+            Ok(admitted) => return Ok(Some((login.user, admitted))),
+            Err(e @ ServerError::InvalidUser(_)) => reply(host, "432", &["*", name, &e.to_string()]),
+            Err(ServerError::Maintenance(message)) => {
+                writer.write_all(Message::new("ERROR", [message]).to_line().as_bytes())?;
+                return Ok(None);
+            }
+            Err(e @ ServerError::ShuttingDown) => {
+                writer.write_all(Message::new("ERROR", [e.to_string()]).to_line().as_bytes())?;
+                return Ok(None);
+            }
+            Err(ServerError::Banned(why)) => {
+                writer.write_all(reply(host, "465", &["*", "You are banned from this server"]).to_line().as_bytes())?;
+                writer.write_all(Message::new("ERROR", [why]).to_line().as_bytes())?;
+                return Ok(None);
+            }
+            Err(ServerError::BadPassword) => {
+                writer.write_all(reply(host, "464", &["*", "Password incorrect"]).to_line().as_bytes())?;
+                writer.write_all(Message::new("ERROR", ["Bad password"]).to_line().as_bytes())?;
+                return Ok(None);
+            }
+            Err(e) => reply(host, "433", &["*", name, &e.to_string()]),
+        };
+        writer.write_all(refusal.to_line().as_bytes())?;
+        nick = None;
+    }
+
+    writer.write_all(Message::new("ERROR", ["Took too long to register"]).to_line().as_bytes())?;
+    Ok(None)
+}
+
+/// What a client gets right after registering: the welcome numerics, then the MOTD if there is one.
+pub fn welcome(user: &User, host: &str, motd: Option<&str>) -> Vec<Message> {
+    let nick = user.name.as_str();
+    let mut welcome = vec![
+        reply(host, "001", &[nick, &format!("Welcome to {host}, {}", source(user, host))]),
+        reply(host, "002", &[nick, &format!("Your host is {host}, running basic-irc")]),
+        reply(host, "003", &[nick, "This server was created sometime"]),
+        reply(host, "004", &[nick, host, "basic-irc", "o", "o"]),
+    ];
+
+    match motd {
+        Some(motd) => {
+            welcome.push(reply(host, "375", &[nick, &format!("- {host} Message of the day -")]));
+            welcome.extend(motd.lines().map(|line| reply(host, "372", &[nick, &format!("- {line}")])));
+            welcome.push(reply(host, "376", &[nick, "End of MOTD command"]));
+        }
+        None => welcome.push(reply(host, "422", &[nick, "MOTD File is missing"])),
+    }
+    welcome
+}
+
+/// How a message for `me` reads over IRC. Messages with line breaks in them become one per line.
+fn to_irc(message: &ServerMessage, me: &User, host: &str) -> Vec<Message> {
+    if let Some((from, to)) = message.as_renamed() {
+        return vec![Message::new("NICK", [to]).with_prefix(source(&User::new(from), host))];
+    }
+    let joined;
+    let (prefix, command, target, text) = match message {
+        ServerMessage::Chat { from, text, channel, .. } => {
+            (source(from, host), "PRIVMSG", channel.as_deref().unwrap_or(LOBBY), text)
+        }
+        ServerMessage::Private { from, text, .. } => (source(from, host), "PRIVMSG", me.name.as_str(), text),
+        ServerMessage::Notice { text, .. } => (host.to_string(), "NOTICE", me.name.as_str(), text),
+        ServerMessage::Presence { user, change: PresenceChange::Quit { reason } } => {
+            return vec![Message::new("QUIT", [reason.as_deref().unwrap_or("Quit")]).with_prefix(source(user, host))];
+        }
+        // IRC has quitting the server, but only joining channels, so someone connecting is just a notice
+        ServerMessage::Presence { user, change } => {
+            joined = format!("{user} {change}");
+            (host.to_string(), "NOTICE", me.name.as_str(), &joined)
+        }
+        ServerMessage::Names { channel, users } => {
+            let channel = channel.as_deref().unwrap_or("*");
+            let names = users.iter().map(|member| member.user.name.as_str()).collect::<Vec<_>>().join(" ");
+            return vec![
+                reply(host, "353", &[&me.name, "=", channel, &names]),
+                reply(host, "366", &[&me.name, channel, "End of /NAMES list"]),
+            ];
+        }
+        ServerMessage::Channels { channels } => {
+            let listed = channels.iter().map(|(channel, members)| reply(host, "322", &[&me.name, channel, &members.to_string(), ""]));
+            return listed.chain([reply(host, "323", &[&me.name, "End of /LIST"])]).collect();
+        }
+        ServerMessage::Sequenced { message, .. } => return to_irc(message, me, host),
+        ServerMessage::Ping { token } => return vec![Message::new("PING", [token.to_string()])],
+        // What IRC servers say right before they close the link
+        ServerMessage::Disconnected { reason } => return vec![Message::new("ERROR", [format!("Closing Link: {reason}")])],
+    };
+
+    text.lines().map(|line| Message::new(command, [target, line]).with_prefix(prefix.clone())).collect()
+}
+
+/// Sits between the writer thread and an IRC client, turning the frames the server queues into IRC lines.
+pub struct Translator<W> {
+    inner: W,
+    me: User,
+    host: String,
+    /// Frames don't have to arrive in one write, so whatever's left of one waits here.
+    pending: Vec<u8>,
+}
+
+impl<W> Translator<W> {
+    pub fn new(inner: W, me: User, host: impl Into<String>) -> Self {
+        Self { inner, me, host: host.into(), pending: Vec::new() }
+    }
+}
+
+impl<W: Write> Write for Translator<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        while self.pending.len() >= PREFIX_LEN {
+            let len = u32::from_be_bytes(self.pending[..PREFIX_LEN].try_into().expect("Checked the length")) as usize;
+            if self.pending.len() < PREFIX_LEN + len {
+                break;
+            }
+
+            let frame: Vec<u8> = self.pending.drain(..PREFIX_LEN + len).collect();
+            let lines = match decode_message(&frame[PREFIX_LEN..], &FrameLimits::default()) {
+                Ok(Outgoing::Server(message)) => to_irc(&message, &self.me, &self.host),
+                Ok(Outgoing::Irc(msg)) => vec![msg],
+                Err(e) => {
+                    warn!("[IRC] Couldn't translate a frame for {}: {e}", self.me);
+                    continue;
+                }
+            };
+            for line in lines {
+                self.inner.write_all(line.to_line().as_bytes())?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sits between an IRC client and `handle_chat`, turning what the client sends into the lines and
+/// `/commands` native clients would send. Anything that only needs an IRC answer, like `PING`, gets
+/// answered straight away through `outbound`.
+pub struct Inbound<R> {
+    inner: R,
+    me: User,
+    host: String,
+    outbound: Outbound,
+    raw: Vec<u8>,
+    translated: Vec<u8>,
+    pos: usize,
+    quit: bool,
+}
+
+impl<R> Inbound<R> {
+    pub fn new(inner: R, me: User, host: impl Into<String>, outbound: Outbound) -> Self {
+        Self {
+            inner,
+            me,
+            host: host.into(),
+            outbound,
+            raw: Vec::with_capacity(MAX_LINE_LEN),
+            translated: Vec::new(),
+            pos: 0,
+            quit: false,
+        }
+    }
+
+    fn answer(&self, msg: Message) {
+        if let Ok(frame) = encode_message(&msg) {
+            // Full means they're not keeping up anyway, and closed means they're gone
+            let _ = self.outbound.send(frame.into());
+        }
+    }
+
+    /// The native lines for one IRC message, answering it directly if it needs that. Sets `quit` on `QUIT`.
+    fn translate(&mut self, msg: &Message) -> Vec<String> {
+        let (host, nick) = (self.host.as_str(), self.me.name.as_str());
+        let me = source(&self.me, host);
+
+        match (msg.command.as_str(), msg.params.as_slice()) {
+            ("PRIVMSG" | "NOTICE", [targets, text, ..]) => targets
+                .split(',')
+                .map(|target| match target {
+                    _ if target == LOBBY || target.starts_with('#') => format!("/say {target} {text}"),
+                    _ => format!("/msg {target} {text}"),
+                })
+                .collect(),
+            ("PRIVMSG", [_]) => {
+                self.answer(reply(host, "412", &[nick, "No text to send"]));
+                vec![]
+            }
+            ("PRIVMSG", []) => {
+                self.answer(reply(host, "411", &[nick, "No recipient given (PRIVMSG)"]));
+                vec![]
+            }
+            ("JOIN" | "PART", [channels, ..]) => {
+                let command = msg.command.to_ascii_lowercase();
+                channels
+                    .split(',')
+                    .filter_map(|channel| match channel::validate(channel) {
+                        Ok(()) => {
+                            self.answer(Message::new(msg.command.as_str(), [channel]).with_prefix(me.as_str()));
+                            Some(format!("/{command} {channel}"))
+                        }
+                        Err(_) => {
+                            self.answer(reply(host, "403", &[nick, channel, "No such channel"]));
+                            None
+                        }
+                    })
+                    .collect()
+            }
+            ("NAMES", []) => vec!["/names".to_string()],
+            ("LIST", _) => vec!["/list".to_string()],
+            ("NAMES", [channels, ..]) => channels.split(',').map(|channel| format!("/names {channel}")).collect(),
+            ("KILL", [target, reason, ..]) => vec![format!("/kill {target} {reason}")],
+            ("WALLOPS", [text, ..]) => vec![format!("/wallops {text}")],
+            ("PING", [token, ..]) => {
+                self.answer(reply(host, "PONG", &[host, token]));
+                vec![]
+            }
+            ("QUIT", _) => {
+                self.quit = true;
+                vec![]
+            }
+            ("USER" | "PASS", _) => {
+                self.answer(reply(host, "462", &[nick, "You may not reregister"]));
+                vec![]
+            }
+            // Whatever the last parameter is, it's the token, and all that matters is that it came back
+            ("PONG", [.., token]) => vec![format!("/pong {token}")],
+            ("PONG" | "MODE" | "CAP", _) => vec![],
+            (command, [] | [_]) if ["JOIN", "PART", "KILL", "WALLOPS", "PING"].contains(&command) => {
+                self.answer(reply(host, "461", &[nick, command, "Not enough parameters"]));
+                vec![]
+            }
+            (command, _) => {
+                self.answer(reply(host, "421", &[nick, command, "Unknown command"]));
+                vec![]
+            }
+        }
+    }
+
+    /// Reads and translates one line from the client. `false` once there's nothing more coming.
+    fn next_line(&mut self) -> std::io::Result<bool>
+    where
+        R: BufRead,
+    {
+        if self.quit {
+            return Ok(false);
+        }
+
+        self.raw.clear();
+        let n = self.inner.by_ref().take(MAX_LINE_LEN as u64).read_until(b'\n', &mut self.raw)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        if n == MAX_LINE_LEN && self.raw.last() != Some(&b'\n') {
+            // Throw away the rest of it, however long it is
+            let mut rest = Vec::new();
+            while self.inner.by_ref().take(MAX_LINE_LEN as u64).read_until(b'\n', &mut rest)? == MAX_LINE_LEN
+                && rest.last() != Some(&b'\n')
+            {
+                rest.clear();
+            }
+            self.answer(reply(&self.host, "417", &[&self.me.name, "Input line was too long"]));
+            return Ok(true);
+        }
+
+        // Malformed messages get ignored, like RFC 2812 says
+        if let Ok(msg) = Message::parse(&String::from_utf8_lossy(&self.raw)) {
+            for line in self.translate(&msg) {
+                self.translated.extend(line.into_bytes());
+                self.translated.push(b'\n');
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> Read for Inbound<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Inbound<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.pos == self.translated.len() {
+            self.translated.clear();
+            self.pos = 0;
+            if !self.next_line()? {
+                break;
+            }
+        }
+        Ok(&self.translated[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.translated.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc::Receiver;
+    use crate::frame::read_message;
+    use crate::outbound::Frame;
+    use crate::response::Member;
+    use crate::user::UserError;
+    use super::*;
+
+    fn lines(bytes: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(bytes).lines().map(str::to_string).collect()
+    }
+
+    fn answers(queue: &Receiver<Frame>) -> Vec<String> {
+        queue
+            .try_iter()
+            .map(|frame| read_message::<_, Message>(&mut Cursor::new(frame.to_vec()), &FrameLimits::default()).unwrap())
+            .map(|msg| msg.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn registers_after_nick_and_user() {
+        let input = "CAP LS 302\r\nNICK alice\r\nPING early\r\nUSER alice 0 * :Alice A\r\nPRIVMSG #x :hi\r\n";
+        let mut reader = Cursor::new(input);
+        let mut output = Vec::new();
+
+        let (user, ()) = register(&mut reader, &mut output, "irc", |_| Ok(())).unwrap().unwrap();
+        assert_eq!(User::new("alice"), user);
+        assert_eq!(vec![":irc CAP * LS :", ":irc PONG irc early"], lines(&output));
+        // Whatever comes after registering is left for chat
+        assert_eq!("PRIVMSG #x :hi\r\n", &input[reader.position() as usize..]);
+    }
+
+    #[test]
+    fn taken_nicks_can_be_retried() {
+        let mut reader = Cursor::new("NICK bad nick\r\nUSER a 0 * :A\r\nNICK taken\r\nNICK alice\r\n");
+        let mut output = Vec::new();
+
+        let registered = register(&mut reader, &mut output, "irc", |login| match login.user.name.as_str() {
+            "bad" => Err(ServerError::InvalidUser(UserError::BadNick)),
+            "taken" => Err(ServerError::AlreadyConnected(login.user.name.clone())),
+            _ => Ok(()),
+        });
+
+        assert_eq!(User::new("alice"), registered.unwrap().unwrap().0);
+        let output = lines(&output);
+        assert!(output[0].starts_with(":irc 432 * bad :"));
+        assert!(output[1].starts_with(":irc 433 * taken :"));
+    }
+
+    #[test]
+    fn maintenance_and_quitting_end_registration() {
+        let mut output = Vec::new();
+        let maintenance = |_: &Login| -> Result<(), ServerError> { Err(ServerError::Maintenance("back soon".to_string())) };
+        assert!(register(&mut Cursor::new("NICK a\r\nUSER a 0 * :A\r\n"), &mut output, "irc", maintenance).unwrap().is_none());
+        assert_eq!(vec!["ERROR :back soon"], lines(&output));
+
+        assert!(register(&mut Cursor::new("QUIT\r\nNICK a\r\nUSER a 0 * :A\r\n"), &mut Vec::new(), "irc", |_| Ok(())).unwrap().is_none());
+        assert!(register(&mut Cursor::new("NICK a\r\n"), &mut Vec::new(), "irc", |_| Ok(())).unwrap().is_none());
+    }
+
+    #[test]
+    fn passwords_come_before_nick_and_user() {
+        let knows = |login: &Login| if login.knows(Some("sesame")) { Ok(()) } else { Err(ServerError::BadPassword) };
+        let mut reader = Cursor::new("PASS sesame\r\nNICK alice\r\nUSER a 0 * :A\r\n");
+        assert!(register(&mut reader, &mut Vec::new(), "irc", knows).unwrap().is_some());
+
+        let mut output = Vec::new();
+        let mut reader = Cursor::new("PASS nope\r\nNICK alice\r\nUSER a 0 * :A\r\n");
+        assert!(register(&mut reader, &mut output, "irc", knows).unwrap().is_none());
+        assert_eq!(vec![":irc 464 * :Password incorrect", "ERROR :Bad password"], lines(&output));
+    }
+
+    #[test]
+    fn translates_outgoing_frames() {
+        let me = User::new("bob");
+        let mut output = Vec::new();
+        let mut translator = Translator::new(&mut output, me.clone(), "irc");
+
+        let chat = ServerMessage::Chat { from: User::new("alice"), text: "hi".to_string(), received_ms: 0, channel: None };
+        let private = ServerMessage::Private { from: User::new("alice"), to: me, text: "psst".to_string() };
+        let notice = ServerMessage::notice("one\ntwo");
+        let renamed = ServerMessage::renamed(&User::new("alice"), &User::new("alicia"));
+        let mut frames = Vec::new();
+        for frame in [encode_message(&chat), encode_message(&private), encode_message(&notice), encode_message(&renamed)] {
+            frames.extend(frame.unwrap());
+        }
+        let joined = ServerMessage::Presence { user: User::new("carol"), change: PresenceChange::Joined };
+        let quit = ServerMessage::Presence { user: User::new("carol"), change: PresenceChange::Quit { reason: None } };
+        let names = ServerMessage::Names { channel: None, users: vec![Member { user: User::new("alice"), idle_secs: Some(5) }] };
+        let channels = ServerMessage::Channels { channels: [("#rust".to_string(), 2)].into() };
+        let ping = ServerMessage::Ping { token: 3 };
+        for message in [joined, quit, names, channels, ping] {
+            frames.extend(encode_message(&message).unwrap());
+        }
+        frames.extend(encode_message(&Message::new("PONG", ["irc", "x"])).unwrap());
+
+        // Split mid-frame, like a batched write could be
+        translator.write_all(&frames[..7]).unwrap();
+        translator.write_all(&frames[7..]).unwrap();
+        let expected = [
+            ":alice!alice@irc PRIVMSG &lobby hi",
+            ":alice!alice@irc PRIVMSG bob psst",
+            ":irc NOTICE bob one",
+            ":irc NOTICE bob two",
+            ":alice!alice@irc NICK alicia",
+            ":irc NOTICE bob :carol has joined",
+            ":carol!carol@irc QUIT Quit",
+            ":irc 353 bob = * alice",
+            ":irc 366 bob * :End of /NAMES list",
+            ":irc 322 bob #rust 2 :",
+            ":irc 323 bob :End of /LIST",
+            "PING 3",
+            "PONG irc x",
+        ];
+        assert_eq!(Vec::from(expected), lines(&output));
+    }
+
+    #[test]
+    fn translates_incoming_lines() {
+        let (outbound, queue) = Outbound::new(16);
+        let input = "PRIVMSG #rust,bob :hello there\r\nJOIN #a,nope\r\nPING t\r\nPONG irc :3\r\nFROB\r\nNAMES #a\r\nLIST\r\nPART #a :bye\r\nQUIT :later\r\nPRIVMSG #rust :too late\r\n";
+        let mut inbound = Inbound::new(Cursor::new(input), User::new("alice"), "irc", outbound);
+
+        let mut translated = String::new();
+        inbound.read_to_string(&mut translated).unwrap();
+        assert_eq!("/say #rust hello there\n/msg bob hello there\n/join #a\n/pong 3\n/names #a\n/list\n/part #a\n", translated);
+        let expected = [
+            ":alice!alice@irc JOIN #a",
+            ":irc 403 alice nope :No such channel",
+            ":irc PONG irc t",
+            ":irc 421 alice FROB :Unknown command",
+            ":alice!alice@irc PART #a",
+        ];
+        assert_eq!(Vec::from(expected), answers(&queue));
+    }
+
+    #[test]
+    fn overlong_lines_get_skipped() {
+        let (outbound, queue) = Outbound::new(16);
+        let input = format!("PRIVMSG #a :{}\r\nPRIVMSG #a :short\r\n", "x".repeat(2 * MAX_LINE_LEN));
+        let mut inbound = Inbound::new(Cursor::new(input), User::new("alice"), "irc", outbound);
+
+        let mut translated = String::new();
+        inbound.read_to_string(&mut translated).unwrap();
+        assert_eq!("/say #a short\n", translated);
+        assert_eq!(vec![":irc 417 alice :Input line was too long"], answers(&queue));
+    }
+}
@@ -0,0 +1,184 @@
+//! The last few messages said in the lobby and each channel, shown to people when they connect or join so
+//! they don't land in the middle of a conversation with no idea what it's about. Optionally kept on disk too,
+//! one message per line, so a restart doesn't wipe it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use crate::channel::LOBBY;
+use crate::response::ServerMessage;
+
+/// Chat kept per channel, up to `depth` each, past which the oldest get forgotten.
+#[derive(Debug, Default)]
+pub struct History {
+    depth: usize,
+    /// Keyed by channel, with the lobby under `LOBBY`.
+    buffers: HashMap<String, VecDeque<ServerMessage>>,
+    store: Option<Store>,
+}
+
+/// The file history is appended to, which gets rewritten with just what's kept once it's grown too far past it.
+#[derive(Debug)]
+struct Store {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        Self { depth, ..Default::default() }
+    }
+
+    /// History kept in `path` as well, picking up whatever's already there.
+    pub fn open(depth: usize, path: &Path) -> std::io::Result<Self> {
+        let mut history = Self::new(depth);
+        let mut skipped = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(message) => history.keep(message),
+                        Err(_) => skipped += 1,
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if skipped > 0 {
+            warn!("[HISTORY] Skipped {skipped} lines in {} that weren't messages", path.display());
+        }
+
+        history.store = Some(history.rewrite(path)?);
+        Ok(history)
+    }
+
+    /// Keeps `message` if it's chat, writing it to disk too if there's a file.
+    pub fn push(&mut self, message: &ServerMessage) {
+        if self.depth == 0 || !matches!(message, ServerMessage::Chat { .. }) {
+            return;
+        }
+        self.keep(message.clone());
+        if let Err(e) = self.append(message) {
+            let path = self.store.as_ref().map(|store| store.path.display().to_string()).unwrap_or_default();
+            warn!("[HISTORY] Couldn't write to {path}, only keeping history in memory from now on: {e}");
+            self.store = None;
+        }
+    }
+
+    /// What was said lately in `channel`, or the lobby if `None`, oldest first.
+    pub fn recent(&self, channel: Option<&str>) -> impl Iterator<Item = &ServerMessage> {
+        self.buffers.get(channel.unwrap_or(LOBBY)).into_iter().flatten()
+    }
+
+    fn keep(&mut self, message: ServerMessage) {
+        let ServerMessage::Chat { channel, .. } = &message else {
+            return;
+        };
+        if self.depth == 0 {
+            return;
+        }
+
+        let buffer = self.buffers.entry(channel.as_deref().unwrap_or(LOBBY).to_string()).or_default();
+        if buffer.len() == self.depth {
+            buffer.pop_front();
+        }
+        buffer.push_back(message);
+    }
+
+    fn append(&mut self, message: &ServerMessage) -> std::io::Result<()> {
+        let kept = self.buffers.values().map(VecDeque::len).sum::<usize>();
+        let Some(store) = &mut self.store else {
+            return Ok(());
+        };
+
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        store.file.write_all(&line)?;
+        store.lines += 1;
+
+        if store.lines > 2 * kept.max(self.depth) {
+            let path = store.path.clone();
+            self.store = Some(self.rewrite(&path)?);
+        }
+        Ok(())
+    }
+
+    /// Writes just what's kept to `path`, swapping it in whole so a crash halfway leaves the old file.
+    fn rewrite(&self, path: &Path) -> std::io::Result<Store> {
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        let mut lines = 0;
+        for message in self.buffers.values().flatten() {
+            serde_json::to_writer(&mut file, message)?;
+            file.write_all(b"\n")?;
+            lines += 1;
+        }
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Store { path: path.to_path_buf(), file, lines })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::user::User;
+    use super::*;
+
+    fn said(channel: Option<&str>, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new("alice"), text: text.to_string(), received_ms: 1, channel: channel.map(str::to_string) }
+    }
+
+    fn texts<'a>(messages: impl Iterator<Item = &'a ServerMessage>) -> Vec<&'a str> {
+        messages
+            .map(|message| match message {
+                ServerMessage::Chat { text, .. } => text.as_str(),
+                other => panic!("Didn't expect {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_latest_per_channel() {
+        let mut history = History::new(2);
+        for (channel, text) in [(None, "1"), (Some("#rust"), "a"), (None, "2"), (None, "3")] {
+            history.push(&said(channel, text));
+        }
+        history.push(&ServerMessage::notice("not chat"));
+
+        assert_eq!(vec!["2", "3"], texts(history.recent(None)));
+        assert_eq!(vec!["a"], texts(history.recent(Some("#rust"))));
+        assert!(texts(history.recent(Some("#nope"))).is_empty());
+
+        let mut none = History::new(0);
+        none.push(&said(None, "1"));
+        assert!(texts(none.recent(None)).is_empty());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("basic-irc-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut history = History::open(3, &path).unwrap();
+        for i in 0..20 {
+            history.push(&said(None, &i.to_string()));
+        }
+        history.push(&said(Some("#rust"), "a"));
+        // Rewritten along the way, so the file doesn't grow forever
+        assert!(fs::read_to_string(&path).unwrap().lines().count() <= 2 * 4);
+
+        fs::write(&path, format!("{}not json\n", fs::read_to_string(&path).unwrap())).unwrap();
+        let reopened = History::open(2, &path).unwrap();
+        assert_eq!(vec!["18", "19"], texts(reopened.recent(None)));
+        assert_eq!(vec!["a"], texts(reopened.recent(Some("#rust"))));
+
+        drop(reopened);
+        fs::remove_file(&path).unwrap();
+    }
+}
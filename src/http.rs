@@ -0,0 +1,217 @@
+//! Just enough HTTP/1.1 for the server's little side endpoints: one request per connection, small bodies
+//! with a `Content-Length` only, and the connection closed after the response.
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+/// Most a request line and its headers may add up to.
+const MAX_HEAD: u64 = 8 * 1024;
+/// Most a request body may be.
+const MAX_BODY: usize = 64 * 1024;
+
+/// The parts of a request the endpoints care about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Without the query string.
+    pub path: String,
+    /// Names lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// The first header called `name`, which has to be lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+}
+
+/// The responses there are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    MethodNotAllowed,
+}
+
+impl Status {
+    fn line(self) -> &'static str {
+        match self {
+            Status::Ok => "200 OK",
+            Status::BadRequest => "400 Bad Request",
+            Status::Unauthorized => "401 Unauthorized",
+            Status::NotFound => "404 Not Found",
+            Status::MethodNotAllowed => "405 Method Not Allowed",
+        }
+    }
+}
+
+/// Reads a request off `stream`, body and all.
+pub fn read_request(stream: impl Read) -> std::io::Result<Request> {
+    let bad = |why: &str| std::io::Error::new(ErrorKind::InvalidData, why.to_string());
+    let mut reader = BufReader::new(stream.take(MAX_HEAD));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) = (words.next(), words.next(), words.next(), words.next()) else {
+        return Err(bad("Request line isn't `<method> <path> <version>`"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(bad("Only HTTP/1.x is spoken here"));
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        headers: Vec::new(),
+        body: String::new(),
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(bad("Headers never ended"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| bad("Header without a `:`"))?;
+        request.headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let len = match request.header("content-length") {
+        Some(len) => len.parse().map_err(|_| bad("Content-Length isn't a number"))?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return Err(bad("Body's too big"));
+    }
+    // Past the head now, so the body gets its own limit
+    let mut body = vec![0; len];
+    reader.get_mut().set_limit(len as u64);
+    reader.read_exact(&mut body)?;
+    request.body = String::from_utf8(body).map_err(|_| bad("Body isn't UTF-8"))?;
+    Ok(request)
+}
+
+/// Reads from a stream until a deadline, however many reads it takes, so a request can't be dragged out by sending
+/// it a byte at a time.
+pub struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    pub fn new(stream: &'a TcpStream, within: Duration) -> Self {
+        Self { stream, until: Instant::now() + within }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// `segment` of a path with its `%XX` escapes turned back into what they stand for, or `None` if they don't stand
+/// for UTF-8.
+pub fn percent_decode(segment: &str) -> Option<String> {
+    let digit = |byte: Option<u8>| char::from(byte?).to_digit(16);
+    let mut bytes = segment.bytes();
+    let mut decoded = Vec::with_capacity(segment.len());
+    while let Some(byte) = bytes.next() {
+        decoded.push(match byte {
+            b'%' => (digit(bytes.next())? * 16 + digit(bytes.next())?) as u8,
+            byte => byte,
+        });
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Writes a whole response with `body`.
+pub fn respond(mut stream: impl Write, status: Status, content_type: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        status.line(),
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    #[test]
+    fn reads_request_line() {
+        let request = read_request(Cursor::new("GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\nextra")).unwrap();
+        assert_eq!(("GET", "/metrics", ""), (request.method.as_str(), request.path.as_str(), request.body.as_str()));
+        assert_eq!((Some("localhost"), None), (request.header("host"), request.header("accept")));
+    }
+
+    #[test]
+    fn reads_body() {
+        let request = read_request(Cursor::new("POST /notice HTTP/1.1\r\nCONTENT-LENGTH: 5\r\n\r\nhello, and more")).unwrap();
+        assert_eq!("hello", request.body);
+        let too_big = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert_eq!(ErrorKind::InvalidData, read_request(Cursor::new(too_big)).unwrap_err().kind());
+        let short = read_request(Cursor::new("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\nhello"));
+        assert_eq!(ErrorKind::UnexpectedEof, short.unwrap_err().kind());
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        for bad in ["hello\r\n\r\n", "GET / SPDY/3\r\n\r\n", "GET / HTTP/1.1\r\nHost: x\r\n", &"x".repeat(10_000)] {
+            assert_eq!(ErrorKind::InvalidData, read_request(Cursor::new(bad)).unwrap_err().kind(), "{bad:.20}");
+        }
+    }
+
+    #[test]
+    fn percent_decodes() {
+        assert_eq!(Some("bob"), percent_decode("bob").as_deref());
+        assert_eq!(Some("b b/ö"), percent_decode("b%20b%2F%C3%b6").as_deref());
+        for bad in ["%", "%4", "%zz", "%+1", "%FF"] {
+            assert_eq!(None, percent_decode(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn deadline_covers_the_whole_request() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let trickle = std::thread::spawn(move || {
+            // Every byte well inside any per-read timeout, but never the whole request
+            while client.write_all(b"X").is_ok() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let started = Instant::now();
+        let e = read_request(Deadline::new(&server, Duration::from_millis(100))).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock), "{e:?}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(server);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn response() {
+        let mut out = Vec::new();
+        respond(&mut out, Status::NotFound, "text/plain", "nope\n").unwrap();
+        let head = "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n";
+        assert_eq!(format!("{head}\r\nnope\n"), String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Longest line RFC 2812 allows, counting the CR-LF.
+pub const MAX_LINE_LEN: usize = 512;
+const MAX_PARAMS: usize = 15;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum IrcError {
+    #[error("Empty message")]
+    Empty,
+    #[error("Message has a prefix but no command")]
+    NoCommand,
+    #[error("Command isn't letters or a 3 digit numeric: `{0}`")]
+    BadCommand(String),
+}
+
+/// One IRC message, i.e. `[:prefix] COMMAND [params] [:trailing]`, the trailing param being the last of
+/// `params` once parsed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl Message {
+    pub fn new<P: Into<String>>(command: impl Into<String>, params: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            prefix: None,
+            command: command.into(),
+            params: params.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Parses one line, with or without its CR-LF. Commands come back uppercase, since they're case-insensitive.
+    pub fn parse(line: &str) -> Result<Self, IrcError> {
+        let mut rest = line.trim_end_matches(['\r', '\n']).trim_start_matches(' ');
+        if rest.is_empty() {
+            return Err(IrcError::Empty);
+        }
+
+        let mut prefix = None;
+        if let Some(after) = rest.strip_prefix(':') {
+            let (p, after) = after.split_once(' ').ok_or(IrcError::NoCommand)?;
+            prefix = Some(p.to_string());
+            rest = after.trim_start_matches(' ');
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        let numeric = command.len() == 3 && command.bytes().all(|b| b.is_ascii_digit());
+        if command.is_empty() || !(numeric || command.bytes().all(|b| b.is_ascii_alphabetic())) {
+            return Err(IrcError::BadCommand(command.to_string()));
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            // Everything after a `:`, or the 15th param, is the trailing one and can have spaces
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            if params.len() == MAX_PARAMS - 1 {
+                params.push(rest.to_string());
+                break;
+            }
+
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = after;
+        }
+
+        Ok(Self { prefix, command: command.to_ascii_uppercase(), params })
+    }
+
+    /// The message as it goes on the wire, CR-LF and all. Anything past 512 bytes gets cut off the end, and
+    /// line breaks in params become spaces so they can't start a message of their own.
+    pub fn to_line(&self) -> String {
+        let clean = |s: &String| s.replace(['\r', '\n'], " ");
+        let message = Self {
+            prefix: self.prefix.as_ref().map(clean),
+            command: clean(&self.command),
+            params: self.params.iter().map(clean).collect(),
+        };
+
+        let mut line = message.to_string();
+        if line.len() > MAX_LINE_LEN - 2 {
+            let mut end = MAX_LINE_LEN - 2;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        line.push_str("\r\n");
+        line
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{prefix} ")?;
+        }
+        write!(f, "{}", self.command)?;
+
+        for (i, param) in self.params.iter().enumerate() {
+            let last = i == self.params.len() - 1;
+            if last && (param.is_empty() || param.contains(' ') || param.starts_with(':')) {
+                write!(f, " :{param}")?;
+            } else {
+                write!(f, " {param}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_everything() {
+        let msg = Message::parse(":alice!a@host PRIVMSG #rust :hello there :)\r\n").unwrap();
+        assert_eq!(Some("alice!a@host"), msg.prefix.as_deref());
+        assert_eq!("PRIVMSG", msg.command);
+        assert_eq!(vec!["#rust", "hello there :)"], msg.params);
+    }
+
+    #[test]
+    fn parses_the_odd_bits() {
+        assert_eq!(Message::new("NICK", ["alice"]), Message::parse("nick   alice").unwrap());
+        assert_eq!(Message::new("QUIT", Vec::<String>::new()), Message::parse("QUIT").unwrap());
+        assert_eq!(Message::new("TOPIC", ["#rust", ""]), Message::parse("TOPIC #rust :").unwrap());
+        assert_eq!(Message::new("001", ["alice", "Welcome"]).with_prefix("irc"), Message::parse(":irc 001 alice Welcome").unwrap());
+
+        // The 15th param takes the rest of the line, spaces and all
+        let line = format!("CMD {} and the rest", (1..16).map(|i| i.to_string()).collect::<Vec<_>>().join(" "));
+        assert_eq!("15 and the rest", Message::parse(&line).unwrap().params[14]);
+    }
+
+    #[test]
+    fn bad_messages() {
+        assert_eq!(Err(IrcError::Empty), Message::parse("\r\n"));
+        assert_eq!(Err(IrcError::NoCommand), Message::parse(":prefix"));
+        assert_eq!(Err(IrcError::BadCommand("PRIV-MSG".to_string())), Message::parse("PRIV-MSG x"));
+        assert_eq!(Err(IrcError::BadCommand("1234".to_string())), Message::parse("1234 x"));
+    }
+
+    #[test]
+    fn serializes() {
+        let msg = Message::new("PRIVMSG", ["#rust", "hello there"]).with_prefix("alice");
+        assert_eq!(":alice PRIVMSG #rust :hello there\r\n", msg.to_line());
+        assert_eq!("PING token\r\n", Message::new("PING", ["token"]).to_line());
+        assert_eq!("PRIVMSG bob ::)\r\n", Message::new("PRIVMSG", ["bob", ":)"]).to_line());
+        assert_eq!("TOPIC #rust :\r\n", Message::new("TOPIC", ["#rust", ""]).to_line());
+    }
+
+    #[test]
+    fn round_trips() {
+        for line in [":a!b@c PRIVMSG #x :hi there", "JOIN #a", ":irc 433 * alice :Nickname is already in use"] {
+            assert_eq!(line, Message::parse(line).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn lines_stay_lines() {
+        assert_eq!("NOTICE bob :one two\r\n", Message::new("NOTICE", ["bob", "one\ntwo"]).to_line());
+
+        // Everything before the text is an odd number of bytes, so 510 lands in the middle of an é
+        let long = Message::new("PRIVMSG", ["#rusty".to_string(), "é".repeat(300)]).to_line();
+        assert_eq!(MAX_LINE_LEN - 1, long.len());
+        assert!(long.ends_with("é\r\n"));
+    }
+}
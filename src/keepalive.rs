@@ -0,0 +1,134 @@
+//! Finding connections that are gone without having said so. A client whose network dropped leaves a TCP
+//! connection that looks fine until a write to it fails, which with nobody talking could be never. So
+//! connections that have gone quiet get a `ServerMessage::Ping`, and ones that don't answer it in time get hung
+//! up on, which their `handle_chat` sees as a ping timeout.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+use crate::frame::encode_message;
+use crate::metrics::Metrics;
+use crate::outbound::{Frame, Outbound};
+use crate::registry::Registry;
+use crate::response::ServerMessage;
+use crate::user::User;
+
+/// How often the connections get looked over.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long a connection may go without sending anything before it gets pinged. Zero never pings.
+    pub ping_after: Duration,
+    /// How long it then has to answer before it gets hung up on.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self { ping_after: Duration::from_secs(60), timeout: Duration::from_secs(30) }
+    }
+}
+
+/// Starts a thread looking after `users` for as long as the server's up, unless `keepalive` never pings.
+pub(crate) fn start(keepalive: Keepalive, users: Arc<Registry<Outbound>>, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    if keepalive.ping_after.is_zero() {
+        return Ok(());
+    }
+
+    thread::Builder::new().name("keepalive".to_string()).spawn(move || {
+        for token in 1.. {
+            thread::sleep(TICK);
+            for user in sweep(keepalive, &users, token, SystemTime::now()) {
+                metrics.ping_timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!("[KEEPALIVE] <{user}> Didn't answer a ping within {}s, hanging up", keepalive.timeout.as_secs());
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Pings everyone in `users` who's been quiet for `keepalive.ping_after` as of `now`, with `token`, and hangs
+/// up on anyone who was pinged at least `keepalive.timeout` ago and hasn't said anything since. Returns who got
+/// hung up on.
+fn sweep(keepalive: Keepalive, users: &Registry<Outbound>, token: u64, now: SystemTime) -> Vec<User> {
+    let ping: Frame = match encode_message(&ServerMessage::Ping { token }) {
+        Ok(frame) => frame.into(),
+        Err(_) => return Vec::new(),
+    };
+
+    let mut timed_out = Vec::new();
+    users.for_each(|user, outbound| {
+        let Some(diagnostics) = outbound.diagnostics() else {
+            return;
+        };
+        if diagnostics.timed_out() || diagnostics.quiet(now) < keepalive.ping_after {
+            return;
+        }
+
+        match diagnostics.ping(now) {
+            // Full means it's not reading, which it'll get hung up on for soon enough anyway
+            None => {
+                let _ = outbound.send(ping.clone());
+            }
+            Some(waited) if waited >= keepalive.timeout => {
+                diagnostics.time_out();
+                outbound.hang_up();
+                timed_out.push(user.clone());
+            }
+            Some(_) => {}
+        }
+    });
+    timed_out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::IpAddr;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+    use parking_lot::Mutex;
+    use crate::accounting::{IoLimits, IoMeter};
+    use crate::diagnostics::Diagnostics;
+    use crate::frame::{read_message, FrameLimits};
+    use super::*;
+
+    #[test]
+    fn pings_then_hangs_up() {
+        let keepalive = Keepalive { ping_after: Duration::from_secs(60), timeout: Duration::from_secs(30) };
+        let users: Registry<Outbound> = Default::default();
+        let meter = Arc::new(Mutex::new(IoMeter::new(IoLimits::default(), Instant::now())));
+        let diagnostics = Arc::new(Diagnostics::new(IpAddr::from([127, 0, 0, 1]), meter));
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let (outbound, queue) = Outbound::new(8);
+        let hanging_up = hung_up.clone();
+        let outbound = outbound.with_diagnostics(diagnostics.clone());
+        let outbound = outbound.with_hang_up(move || hanging_up.store(true, Ordering::Relaxed));
+        users.claim_nick(&User::new("quiet"), outbound).unwrap();
+        let later = |secs| SystemTime::now() + Duration::from_secs(secs);
+
+        assert!(sweep(keepalive, &users, 1, later(30)).is_empty());
+        assert!(queue.try_recv().is_err());
+
+        // Pinged once, not again while it's waiting on an answer
+        assert!(sweep(keepalive, &users, 2, later(61)).is_empty());
+        assert!(sweep(keepalive, &users, 3, later(62)).is_empty());
+        let ping = queue.try_recv().unwrap().to_vec();
+        assert_eq!(ServerMessage::Ping { token: 2 }, read_message(&mut Cursor::new(ping), &FrameLimits::default()).unwrap());
+        assert!(queue.try_recv().is_err());
+
+        // Answering starts it over
+        diagnostics.heard_from();
+        assert!(sweep(keepalive, &users, 4, later(30)).is_empty());
+        assert!(sweep(keepalive, &users, 5, later(61)).is_empty());
+        assert!(sweep(keepalive, &users, 6, later(90)).is_empty());
+        assert!(!hung_up.load(Ordering::Relaxed));
+
+        assert_eq!(vec![User::new("quiet")], sweep(keepalive, &users, 7, later(91)));
+        assert!(hung_up.load(Ordering::Relaxed) && diagnostics.timed_out());
+        assert!(sweep(keepalive, &users, 8, later(300)).is_empty());
+    }
+}
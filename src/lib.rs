@@ -0,0 +1,77 @@
+//! A basic IRC-ish chat server and client. `server::start` runs a server, `client::Client` talks to one, and
+//! `frame`, `response`, `command` and `user` are what goes over the wire between them. All but `command` come
+//! from the `protocol` crate, for anything else that wants to talk to a server. The binary is just these plus
+//! argument parsing. Cargo features can leave out the heavier parts, see Cargo.toml.
+
+pub use protocol::{frame, response, user};
+
+pub mod accounting;
+#[cfg(unix)]
+pub mod admin;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod bans;
+pub mod bouncer;
+pub mod channel;
+pub mod client;
+pub mod cluster;
+pub mod command;
+#[cfg(unix)]
+pub mod daemon;
+#[cfg(feature = "mdns")]
+pub mod discovery;
+#[cfg(feature = "mio")]
+pub mod event_loop;
+pub mod exit;
+pub mod flood;
+#[cfg(feature = "irc")]
+pub mod irc;
+pub mod keepalive;
+pub mod maintenance;
+pub mod mirror;
+pub mod outbound;
+pub mod output;
+pub mod plugin;
+pub mod registry;
+pub mod roles;
+pub mod scuffed_clone;
+pub mod secret;
+pub mod server;
+pub mod session;
+pub mod signing;
+pub mod storage;
+pub mod tail;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trigger;
+
+mod away;
+mod backfill;
+mod bandwidth;
+mod bidi;
+mod budget;
+mod capacity;
+mod clock;
+mod control;
+mod diagnostics;
+mod exporter;
+#[cfg(feature = "irc")]
+mod gateway;
+mod history;
+mod http;
+mod listener;
+mod metrics;
+mod pool;
+mod server_friendly_string;
+mod shutdown;
+mod stats;
+mod stun;
+mod template;
+#[cfg(test)]
+mod testing;
+mod token_bucket;
+mod transcript;
+mod transport;
+#[cfg(feature = "upnp")]
+mod upnp;
+mod websocket;
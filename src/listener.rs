@@ -0,0 +1,103 @@
+use std::net::{SocketAddr, TcpListener};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Binds `acceptors` listeners to `address`. On Linux they all share the port through `SO_REUSEPORT`, so the
+/// kernel spreads incoming connections across them and each can be accepted from on its own thread.
+/// Everywhere else there's only ever one.
+pub fn bind(address: SocketAddr, acceptors: usize) -> std::io::Result<Vec<TcpListener>> {
+    if acceptors <= 1 {
+        return Ok(vec![bind_one(address)?]);
+    }
+
+    bind_reuse_port(address, acceptors)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_reuse_port(address: SocketAddr, acceptors: usize) -> std::io::Result<Vec<TcpListener>> {
+    let first = listener(address, true)?;
+    // If we were asked for any port, the rest need to land on whichever one the first got
+    let address = first.local_addr()?;
+
+    let mut listeners = vec![first];
+    for _ in 1..acceptors {
+        listeners.push(listener(address, true)?);
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_reuse_port(address: SocketAddr, acceptors: usize) -> std::io::Result<Vec<TcpListener>> {
+    tracing::warn!("SO_REUSEPORT is only supported on Linux, using 1 acceptor instead of {acceptors}");
+    Ok(vec![bind_one(address)?])
+}
+
+/// Like `TcpListener::bind`, except `[::]` always takes IPv4 connections too instead of leaving that up to
+/// the OS, which doesn't everywhere.
+pub fn bind_one(address: SocketAddr) -> std::io::Result<TcpListener> {
+    listener(address, false)
+}
+
+fn listener(address: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    // Same as what `TcpListener::bind` does, so restarts don't trip over connections in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(target_os = "linux")]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::*;
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    }
+
+    #[test]
+    fn single_acceptor() {
+        assert_eq!(1, bind(localhost(), 1).unwrap().len());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reuse_port_shares_one_port() {
+        let listeners = bind(localhost(), 4).unwrap();
+        assert_eq!(4, listeners.len());
+
+        let port = listeners[0].local_addr().unwrap().port();
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap().port() == port));
+
+        // And the port actually takes connections
+        std::net::TcpStream::connect(listeners[0].local_addr().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn dual_stack() {
+        // Not every machine running the tests has IPv6
+        let Ok(listener) = bind_one("[::]:0".parse().unwrap()) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        std::net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        std::net::TcpStream::connect((std::net::Ipv6Addr::LOCALHOST, port)).unwrap();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    #[test]
+    fn reuse_port_falls_back_to_one_acceptor() {
+        assert_eq!(1, bind(localhost(), 4).unwrap().len());
+    }
+}
@@ -1,36 +1,453 @@
-use std::io::{stdin, stdout};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
-use anyhow::Result;
-use clap::Parser;
-use crate::args::{Args, Mode};
-use crate::user::User;
-use crate::client::Client;
+use std::io::{stdin, stdout, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches, ValueEnum};
+use rust_threading::{bouncer, client, server, signing};
+#[cfg(feature = "tokio")]
+use rust_threading::async_server;
+#[cfg(feature = "mdns")]
+use rust_threading::discovery;
+#[cfg(feature = "mio")]
+use rust_threading::event_loop;
+#[cfg(feature = "tls")]
+use rust_threading::tls::{self, TlsStream};
+use rust_threading::accounting::IoLimits;
+use rust_threading::bouncer::BouncerOptions;
+use rust_threading::client::{Client, ClientError, Console, Terminal};
+#[cfg(unix)]
+use rust_threading::admin;
+#[cfg(unix)]
+use rust_threading::daemon::{self, Daemon};
+use rust_threading::cluster::ClusterOptions;
+use rust_threading::exit::Exit;
+use rust_threading::mirror::MirrorOptions;
+use rust_threading::outbound::WriterOptions;
+use rust_threading::plugin::Plugins;
+use rust_threading::roles::Roles;
+use rust_threading::scuffed_clone::{HangUp, ScuffedClone};
+use rust_threading::secret;
+use rust_threading::session::Session;
+use rust_threading::storage::Accounts;
+use rust_threading::tail;
+use rust_threading::trigger::Triggers;
+use rust_threading::user::User;
+use tracing::info;
+use crate::args::{split_port, Args, Mode, Runtime};
 
 mod args;
-mod server;
-mod client;
-mod user;
-mod server_friendly_string;
-mod response;
-mod scuffed_clone;
+mod check;
+mod config;
+mod doctor;
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// How many times the client tries again when the server's too busy to let it in, before giving up.
+const BUSY_RETRIES: u32 = 5;
+
+fn main() -> ExitCode {
+    match run_main() {
+        Ok(()) => Exit::Clean.into(),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            exit_for(&e).into()
+        }
+    }
+}
+
+/// The exit code for how `e` ended things, going by what's at the bottom of it.
+fn exit_for(e: &anyhow::Error) -> Exit {
+    if let Some(e) = e.downcast_ref::<ClientError>() {
+        return Exit::from(e);
+    }
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) => Exit::of_io(e),
+        None => Exit::Failed,
+    }
+}
+
+fn run_main() -> Result<()> {
+    let cli = std::env::args_os().collect::<Vec<_>>();
+    if let Some(path) = config::init_path(&cli) {
+        let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&path);
+        let mut file = file.with_context(|| format!("Couldn't make {}", path.display()))?;
+        file.write_all(config::commented_defaults(&Args::command()).as_bytes())?;
+        println!("Wrote the default config to {}", path.display());
+        return Ok(());
+    }
+
+    // Settings from --config go first, then the environment, so anything given on the command line wins
+    let config = config::path(&cli);
+    let from_config = match &config {
+        Some(path) => config::load(path, &Args::command())?,
+        None => Vec::new(),
+    };
+    let from_env = config::from_env(std::env::vars_os(), &Args::command())?;
+    let flags = [&cli[..1], &from_config, &from_env, &cli[1..]].concat();
+    let matches = Args::command().get_matches_from(flags);
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (host, port) = args.endpoint();
+    let (host, port) = (host.to_string(), port);
+
+    if args.show_config {
+        let layers = [("config", &from_config[..]), ("environment", &from_env[..]), ("command line", &cli[1..])];
+        print!("{}", config::effective(&Args::command(), &matches, &layers));
+        return Ok(());
+    }
+
+    if args.check_config {
+        if !matches!(args.mode, Mode::Server) {
+            bail!("--check-config only checks servers' config");
+        }
+        let report = check::check(&args, config.as_deref());
+        println!("{report}");
+        if !report.passed() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(path) = &args.password_file {
+        args.password = Some(secret::read(path)?);
+    }
+    if let Some(path) = &args.account_password_file {
+        args.account_password = Some(secret::read(path)?);
+    }
+    if let Some(path) = &args.oper_password_file {
+        args.oper_password = Some(secret::read(path)?);
+    }
 
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), args.port);
     match args.mode {
         Mode::Server => {
-            server::start(addr)?;
+            tail::init(args.log_level, args.log_format);
+            let (flood, keepalive) = (args.flood(), args.keepalive());
+            if let Some(option) = check::threads_only(&args).first().filter(|_| args.runtime != Runtime::Threads) {
+                bail!("Only the threads runtime takes {option}");
+            }
+            let cluster = if args.cluster_listen.is_some() || !args.peer.is_empty() {
+                if args.trust.is_empty() && !args.trust_any_node {
+                    bail!("A cluster needs --trust for the other nodes' keys, or --trust-any-node to take them at their word");
+                }
+                Some(ClusterOptions {
+                    node: args.node_name.unwrap_or_else(|| format!("node-{}", std::process::id())),
+                    listen: args.cluster_listen,
+                    peers: args.peer,
+                    key: match &args.node_key {
+                        Some(path) => signing::load_or_create(path)?,
+                        None => signing::generate()?,
+                    },
+                    trusted: args.trust.into_iter().collect(),
+                    trust_any: args.trust_any_node,
+                })
+            } else {
+                None
+            };
+            #[cfg(feature = "tls")]
+            let tls = match (args.tls, &args.tls_cert, &args.tls_key) {
+                (false, ..) => None,
+                (true, Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+                (true, ..) => bail!("--tls needs --tls-cert and --tls-key"),
+            };
+            if args.tls && args.runtime != Runtime::Threads {
+                bail!("Only the threads runtime does TLS");
+            }
+            if args.ws_port.is_some() && args.runtime != Runtime::Threads {
+                bail!("Only the threads runtime takes WebSocket connections");
+            }
+            if args.unix.is_some() && args.runtime != Runtime::Threads {
+                bail!("Only the threads runtime listens on a Unix socket");
+            }
+            if args.unix.is_some() && (args.mdns || args.upnp || args.stun_server.is_some()) {
+                bail!("--unix servers are only for this machine, there's no port for --mdns, --upnp or --stun-server");
+            }
+            let admin_api = match (args.admin_port, args.admin_token) {
+                (None, _) => None,
+                (Some(port), Some(token)) if !token.is_empty() => Some((port, token)),
+                (Some(_), _) => bail!("--admin-port needs --admin-token, or anyone could kick everyone"),
+            };
+            #[cfg(not(feature = "tls"))]
+            if args.tls {
+                bail!("--tls needs the tls feature, which this was built without");
+            }
+            if !args.oper.is_empty() && args.accounts.is_none() && args.oper_password.is_none() {
+                bail!("--oper needs --accounts or --oper-password, or anyone connecting with an operator's nick would be one");
+            }
+            let accounts = match &args.accounts {
+                Some(path) => {
+                    let accounts = Accounts::open(path)?;
+                    info!("[ACCOUNT] {} registered nick(s) in {}", accounts.count()?, path.display());
+                    Some(Arc::new(accounts))
+                }
+                None => None,
+            };
+            let options = server::Options {
+                memory_budget: args.memory_budget,
+                max_line_len: args.max_line_len,
+                max_message_len: args.max_message_len,
+                workers: args.workers,
+                accept_queue: args.accept_queue,
+                max_clients: args.max_clients,
+                max_accepts_per_sec: args.max_accepts_per_sec,
+                accept_burst: args.accept_burst,
+                acceptors: args.acceptors,
+                io_limits: IoLimits {
+                    bytes_per_sec: args.max_bytes_per_sec,
+                    lines_per_sec: args.max_lines_per_sec,
+                    ..Default::default()
+                },
+                flood,
+                keepalive,
+                send_queue_len: args.send_queue_len,
+                writer: WriterOptions {
+                    bytes_per_sec: args.max_egress_bytes_per_sec,
+                    batch_window: Duration::from_millis(args.batch_window_ms),
+                    batch_bytes: args.batch_bytes,
+                },
+                advertise: args.mdns,
+                upnp: args.upnp,
+                stun_server: args.stun_server,
+                cluster,
+                roles: Roles { operators: args.oper.into_iter().map(User::new).collect(), password: args.oper_password },
+                maintenance_message: args.maintenance_message,
+                server_name: args.server_name,
+                motd: args.motd.map(std::fs::read_to_string).transpose()?,
+                protocol: args.protocol,
+                #[cfg(feature = "tls")]
+                tls,
+                password: args.password.clone(),
+                accounts,
+                history: args.history,
+                history_file: args.history_file,
+                admin_socket: args.admin_socket,
+                bans_file: args.bans_file,
+                metrics_port: args.metrics_port,
+                ws_port: args.ws_port,
+                unix: args.unix,
+                admin_api,
+                mirror: args.mirror.map(|upstream| MirrorOptions {
+                    upstream,
+                    name: args.name.clone().unwrap_or_else(|| "mirror".to_string()),
+                }),
+            };
+            if let Some(mirror) = &options.mirror {
+                let name = mirror.channels_name();
+                User::new(name.clone()).validate().with_context(|| format!("The mirror follows channels as {name}"))?;
+            }
+            #[cfg(not(unix))]
+            if options.admin_socket.is_some() {
+                bail!("--admin-socket needs Unix sockets, which there aren't here, use --admin-port instead");
+            }
+            let addr = resolve(&host, port)?;
+            match args.runtime {
+                Runtime::Threads => server::start(addr, options)?,
+                #[cfg(feature = "mio")]
+                Runtime::Mio => event_loop::start(addr, options)?,
+                #[cfg(feature = "tokio")]
+                Runtime::Tokio => async_server::start(addr, options)?,
+                #[allow(unreachable_patterns)]
+                runtime => {
+                    let runtime = runtime.to_possible_value().expect("None of them are skipped");
+                    bail!("--runtime {0} needs the {0} feature, which this was built without", runtime.get_name());
+                }
+            }
         }
         Mode::Client => {
+            if let Some(socket) = &args.attach {
+                #[cfg(unix)]
+                return Ok(daemon::attach_terminal(socket)?);
+                #[cfg(not(unix))]
+                bail!("--attach needs Unix sockets, which {socket:?} can't be here");
+            }
+
             let name = args.name.unwrap_or_else(|| {
                 client::get_input(b"Enter a username: ", stdin().lock(), stdout().lock())
                     .expect("Couldn't get username")
             });
+            let mut user = User::new(name.trim());
+            if let Some(display_name) = args.display_name {
+                user = user.with_display_name(display_name);
+            }
+            user.validate()?;
+            if args.unix.is_some() && (args.tls || args.discover) {
+                bail!("--unix only ever talks to a server on this machine, it's not for --tls or --discover");
+            }
+
+            #[cfg(not(feature = "mdns"))]
+            if args.discover {
+                bail!("--discover needs the mdns feature, which this was built without");
+            }
+            #[cfg(feature = "mdns")]
+            let (addrs, host) = if args.discover {
+                let servers = discovery::discover(Duration::from_secs(args.discover_secs))?;
+                if servers.is_empty() {
+                    bail!("No servers found on the LAN");
+                }
+
+                match discovery::pick(&servers, stdin().lock(), stdout().lock())? {
+                    Some(addr) => (vec![addr], addr.ip().to_string()),
+                    None => bail!("That's not one of the servers"),
+                }
+            } else {
+                // Tries each of them in turn, e.g. both ::1 and 127.0.0.1 for localhost
+                (resolve_all(&host, port)?, host)
+            };
+            #[cfg(not(feature = "mdns"))]
+            let addrs = resolve_all(&host, port)?;
+
+            let plugins = Plugins::load(&args.plugin)?;
+            let triggers = Triggers::parse(&args.trigger, Duration::from_secs(args.trigger_cooldown_secs))?;
+            let session = match args.session {
+                Some(path) => Some((Session::load(&path)?, path)),
+                None => None,
+            };
+            let console: Arc<dyn Console> = match &args.daemon {
+                #[cfg(unix)]
+                Some(socket) => {
+                    let daemon = Daemon::listen(socket)?;
+                    println!("Running as a daemon, attach with --mode client --attach {}", socket.display());
+                    Arc::new(daemon)
+                }
+                #[cfg(not(unix))]
+                Some(socket) => bail!("--daemon needs Unix sockets, which {socket:?} can't be here"),
+                None => Arc::new(Terminal),
+            };
+
+            let (stats, bandwidth_cap, passwords) = (args.stats, args.bandwidth_cap, (args.password, args.account_password));
+            if let Some(socket) = &args.unix {
+                #[cfg(unix)]
+                {
+                    let mut connect = || Ok(UnixStream::connect(socket)?);
+                    let client = Client::new(user, connect()?).with_output(args.output);
+                    let client = client.with_plugins(plugins).with_console(console);
+                    run((client, &mut connect), session, (stats, triggers), bandwidth_cap, passwords)?;
+                }
+                #[cfg(not(unix))]
+                bail!("--unix needs Unix sockets, which {socket:?} can't be here");
+            } else if args.tls {
+                #[cfg(feature = "tls")]
+                {
+                    let config = tls::client_config(args.tls_ca.as_deref())?;
+                    let mut connect = || -> Result<_> {
+                        Ok(TlsStream::connect(TcpStream::connect(addrs.as_slice())?, config.clone(), &host)?)
+                    };
+                    let client = Client::new(user, connect()?).with_output(args.output);
+                    let client = client.with_plugins(plugins).with_console(console);
+                    run((client, &mut connect), session, (stats, triggers), bandwidth_cap, passwords)?;
+                }
+                #[cfg(not(feature = "tls"))]
+                bail!("--tls needs the tls feature, which this was built without, so it can't talk TLS to {host}");
+            } else {
+                let mut connect = || Ok(TcpStream::connect(addrs.as_slice())?);
+                let client = Client::new(user, connect()?).with_output(args.output);
+                let client = client.with_plugins(plugins).with_console(console);
+                run((client, &mut connect), session, (stats, triggers), bandwidth_cap, passwords)?;
+            }
+        }
+        Mode::Bouncer => {
+            tail::init(args.log_level, args.log_format);
+            let Some(upstream) = &args.upstream else {
+                bail!("--mode bouncer needs --upstream");
+            };
+            let Some(name) = args.name else {
+                bail!("--mode bouncer needs --name to log in upstream with");
+            };
+            let upstream = match split_port(upstream) {
+                (upstream_host, Some(upstream_port)) => resolve(upstream_host, upstream_port)?,
+                (_, None) => bail!("--upstream needs a port, like {upstream}:6667"),
+            };
 
-            Client::new(User::new(name), TcpStream::connect(addr)?).start()?;
+            let options = BouncerOptions {
+                upstream,
+                name,
+                backlog: args.backlog,
+                send_queue_len: args.send_queue_len,
+                max_line_len: args.max_line_len,
+            };
+            bouncer::start(resolve(&host, port)?, options)?;
+        }
+        Mode::Admin => {
+            let Some(socket) = &args.admin_socket else {
+                bail!("--mode admin needs --admin-socket to send the command to");
+            };
+            if args.admin_command.is_empty() {
+                bail!("--mode admin needs a command after the options, like `tail --level warn`");
+            }
+            #[cfg(unix)]
+            admin::run(socket, &args.admin_command.join(" "))?;
+            #[cfg(not(unix))]
+            bail!("--admin-socket needs Unix sockets, which {socket:?} can't be here, use the server's --admin-port instead");
+        }
+        Mode::Doctor => {
+            let report = doctor::examine(&args);
+            println!("{report}");
+            if !report.passed() {
+                std::process::exit(1);
+            }
         }
     }
 
     Ok(())
 }
+
+/// Talks to the server through `client` until the user's done, logging in with `password` and identifying
+/// with `account_password` if there are any, picking up `session` if there is one, keeping count of things
+/// with `stats`, acting on `triggers` and warning after `bandwidth_cap` bytes. If the server's too busy to let
+/// us in, it's tried again over a new connection from `connect` once it's said to, a few times.
+fn run<S>(
+    (client, connect): (Client<S>, &mut dyn FnMut() -> Result<S>),
+    session: Option<(Session, PathBuf)>,
+    (stats, triggers): (bool, Triggers),
+    bandwidth_cap: Option<u64>,
+    (password, account_password): (Option<String>, Option<String>),
+) -> Result<()>
+where
+    S: Read + Write + ScuffedClone + HangUp + Send,
+{
+    let mut client = match session {
+        Some((session, path)) => client.with_session(path, session),
+        None => client,
+    };
+    if stats {
+        client = client.with_stats();
+    }
+    client = client.with_triggers(triggers);
+    if let Some(cap) = bandwidth_cap {
+        client = client.with_bandwidth_cap(cap);
+    }
+    if let Some(password) = password {
+        client = client.with_password(password);
+    }
+    if let Some(account_password) = account_password {
+        client = client.with_account_password(account_password);
+    }
+    let mut retries = 0;
+    loop {
+        let ended = client.start();
+        let wait = match ended.as_ref().err().and_then(ClientError::retry_after) {
+            Some(wait) if retries < BUSY_RETRIES => wait,
+            _ => return Ok(ended?),
+        };
+        retries += 1;
+        eprintln!("The server's busy, trying again in {} ms ({retries} of {BUSY_RETRIES})", wait.as_millis());
+        std::thread::sleep(wait);
+        client.reconnect(connect()?);
+    }
+}
+
+/// The first address `host` resolves to, which can be an IP or a host name, for the server to listen on.
+fn resolve(host: &str, port: u16) -> Result<SocketAddr> {
+    Ok(resolve_all(host, port)?[0])
+}
+
+fn resolve_all(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        Err(e) => bail!("Couldn't resolve {host}: {e}"),
+    };
+    if addrs.is_empty() {
+        bail!("{host} doesn't resolve to any address");
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,64 @@
+use parking_lot::Mutex;
+
+/// What new connections get told while the server's in maintenance, unless the operator says otherwise.
+pub const DEFAULT_MESSAGE: &str = "Server is down for maintenance, try again later";
+
+/// Whether the server's in maintenance mode. While it is, whoever's already connected stays connected, but
+/// new connections get turned away with a message.
+#[derive(Debug)]
+pub struct Maintenance {
+    default_message: String,
+    /// What new connections are being told, if we're in maintenance.
+    message: Mutex<Option<String>>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new(DEFAULT_MESSAGE)
+    }
+}
+
+impl Maintenance {
+    pub fn new(default_message: impl Into<String>) -> Self {
+        Self {
+            default_message: default_message.into(),
+            message: Mutex::new(None),
+        }
+    }
+
+    /// Starts turning new connections away with `message`, or the default one. Returns what they'll be told.
+    pub fn start(&self, message: Option<String>) -> String {
+        let message = message.unwrap_or_else(|| self.default_message.clone());
+        *self.message.lock() = Some(message.clone());
+        message
+    }
+
+    /// Lets new connections back in. Returns whether we were in maintenance at all.
+    pub fn end(&self) -> bool {
+        self.message.lock().take().is_some()
+    }
+
+    /// What to turn new connections away with, or `None` if they're welcome.
+    pub fn message(&self) -> Option<String> {
+        self.message.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_and_end() {
+        let maintenance = Maintenance::new("back soon");
+        assert_eq!(None, maintenance.message());
+
+        assert_eq!("back soon", maintenance.start(None));
+        assert_eq!("upgrading", maintenance.start(Some("upgrading".to_string())));
+        assert_eq!(Some("upgrading".to_string()), maintenance.message());
+
+        assert!(maintenance.end());
+        assert!(!maintenance.end());
+        assert_eq!(None, maintenance.message());
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write as _};
+use std::io::{IoSlice, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use parking_lot::Mutex;
+
+/// Upper bounds (in seconds) of the buckets used for anything latency related.
+pub const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A handshake slower than this always trips the slow-auth alarm, regardless of what's typical.
+const SLOW_AUTH_FLOOR: Duration = Duration::from_millis(250);
+/// A handshake this many times slower than the median trips the slow-auth alarm.
+const SLOW_AUTH_FACTOR: u32 = 4;
+/// Don't trust the median until we've seen this many handshakes.
+const MIN_SAMPLES: u64 = 20;
+/// Log the broadcast latency percentiles every time this many more messages have gone out.
+const BROADCAST_SUMMARY_EVERY: u64 = 1000;
+/// Warn every time an IP racks up this many more failures.
+const FAILURE_ALARM_EVERY: u64 = 5;
+/// So a flood of unique addresses can't grow the failure map forever.
+const MAX_TRACKED_IPS: usize = 4096;
+
+/// Fixed-bucket histogram, the same shape Prometheus uses: each bucket counts observations at or under
+/// its bound, plus one more for everything over the last bound.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The average of everything observed, `None` without anything to average.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_secs_f64(self.sum / self.count as f64))
+    }
+
+    /// Estimates the `q`th quantile (0.0..=1.0) as the upper bound of the bucket it falls in. Returns
+    /// `None` with no observations, or if it lands in the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((self.count as f64) * q.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return self.bounds.get(i).map(|b| Duration::from_secs_f64(*b));
+            }
+        }
+
+        None
+    }
+
+    /// Appends the histogram to `out` in Prometheus' text format as `name`, buckets cumulative like it wants.
+    pub fn expose(&self, out: &mut String, name: &str) {
+        let mut seen = 0;
+        for (bound, n) in self.bounds.iter().zip(&self.counts) {
+            seen += n;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {seen}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+/// Something about a handshake that's worth a warning in the logs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthAlarm {
+    Slow { ip: IpAddr, took: Duration, threshold: Duration },
+    RepeatedFailures { ip: IpAddr, failures: u64 },
+}
+
+impl Display for AuthAlarm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthAlarm::Slow { ip, took, threshold } => {
+                write!(f, "Slow handshake from {ip}: took {took:?} (alarm threshold {threshold:?})")
+            }
+            AuthAlarm::RepeatedFailures { ip, failures } => {
+                write!(f, "{ip} has failed {failures} handshakes")
+            }
+        }
+    }
+}
+
+/// Everything the server keeps count of, shared by every thread.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub handshakes: HandshakeMetrics,
+    pub broadcast: BroadcastMetrics,
+    /// Lines thrown away for being over the connection's max line length.
+    pub oversized_lines: AtomicU64,
+    /// Connections cut off for going over their I/O budget.
+    pub io_disconnects: AtomicU64,
+    /// Messages dropped for coming faster than the connection's flood limits.
+    pub flood_drops: AtomicU64,
+    /// Connections cut off for flooding on after their messages started getting dropped.
+    pub flood_disconnects: AtomicU64,
+    /// Connections hung up on for not answering a ping.
+    pub ping_timeouts: AtomicU64,
+    /// Frames dropped because the connection they were for had a full send queue.
+    pub dropped_frames: AtomicU64,
+    /// Chat messages sent on to whoever was meant to hear them.
+    pub messages_broadcast: AtomicU64,
+    /// Bytes read from clients, past TLS. Shared with every connection's `Counted` reader.
+    pub bytes_in: Arc<AtomicU64>,
+    /// Bytes written out to clients, past TLS. Shared with every connection's `Counted` writer.
+    pub bytes_out: Arc<AtomicU64>,
+    /// Our public address as a STUN server saw it at startup, if we asked one.
+    pub public_address: Mutex<Option<SocketAddr>>,
+}
+
+#[derive(Debug)]
+struct HandshakeStats {
+    durations: Histogram,
+    failures: BTreeMap<IpAddr, u64>,
+    /// Every failure, including from IPs past `MAX_TRACKED_IPS`.
+    failed: u64,
+}
+
+/// Handshake durations and failures per IP, shared by every connection.
+#[derive(Debug)]
+pub struct HandshakeMetrics {
+    stats: Mutex<HandshakeStats>,
+}
+
+impl Default for HandshakeMetrics {
+    fn default() -> Self {
+        Self {
+            stats: Mutex::new(HandshakeStats {
+                durations: Histogram::new(LATENCY_BUCKETS),
+                failures: BTreeMap::new(),
+                failed: 0,
+            }),
+        }
+    }
+}
+
+impl HandshakeMetrics {
+    /// Records one handshake, returning any alarms it tripped. Slowness is judged against the handshakes
+    /// seen _before_ this one, so a single slow one can't hide itself.
+    pub fn record(&self, ip: IpAddr, took: Duration, succeeded: bool) -> Vec<AuthAlarm> {
+        let mut stats = self.stats.lock();
+        let mut alarms = Vec::new();
+
+        let typical = match stats.durations.count() {
+            n if n >= MIN_SAMPLES => stats.durations.quantile(0.5).map(|p50| p50 * SLOW_AUTH_FACTOR),
+            _ => None,
+        };
+        let threshold = typical.map_or(SLOW_AUTH_FLOOR, |t| t.max(SLOW_AUTH_FLOOR));
+        if took > threshold {
+            alarms.push(AuthAlarm::Slow { ip, took, threshold });
+        }
+        stats.durations.observe(took);
+
+        stats.failed += u64::from(!succeeded);
+        if !succeeded && (stats.failures.len() < MAX_TRACKED_IPS || stats.failures.contains_key(&ip)) {
+            let failures = stats.failures.entry(ip).or_default();
+            *failures += 1;
+            if failures.is_multiple_of(FAILURE_ALARM_EVERY) {
+                alarms.push(AuthAlarm::RepeatedFailures { ip, failures: *failures });
+            }
+        }
+
+        alarms
+    }
+
+    /// How long handshakes have taken so far.
+    pub fn durations(&self) -> Histogram {
+        self.stats.lock().durations.clone()
+    }
+
+    /// How many handshakes have failed so far.
+    pub fn failed(&self) -> u64 {
+        self.stats.lock().failed
+    }
+}
+
+/// How long messages take from the server reading them to being written out to every recipient.
+#[derive(Debug)]
+pub struct BroadcastMetrics {
+    latency: Mutex<Histogram>,
+}
+
+impl Default for BroadcastMetrics {
+    fn default() -> Self {
+        Self {
+            latency: Mutex::new(Histogram::new(LATENCY_BUCKETS)),
+        }
+    }
+}
+
+/// The p50/p99 broadcast latency over every message so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // `None` means the quantile landed past the last bucket
+        let show = |d: Option<Duration>| d.map_or(">10s".to_string(), |d| format!("{d:?}"));
+        write!(f, "{} messages, p50 {}, p99 {}", self.count, show(self.p50), show(self.p99))
+    }
+}
+
+impl BroadcastMetrics {
+    /// Records how long one message took to fan out. Every so often this hands back a summary to log.
+    pub fn record(&self, took: Duration) -> Option<LatencySummary> {
+        let mut latency = self.latency.lock();
+        latency.observe(took);
+
+        latency.count().is_multiple_of(BROADCAST_SUMMARY_EVERY).then(|| LatencySummary {
+            count: latency.count(),
+            p50: latency.quantile(0.5),
+            p99: latency.quantile(0.99),
+        })
+    }
+
+    /// How long messages have taken to fan out so far.
+    pub fn latency(&self) -> Histogram {
+        self.latency.lock().clone()
+    }
+}
+
+/// Wraps a stream so every byte read from or written to it adds to a total shared across connections.
+#[derive(Debug)]
+pub struct Counted<S> {
+    inner: S,
+    total: Arc<AtomicU64>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, total: Arc<AtomicU64>) -> Self {
+        Self { inner, total }
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.total.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.total.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    // Passed through, or writers would lose their batching to the one-slice-at-a-time default
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let n = self.inner.write_vectored(bufs)?;
+        self.total.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn failures(metrics: &HandshakeMetrics, ip: &IpAddr) -> u64 {
+        metrics.stats.lock().failures.get(ip).copied().unwrap_or_default()
+    }
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut hist = Histogram::new(LATENCY_BUCKETS);
+        assert_eq!(None, hist.quantile(0.5));
+
+        (0..9).for_each(|_| hist.observe(Duration::from_millis(3)));
+        hist.observe(Duration::from_millis(400));
+
+        assert_eq!(10, hist.count());
+        assert_eq!(Some(Duration::from_millis(5)), hist.quantile(0.5));
+        assert_eq!(Some(Duration::from_millis(500)), hist.quantile(0.99));
+
+        hist.observe(Duration::from_secs(60));
+        assert_eq!(None, hist.quantile(1.0));
+    }
+
+    #[test]
+    fn slow_handshake_over_floor() {
+        let metrics = HandshakeMetrics::default();
+        assert!(metrics.record(IP, Duration::from_millis(10), true).is_empty());
+        assert_eq!(
+            vec![AuthAlarm::Slow { ip: IP, took: Duration::from_secs(1), threshold: SLOW_AUTH_FLOOR }],
+            metrics.record(IP, Duration::from_secs(1), true)
+        );
+    }
+
+    #[test]
+    fn slow_handshake_relative_to_median() {
+        let metrics = HandshakeMetrics::default();
+        (0..MIN_SAMPLES).for_each(|_| { metrics.record(IP, Duration::from_millis(90), true); });
+
+        // Median bucket is 100ms, so 4x that is the bar
+        assert!(metrics.record(IP, Duration::from_millis(300), true).is_empty());
+        assert!(matches!(
+            metrics.record(IP, Duration::from_millis(450), true)[..],
+            [AuthAlarm::Slow { threshold, .. }] if threshold == Duration::from_millis(400)
+        ));
+    }
+
+    #[test]
+    fn broadcast_latency_summary() {
+        let metrics = BroadcastMetrics::default();
+        for i in 1..BROADCAST_SUMMARY_EVERY {
+            let took = if i % 50 == 0 { Duration::from_millis(40) } else { Duration::from_micros(500) };
+            assert_eq!(None, metrics.record(took));
+        }
+
+        assert_eq!(
+            Some(LatencySummary {
+                count: BROADCAST_SUMMARY_EVERY,
+                p50: Some(Duration::from_millis(1)),
+                p99: Some(Duration::from_millis(50)),
+            }),
+            metrics.record(Duration::from_micros(500))
+        );
+    }
+
+    #[test]
+    fn repeated_failures_alarm() {
+        let metrics = HandshakeMetrics::default();
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        for i in 1..FAILURE_ALARM_EVERY {
+            assert!(metrics.record(IP, Duration::ZERO, false).is_empty());
+            assert_eq!(i, failures(&metrics, &IP));
+        }
+        metrics.record(other, Duration::ZERO, false);
+        metrics.record(IP, Duration::ZERO, true);
+
+        assert_eq!(
+            vec![AuthAlarm::RepeatedFailures { ip: IP, failures: FAILURE_ALARM_EVERY }],
+            metrics.record(IP, Duration::ZERO, false)
+        );
+        assert_eq!(1, failures(&metrics, &other));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::client::{Client, ClientError};
+use crate::frame::encode_message;
+use crate::outbound::{Frame, Outbound};
+use crate::registry::Registry;
+use crate::response::ServerMessage;
+use crate::scuffed_clone::HangUp;
+use crate::user::User;
+
+const RECONNECT: Duration = Duration::from_secs(5);
+/// How often the mirror asks upstream what channels it has, to follow new ones too.
+const LIST_EVERY: Duration = Duration::from_secs(30);
+
+/// Where a mirror gets its traffic from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorOptions {
+    pub upstream: SocketAddr,
+    /// Nick the mirror logs into the upstream server with.
+    pub name: String,
+}
+
+impl MirrorOptions {
+    /// Nick the mirror's second connection logs in with, the one in every channel. Anyone in a channel
+    /// isn't in the lobby, so one connection can't follow both.
+    pub fn channels_name(&self) -> String {
+        format!("{}-channels", self.name)
+    }
+}
+
+/// Follows the upstream server as regular clients, passing its public chat on to local users in the same
+/// channel, or the lobby. DMs, notices and anything else said just to the mirror stay with it. Keeps
+/// reconnecting for as long as the server's up.
+pub fn start(options: MirrorOptions, users: Arc<Registry<Outbound>>) -> std::io::Result<()> {
+    thread::Builder::new().name("mirror".to_string()).spawn(move || loop {
+        match follow(&options, &users) {
+            // Nick's taken, no point hammering the upstream with it
+            Err(ClientError::Auth(resp)) => {
+                warn!("[MIRROR] Upstream {} turned us away, giving up: {resp:?}", options.upstream);
+                return;
+            }
+            Err(e) => warn!("[MIRROR] Lost upstream {}, reconnecting: {e}", options.upstream),
+            Ok(()) => {}
+        }
+        thread::sleep(RECONNECT);
+    })?;
+
+    Ok(())
+}
+
+/// Follows the upstream's lobby as `name`, and every channel it has as `channels_name`, until either hangs up.
+fn follow(options: &MirrorOptions, users: &Registry<Outbound>) -> Result<(), ClientError> {
+    let (mut lobby, lobby_conn) = connect(options.upstream, &options.name)?;
+    let (mut channels, channels_conn) = connect(options.upstream, &options.channels_name())?;
+    info!("[MIRROR] Mirroring {} as {} and {}", options.upstream, options.name, options.channels_name());
+
+    let (joining, to_join) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(|| join_everything(&channels_conn, to_join));
+        let lobby = scope.spawn(|| {
+            let ended = relay(&mut lobby, users, None);
+            channels_conn.hang_up();
+            ended
+        });
+        let ended = relay(&mut channels, users, Some(&joining));
+        // Hangs the joiner up too
+        drop(joining);
+        lobby_conn.hang_up();
+        let lobby = lobby.join().expect("The lobby relay doesn't panic");
+        ended.and(lobby)
+    })
+}
+
+/// Logs into `upstream` as `name`, with another handle on the connection for writing to it and hanging up.
+fn connect(upstream: SocketAddr, name: &str) -> Result<(Client<TcpStream>, TcpStream), ClientError> {
+    let stream = TcpStream::connect(upstream)?;
+    let conn = stream.try_clone()?;
+    let mut client = Client::new(User::new(name), stream);
+    client.do_auth_flow()?;
+    Ok((client, conn))
+}
+
+/// Passes the public chat `upstream` hears on to local users in the same channel, until it hangs up. What
+/// channels there are, when it's told, go to `joining`.
+fn relay(
+    upstream: &mut Client<TcpStream>,
+    users: &Registry<Outbound>,
+    joining: Option<&Sender<Vec<String>>>,
+) -> Result<(), ClientError> {
+    loop {
+        let message = upstream.next_message()?;
+        match &message {
+            ServerMessage::Chat { channel, .. } => {
+                let frame: Frame = encode_message(&message)?.into();
+                for user in users.send_to_channel(&frame, channel.as_deref(), None) {
+                    warn!("[MIRROR] {user} isn't keeping up, disconnecting them");
+                }
+            }
+            ServerMessage::Channels { channels } => {
+                if let Some(joining) = joining {
+                    let _ = joining.send(channels.keys().cloned().collect());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Asks `upstream` what channels it has every `LIST_EVERY`, joining whichever of them `to_join` hears about
+/// that it hasn't yet, until `to_join` or the connection's gone.
+fn join_everything(mut upstream: &TcpStream, to_join: Receiver<Vec<String>>) {
+    let mut joined = BTreeSet::new();
+    let mut lines = vec!["/list".to_string()];
+    loop {
+        for line in lines.drain(..) {
+            if upstream.write_all(format!("{line}\n").as_bytes()).is_err() {
+                return;
+            }
+        }
+        match to_join.recv_timeout(LIST_EVERY) {
+            Ok(channels) => {
+                let new = channels.into_iter().filter(|channel| joined.insert(channel.clone()));
+                lines.extend(new.map(|channel| format!("/join {channel}")));
+            }
+            Err(RecvTimeoutError::Timeout) => lines.push("/list".to_string()),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor};
+    use std::net::TcpListener;
+    use crate::frame::{FrameLimits, read_message, write_message};
+    use crate::response::{AuthResponse, Handshake};
+    use crate::testing::accepted;
+    use super::*;
+
+    fn chat(text: &str, channel: Option<&str>) -> ServerMessage {
+        let channel = channel.map(str::to_string);
+        ServerMessage::Chat { from: User::new("far"), text: text.to_string(), received_ms: 1, channel }
+    }
+
+    /// Lets the mirror's next connection in, returning who it logged in as.
+    fn let_in(upstream: &TcpListener) -> (TcpStream, User) {
+        let (mut stream, _) = upstream.accept().unwrap();
+        let _: Handshake = read_message(&mut stream, &FrameLimits::default()).unwrap();
+        stream.write_all(&accepted()).unwrap();
+        let hello: User = read_message(&mut stream, &FrameLimits::default()).unwrap();
+        write_message(&mut stream, &AuthResponse::Success).unwrap();
+        (stream, hello)
+    }
+
+    #[test]
+    fn mirrors_public_chat_where_it_was_said() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = MirrorOptions { upstream: upstream.local_addr().unwrap(), name: "mirror".to_string() };
+
+        let users: Registry<Outbound> = Default::default();
+        let (in_lobby, lobby) = Outbound::new(8);
+        let (in_rust, rust) = Outbound::new(8);
+        users.claim_nick(&User::new("local"), in_lobby).unwrap();
+        users.claim_nick(&User::new("rustacean"), in_rust).unwrap();
+        users.channels().join(&User::new("rustacean"), "#rust");
+
+        let follower = thread::scope(|scope| {
+            let follower = scope.spawn(|| follow(&options, &users));
+            let (mut lobby_conn, lobby_nick) = let_in(&upstream);
+            let (mut channels_conn, channels_nick) = let_in(&upstream);
+            assert_eq!((User::new("mirror"), User::new("mirror-channels")), (lobby_nick, channels_nick));
+
+            // It asks what channels there are and joins them
+            let mut lines = BufReader::new(channels_conn.try_clone().unwrap()).lines();
+            assert_eq!("/list", lines.next().unwrap().unwrap());
+            let listed = ServerMessage::Channels { channels: [("#rust".to_string(), 3)].into() };
+            write_message(&mut channels_conn, &listed).unwrap();
+            assert_eq!("/join #rust", lines.next().unwrap().unwrap());
+
+            // What's just for the mirror comes first, so it's been dropped by the time the chat's through
+            let private = ServerMessage::Private { from: User::new("far"), to: User::new("mirror"), text: "psst".to_string() };
+            for message in [private, ServerMessage::notice("Killed by oper: bye"), chat("hi", None)] {
+                write_message(&mut lobby_conn, &message).unwrap();
+            }
+            write_message(&mut channels_conn, &chat("hi rust", Some("#rust"))).unwrap();
+            for (queue, expected) in [(&lobby, chat("hi", None)), (&rust, chat("hi rust", Some("#rust")))] {
+                let frame = queue.recv_timeout(Duration::from_secs(5)).unwrap();
+                let mirrored: ServerMessage = read_message(&mut Cursor::new(frame.to_vec()), &FrameLimits::default()).unwrap();
+                assert_eq!(expected, mirrored);
+            }
+
+            // Either connection going ends the follow
+            drop(lobby_conn);
+            follower.join().unwrap()
+        });
+        assert!(follower.is_err());
+        assert!(lobby.try_recv().is_err());
+        assert!(rust.try_recv().is_err());
+    }
+}
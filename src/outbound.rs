@@ -0,0 +1,391 @@
+use std::fmt::{Debug, Formatter};
+use std::io::{IoSlice, Write};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{warn, Span};
+use crate::diagnostics::Diagnostics;
+use crate::frame::write_all_vectored;
+use crate::token_bucket::TokenBucket;
+
+/// An encoded frame, shared between every connection it's going out to.
+pub type Frame = Arc<[u8]>;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OutboundError {
+    #[error("Send queue is full")]
+    Full,
+    #[error("Connection is gone")]
+    Closed,
+}
+
+/// How a connection's writer thread paces and batches its writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriterOptions {
+    /// Bytes per second going out to the client, or `None` to write as fast as it'll take them.
+    pub bytes_per_sec: Option<u64>,
+    /// How long to wait for more frames after the first one before writing them all at once. Zero still
+    /// batches up whatever's already queued, it just doesn't wait for more.
+    pub batch_window: Duration,
+    /// Stop batching once a write would be at least this big.
+    pub batch_bytes: usize,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: None,
+            batch_window: Duration::ZERO,
+            batch_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// The sending side of a connection's send queue. Everyone who wants to talk to the connection goes through
+/// one of these, and only the connection's own writer thread ever touches its stream, so a slow client only
+/// holds up itself.
+#[derive(Clone)]
+pub struct Outbound {
+    queue: SyncSender<Frame>,
+    counts: Arc<QueueCounts>,
+    hang_up: Option<Arc<dyn Fn() + Send + Sync>>,
+    /// Called after every frame queued, for runtimes that write the queue out themselves instead of on a thread.
+    wake: Option<Arc<dyn Fn() + Send + Sync>>,
+    diagnostics: Option<Arc<Diagnostics>>,
+    /// Whether the connection's given `/oper` the operator password.
+    opered: Arc<AtomicBool>,
+}
+
+impl Debug for Outbound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Outbound").field("queue", &self.queue).finish_non_exhaustive()
+    }
+}
+
+/// How a send queue's doing, for `inspect` on the admin socket.
+#[derive(Debug, Default)]
+struct QueueCounts {
+    capacity: usize,
+    /// Frames waiting for the writer.
+    waiting: AtomicUsize,
+    bytes_written: AtomicU64,
+}
+
+/// The receiving end of a send queue, for `spawn_writer`. Derefs to the channel so tests can look at what's in it.
+#[derive(Debug)]
+pub struct Queue {
+    frames: Receiver<Frame>,
+    counts: Arc<QueueCounts>,
+}
+
+impl Deref for Queue {
+    type Target = Receiver<Frame>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.frames
+    }
+}
+
+impl Outbound {
+    /// Makes a send queue holding up to `queue_len` frames. Nothing gets written until the receiving end
+    /// is handed to `spawn_writer`.
+    pub fn new(queue_len: usize) -> (Self, Queue) {
+        let (queue, frames) = mpsc::sync_channel(queue_len);
+        let counts = Arc::new(QueueCounts { capacity: queue_len, ..Default::default() });
+        let (hang_up, wake, diagnostics) = (None, None, None);
+        let outbound = Self { queue, counts: counts.clone(), hang_up, wake, diagnostics, opered: Default::default() };
+        (outbound, Queue { frames, counts })
+    }
+
+    /// Keeps what there is to know about the connection, for anyone who looks it up by nick.
+    pub fn with_diagnostics(mut self, diagnostics: Arc<Diagnostics>) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    pub fn diagnostics(&self) -> Option<&Diagnostics> {
+        self.diagnostics.as_deref()
+    }
+
+    /// Frames waiting to go out, and how many can.
+    pub fn waiting(&self) -> (usize, usize) {
+        (self.counts.waiting.load(Ordering::Relaxed), self.counts.capacity)
+    }
+
+    /// Bytes the writer's written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.counts.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Notes the connection's given `/oper` the operator password, see `Roles`.
+    pub fn oper(&self) {
+        self.opered.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_opered(&self) -> bool {
+        self.opered.load(Ordering::Relaxed)
+    }
+
+    /// Gives the connection a way to be cut off, for `hang_up`.
+    pub fn with_hang_up(mut self, hang_up: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hang_up = Some(Arc::new(hang_up));
+        self
+    }
+
+    /// Has `wake` called whenever there's something new on the queue, for whoever's waiting to write it out.
+    /// It's dropped with the last `Outbound`, after the queue's sending end, so it can tell them that too.
+    pub fn with_wake(mut self, wake: impl Fn() + Send + Sync + 'static) -> Self {
+        self.wake = Some(Arc::new(wake));
+        self
+    }
+
+    /// Stops reading from the connection, so it winds down once whatever's queued has gone out.
+    pub fn hang_up(&self) {
+        if let Some(hang_up) = &self.hang_up {
+            hang_up();
+        }
+    }
+
+    /// Queues `frame` without blocking.
+    pub fn send(&self, frame: Frame) -> Result<(), OutboundError> {
+        // Counted first, so the writer can't take it off before it's on
+        self.counts.waiting.fetch_add(1, Ordering::Relaxed);
+        self.queue.try_send(frame).map_err(|e| {
+            self.counts.waiting.fetch_sub(1, Ordering::Relaxed);
+            match e {
+                TrySendError::Full(_) => OutboundError::Full,
+                TrySendError::Disconnected(_) => OutboundError::Closed,
+            }
+        })?;
+        if let Some(wake) = &self.wake {
+            wake();
+        }
+        Ok(())
+    }
+}
+
+impl Queue {
+    /// The next frame to write, for runtimes that write it themselves instead of with `spawn_writer`.
+    /// `Disconnected` once every `Outbound` for the queue is gone and there's nothing left on it.
+    pub fn try_next(&self) -> Result<Frame, TryRecvError> {
+        let frame = self.frames.try_recv()?;
+        self.counts.waiting.fetch_sub(1, Ordering::Relaxed);
+        Ok(frame)
+    }
+
+    /// Counts `bytes` of what `try_next` gave as written.
+    pub fn wrote(&self, bytes: usize) {
+        self.counts.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Starts a thread writing everything that comes through `queue` to `writer`. It stops once every `Outbound`
+/// for the queue is dropped or a write fails.
+pub fn spawn_writer<W>(writer: W, queue: Queue, options: WriterOptions) -> std::io::Result<JoinHandle<()>>
+where
+    W: Write + Send + 'static,
+{
+    // Logging under whichever connection it's writing to
+    let span = Span::current();
+    thread::Builder::new()
+        .name("writer".to_string())
+        .spawn(move || {
+            let _entered = span.enter();
+            if let Err(e) = write_queue(writer, queue, options) {
+                warn!("[WRITER] Failed writing to connection: {e:?}");
+            }
+        })
+}
+
+fn write_queue<W: Write>(mut writer: W, queue: Queue, options: WriterOptions) -> std::io::Result<()> {
+    // A second's worth of burst, so short chats go out right away and only sustained floods get paced
+    let mut bucket = options.bytes_per_sec.map(|rate| TokenBucket::new(rate, rate, Instant::now()));
+    let mut batch = Vec::new();
+
+    while let Ok(frame) = queue.recv() {
+        batch.clear();
+        batch.push(frame);
+        let closed = fill_batch(&mut batch, &queue, &options);
+        queue.counts.waiting.fetch_sub(batch.len(), Ordering::Relaxed);
+
+        if let Some(bucket) = bucket.as_mut() {
+            let wait = bucket.take(batch_len(&batch) as u64, Instant::now());
+            if !wait.is_zero() {
+                thread::sleep(wait);
+            }
+        }
+
+        // One syscall for the whole batch instead of one per message, without copying the frames together
+        let mut slices: Vec<_> = batch.iter().map(|frame| IoSlice::new(frame)).collect();
+        write_all_vectored(&mut writer, &mut slices)?;
+        queue.counts.bytes_written.fetch_add(batch_len(&batch) as u64, Ordering::Relaxed);
+        if closed {
+            break;
+        }
+    }
+
+    writer.flush()
+}
+
+/// Adds frames to `batch` until the window's up, it's big enough, or nothing else is coming. Returns whether
+/// the queue has been closed.
+fn fill_batch(batch: &mut Vec<Frame>, queue: &Receiver<Frame>, options: &WriterOptions) -> bool {
+    let deadline = Instant::now() + options.batch_window;
+    let mut len = batch_len(batch);
+
+    while len < options.batch_bytes {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let next = if remaining.is_zero() {
+            queue.try_recv().map_err(|e| e == TryRecvError::Disconnected)
+        } else {
+            queue.recv_timeout(remaining).map_err(|e| e == RecvTimeoutError::Disconnected)
+        };
+
+        match next {
+            Ok(frame) => {
+                len += frame.len();
+                batch.push(frame);
+            }
+            Err(closed) => return closed,
+        }
+    }
+
+    false
+}
+
+fn batch_len(batch: &[Frame]) -> usize {
+    batch.iter().map(|frame| frame.len()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn send_queue_is_bounded() {
+        let (outbound, receiver) = Outbound::new(1);
+        outbound.send(Arc::from(&b"one"[..])).unwrap();
+        assert_eq!(Err(OutboundError::Full), outbound.send(Arc::from(&b"two"[..])));
+
+        drop(receiver);
+        assert_eq!(Err(OutboundError::Closed), outbound.send(Arc::from(&b"three"[..])));
+    }
+
+    #[test]
+    fn writes_in_order() {
+        let (outbound, receiver) = Outbound::new(8);
+        outbound.send(Arc::from(&b"one "[..])).unwrap();
+        outbound.send(Arc::from(&b"two"[..])).unwrap();
+        assert_eq!((2, 8), outbound.waiting());
+        let counts = receiver.counts.clone();
+        drop(outbound);
+
+        let mut written = Vec::new();
+        write_queue(&mut written, receiver, WriterOptions::default()).unwrap();
+        assert_eq!(b"one two", &written[..]);
+        assert_eq!((0, 7), (counts.waiting.load(Ordering::Relaxed), counts.bytes_written.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn wakes_whoever_writes_it_out() {
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        let (outbound, queue) = Outbound::new(1);
+        let outbound = outbound.with_wake(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        outbound.send(Arc::from(&b"one"[..])).unwrap();
+        assert!(outbound.send(Arc::from(&b"two"[..])).is_err());
+        assert_eq!(1, woken.load(Ordering::Relaxed));
+
+        assert_eq!(b"one", &*queue.try_next().unwrap());
+        queue.wrote(3);
+        assert_eq!(((0, 1), 3), (outbound.waiting(), outbound.bytes_written()));
+        assert_eq!(Err(TryRecvError::Empty), queue.try_next());
+        drop(outbound);
+        assert_eq!(Err(TryRecvError::Disconnected), queue.try_next());
+    }
+
+    #[test]
+    fn shaping_paces_writes() {
+        let (outbound, receiver) = Outbound::new(8);
+        // The first 100 bytes are burst, the next 50 have to wait half a second at 100 B/s
+        outbound.send(Arc::from(vec![0; 100])).unwrap();
+        outbound.send(Arc::from(vec![0; 50])).unwrap();
+        drop(outbound);
+
+        let started = Instant::now();
+        let mut written = Vec::new();
+        let options = WriterOptions { bytes_per_sec: Some(100), batch_bytes: 1, ..Default::default() };
+        write_queue(&mut written, receiver, options).unwrap();
+
+        assert_eq!(150, written.len());
+        assert!(started.elapsed() >= Duration::from_millis(450));
+    }
+
+    /// Counts the writes that make it through.
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> std::io::Result<usize> {
+            self.0.push(bufs.iter().flat_map(|buf| buf.to_vec()).collect());
+            Ok(self.0.last().unwrap().len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queued_frames_go_out_in_one_write() {
+        let (outbound, receiver) = Outbound::new(8);
+        (0..5).for_each(|_| outbound.send(Arc::from(&b"hi"[..])).unwrap());
+        drop(outbound);
+
+        let mut writes = Writes::default();
+        write_queue(&mut writes, receiver, WriterOptions::default()).unwrap();
+        assert_eq!(vec![b"hihihihihi".to_vec()], writes.0);
+    }
+
+    #[test]
+    fn batches_stop_at_max_size() {
+        let (outbound, receiver) = Outbound::new(8);
+        (0..5).for_each(|_| outbound.send(Arc::from(&b"hi"[..])).unwrap());
+        drop(outbound);
+
+        let mut writes = Writes::default();
+        write_queue(&mut writes, receiver, WriterOptions { batch_bytes: 4, ..Default::default() }).unwrap();
+        assert_eq!(vec![b"hihi".to_vec(), b"hihi".to_vec(), b"hi".to_vec()], writes.0);
+    }
+
+    #[test]
+    fn batch_window_waits_for_stragglers() {
+        let (outbound, receiver) = Outbound::new(8);
+        let options = WriterOptions { batch_window: Duration::from_secs(5), ..Default::default() };
+        let writer = thread::spawn(move || {
+            let mut writes = Writes::default();
+            write_queue(&mut writes, receiver, options).unwrap();
+            writes.0
+        });
+
+        outbound.send(Arc::from(&b"one "[..])).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        outbound.send(Arc::from(&b"two"[..])).unwrap();
+        drop(outbound);
+
+        assert_eq!(vec![b"one two".to_vec()], writer.join().unwrap());
+    }
+}
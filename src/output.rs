@@ -0,0 +1,159 @@
+//! `--output json` for the client: everything it'd show, as one JSON object a line instead, for `jq`, log
+//! shippers and health checks. Every object has the `event` it is and `at_ms`, when it happened in
+//! milliseconds since the Unix epoch, e.g.
+//!
+//! ```json
+//! {"at_ms":1700000000000,"event":"message","from":"alice","channel":"#rust","text":"hi","latency_ms":12}
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use crate::response::{PresenceChange, ServerMessage};
+
+/// How the client shows what happens.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Output {
+    /// For people, in a terminal.
+    #[default]
+    Text,
+    /// One JSON object a line.
+    Json,
+}
+
+/// Something the client has to say, as it goes out with `--output json`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Record<'a> {
+    /// Logged in as `nick`.
+    Connected { nick: &'a str },
+    /// Chat, in the lobby or `channel`, with how long it took to get here if the server said when it read it.
+    Message {
+        from: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel: Option<&'a str>,
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latency_ms: Option<u64>,
+    },
+    /// A `/msg`, to us or from us.
+    Private { from: &'a str, to: &'a str, text: &'a str },
+    /// Something we said, where `to` is who it was `/msg`-ed to, if anyone.
+    Sent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        to: Option<&'a str>,
+        text: &'a str,
+    },
+    /// From the server, with how long to hold off sending if that's what it's asking.
+    Notice {
+        text: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    Join { nick: &'a str },
+    Quit {
+        nick: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    Rename { from: &'a str, to: &'a str },
+    Names {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel: Option<&'a str>,
+        nicks: Vec<&'a str>,
+    },
+    /// The answer to `/list`, each channel with how many are in it.
+    Channels { channels: &'a BTreeMap<String, usize> },
+    /// From the client itself, like the answer to `/stats` or a warning about bandwidth.
+    Info { text: &'a str },
+    /// Something that didn't work, like a command the server wouldn't take.
+    Error { error: &'a str },
+    /// The server's gone, with the code the client's going to exit with.
+    Disconnected { reason: &'a str, exit_code: u8 },
+}
+
+impl<'a> Record<'a> {
+    /// What `msg` from the server is, where `latency` is how long it took to get here if that's known. What
+    /// a bouncer numbered is the message inside.
+    pub fn from_message(msg: &'a ServerMessage, latency: Option<Duration>) -> Self {
+        if let Some((from, to)) = msg.as_renamed() {
+            return Record::Rename { from, to };
+        }
+        match msg {
+            ServerMessage::Chat { from, text, channel, .. } => Record::Message {
+                from: &from.name,
+                channel: channel.as_deref(),
+                text,
+                latency_ms: latency.map(|latency| latency.as_millis() as u64),
+            },
+            ServerMessage::Private { from, to, text } => Record::Private { from: &from.name, to: &to.name, text },
+            ServerMessage::Notice { text, retry_after_ms } => Record::Notice { text, retry_after_ms: *retry_after_ms },
+            // The `disconnected` record with the exit code comes once the server's hung up
+            ServerMessage::Disconnected { reason } => Record::Notice { text: reason, retry_after_ms: None },
+            ServerMessage::Presence { user, change: PresenceChange::Joined } => Record::Join { nick: &user.name },
+            ServerMessage::Presence { user, change: PresenceChange::Quit { reason } } => {
+                Record::Quit { nick: &user.name, reason: reason.as_deref() }
+            }
+            ServerMessage::Names { channel, users } => Record::Names {
+                channel: channel.as_deref(),
+                nicks: users.iter().map(|member| member.user.name.as_str()).collect(),
+            },
+            ServerMessage::Channels { channels } => Record::Channels { channels },
+            ServerMessage::Sequenced { message, .. } => Record::from_message(message, None),
+            ServerMessage::Ping { .. } => unreachable!("Pings get answered, not shown"),
+        }
+    }
+
+    /// The line for this, as it happened `at`.
+    pub fn line(&self, at: SystemTime) -> String {
+        #[derive(Serialize)]
+        struct Stamped<'r, 'a> {
+            at_ms: u64,
+            #[serde(flatten)]
+            record: &'r Record<'a>,
+        }
+
+        let at_ms = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        serde_json::to_string(&Stamped { at_ms, record: self }).expect("Records always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::user::User;
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let at = UNIX_EPOCH + Duration::from_millis(1500);
+        let chat = ServerMessage::Chat {
+            from: User::new("alice").with_display_name("Alice"),
+            text: "hi \"there\"".to_string(),
+            received_ms: 1488,
+            channel: Some("#rust".to_string()),
+        };
+        assert_eq!(
+            r##"{"at_ms":1500,"event":"message","from":"alice","channel":"#rust","text":"hi \"there\"","latency_ms":12}"##,
+            Record::from_message(&chat, Some(Duration::from_millis(12))).line(at)
+        );
+        assert_eq!(r#"{"at_ms":1500,"event":"sent","text":"yo"}"#, Record::Sent { to: None, text: "yo" }.line(at));
+    }
+
+    #[test]
+    fn from_messages() {
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let quit = ServerMessage::Presence { user: alice.clone(), change: PresenceChange::Quit { reason: None } };
+        assert_eq!(Record::Quit { nick: "alice", reason: None }, Record::from_message(&quit, None));
+
+        let renamed = ServerMessage::renamed(&alice, &bob);
+        assert_eq!(Record::Rename { from: "alice", to: "bob" }, Record::from_message(&renamed, None));
+
+        let numbered = ServerMessage::Sequenced {
+            buffer: "alice".to_string(),
+            seq: 3,
+            message: Box::new(ServerMessage::Private { from: alice, to: bob, text: "psst".to_string() }),
+        };
+        let private = Record::Private { from: "alice", to: "bob", text: "psst" };
+        assert_eq!(private, Record::from_message(&numbered, Some(Duration::from_secs(1))));
+    }
+}
@@ -0,0 +1,227 @@
+#[cfg(feature = "plugins")]
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+#[cfg(feature = "plugins")]
+use mlua::{Function, IntoLuaMulti, Lua, RegistryKey, Table, Value};
+use thiserror::Error;
+use crate::user::User;
+
+#[derive(Error, Debug)]
+pub enum PluginError {
+    #[error("Couldn't read plugin {0}: `{1}`")]
+    Read(String, std::io::Error),
+    #[cfg(feature = "plugins")]
+    #[error("Lua error: `{0}`")]
+    Lua(#[from] mlua::Error),
+    #[cfg(not(feature = "plugins"))]
+    #[error("This was built without the plugins feature, so {0} can't be loaded")]
+    NotBuilt(String),
+}
+
+/// One loaded script, going by its file name.
+#[cfg(feature = "plugins")]
+struct Plugin {
+    name: String,
+    hooks: RegistryKey,
+}
+
+/// Lua scripts that get a say in what the client sends and shows. Each script returns a table with any of
+/// these in it:
+///
+/// - `on_connect(nick)`, once logged in. Can return a line to send, like `"/join #rust"`.
+/// - `on_outgoing(line)`, for each line typed before it's sent.
+/// - `on_incoming(from, text)`, for each chat message before it's shown.
+///
+/// `on_outgoing` and `on_incoming` return the text to use instead, `false` to drop it, or `nil` to leave it
+/// be. Each plugin gets what the one before it returned, in the order they were given. A hook that errors
+/// gets reported and leaves the text alone, so one broken script doesn't take the client down.
+///
+/// The scripts all share one Lua state, with the standard library minus the unsafe bits.
+#[cfg(feature = "plugins")]
+pub struct Plugins {
+    lua: Lua,
+    plugins: Vec<Plugin>,
+}
+
+#[cfg(feature = "plugins")]
+impl Default for Plugins {
+    fn default() -> Self {
+        Self { lua: Lua::new(), plugins: Vec::new() }
+    }
+}
+
+#[cfg(feature = "plugins")]
+impl Debug for Plugins {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.plugins.iter().map(|p| &p.name)).finish()
+    }
+}
+
+#[cfg(feature = "plugins")]
+impl Plugins {
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self, PluginError> {
+        let mut plugins = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let source = std::fs::read_to_string(path).map_err(|e| PluginError::Read(path.display().to_string(), e))?;
+            let name = path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned());
+            plugins.add(name, &source)?;
+        }
+        Ok(plugins)
+    }
+
+    pub(crate) fn add(&mut self, name: String, source: &str) -> Result<(), PluginError> {
+        let hooks: Table = self.lua.load(source).set_name(name.as_str()).eval()?;
+        let hooks = self.lua.create_registry_value(hooks)?;
+        self.plugins.push(Plugin { name, hooks });
+        Ok(())
+    }
+
+    /// Lines to send now that `user` is connected.
+    pub fn connected(&self, user: &User) -> Vec<String> {
+        self.plugins
+            .iter()
+            .filter_map(|plugin| match self.call(plugin, "on_connect", user.name.as_str()) {
+                Ok(Value::String(line)) => Some(line.to_string_lossy().into_owned()),
+                Ok(Value::Nil) => None,
+                Ok(other) => {
+                    eprintln!("[PLUGIN] {}: on_connect has to return a string or nil, not {}", plugin.name, other.type_name());
+                    None
+                }
+                Err(e) => {
+                    eprintln!("[PLUGIN] {}: on_connect failed: {e}", plugin.name);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// What to send instead of `line`, or `None` if it shouldn't be sent at all.
+    pub fn outgoing(&self, line: String) -> Option<String> {
+        self.filter(line, "on_outgoing", |line| line.to_string())
+    }
+
+    /// What to show instead of `text` from `from`, or `None` if it shouldn't be shown at all.
+    pub fn incoming(&self, from: &User, text: String) -> Option<String> {
+        self.filter(text, "on_incoming", |text| (from.name.clone(), text.to_string()))
+    }
+
+    fn filter<A>(&self, mut text: String, hook: &str, args: impl Fn(&str) -> A) -> Option<String>
+    where
+        A: for<'lua> IntoLuaMulti<'lua>,
+    {
+        for plugin in &self.plugins {
+            match self.call(plugin, hook, args(&text)) {
+                Ok(Value::Nil) => {}
+                Ok(Value::Boolean(false)) => return None,
+                Ok(Value::String(replaced)) => text = replaced.to_string_lossy().into_owned(),
+                Ok(other) => {
+                    eprintln!("[PLUGIN] {}: {hook} has to return a string, false or nil, not {}", plugin.name, other.type_name());
+                }
+                Err(e) => eprintln!("[PLUGIN] {}: {hook} failed: {e}", plugin.name),
+            }
+        }
+        Some(text)
+    }
+
+    /// Calls `hook` on `plugin` if it has one, returning `nil` if it doesn't.
+    fn call<'lua>(&'lua self, plugin: &Plugin, hook: &str, args: impl IntoLuaMulti<'lua>) -> mlua::Result<Value<'lua>> {
+        let hooks: Table = self.lua.registry_value(&plugin.hooks)?;
+        match hooks.get::<_, Option<Function>>(hook)? {
+            Some(hook) => hook.call(args),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+/// Without the `plugins` feature there's no Lua, so there are never any plugins to have a say.
+#[cfg(not(feature = "plugins"))]
+#[derive(Debug, Default)]
+pub struct Plugins;
+
+#[cfg(not(feature = "plugins"))]
+impl Plugins {
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self, PluginError> {
+        match paths.first() {
+            Some(path) => Err(PluginError::NotBuilt(path.as_ref().display().to_string())),
+            None => Ok(Self),
+        }
+    }
+
+    pub fn connected(&self, _: &User) -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn outgoing(&self, line: String) -> Option<String> {
+        Some(line)
+    }
+
+    pub fn incoming(&self, _: &User, text: String) -> Option<String> {
+        Some(text)
+    }
+}
+
+#[cfg(all(test, feature = "plugins"))]
+mod tests {
+    use super::*;
+
+    fn plugins(sources: &[&str]) -> Plugins {
+        let mut plugins = Plugins::default();
+        for (i, source) in sources.iter().enumerate() {
+            plugins.add(format!("test{i}.lua"), source).unwrap();
+        }
+        plugins
+    }
+
+    #[test]
+    fn hooks_chain_in_order() {
+        let plugins = plugins(&[
+            "return { on_outgoing = function(line) return line .. '!' end }",
+            "return { on_outgoing = function(line) return line:upper() end }",
+        ]);
+        assert_eq!(Some("HI!".to_string()), plugins.outgoing("hi".to_string()));
+    }
+
+    #[test]
+    fn dropping_and_leaving_alone() {
+        let plugins = plugins(&[r#"
+            return {
+                on_incoming = function(from, text)
+                    if from == "spammer" then return false end
+                    if text:find("secret") then return "[redacted]" end
+                end,
+            }
+        "#]);
+
+        assert_eq!(None, plugins.incoming(&User::new("spammer"), "buy now".to_string()));
+        assert_eq!(Some("[redacted]".to_string()), plugins.incoming(&User::new("alice"), "my secret".to_string()));
+        assert_eq!(Some("hi".to_string()), plugins.incoming(&User::new("alice"), "hi".to_string()));
+        // No on_outgoing at all
+        assert_eq!(Some("hi".to_string()), plugins.outgoing("hi".to_string()));
+    }
+
+    #[test]
+    fn on_connect_lines() {
+        let plugins = plugins(&[
+            "return { on_connect = function(nick) return '/join #' .. nick end }",
+            "return { on_connect = function(nick) end }",
+        ]);
+        assert_eq!(vec!["/join #alice"], plugins.connected(&User::new("alice")));
+    }
+
+    #[test]
+    fn broken_hooks_leave_text_alone() {
+        let plugins = plugins(&[
+            "return { on_outgoing = function(line) error('oops') end }",
+            "return { on_outgoing = function(line) return 42 end }",
+        ]);
+        assert_eq!(Some("hi".to_string()), plugins.outgoing("hi".to_string()));
+    }
+
+    #[test]
+    fn scripts_have_to_return_hooks() {
+        assert!(Plugins::default().add("bad.lua".to_string(), "return 1").is_err());
+        assert!(Plugins::default().add("bad.lua".to_string(), "this isn't lua").is_err());
+        assert!(matches!(Plugins::load(&["/nonexistent/plugin.lua"]), Err(PluginError::Read(..))));
+    }
+}
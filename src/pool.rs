@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use parking_lot::Mutex;
+use tracing::warn;
+
+/// A fixed set of worker threads that each run `handler` on whatever gets submitted. Submissions wait in a
+/// bounded queue until a worker is free, and get handed back once that queue is full, so a flood of work
+/// can't turn into a flood of threads.
+#[derive(Debug)]
+pub struct Pool<T: Send + 'static> {
+    queue: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    pub fn new<F>(size: usize, queue_len: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "A pool with no workers would never do anything");
+
+        let (queue, receiver) = mpsc::sync_channel::<T>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (0..size)
+            .map(|i| {
+                let receiver = receiver.clone();
+                let handler = handler.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{i}"))
+                    .spawn(move || work(&receiver, handler.as_ref()))
+                    .expect("Couldn't spawn a pool worker")
+            })
+            .collect();
+
+        Self {
+            queue: Some(queue),
+            workers,
+        }
+    }
+
+    /// Queues `item` for the next free worker, or hands it right back if the queue is full.
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        let queue = self.queue.as_ref().expect("Pool queue is only taken on drop");
+        match queue.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item) | TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+}
+
+fn work<T, F: Fn(T)>(receiver: &Mutex<Receiver<T>>, handler: &F) {
+    loop {
+        // Only hold the lock long enough to grab an item, not while handling it
+        let item = receiver.lock().recv();
+        match item {
+            Ok(item) => handler(item),
+            // The pool got dropped
+            Err(_) => break,
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for Pool<T> {
+    /// Lets the workers finish whatever's already queued, then waits for them.
+    fn drop(&mut self) {
+        drop(self.queue.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                warn!("A pool worker panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    #[test]
+    fn runs_everything_submitted() {
+        let total = Arc::new(AtomicUsize::new(0));
+        {
+            let total = total.clone();
+            let pool = Pool::new(4, 100, move |n: usize| { total.fetch_add(n, Ordering::Relaxed); });
+            (1..=100).for_each(|n| pool.try_submit(n).unwrap());
+        }
+
+        assert_eq!(5050, total.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn full_queue_hands_item_back() {
+        // Park the only worker so nothing gets pulled off the queue
+        let gate = Arc::new(Barrier::new(2));
+        let started = Arc::new(Barrier::new(2));
+        let pool = {
+            let (gate, started) = (gate.clone(), started.clone());
+            Pool::new(1, 1, move |_: usize| {
+                started.wait();
+                gate.wait();
+            })
+        };
+
+        pool.try_submit(1).unwrap();
+        started.wait();
+
+        pool.try_submit(2).unwrap();
+        assert_eq!(Err(3), pool.try_submit(3));
+
+        // Let both the in-progress and the queued item finish so the drop doesn't hang
+        gate.wait();
+        started.wait();
+        gate.wait();
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use crate::channel::Channels;
+use crate::outbound::{Frame, OutboundError, Outbound};
+use crate::server::ServerError;
+use crate::user::User;
+
+/// How many pieces users are split into. A few times more than there are cores, so connections coming and
+/// going rarely hold up each other or a broadcast.
+const SHARDS: usize = 16;
+
+/// Every connected user along with whatever the server needs to talk to them (their stream, for now), and
+/// which channels they're in.
+///
+/// Users are split into shards by their nick, each behind its own lock, so sending to one person or letting
+/// someone go only locks their shard, and broadcasts only ever read. Claiming a nick has to know nobody else
+/// looks like it, which is what `names` is for: it's held across every claim, rename and release, so
+/// those happen one at a time, but it's a lookup rather than a comparison with everyone.
+///
+/// Locks are always taken in the order `names`, then shards, then `channels`, with at most one shard held
+/// unless it's a rename, which takes its two in index order.
+#[derive(Debug)]
+pub struct Registry<T> {
+    shards: Box<[RwLock<BTreeMap<User, T>>]>,
+    hasher: RandomState,
+    /// Everyone's `User::folded_names`, and whose they are.
+    names: Mutex<BTreeMap<String, User>>,
+    channels: Mutex<Channels>,
+    /// Set under `names` once nobody else gets in, so no claim can sneak in after `close`.
+    closed: AtomicBool,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            hasher: RandomState::new(),
+            names: Mutex::new(BTreeMap::new()),
+            channels: Mutex::new(Channels::default()),
+            closed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    /// Atomically registers `user` if nobody else has their nick, or one that looks like it. If it's
+    /// taken, nothing is inserted and `conn` is dropped.
+    pub fn claim_nick(&self, user: &User, conn: T) -> Result<(), ServerError> {
+        let mut names = self.names.lock();
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(ServerError::ShuttingDown);
+        }
+        taken(&names, user, None)?;
+
+        names.extend(user.folded_names().map(|name| (name, user.clone())));
+        self.shard(user).write().insert(user.clone(), conn);
+        Ok(())
+    }
+
+    /// Turns away anyone who tries to claim a nick from now on, handing back everyone who already has.
+    pub fn close(&self) -> Vec<(User, T)>
+    where
+        T: Clone,
+    {
+        let _names = self.names.lock();
+        self.closed.store(true, Ordering::Relaxed);
+        let mut everyone = Vec::new();
+        self.for_each(|user, conn| everyone.push((user.clone(), conn.clone())));
+        everyone.sort_by(|(a, _), (b, _)| a.cmp(b));
+        everyone
+    }
+
+    /// Atomically moves `from` over to the nick `to`, channels and all, if nobody else has it or one that
+    /// looks like it. Does nothing if `from` isn't registered, they're on their way out anyway.
+    pub fn rename(&self, from: &User, to: &User) -> Result<(), ServerError> {
+        let mut names = self.names.lock();
+        taken(&names, to, Some(from))?;
+
+        let (old, new) = (self.shard_index(from), self.shard_index(to));
+        let moved = if old == new {
+            let mut shard = self.shards[old].write();
+            shard.remove_entry(from).map(|(from, conn)| {
+                shard.insert(to.clone(), conn);
+                from
+            })
+        } else {
+            let (mut first, mut second) = (self.shards[old.min(new)].write(), self.shards[old.max(new)].write());
+            let (source, dest) = if old < new { (&mut first, &mut second) } else { (&mut second, &mut first) };
+            source.remove_entry(from).map(|(from, conn)| {
+                dest.insert(to.clone(), conn);
+                from
+            })
+        };
+        let Some(from) = moved else {
+            return Ok(());
+        };
+
+        // The one in the registry, display name and all
+        for name in from.folded_names() {
+            names.remove(&name);
+        }
+        names.extend(to.folded_names().map(|name| (name, to.clone())));
+        self.channels.lock().rename(&from, to);
+        Ok(())
+    }
+
+    /// Removes `user` from the server and every channel, handing back their connection if they were registered.
+    pub fn release(&self, user: &User) -> Option<T> {
+        let mut names = self.names.lock();
+        let (user, conn) = self.shard(user).write().remove_entry(user)?;
+        for name in user.folded_names() {
+            names.remove(&name);
+        }
+        drop(names);
+
+        self.channels.lock().leave_all(&user);
+        Some(conn)
+    }
+
+    /// How many users are connected.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `user`'s connection, if they're here.
+    pub fn get(&self, user: &User) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.shard(user).read().get(user).cloned()
+    }
+
+    /// Calls `f` with everyone, a shard at a time, so nobody can come or go from the one it's in meanwhile.
+    /// Don't take any other registry locks in it.
+    pub fn for_each(&self, mut f: impl FnMut(&User, &T)) {
+        for shard in self.shards.iter() {
+            for (user, conn) in shard.read().iter() {
+                f(user, conn);
+            }
+        }
+    }
+
+    /// Calls `f` with everyone in `channel`, where `None` is the lobby, like `for_each`.
+    pub fn for_each_in(&self, channel: Option<&str>, mut f: impl FnMut(&User, &T)) {
+        for shard in self.shards.iter() {
+            let shard = shard.read();
+            let channels = self.channels.lock();
+            for (user, conn) in shard.iter().filter(|(user, _)| channels.hears(user, channel)) {
+                f(user, conn);
+            }
+        }
+    }
+
+    /// Locks channel membership. Don't take any other registry lock while holding this.
+    pub fn channels(&self) -> MutexGuard<'_, Channels> {
+        self.channels.lock()
+    }
+
+    fn shard(&self, user: &User) -> &RwLock<BTreeMap<User, T>> {
+        &self.shards[self.shard_index(user)]
+    }
+
+    fn shard_index(&self, user: &User) -> usize {
+        self.hasher.hash_one(&user.name) as usize % SHARDS
+    }
+}
+
+/// Whether `user`'s nick, or one that looks like it, is taken by anyone but `except`.
+fn taken(names: &BTreeMap<String, User>, user: &User, except: Option<&User>) -> Result<(), ServerError> {
+    let others: Vec<_> = user.folded_names().filter_map(|name| names.get(&name)).filter(|other| Some(*other) != except).collect();
+    if others.contains(&user) {
+        return Err(ServerError::AlreadyConnected(user.name.clone()));
+    }
+    match others.first() {
+        Some(other) => Err(ServerError::LooksLike(user.name.clone(), other.name.clone())),
+        None => Ok(()),
+    }
+}
+
+impl Registry<Outbound> {
+    /// Queues `frame` for everyone except `except`, handing back whoever's queue was too full to take it.
+    /// They've fallen too far behind to catch up, so they get hung up on. People on their way out don't count,
+    /// they're not getting anything anyway.
+    pub fn send_to_all(&self, frame: &Frame, except: Option<&User>) -> Vec<User> {
+        let mut full = Vec::new();
+        self.for_each(|user, outbound| {
+            if Some(user) != except && queue(outbound, frame.clone()) == Err(OutboundError::Full) {
+                full.push(user.clone());
+            }
+        });
+        full
+    }
+
+    /// Queues `frame` for everyone in `channel` except `except`, where `None` is the lobby. Hands back
+    /// whoever's queue was too full, like `send_to_all`.
+    pub fn send_to_channel(&self, frame: &Frame, channel: Option<&str>, except: Option<&User>) -> Vec<User> {
+        let mut full = Vec::new();
+        self.for_each_in(channel, |user, outbound| {
+            if Some(user) != except && queue(outbound, frame.clone()) == Err(OutboundError::Full) {
+                full.push(user.clone());
+            }
+        });
+        full
+    }
+
+    /// Queues `frame` for just `user`, hanging up on them if it's full like `send_to_all`.
+    pub fn send_to(&self, user: &User, frame: Frame) -> Result<(), OutboundError> {
+        queue(self.shard(user).read().get(user).ok_or(OutboundError::Closed)?, frame)
+    }
+
+    /// Sends `user` one last `frame` and hangs up on them. They get released once their connection notices.
+    /// Returns whether they were here to kill.
+    pub fn kill(&self, user: &User, frame: Frame) -> bool {
+        let shard = self.shard(user).read();
+        let Some(outbound) = shard.get(user) else {
+            return false;
+        };
+
+        // Full doesn't matter, they're going either way
+        let _ = outbound.send(frame);
+        outbound.hang_up();
+        true
+    }
+}
+
+/// Queues `frame` on `outbound`, or hangs up on it if the queue's full.
+fn queue(outbound: &Outbound, frame: Frame) -> Result<(), OutboundError> {
+    let queued = outbound.send(frame);
+    if queued == Err(OutboundError::Full) {
+        outbound.hang_up();
+    }
+    queued
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn claim_nick_rejects_duplicates() {
+        let registry = Registry::default();
+        let user = User::new("hello");
+
+        assert!(registry.claim_nick(&user, 1).is_ok());
+        assert!(matches!(registry.claim_nick(&user, 2), Err(ServerError::AlreadyConnected(_))));
+        // The original claim wins
+        assert_eq!(Some(1), registry.get(&user));
+    }
+
+    #[test]
+    fn claim_nick_rejects_lookalikes() {
+        let registry = Registry::default();
+        registry.claim_nick(&User::new("alice"), 1).unwrap();
+
+        let lookalike = registry.claim_nick(&User::new("aIice"), 2);
+        assert!(matches!(lookalike, Err(ServerError::LooksLike(ref new, ref old)) if new == "aIice" && old == "alice"));
+        let impersonator = User::new("bob").with_display_name("Alice");
+        assert!(matches!(registry.claim_nick(&impersonator, 3), Err(ServerError::LooksLike(..))));
+        assert!(registry.claim_nick(&User::new("bob"), 4).is_ok());
+    }
+
+    #[test]
+    fn claim_nick_after_release() {
+        let registry = Registry::default();
+        let user = User::new("hello");
+
+        registry.claim_nick(&user, 1).unwrap();
+        assert_eq!(Some(1), registry.release(&user));
+        assert!(registry.claim_nick(&user, 2).is_ok());
+    }
+
+    #[test]
+    fn rename_moves_everything_over() {
+        let registry = Registry::default();
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        registry.claim_nick(&alice, 1).unwrap();
+        registry.claim_nick(&bob, 2).unwrap();
+        registry.channels().join(&alice, "#rust");
+
+        assert!(matches!(registry.rename(&alice, &bob), Err(ServerError::AlreadyConnected(_))));
+        assert!(matches!(registry.rename(&alice, &User::new("B0B")), Err(ServerError::LooksLike(..))));
+        // Looking like your old self is fine
+        registry.rename(&alice, &User::new("ALICE")).unwrap();
+
+        let renamed = User::new("ALICE");
+        assert_eq!(Some(1), registry.get(&renamed));
+        assert_eq!(None, registry.get(&alice));
+        assert_eq!(Some("#rust"), registry.channels().current(&renamed));
+        assert!(!registry.channels().hears(&alice, Some("#rust")));
+    }
+
+    #[test]
+    fn kill_sends_last_words_and_hangs_up() {
+        let registry = Registry::default();
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let (outbound, queue) = Outbound::new(1);
+        let flag = hung_up.clone();
+        registry.claim_nick(&User::new("bob"), outbound.with_hang_up(move || flag.store(true, Ordering::Relaxed))).unwrap();
+
+        assert!(!registry.kill(&User::new("alice"), Frame::from(*b"bye")));
+        assert!(registry.kill(&User::new("bob"), Frame::from(*b"bye")));
+        assert!(hung_up.load(Ordering::Relaxed));
+        assert_eq!(b"bye"[..], *queue.try_recv().unwrap());
+    }
+
+    #[test]
+    fn close_turns_everyone_else_away() {
+        let registry = Registry::default();
+        registry.claim_nick(&User::new("alice"), 1).unwrap();
+        registry.claim_nick(&User::new("bob"), 2).unwrap();
+
+        assert_eq!(vec![(User::new("alice"), 1), (User::new("bob"), 2)], registry.close());
+        assert!(matches!(registry.claim_nick(&User::new("carol"), 3), Err(ServerError::ShuttingDown)));
+        registry.release(&User::new("alice"));
+        assert!(matches!(registry.claim_nick(&User::new("alice"), 4), Err(ServerError::ShuttingDown)));
+    }
+
+    #[test]
+    fn send_to_channel_only_reaches_members() {
+        let registry = Registry::default();
+        let mut queues = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let (outbound, queue) = Outbound::new(4);
+            registry.claim_nick(&User::new(name), outbound).unwrap();
+            queues.push(queue);
+        }
+        registry.channels().join(&User::new("alice"), "#rust");
+        registry.channels().join(&User::new("bob"), "#rust");
+
+        registry.send_to_channel(&Frame::from(*b"rust"), Some("#rust"), Some(&User::new("alice")));
+        registry.send_to_channel(&Frame::from(*b"lobby"), None, None);
+        let received: Vec<Vec<_>> = queues.iter().map(|q| q.try_iter().map(|f| f.to_vec()).collect()).collect();
+        assert_eq!(vec![vec![], vec![b"rust".to_vec()], vec![b"lobby".to_vec()]], received);
+
+        // Leaving the server leaves the channels too
+        registry.release(&User::new("bob"));
+        assert!(!registry.channels().hears(&User::new("bob"), Some("#rust")));
+    }
+
+    #[test]
+    fn claim_nick_concurrent_same_nick() {
+        const THREADS: usize = 32;
+
+        // Run it a bunch since a race won't show up every time
+        for _ in 0..50 {
+            let registry = Arc::new(Registry::default());
+            let barrier = Arc::new(Barrier::new(THREADS));
+
+            let handles: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let registry = registry.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        registry.claim_nick(&User::new("hello"), i).is_ok()
+                    })
+                })
+                .collect();
+
+            let wins = handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count();
+            assert_eq!(1, wins);
+            assert_eq!(1, registry.len());
+        }
+    }
+
+    #[test]
+    fn claim_nick_concurrent_lookalikes() {
+        // In different shards, most likely, but only one can have it
+        let nicks = ["alice", "ALICE", "aIice", "a1ice", "alIce", "Alice"];
+
+        for _ in 0..50 {
+            let registry = Arc::new(Registry::default());
+            let barrier = Arc::new(Barrier::new(nicks.len()));
+
+            let handles: Vec<_> = nicks
+                .into_iter()
+                .map(|nick| {
+                    let (registry, barrier) = (registry.clone(), barrier.clone());
+                    thread::spawn(move || {
+                        barrier.wait();
+                        registry.claim_nick(&User::new(nick), ()).is_ok()
+                    })
+                })
+                .collect();
+
+            let wins = handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count();
+            assert_eq!((1, 1), (wins, registry.len()));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeSet;
+use crate::response::same_secret;
+use crate::user::User;
+
+/// What someone's allowed to do on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    /// Can use commands like `/kick`, `/ban`, `/unban`, `/bans`, `/kill`, `/wallops` and `/maintenance`.
+    Operator,
+}
+
+/// Who gets which role. Everyone's a plain user unless they're listed as an operator, and even then only once
+/// they've shown they're who the nick says: by it being registered, since connecting as a registered nick
+/// takes its password, or by giving `/oper` the operator `password`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Roles {
+    pub operators: BTreeSet<User>,
+    /// What `/oper` takes, if there's anything it does.
+    pub password: Option<String>,
+}
+
+impl Roles {
+    /// `user`'s role, where `registered` is whether their nick's registered and `opered` whether their
+    /// connection's given `/oper` the operator password.
+    pub fn of(&self, user: &User, (registered, opered): (bool, bool)) -> Role {
+        if self.operators.contains(user) && (registered || opered) {
+            Role::Operator
+        } else {
+            Role::User
+        }
+    }
+
+    /// Whether `given` is the operator password.
+    pub fn is_password(&self, given: &str) -> bool {
+        self.password.as_deref().is_some_and(|password| same_secret(given, password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_users_who_proved_it_are_operators() {
+        let roles = Roles { operators: BTreeSet::from([User::new("admin")]), password: Some("hunter2".to_string()) };
+        assert_eq!(Role::Operator, roles.of(&User::new("admin"), (true, false)));
+        assert_eq!(Role::Operator, roles.of(&User::new("admin"), (false, true)));
+        assert_eq!(Role::User, roles.of(&User::new("admin"), (false, false)));
+        assert_eq!(Role::User, roles.of(&User::new("bob"), (true, true)));
+
+        assert!(roles.is_password("hunter2"));
+        assert!(!roles.is_password("hunter3"));
+        assert!(!Roles::default().is_password(""));
+    }
+}
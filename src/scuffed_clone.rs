@@ -1,5 +1,7 @@
 use std::io::Cursor;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 
 // So I can use TcpStream for real, but an std::io::Cursor in testing
 pub trait ScuffedClone {
@@ -12,9 +14,39 @@ impl ScuffedClone for TcpStream {
     }
 }
 
+#[cfg(unix)]
+impl ScuffedClone for UnixStream {
+    fn scuffed_clone(&self) -> Self {
+        self.try_clone().expect("Scuffed clone on a UnixStream didn't work either")
+    }
+}
+
 impl<T: Clone> ScuffedClone for Cursor<T> {
     fn scuffed_clone(&self) -> Self {
         self.clone()
     }
 }
 
+/// Cuts off whoever's reading the connection, from any thread. Writes already queued still go out.
+pub trait HangUp {
+    fn hang_up(&self);
+}
+
+impl HangUp for TcpStream {
+    fn hang_up(&self) {
+        // Already being gone is fine, that's what we wanted anyway
+        let _ = self.shutdown(Shutdown::Read);
+    }
+}
+
+#[cfg(unix)]
+impl HangUp for UnixStream {
+    fn hang_up(&self) {
+        let _ = self.shutdown(Shutdown::Read);
+    }
+}
+
+impl<T> HangUp for Cursor<T> {
+    fn hang_up(&self) {}
+}
+
@@ -0,0 +1,67 @@
+//! Secrets kept in files rather than on the command line or in a config, like passwords and private keys.
+//! Anything on the command line shows up in `ps`, and a config tends to get passed around, but a file can be
+//! locked down. So these are only read from files that are, and anything anyone at all can read is refused.
+
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("Couldn't read {0}: `{1}`")]
+    Read(String, std::io::Error),
+    #[error("{0} can be read by anyone, so it won't be used until it can't, e.g. after chmod 600 {0}")]
+    WorldReadable(String),
+}
+
+/// Fails unless `path` is kept from everyone but its owner and group. Windows has no such thing as
+/// world-readable, so everything's private enough there.
+pub fn check_private(path: &Path) -> Result<(), SecretError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(path).map_err(|e| SecretError::Read(path.display().to_string(), e))?;
+        if metadata.permissions().mode() & 0o004 != 0 {
+            return Err(SecretError::WorldReadable(path.display().to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// The secret in `path`, without the newline editors like to leave at the end.
+pub fn read(path: &Path) -> Result<String, SecretError> {
+    check_private(path)?;
+    let secret = std::fs::read_to_string(path).map_err(|e| SecretError::Read(path.display().to_string(), e))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Writes `secret` to a new file at `path` that only its owner can read.
+pub fn create(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, secret)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use super::*;
+
+    #[test]
+    fn only_reads_private_files() {
+        let path = std::env::temp_dir().join(format!("basic-irc-secret-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        create(&path, b"hunter2\n").unwrap();
+        assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        assert_eq!("hunter2", read(&path).unwrap());
+        assert!(create(&path, b"again").is_err());
+
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(read(&path), Err(SecretError::WorldReadable(_))));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(read(&path), Err(SecretError::Read(..))));
+    }
+}
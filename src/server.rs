@@ -1,243 +1,2621 @@
-use std::collections::BTreeMap;
-use std::fmt::Debug;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::fmt::{Debug, Write as _};
+use std::io::{BufRead, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::{Arc, mpsc};
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::thread;
 use parking_lot::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
 use thiserror::Error;
-use crate::response::AuthResponse;
-use crate::scuffed_clone::ScuffedClone;
-use crate::user::User;
+use tracing::{field, info, info_span, warn, Span};
+use crate::accounting::{enforce, IoLimits, IoMeter, Metered};
+#[cfg(unix)]
+use crate::admin::AdminSocket;
+use crate::bans::Bans;
+use crate::budget::{MemoryBudget, Reservation};
+use crate::capacity::{AcceptRate, ClientLimit, Seat, Seated};
+use crate::channel;
+use crate::cluster::{Cluster, ClusterOptions};
+use crate::command::Command;
+use crate::control;
+use crate::diagnostics::Diagnostics;
+use crate::exporter;
+#[cfg(feature = "mdns")]
+use crate::discovery;
+#[cfg(feature = "irc")]
+use crate::gateway;
+use crate::history::History;
+use crate::keepalive::{self, Keepalive};
+use crate::flood::{Flood, FloodGuard, FloodLimits};
+use crate::frame::{decode_message, encode_message, FrameError, FrameLimits, Framed, write_message};
+use crate::maintenance::{self, Maintenance};
+use crate::metrics::{Counted, Metrics};
+use crate::mirror::{self, MirrorOptions};
+use crate::outbound::{Frame, Outbound, OutboundError, spawn_writer, WriterOptions};
+use crate::pool::Pool;
+use crate::registry::Registry;
+use crate::response::{self, AuthResponse, Capabilities, Handshake, Login, Member, PresenceChange, ServerMessage};
+use crate::roles::{Role, Roles};
+use crate::scuffed_clone::{HangUp, ScuffedClone};
+use crate::server_friendly_string::ServerFriendlyString;
+use crate::shutdown::{self, Shutdown};
+use crate::storage::{Accounts, StorageError};
+use crate::stun;
+use crate::tail;
+use crate::template::ServerInfo;
+#[cfg(unix)]
+use crate::transport::SocketFile;
+use crate::transport::{self, Listener, Stream, Transport};
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+#[cfg(feature = "upnp")]
+use crate::upnp::{self, PortMapping};
+use crate::user::{User, UserError};
+use crate::websocket::WsStream;
 
 pub const VALIDATE_BUFFER_SIZE: usize = 256;
+/// A hello is just a `Handshake` and then a `Login`, so neither has any business being big or deeply nested
+pub(crate) const HELLO_LIMITS: FrameLimits = FrameLimits { max_len: VALIDATE_BUFFER_SIZE, max_depth: 2 };
+/// Optional features every server offers clients in the handshake. None yet, see `features`.
+const FEATURES: &[&str] = &[];
 const CHANNEL_SIZE: usize = 128;
-type SharedMap<K, V> = Arc<Mutex<BTreeMap<K, V>>>;
-type ChatLine = (User, String);
+/// How long shutting down waits for everyone's goodbye to go out before giving up on them.
+pub(crate) const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+type SharedRegistry = Arc<Registry<Outbound>>;
+
+/// What clients speak to the server.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Length-prefixed JSON, what the bundled client speaks.
+    Native,
+    /// RFC 2812 IRC, for everyone else's clients.
+    Irc,
+}
+
+/// Knobs for the server that have nothing to do with where it listens.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Bytes of chat the whole server may have read but not yet broadcast, at once. Messages over it get dropped.
+    /// Send queues and history aren't counted, they're capped by `send_queue_len` and `history` instead.
+    pub memory_budget: usize,
+    /// Longest line a single connection may send. Anything longer gets thrown away.
+    pub max_line_len: usize,
+    /// Longest message in grapheme clusters. Longer ones get cut short rather than dropped.
+    pub max_message_len: usize,
+    /// Worker threads handling connections. Each client holds one for as long as it's connected, so this is the
+    /// most that can be chatting at once. Each also gets a writer thread of its own, counted under `max_clients`,
+    /// so the server runs at most `workers` plus `max_clients` threads for connections.
+    pub workers: usize,
+    /// Connections that may wait for a free worker before new ones get turned away. Only ever used with more
+    /// `max_clients` than `workers`, and then they wait until somebody leaves.
+    pub accept_queue: usize,
+    /// Most clients connected at once, counting ones waiting for a worker and writers still finishing up after
+    /// their client's gone, or `None` for one per worker, so anyone past them is told the server's full rather
+    /// than left waiting. `None` doesn't limit them at all with the mio and tokio runtimes, which have no workers to
+    /// wait for.
+    pub max_clients: Option<usize>,
+    /// Also takes WebSocket connections on this port, for browsers.
+    pub ws_port: Option<u16>,
+    /// Takes connections on this Unix socket instead of the TCP port, for when everyone's on this machine.
+    pub unix: Option<PathBuf>,
+    /// New connections let in per second, 0 for as many as come. Anyone past it is told when to come back.
+    pub max_accepts_per_sec: u64,
+    /// Connections let in all at once before `max_accepts_per_sec` kicks in.
+    pub accept_burst: u64,
+    /// Threads accepting connections, each with its own `SO_REUSEPORT` listener. Only Linux gets more than one.
+    pub acceptors: usize,
+    /// Most any one connection may send before it gets throttled, then cut off.
+    pub io_limits: IoLimits,
+    /// How fast any one connection may say things before what it says gets dropped, then it gets cut off.
+    pub flood: FloodLimits,
+    /// When quiet connections get pinged, and how long they have to answer before they're hung up on.
+    pub keepalive: Keepalive,
+    /// Frames that may wait to go out to one connection before it gets hung up on for not keeping up.
+    pub send_queue_len: usize,
+    /// How each connection's writer paces and batches what it sends to its client.
+    pub writer: WriterOptions,
+    /// Whether to advertise the server on the LAN over mDNS.
+    pub advertise: bool,
+    /// Whether to ask the router to forward the port over UPnP.
+    pub upnp: bool,
+    /// STUN server (`host:port`) to ask for our public IP at startup.
+    pub stun_server: Option<String>,
+    /// Linking up with other servers, if this one's part of a cluster.
+    pub cluster: Option<ClusterOptions>,
+    /// Upstream server to mirror to local users, who then can only pick which of its channels to follow.
+    pub mirror: Option<MirrorOptions>,
+    /// Who gets to use operator commands.
+    pub roles: Roles,
+    /// What new connections get told while the server's in maintenance, unless the operator gives a message.
+    pub maintenance_message: String,
+    /// What the server calls itself in templates.
+    pub server_name: String,
+    /// Sent to everyone right after they connect.
+    pub motd: Option<String>,
+    /// What clients speak to the server.
+    pub protocol: Protocol,
+    /// Certificate and key to talk TLS with, or `None` for plain TCP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// What clients have to log in with, if anything.
+    pub password: Option<String>,
+    /// Registered nicks, or `None` if nobody gets to `/register` here.
+    pub accounts: Option<Arc<Accounts>>,
+    /// Messages kept per channel (and the lobby) for showing people when they connect or join.
+    pub history: usize,
+    /// Where to keep that history so it's still there after a restart, if anywhere.
+    pub history_file: Option<PathBuf>,
+    /// Unix socket to take admin commands like `tail` on, if any.
+    pub admin_socket: Option<PathBuf>,
+    /// Where to keep bans so they're still there after a restart, if anywhere.
+    pub bans_file: Option<PathBuf>,
+    /// Port to serve Prometheus metrics on, on the same address as the server, if any.
+    pub metrics_port: Option<u16>,
+    /// Port for the admin HTTP API, on the same address as the server, and the token it needs. Off if `None`.
+    pub admin_api: Option<(u16, String)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            memory_budget: 64 * 1024 * 1024,
+            max_line_len: 64 * 1024,
+            max_message_len: 512,
+            workers: 64,
+            accept_queue: 128,
+            max_clients: None,
+            ws_port: None,
+            unix: None,
+            max_accepts_per_sec: 100,
+            accept_burst: 200,
+            acceptors: 1,
+            io_limits: IoLimits::default(),
+            flood: FloodLimits::default(),
+            keepalive: Keepalive::default(),
+            send_queue_len: 256,
+            writer: WriterOptions::default(),
+            advertise: false,
+            upnp: false,
+            stun_server: None,
+            cluster: None,
+            mirror: None,
+            roles: Roles::default(),
+            maintenance_message: maintenance::DEFAULT_MESSAGE.to_string(),
+            server_name: ServerInfo::default().name,
+            motd: None,
+            protocol: Protocol::Native,
+            #[cfg(feature = "tls")]
+            tls: None,
+            password: None,
+            accounts: None,
+            history: 50,
+            history_file: None,
+            admin_socket: None,
+            bans_file: None,
+            metrics_port: None,
+            admin_api: None,
+        }
+    }
+}
+
+/// A line of chat on its way to the broadcaster, stamped with when the server read it.
+#[derive(Debug)]
+pub(crate) struct ChatLine {
+    pub(crate) from: User,
+    text: String,
+    received: Instant,
+    received_at: SystemTime,
+    // Held until the line has been broadcast, so in-flight messages count against the memory budget
+    _memory: Option<Reservation>,
+    // For a `/nick`, where the connection hears what it's called now, if it worked
+    renamed: Option<SyncSender<User>>,
+    // Not something they said at all, but them connecting or disconnecting
+    presence: Option<PresenceChange>,
+    // Not from anyone, just to hear back once everything ahead of it has been broadcast
+    flushed: Option<SyncSender<()>>,
+}
+
+impl ChatLine {
+    pub(crate) fn new(from: User, text: impl Into<String>) -> Self {
+        Self {
+            from,
+            text: text.into(),
+            received: Instant::now(),
+            received_at: SystemTime::now(),
+            _memory: None,
+            renamed: None,
+            presence: None,
+            flushed: None,
+        }
+    }
+
+    /// `from` connecting or disconnecting, for everyone else to hear about.
+    pub(crate) fn presence(from: User, change: PresenceChange) -> Self {
+        Self { presence: Some(change), ..Self::new(from, "") }
+    }
+
+    /// Gets an answer on `done` once the broadcaster's got to it, and so through everything sent before it.
+    fn flush(done: SyncSender<()>) -> Self {
+        Self { flushed: Some(done), ..Self::new(User::new(""), "") }
+    }
+
+    fn reserved(mut self, memory: Reservation) -> Self {
+        self._memory = Some(memory);
+        self
+    }
+
+    /// The message to send out, said in `channel`.
+    pub(crate) fn to_message(&self, channel: Option<String>) -> ServerMessage {
+        ServerMessage::Chat {
+            from: self.from.clone(),
+            text: self.text.clone(),
+            // Only fails if the clock is set before 1970, in which case there's no sensible timestamp anyway
+            received_ms: self.received_at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            channel,
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("Failed to read/write from stream: `{0}`")]
     IO(#[from] std::io::Error),
-    #[error("Failed serializing user info: `{0}`")]
-    Serde(#[from] serde_json::Error),
+    #[error("Bad frame: `{0}`")]
+    Frame(#[from] FrameError),
     #[error("A user is already connected with that name: `{0}`")]
     AlreadyConnected(String),
+    #[error("Name is too close to one that's taken: {0} looks like {1}")]
+    LooksLike(String, String),
+    #[error("Not a valid user: `{0}`")]
+    InvalidUser(#[from] UserError),
+    #[error("Server is in maintenance, not letting anyone in: {0}")]
+    Maintenance(String),
+    #[error("Client doesn't speak our protocol: {0}")]
+    Incompatible(String),
+    #[error("Wrong password")]
+    BadPassword,
+    #[error("{0} is registered, connect with its password to use it")]
+    Unidentified(String),
+    #[error("Couldn't check the accounts: `{0}`")]
+    Storage(#[from] StorageError),
+    #[error("{0}")]
+    Banned(String),
+    #[error("Server is shutting down")]
+    ShuttingDown,
+}
+
+impl ServerError {
+    /// What to tell a client `let_in` turned away, whichever runtime they came in on. `None` if there's
+    /// nothing to tell them, because what they sent wasn't even a hello.
+    pub(crate) fn answer(&self) -> Option<AuthResponse> {
+        let answer = match self {
+            ServerError::IO(_) | ServerError::Frame(_) | ServerError::Incompatible(_) => return None,
+            ServerError::BadPassword => return Some(AuthResponse::BadPassword),
+            ServerError::Storage(_) => "Couldn't check your login, try again later".to_string(),
+            ServerError::AlreadyConnected(name) => format!("Name is already taken: {name}"),
+            ServerError::InvalidUser(e) => e.to_string(),
+            ServerError::Maintenance(message) => message.clone(),
+            ServerError::LooksLike(..) | ServerError::Unidentified(_) | ServerError::Banned(_) | ServerError::ShuttingDown => {
+                self.to_string()
+            }
+        };
+        Some(AuthResponse::Error(answer))
+    }
 }
 
-pub fn start(address: SocketAddr) -> std::io::Result<()> {
-    let listener = TcpListener::bind(address)?;
-    eprintln!("Listening on port {}", listener.local_addr().expect("Can't get local_addr for server").port());
+/// The optional features the server offers clients in the handshake, which depend on whether it has a
+/// password.
+pub(crate) fn features(password: bool) -> Vec<&'static str> {
+    FEATURES.iter().copied().chain(password.then_some(response::PASSWORD)).collect()
+}
 
-    let connected_users: SharedMap<User, TcpStream> = Default::default();
-    let (sender, receiver) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+/// What a client's handshake gets answered with.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum HandshakeAnswer {
+    Capabilities(Capabilities),
+    /// It wasn't a handshake at all.
+    Missing(AuthResponse),
+}
 
-    thread::scope(|scope| {
-        let users = connected_users.clone();
-        scope.spawn(move || { broadcast_messages(users, receiver); });
-
-        for stream_res in listener.incoming() {
-            match stream_res {
-                Ok(stream) => {
-                    let users = connected_users.clone();
-                    let tx = sender.clone();
-                    scope.spawn(move || handle_connection(stream, users, tx));
+/// Answers the handshake in `frame`, offering `features`. Every runtime answers handshakes here, so they all
+/// speak to the same clients. The answer always goes back, and an error means turning them away once it has.
+pub(crate) fn answer_handshake(frame: &[u8], features: &[&str]) -> (HandshakeAnswer, Result<Capabilities, ServerError>) {
+    let handshake: Handshake = match decode_message(frame, &HELLO_LIMITS) {
+        Ok(handshake) => handshake,
+        // Most likely a `User` from a client that predates handshakes
+        Err(e) => {
+            let incompatible = ServerError::Incompatible(format!("no handshake ({e})"));
+            return (HandshakeAnswer::Missing(AuthResponse::needs_handshake()), Err(incompatible));
+        }
+    };
+
+    let capabilities = handshake.answer(features).stamped(SystemTime::now());
+    let shaken = match &capabilities {
+        Capabilities::Rejected(reason) => Err(ServerError::Incompatible(reason.clone())),
+        accepted => Ok(accepted.clone()),
+    };
+    (HandshakeAnswer::Capabilities(capabilities), shaken)
+}
+
+/// Lets in whoever sent the hello in `frame`, if `check_login` does, their nick's valid and `claim` takes it for
+/// them. Every runtime reads hellos here, so they all turn the same people away for the same reasons.
+/// `ServerError::answer` says what to tell them.
+pub(crate) fn let_in(
+    frame: &[u8],
+    options: &Options,
+    claim: impl FnOnce(&User) -> Result<(), ServerError>,
+) -> Result<User, ServerError> {
+    let login: Login = decode_message(frame, &HELLO_LIMITS)?;
+    // Holds up whatever's reading it if there's an account password to check, but only while logging in
+    check_login(&login, options)?;
+    login.user.validate()?;
+    claim(&login.user)?;
+    Ok(login.user)
+}
+
+/// Whether `login` gets any further: it has to know the server's password if there is one, and its account's
+/// if its nick is registered. Checked before anything else, so nobody can find out who's online without it.
+/// Checking an account's password is slow on purpose, tens of milliseconds.
+pub(crate) fn check_login(login: &Login, options: &Options) -> Result<(), ServerError> {
+    if !login.knows(options.password.as_deref()) {
+        return Err(ServerError::BadPassword);
+    }
+    match &options.accounts {
+        Some(accounts) if !accounts.lets_in(&login.user.name, login.account_password.as_deref())? => {
+            Err(ServerError::Unidentified(login.user.name.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn start(address: SocketAddr, options: Options) -> std::io::Result<()> {
+    #[cfg(not(feature = "irc"))]
+    if options.protocol == Protocol::Irc {
+        return Err(std::io::Error::new(ErrorKind::Unsupported, "This was built without the irc feature, so it can't speak IRC"));
+    }
+    let metrics: Arc<Metrics> = Default::default();
+    let listeners = transport::bind(address, options.acceptors, (options.ws_port, options.unix.as_deref()))?;
+    #[cfg(unix)]
+    let _socket = options.unix.clone().map(SocketFile);
+    // Only ever 0 without a TCP port, which is for servers nobody's meant to find or reach from outside
+    let port = listeners[0].0.port().unwrap_or_default();
+    let acceptors = listeners.iter().filter(|(_, transport)| *transport == Transport::Tcp).count();
+    if acceptors > 0 {
+        info!("Listening on port {port} with {acceptors} acceptor(s)");
+    }
+    let shutdown = Arc::new(Shutdown::default());
+    for (listener, transport) in &listeners {
+        shutdown.watch(listener)?;
+        match transport {
+            Transport::Tcp => {}
+            Transport::WebSocket => info!("[WS] Taking WebSocket connections on ws://{listener}"),
+            Transport::Unix => info!("Listening on {listener}"),
+        }
+    }
+    shutdown::on_signals(shutdown.clone())?;
+    let _advertisement = advertise(&options, port);
+    let _mapping = map_port(&options, port);
+    *metrics.public_address.lock() = public_address(&options, port);
+
+    let max_clients = options.max_clients.unwrap_or(options.workers);
+    let shared = Arc::new(Shared::open(address, &options, (metrics, shutdown.clone()), Some(max_clients))?);
+    let (users, flusher) = (shared.users.clone(), shared.sender.clone());
+    #[cfg(feature = "tls")]
+    let tls = options.tls.is_some();
+    #[cfg(not(feature = "tls"))]
+    let tls = false;
+    let (workers, accept_queue, door) = (options.workers, options.accept_queue, shared.clone());
+    // The seat goes to the connection's writer once there is one, and is given up with the handler if there isn't
+    let pool = Pool::new(workers, accept_queue, move |((stream, transport), peer, seat): Incoming| {
+        // The handshake happens on the first read, so on this worker rather than holding up the acceptor. Only
+        // for TCP, what's on a Unix socket never leaves the machine.
+        #[cfg(feature = "tls")]
+        let stream = match (stream, &options.tls) {
+            (Stream::Tcp(tcp), Some(config)) => {
+                match TlsStream::accept(tcp, config.clone()) {
+                    Ok(stream) => unwrap_transport(stream, transport, (peer, seat), (&shared, &options)),
+                    Err(e) => warn!("[TLS] Couldn't start a session with {peer}: {e:?}"),
                 }
-                Err(e) => { eprintln!("Failed on handling incoming stream: {e:?}"); }
+                return;
             }
+            (stream, _) => stream,
+        };
+        unwrap_transport(stream, transport, (peer, seat), (&shared, &options))
+    });
+
+    // Every acceptor feeds the same pool, so the limits on workers and waiting connections are server-wide
+    thread::scope(|scope| {
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let (pool, gates) = (&pool, (&*door.bans, &*shutdown, &door.clients, &door.accepts));
+            thread::Builder::new()
+                .name(format!("acceptor-{i}"))
+                .spawn_scoped(scope, move || accept_connections(listener, pool, (workers, accept_queue, tls), gates))
+                .expect("Couldn't spawn an acceptor");
         }
     });
 
+    info!("Not taking any more connections, saying goodbye to everyone");
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    say_goodbye(&users, &flusher, deadline);
+    drop(flusher);
+
+    // The workers finish up once everyone they're serving has been hung up on
+    let (finished, workers_done) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        drop(pool);
+        let _ = finished.send(());
+    });
+    if workers_done.recv_timeout(deadline.saturating_duration_since(Instant::now())).is_err() {
+        warn!("Some connections were still being served after {}s, not waiting for them", SHUTDOWN_GRACE.as_secs());
+    }
+    let unsent = shutdown.wait_for_writers(deadline);
+    if unsent > 0 {
+        warn!("{unsent} connection(s) still had something to send after {}s", SHUTDOWN_GRACE.as_secs());
+    }
+
+    info!("Shut down");
     Ok(())
 }
 
-fn handle_connection<S: Read + Write + ScuffedClone>(
-    mut stream: S,
-    mut connected_users: SharedMap<User, S>,
-    sender: SyncSender<ChatLine>,
+/// A connection on its way to a worker: how it got here, who it's from, and its place under the most clients.
+type Incoming = ((Stream, Transport), IpAddr, Seat);
+
+/// Takes the chat out of whatever `transport` wraps it in, then serves it like any other connection.
+fn unwrap_transport<S: Read + Write + ScuffedClone + HangUp + Send + Sync + 'static>(
+    stream: S,
+    transport: Transport,
+    (peer, seat): (IpAddr, Seat),
+    (shared, options): (&Shared, &Options),
 ) {
-    match do_auth_flow(&mut stream, &mut connected_users) {
-        Ok(user) => {
-            handle_chat(stream, &user, sender);
-            connected_users.lock().remove(&user);
-        }
-        Err(e) => {
-            eprintln!("Failed validating user: {e:?}");
+    match transport {
+        Transport::Tcp | Transport::Unix => handle_connection(stream, (peer, seat), shared, options),
+        Transport::WebSocket => match WsStream::accept(stream) {
+            Ok(stream) => handle_connection(stream, (peer, seat), shared, options),
+            Err(e) => warn!("[WS] Couldn't upgrade {peer}: {e:?}"),
+        },
+    }
+}
+
+/// Lets everything already said get broadcast, then tells everyone in `users` the server's going away and hangs
+/// up on them, turning away anyone who logs in after. Waits for the broadcast until `deadline` at most.
+pub(crate) fn say_goodbye(users: &Registry<Outbound>, broadcast: &SyncSender<ChatLine>, deadline: Instant) {
+    let (done, flushed) = mpsc::sync_channel(1);
+    if broadcast.send(ChatLine::flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    let goodbye = encode(&ServerMessage::notice("Server shutting down"));
+    for (user, outbound) in users.close() {
+        if let Some(goodbye) = &goodbye {
+            if let Err(e) = outbound.send(goodbye.clone()) {
+                warn!("<{user}> Couldn't say goodbye: {e:?}");
+            }
         }
-    };
+        outbound.hang_up();
+    }
 }
 
-/// Performs the authorization flow for a connecting user. In addition to the `Result`, this function
-/// writes an `AuthResponse` to the stream indicating success or failure.
-fn do_auth_flow<S>(stream: &mut S, connected_users: &mut SharedMap<User, S>) -> Result<User, ServerError>
-where
-    S: Read + Write + ScuffedClone
-{
-    let mut buf = [0; VALIDATE_BUFFER_SIZE];
-    let n = stream.read(&mut buf)?;
+/// Starts advertising the server if it's supposed to be. Not being discoverable isn't worth failing over.
+#[cfg(feature = "mdns")]
+pub(crate) fn advertise(options: &Options, port: u16) -> Option<discovery::Advertisement> {
+    if !options.advertise {
+        return None;
+    }
+
+    discovery::advertise(port)
+        .inspect_err(|e| warn!("[MDNS] Couldn't advertise the server: {e:?}"))
+        .ok()
+}
+
+#[cfg(not(feature = "mdns"))]
+pub(crate) fn advertise(options: &Options, _: u16) -> Option<()> {
+    if options.advertise {
+        warn!("[MDNS] This was built without the mdns feature, so the server can't be advertised");
+    }
+    None
+}
 
-    // Don't try to read the null bytes in the buffer
-    let user: User = serde_json::from_slice(&buf[..n])?;
+/// Has the router forward the port if it's supposed to. Not being reachable from outside isn't worth failing
+/// over either.
+#[cfg(feature = "upnp")]
+pub(crate) fn map_port(options: &Options, port: u16) -> Option<PortMapping> {
+    if !options.upnp {
+        return None;
+    }
 
-    {
-        let mut users = connected_users.lock();
-        if users.contains_key(&user) {
-            let name = user.name.clone();
-            let resp = AuthResponse::Error(format!("Name is already taken: {name}"));
-            stream.write_all(&serde_json::to_vec(&resp)?)?;
-            return Err(ServerError::AlreadyConnected(name));
+    match upnp::map_port(port) {
+        Ok(mapping) => {
+            info!("[UPNP] Reachable from outside at {}", mapping.external);
+            Some(mapping)
+        }
+        Err(e) => {
+            warn!("[UPNP] Couldn't map the port: {e:?}");
+            None
         }
-        users.insert(user.clone(), stream.scuffed_clone());
     }
+}
 
-    stream.write_all(&serde_json::to_vec(&AuthResponse::Success)?)?;
-    Ok(user)
+#[cfg(not(feature = "upnp"))]
+pub(crate) fn map_port(options: &Options, _: u16) -> Option<()> {
+    if options.upnp {
+        warn!("[UPNP] This was built without the upnp feature, so the port can't be mapped");
+    }
+    None
 }
 
-fn handle_chat<R: Read>(stream: R, user: &User, sender: SyncSender<ChatLine>) {
-    let mut buffer = Vec::with_capacity(4096);
-    let mut stream = BufReader::with_capacity(4096, stream);
-    let mut last_pos = 0;
-    let thread_id = format!("[{:?}] ", thread::current().id());
+/// Asks the STUN server for our public IP if there is one, and says where outside clients could reach us.
+pub(crate) fn public_address(options: &Options, port: u16) -> Option<SocketAddr> {
+    let server = options.stun_server.as_ref()?;
+
+    match stun::public_address(server, Duration::from_secs(3)) {
+        Ok(public) => {
+            info!("[STUN] Public address is {}, so clients outside connect to {}:{port} if it's forwarded", public.ip(), public.ip());
+            Some(SocketAddr::new(public.ip(), port))
+        }
+        Err(e) => {
+            warn!("[STUN] Couldn't work out our public address: {e:?}");
+            None
+        }
+    }
+}
 
+/// Hands connections from `listener` to the `pool`, turning away anyone from an address in the `bans`
+/// before they take up a worker, anyone coming faster than `accepts` lets in, and anyone past the most
+/// `clients` there can be, until the server's shutting down. Every transport's listeners go through the
+/// same limits.
+fn accept_connections(
+    (listener, transport): (Listener, Transport),
+    pool: &Pool<Incoming>,
+    (workers, accept_queue, tls): (usize, usize, bool),
+    (bans, shutdown, clients, accepts): (&Bans, &Shutdown, &Arc<ClientLimit>, &AcceptRate),
+) {
+    let quiet = !transport.speaks_first(tls);
     loop {
-        // Basically `read_line` but we want to work with a Vec<u8> directly
-        match stream.read_until(0xA, &mut buffer) {
-            Ok(n) => {
-                if n == 0 {
-                    break;
+        let accepted = listener.accept();
+        if shutdown.stopping() {
+            break;
+        }
+        match accepted {
+            Ok((stream, peer)) => {
+                let seat = match seat(peer, (bans, clients, accepts)) {
+                    Ok(seat) => seat,
+                    Err(refusal) => {
+                        turn_away(stream, peer, &refusal, quiet);
+                        continue;
+                    }
+                };
+                if let Err(((stream, _), peer, _)) = pool.try_submit(((stream, transport), peer, seat)) {
+                    warn!("All {workers} workers are busy and {accept_queue} connections are waiting, turning away {peer}");
+                    turn_away(stream, peer, &Refusal::Full, quiet);
                 }
+            }
+            Err(e) => { warn!("Failed on handling incoming stream: {e:?}"); }
+        }
+    }
+}
 
-                let s = String::from_utf8_lossy(&buffer[last_pos..last_pos + n])
-                    .trim_end()
-                    .to_string();
-                last_pos += n;
+/// Why a connection got turned away as soon as it was accepted, before it's said anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    Banned(String),
+    /// Connections are coming too fast, so come back after this long.
+    Busy(Duration),
+    Full,
+}
 
-                if let Err(e) = sender.send((user.clone(), s.clone())) {
-                    eprintln!("{thread_id} Error sending message: {e:?}");
-                }
+impl Refusal {
+    /// What to tell whoever got turned away.
+    pub(crate) fn answer(&self) -> AuthResponse {
+        match self {
+            Refusal::Banned(why) => AuthResponse::Error(why.clone()),
+            Refusal::Busy(retry_after) => AuthResponse::Busy { retry_after_ms: retry_after.as_millis() as u64 },
+            Refusal::Full => AuthResponse::ServerFull,
+        }
+    }
+}
 
-                eprintln!("{thread_id}<{}> {s:?}", user.name);
-            }
-            Err(e) => {
-                eprintln!("{thread_id}Error reading from stream: {e:?}");
-                break;
+/// Gives a connection from `peer` its place under the most `clients`, unless the `bans` catch where it's from or
+/// it's coming faster than `accepts` lets in. Every runtime lets connections in through here, so they're all
+/// held to the same limits.
+pub(crate) fn seat(
+    peer: IpAddr,
+    (bans, clients, accepts): (&Bans, &Arc<ClientLimit>, &AcceptRate),
+) -> Result<Seat, Refusal> {
+    if let Some(why) = bans.reason_from(peer) {
+        info!("[BANS] Turning away {peer}: {why}");
+        return Err(Refusal::Banned(why));
+    }
+
+    if let Err(retry_after) = accepts.try_accept(Instant::now()) {
+        let turned_away = accepts.turned_away();
+        // A storm's a lot of these, so only every so often
+        if turned_away.is_power_of_two() {
+            warn!("Connections are coming too fast, turning away {peer}, {turned_away} so far");
+        }
+        return Err(Refusal::Busy(retry_after));
+    }
+
+    clients.try_seat().ok_or_else(|| {
+        let (max, turned_away) = (clients.max(), clients.turned_away());
+        warn!("{max} clients are connected already, turning away {peer}, {turned_away} so far");
+        Refusal::Full
+    })
+}
+
+/// Tells `peer` why it's being turned away and hangs up, without a word if it's `quiet`, like for TLS.
+fn turn_away(mut stream: Stream, peer: IpAddr, refusal: &Refusal, quiet: bool) {
+    if quiet {
+        return;
+    }
+    if let Err(e) = write_message(&mut stream, &refusal.answer()) {
+        warn!("Failed telling {peer} why they're turned away: {e:?}");
+    }
+}
+
+/// Everything connections share with each other, whichever runtime's serving them.
+pub(crate) struct Shared {
+    pub(crate) users: SharedRegistry,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) memory: Arc<MemoryBudget>,
+    pub(crate) sender: SyncSender<ChatLine>,
+    cluster: Option<Arc<Cluster>>,
+    maintenance: Arc<Maintenance>,
+    info: Arc<ServerInfo>,
+    history: Arc<Mutex<History>>,
+    pub(crate) bans: Arc<Bans>,
+    pub(crate) clients: Arc<ClientLimit>,
+    pub(crate) accepts: AcceptRate,
+    shutdown: Arc<Shutdown>,
+    #[cfg(unix)]
+    _admin: Option<AdminSocket>,
+}
+
+impl Shared {
+    /// Starts everything there's one of per server, whatever's taking connections: the broadcaster, the cluster,
+    /// the keepalive and whichever of the admin socket, admin API, metrics and mirror there are. `address` is where
+    /// the server's listening, whose IP the metrics and admin API listen on too. `max_clients` connected at once, or
+    /// as many as connect with `None`.
+    pub(crate) fn open(
+        address: SocketAddr,
+        options: &Options,
+        (metrics, shutdown): (Arc<Metrics>, Arc<Shutdown>),
+        max_clients: Option<usize>,
+    ) -> std::io::Result<Self> {
+        let connected_users: SharedRegistry = Default::default();
+        let memory = MemoryBudget::new(options.memory_budget);
+        if let Some(port) = options.metrics_port {
+            exporter::listen(SocketAddr::new(address.ip(), port), metrics.clone(), (connected_users.clone(), memory.clone()))?;
+        }
+        #[cfg(unix)]
+        let admin = options.admin_socket.as_deref().map(|path| AdminSocket::listen(path, connected_users.clone())).transpose()?;
+        let cluster = options.cluster.as_ref().map(|c| Cluster::start(c, connected_users.clone())).transpose()?;
+        if let Some(mirror) = options.mirror.clone() {
+            mirror::start(mirror, connected_users.clone())?;
+        }
+        keepalive::start(options.keepalive, connected_users.clone(), metrics.clone())?;
+        let (sender, receiver) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let maintenance = Arc::new(Maintenance::new(options.maintenance_message.clone()));
+        let bans = Arc::new(match &options.bans_file {
+            Some(path) => Bans::open(path)?,
+            None => Bans::default(),
+        });
+        let info = Arc::new(ServerInfo { name: options.server_name.clone(), started: Instant::now() });
+        if let Some((port, token)) = options.admin_api.clone() {
+            let shared = (connected_users.clone(), metrics.clone(), info.clone());
+            control::listen(SocketAddr::new(address.ip(), port), token, shared)?;
+        }
+        let history = Arc::new(Mutex::new(match &options.history_file {
+            Some(path) => History::open(options.history, path)?,
+            None => History::new(options.history),
+        }));
+
+        {
+            let users = connected_users.clone();
+            let metrics = metrics.clone();
+            let cluster = cluster.clone();
+            let roles = options.roles.clone();
+            let maintenance = maintenance.clone();
+            let info = info.clone();
+            let accounts = options.accounts.clone();
+            let history = history.clone();
+            let bans = bans.clone();
+            thread::spawn(move || {
+                let gates = (&roles, &*maintenance, &*info, accounts.as_deref(), &*bans);
+                broadcast_messages(users, receiver, &metrics, cluster.as_deref(), &history, gates);
+            });
+        }
+
+        Ok(Self {
+            users: connected_users,
+            metrics,
+            memory,
+            sender,
+            cluster,
+            maintenance,
+            info,
+            history,
+            bans,
+            clients: ClientLimit::new(max_clients),
+            accepts: AcceptRate::new(options.max_accepts_per_sec, options.accept_burst),
+            shutdown,
+            #[cfg(unix)]
+            _admin: admin,
+        })
+    }
+
+    /// Lets `user` in with `outbound` as their send queue, see `admit`.
+    #[cfg(any(feature = "mio", feature = "tokio", feature = "irc"))]
+    pub(crate) fn admit(&self, user: &User, outbound: Outbound) -> Result<(), ServerError> {
+        admit(user, &self.users, outbound, self.cluster.as_deref(), (&self.maintenance, &self.info, &self.bans))
+    }
+}
+
+fn handle_connection<S: Read + Write + ScuffedClone + HangUp + Send + Sync + 'static>(
+    mut stream: S,
+    (peer, seat): (IpAddr, Seat),
+    shared: &Shared,
+    options: &Options,
+) {
+    // Everything logged while serving them says who it's about
+    let span = info_span!("conn", %peer, nick = field::Empty);
+    let _entered = span.enter();
+
+    #[cfg(feature = "irc")]
+    if options.protocol == Protocol::Irc {
+        return handle_irc_connection(stream, (peer, seat), shared, options);
+    }
+
+    let Shared { users: connected_users, metrics, memory, sender, cluster, maintenance, info, bans, shutdown, .. } = shared;
+    // Everything is read through this one buffer, so whatever arrived in the same segment as the hello
+    // is still around for `handle_chat`. Metered underneath so every byte counts, even ones we throw away.
+    let meter = Arc::new(Mutex::new(IoMeter::new(options.io_limits, Instant::now())));
+    let counted = Counted::new(stream.scuffed_clone(), metrics.bytes_in.clone());
+    let mut reader = Framed::with_capacity(4096, Metered::new(counted, meter.clone()));
+    let diagnostics = Arc::new(Diagnostics::new(peer, meter.clone()));
+
+    let (outbound, queue) = Outbound::new(options.send_queue_len);
+    let hang_up = stream.scuffed_clone();
+    let outbound = outbound.with_hang_up(move || hang_up.hang_up()).with_diagnostics(diagnostics.clone());
+
+    let started = Instant::now();
+    let gates = (&**maintenance, &**info, &**bans);
+    let auth = do_auth_flow(&mut reader, &mut stream, connected_users, outbound.clone(), cluster.as_deref(), gates, options);
+    for alarm in metrics.handshakes.record(peer, started.elapsed(), auth.is_ok()) {
+        warn!("[AUTH] Warning: {alarm}");
+    }
+
+    match auth {
+        Ok(mut user) => {
+            span.record("nick", field::display(&user.name));
+            // Only started once the auth response is out, so nothing can get written ahead of it. It winds
+            // down on its own once the user is released and the queue runs dry.
+            let writer = Seated::new(Counted::new(stream.scuffed_clone(), metrics.bytes_out.clone()), seat);
+            match spawn_writer(writer, queue, options.writer) {
+                Ok(writer) => shutdown.writing(writer),
+                Err(e) => {
+                    warn!("Couldn't start a writer, dropping connection: {e:?}");
+                    connected_users.release(&user);
+                    return;
+                }
             }
+
+            welcome(shared, &user, options);
+            let quit = handle_chat(reader, &mut user, sender.clone(), memory, metrics, (&diagnostics, &outbound), options);
+            farewell(shared, &user, quit);
+
+            let meter = meter.lock();
+            info!("Disconnected after sending {} bytes in {} lines", meter.total_bytes, meter.total_lines);
+        }
+        Err(e) => {
+            warn!("Failed validating user: {e:?}");
+        }
+    };
+}
+
+/// `handle_connection` for clients speaking IRC. Everything past registering goes through the same
+/// `handle_chat` and broadcaster as native clients, with the gateway translating on the way in and out.
+#[cfg(feature = "irc")]
+fn handle_irc_connection<S: Read + Write + ScuffedClone + HangUp + Send + Sync + 'static>(
+    mut stream: S,
+    (peer, seat): (IpAddr, Seat),
+    shared: &Shared,
+    options: &Options,
+) {
+    let Shared { users: connected_users, metrics, memory, sender, info, history, shutdown, .. } = shared;
+    let meter = Arc::new(Mutex::new(IoMeter::new(options.io_limits, Instant::now())));
+    let counted = Counted::new(stream.scuffed_clone(), metrics.bytes_in.clone());
+    let mut reader = std::io::BufReader::with_capacity(4096, Metered::new(counted, meter.clone()));
+    let diagnostics = Arc::new(Diagnostics::new(peer, meter.clone()));
+
+    let (outbound, queue) = Outbound::new(options.send_queue_len);
+    let hang_up = stream.scuffed_clone();
+    let outbound = outbound.with_hang_up(move || hang_up.hang_up()).with_diagnostics(diagnostics.clone());
+    let host = gateway::host(&info.name);
+
+    let started = Instant::now();
+    let registered = gateway::register(&mut reader, &mut stream, &host, |login| {
+        check_login(login, options)?;
+        login.user.validate()?;
+        shared.admit(&login.user, outbound.clone())
+    });
+    let user = match registered {
+        Ok(Some((user, ()))) => user,
+        Ok(None) => {
+            metrics.handshakes.record(peer, started.elapsed(), false);
+            return;
+        }
+        Err(e) => {
+            metrics.handshakes.record(peer, started.elapsed(), false);
+            warn!("[IRC] Failed registering: {e:?}");
+            return;
+        }
+    };
+    for alarm in metrics.handshakes.record(peer, started.elapsed(), true) {
+        warn!("[AUTH] Warning: {alarm}");
+    }
+    Span::current().record("nick", field::display(&user.name));
+
+    let counted = Counted::new(stream.scuffed_clone(), metrics.bytes_out.clone());
+    let translator = gateway::Translator::new(counted, user.clone(), host.as_str());
+    match spawn_writer(Seated::new(translator, seat), queue, options.writer) {
+        Ok(writer) => shutdown.writing(writer),
+        Err(e) => {
+            warn!("Couldn't start a writer, dropping connection: {e:?}");
+            connected_users.release(&user);
+            return;
+        }
+    }
+
+    let motd = options.motd.as_ref().map(|motd| info.render(motd, &user, connected_users.len()));
+    for msg in gateway::welcome(&user, &host, motd.as_deref()) {
+        if let Ok(frame) = encode_message(&msg) {
+            let _ = outbound.send(frame.into());
         }
     }
+    replay(connected_users, &user, &history.lock(), None);
+    presence(sender, &user, PresenceChange::Joined);
+
+    // IRC clients can't `/nick`, so `user` stays put
+    let inbound = gateway::Inbound::new(reader, user.clone(), host, outbound.clone());
+    let quit = handle_chat(inbound, &mut user.clone(), sender.clone(), memory, metrics, (&diagnostics, &outbound), options);
+    farewell(shared, &user, quit);
+
+    let meter = meter.lock();
+    info!("Disconnected from IRC after sending {} bytes in {} lines", meter.total_bytes, meter.total_lines);
+}
+
+/// Everything `user` gets once they're in, before anything they say: the MOTD if there is one and what they
+/// missed, while everyone else hears they're here.
+pub(crate) fn welcome(shared: &Shared, user: &User, options: &Options) {
+    if let Some(motd) = &options.motd {
+        let online = shared.users.len();
+        notify(&shared.users, user, shared.info.render(motd, user, online));
+    }
+    replay(&shared.users, user, &shared.history.lock(), None);
+    presence(&shared.sender, user, PresenceChange::Joined);
+}
+
+/// Lets go of `user`'s nick now they've gone, and tells everyone why.
+pub(crate) fn farewell(shared: &Shared, user: &User, quit: PresenceChange) {
+    shared.users.release(user);
+    presence(&shared.sender, user, quit);
+}
+
+/// Lets everyone else know `user` connected or disconnected, through the broadcaster so it lands in order with
+/// what they said.
+fn presence(sender: &SyncSender<ChatLine>, user: &User, change: PresenceChange) {
+    if let Err(e) = sender.send(ChatLine::presence(user.clone(), change)) {
+        warn!("<{user}> Couldn't tell everyone about them: {e:?}");
+    }
 }
 
-fn broadcast_messages<S>(users: SharedMap<User, S>, receiver: Receiver<ChatLine>)
+/// Performs the authorization flow for a connecting user, registering `outbound` as their send queue if
+/// `let_in` lets them in. In addition to the `Result`, this function answers the handshake
+/// with `Capabilities`, and then writes an `AuthResponse` to the stream indicating success or failure.
+fn do_auth_flow<R, W>(
+    reader: &mut Framed<R>,
+    stream: &mut W,
+    connected_users: &Registry<Outbound>,
+    outbound: Outbound,
+    cluster: Option<&Cluster>,
+    gates: (&Maintenance, &ServerInfo, &Bans),
+    options: &Options,
+) -> Result<User, ServerError>
 where
-    S: Read + Write + ScuffedClone
+    R: Read,
+    W: Write,
 {
-    for (user, msg) in receiver {
-        let full_msg = format!("<{user}> {msg}").into_bytes();
-
-        users
-            .lock()
-            .iter_mut()
-            .filter(|(u, _)| *u != &user)
-            .for_each(|(u, conn)| {
-                if let Err(e) = conn.write_all(&full_msg) {
-                    eprintln!("[BROADCAST] Failed sending message to {u}: {e:?}");
-                }
-            });
-    }
+    shake_hands(reader, stream, &features(options.password.is_some()))?;
+    let hello = reader.read_frame(&HELLO_LIMITS)?;
+    let user = match let_in(&hello, options, |user| admit(user, connected_users, outbound, cluster, gates)) {
+        Ok(user) => user,
+        Err(e) => {
+            if let Some(answer) = e.answer() {
+                write_message(stream, &answer)?;
+            }
+            return Err(e);
+        }
+    };
+
+    write_message(stream, &AuthResponse::Success)?;
+    Ok(user)
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::Cursor;
-    use super::*;
+/// Reads the client's `Handshake` and answers it, turning away clients that didn't send one or that speak
+/// something too old.
+pub(crate) fn shake_hands<R: Read, W: Write>(
+    reader: &mut Framed<R>,
+    stream: &mut W,
+    features: &[&str],
+) -> Result<Capabilities, ServerError> {
+    let (answer, shaken) = answer_handshake(&reader.read_frame(&HELLO_LIMITS)?, features);
+    write_message(stream, &answer)?;
+    shaken
+}
 
-    #[test]
-    fn do_auth_flow_valid_json() {
-        let user = User::new("hello");
-        let user_json = serde_json::to_vec(&user).unwrap();
-        let mut expected_cursor = {
-            let mut v: Vec<u8> = Vec::new();
-            v.extend(&user_json);
-            v
-        };
+/// Lets `user`, whose nick has been checked already, in with `outbound` as their send queue, whatever protocol they
+/// came in speaking. Nicks in use anywhere else in the `cluster` count as taken too. Nobody gets in during
+/// `maintenance`, and nobody the `bans` catch by nick, or by where `outbound` says they're connecting from, ever does.
+fn admit(
+    user: &User,
+    connected_users: &Registry<Outbound>,
+    outbound: Outbound,
+    cluster: Option<&Cluster>,
+    (maintenance, info, bans): (&Maintenance, &ServerInfo, &Bans),
+) -> Result<(), ServerError> {
+    let peer = outbound.diagnostics().map(Diagnostics::peer);
+    if let Some(why) = bans.reason(user).or_else(|| peer.and_then(|peer| bans.reason_from(peer))) {
+        return Err(ServerError::Banned(why));
+    }
 
-        let mut cursor = Cursor::new(user_json);
+    if let Some(message) = maintenance.message() {
+        let online = connected_users.len();
+        return Err(ServerError::Maintenance(info.render(&message, user, online)));
+    }
 
-        let success_resp = serde_json::to_vec(&AuthResponse::Success).unwrap();
-        expected_cursor.extend(&success_resp);
+    match cluster {
+        Some(cluster) if cluster.is_taken_remotely(user) => return Err(ServerError::AlreadyConnected(user.name.clone())),
+        _ => connected_users.claim_nick(user, outbound)?,
+    }
 
-        assert_eq!(user, do_auth_flow(&mut cursor, &mut Default::default()).unwrap());
-        assert_eq!(&expected_cursor, cursor.get_ref());
+    if let Some(cluster) = cluster {
+        cluster.signed_on(user, SystemTime::now());
     }
+    Ok(())
+}
 
-    // Only necessary because of VALIDATE_BUFFER_SIZE
-    #[test]
-    fn do_auth_flow_buffer_length_failure() {
-        let mut long_str = String::with_capacity(VALIDATE_BUFFER_SIZE);
-        (0..VALIDATE_BUFFER_SIZE).for_each(|_| long_str.push('a'));
-        let user = User::new(long_str.clone());
-        let user_json = serde_json::to_vec(&user).unwrap();
-        let user_json_len = user_json.len();
+/// A line read by a `LineReader`, or however else a runtime reads them.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Line {
+    Text(String),
+    /// The line was over the max length and got thrown away.
+    TooLong,
+}
 
-        let mut cursor = Cursor::new(user_json.clone());
+/// Reads lines into one buffer that's reused for every line, so a connection holds onto about one line's
+/// worth of memory no matter how long it lives.
+struct LineReader<R> {
+    stream: R,
+    buffer: Vec<u8>,
+    max_line_len: usize,
+}
 
-        let res = do_auth_flow(&mut cursor, &mut Default::default()).err().unwrap();
-        // Force a Serde error since idk how to manually create one
-        let se = serde_json::from_slice::<User>(&cursor.get_ref()[..user_json_len - 1]).err().unwrap();
-        assert_eq!(
-            std::mem::discriminant(&res),
-            std::mem::discriminant(&ServerError::Serde(se))
-        );
-        assert_eq!(&user_json, cursor.get_ref());
+impl<R: BufRead> LineReader<R> {
+    fn new(stream: R, max_line_len: usize) -> Self {
+        Self {
+            stream,
+            buffer: Vec::with_capacity(4096.min(max_line_len + 1)),
+            max_line_len,
+        }
     }
 
-    #[test]
-    fn do_auth_flow_already_logged_in() {
-        let user = User::new("hello");
-        let user_json = serde_json::to_vec(&user).unwrap();
-        let mut expected_cursor = {
-            let mut l: Vec<u8> = Vec::new();
-            l.extend(&user_json);
-            l
-        };
-        let mut cursor = Cursor::new(user_json);
+    /// Reads the next line with trailing whitespace trimmed, or `None` once the stream is done.
+    fn next_line(&mut self) -> std::io::Result<Option<Line>> {
+        self.buffer.clear();
+        // One over the max so we can tell a line that's exactly the max apart from one that's too long
+        let read_limit = self.max_line_len as u64 + 1;
 
-        let mut connected_users: SharedMap<User, _> = Default::default();
-        {
-            connected_users.lock().insert(user.clone(), cursor.scuffed_clone());
+        // Basically `read_line` but we want to work with a Vec<u8> directly
+        let n = self.stream.by_ref().take(read_limit).read_until(0xA, &mut self.buffer)?;
+        if n == 0 {
+            return Ok(None);
         }
 
-        let failure_res = serde_json::to_vec(&AuthResponse::Error("Name is already taken: hello".to_string())).unwrap();
-        expected_cursor.extend(failure_res);
+        if n as u64 == read_limit && self.buffer.last() != Some(&0xA) {
+            self.skip_line()?;
+            return Ok(Some(Line::TooLong));
+        }
 
-        let res = do_auth_flow(&mut cursor, &mut connected_users).err().unwrap();
-        assert_eq!(
-            std::mem::discriminant(&res),
-            std::mem::discriminant(&ServerError::AlreadyConnected("".to_string()))
-        );
-        assert_eq!(&expected_cursor, cursor.get_ref());
+        Ok(Some(Line::Text(String::from_utf8_lossy(&self.buffer).trim_end().to_string())))
     }
 
-    #[test]
-    fn broadcast_message() {
-        let user_1 = User::new("one");
-        let user_2 = User::new("two");
+    /// Throws away everything up to and including the next line feed without buffering it.
+    fn skip_line(&mut self) -> std::io::Result<()> {
+        loop {
+            let available = self.stream.fill_buf()?;
+            if available.is_empty() {
+                return Ok(());
+            }
 
-        let connected_users: SharedMap<User, _> = Default::default();
-        connected_users.lock().insert(user_1.clone(), Cursor::new(Vec::<u8>::new()));
-        connected_users.lock().insert(user_2.clone(), Cursor::new(Vec::<u8>::new()));
+            match available.iter().position(|b| *b == 0xA) {
+                Some(i) => {
+                    self.stream.consume(i + 1);
+                    return Ok(());
+                }
+                None => {
+                    let n = available.len();
+                    self.stream.consume(n);
+                }
+            }
+        }
+    }
+}
 
-        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
-        tx.send((user_1.clone(), "hello".to_string())).unwrap();
-        tx.send((user_2.clone(), "yo waddup".to_string())).unwrap();
-        drop(tx);
+fn handle_chat<R: BufRead>(
+    stream: R,
+    user: &mut User,
+    sender: SyncSender<ChatLine>,
+    memory: &Arc<MemoryBudget>,
+    metrics: &Metrics,
+    (diagnostics, outbound): (&Diagnostics, &Outbound),
+    options: &Options,
+) -> PresenceChange {
+    let mut lines = LineReader::new(stream, options.max_line_len);
+    let meter = diagnostics.meter();
+    let mut session = Session::new(user.clone(), options);
 
-        broadcast_messages(connected_users.clone(), rx);
-        {
-            let users = connected_users.lock();
-            assert_eq!(Cursor::new(Vec::from(b"<two> yo waddup")).get_ref(), users.get(&user_1).unwrap().get_ref());
-            assert_eq!(Cursor::new(Vec::from(b"<one> hello")).get_ref(), users.get(&user_2).unwrap().get_ref());
+    let quit = loop {
+        let line = lines.next_line().and_then(|line| {
+            // Lines count against the budget too, not just bytes, so check before doing anything with it
+            meter.lock().record_line(Instant::now());
+            enforce(meter)?;
+            Ok(line)
+        });
+        if let Err(quit) = session.heard(line, (&sender, memory, metrics), (diagnostics, outbound), options) {
+            break quit;
         }
+    };
+    *user = session.user;
+    quit
+}
+
+/// A logged-in connection's chat, one line at a time, whatever's reading them. Every runtime hands what it reads
+/// to `heard`, so they all hold people to the same limits and pass what they say to the same broadcaster.
+#[derive(Debug)]
+pub(crate) struct Session {
+    user: User,
+    /// Strikes against the I/O limits so far, to notice new ones.
+    strikes: u32,
+    flood: FloodGuard,
+}
+
+impl Session {
+    pub(crate) fn new(user: User, options: &Options) -> Self {
+        Self { user, strikes: 0, flood: FloodGuard::new(options.flood, Instant::now()) }
     }
-}
\ No newline at end of file
+
+    /// Who they are now, after any `/nick`.
+    #[cfg(any(feature = "mio", feature = "tokio"))]
+    pub(crate) fn user(&self) -> &User {
+        &self.user
+    }
+
+    /// Deals with what reading the connection's next `line` came to, with the `meter` in `diagnostics` already
+    /// told about it. Ends with why they're going once they are. Blocks for as long as the broadcaster takes to
+    /// take the line, and after a `/nick` until it's said whether it worked.
+    pub(crate) fn heard(
+        &mut self,
+        line: std::io::Result<Option<Line>>,
+        (sender, memory, metrics): (&SyncSender<ChatLine>, &Arc<MemoryBudget>, &Metrics),
+        (diagnostics, outbound): (&Diagnostics, &Outbound),
+        options: &Options,
+    ) -> Result<(), PresenceChange> {
+        let max_line_len = options.max_line_len;
+        let quit = |reason: Option<String>| PresenceChange::Quit { reason };
+        // Going by the meter rather than what `enforce` says, since reading can get it a strike too
+        let (struck, max_strikes) = diagnostics.meter().lock().strikes();
+        if struck > self.strikes {
+            diagnostics.violation(format!("Went over its I/O limits, strike {struck} of {max_strikes}"));
+        }
+        self.strikes = struck;
+
+        if let Ok(Some(_)) = line {
+            diagnostics.heard_from();
+        }
+        let s = match line {
+            Ok(Some(Line::Text(s))) => s,
+            Ok(Some(Line::TooLong)) => {
+                let dropped = metrics.oversized_lines.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!("Line over {max_line_len} bytes, dropping it ({dropped} dropped so far)");
+                diagnostics.violation(format!("Sent a line over {max_line_len} bytes"));
+                return Ok(());
+            }
+            // Hung up on by the keepalive, rather than them hanging up
+            Ok(None) if diagnostics.timed_out() => return Err(quit(Some("Ping timeout".to_string()))),
+            Ok(None) => return Err(quit(None)),
+            Err(e) if e.kind() == ErrorKind::QuotaExceeded => {
+                metrics.io_disconnects.fetch_add(1, Ordering::Relaxed);
+                warn!("Cutting off connection, it's been over its I/O budget for too long");
+                return Err(quit(Some("flooding".to_string())));
+            }
+            Err(e) => {
+                warn!("Error reading from stream: {e:?}");
+                return Err(quit(Some(e.kind().to_string())));
+            }
+        };
+
+        // Answers to pings only need to have been read, they aren't saying anything
+        if let Some(Ok(Command::Pong { .. })) = Command::parse(&s) {
+            return Ok(());
+        }
+        diagnostics.said_something();
+        match self.flood.check(Instant::now()) {
+            Flood::Ok => {}
+            Flood::Dropped { first, retry_after } => {
+                let dropped = metrics.flood_drops.fetch_add(1, Ordering::Relaxed) + 1;
+                if first {
+                    warn!("Flooding, dropping their messages ({dropped} dropped so far)");
+                    diagnostics.violation("Flooded, so its messages got dropped");
+                    let text = "You're sending too fast, your messages are being dropped until you slow down";
+                    warn_flooder(outbound, text, Some(retry_after));
+                }
+                return Ok(());
+            }
+            Flood::Disconnect => {
+                metrics.flood_disconnects.fetch_add(1, Ordering::Relaxed);
+                warn!("Cutting off connection, it kept flooding after being warned");
+                warn_flooder(outbound, "Disconnected for flooding", None);
+                return Err(quit(Some("flooding".to_string())));
+            }
+        }
+        let s = fit_message(s, options.max_message_len, &self.user);
+
+        // Mirrors are read-only, all local users get to do is pick which of the upstream's channels to follow
+        let command = Command::parse(&s);
+        let following = matches!(command, Some(Ok(Command::Join { .. } | Command::Part { .. } | Command::Names { .. })));
+        if options.mirror.is_some() && !following {
+            warn!("Read-only, dropping {s:?}");
+            return Ok(());
+        }
+
+        let Some(reservation) = memory.try_reserve(s.len()) else {
+            let (used, limit, rejected) = (memory.used(), memory.limit(), memory.rejected());
+            warn!("Server is holding {used} of {limit} bytes, dropping message ({rejected} dropped so far)");
+            return Ok(());
+        };
+
+        // Everything already sent goes out under the old nick, so the broadcaster does the renaming, and
+        // nothing else gets read until it's said whether it worked
+        let (answer, renamed) = match command {
+            Some(Ok(Command::Nick { .. })) => {
+                let (tx, rx) = mpsc::sync_channel(1);
+                (Some(tx), Some(rx))
+            }
+            _ => (None, None),
+        };
+        let line = ChatLine { renamed: answer, ..ChatLine::new(self.user.clone(), s.clone()).reserved(reservation) };
+        if let Err(e) = sender.send(line) {
+            warn!("Error sending message: {e:?}");
+        }
+
+        match command {
+            // Passwords stay out of the logs
+            Some(Ok(Command::Register { .. })) => info!("\"/register ...\""),
+            Some(Ok(Command::Oper { .. })) => info!("\"/oper ...\""),
+            _ => info!("{s:?}"),
+        }
+        if let Some(Ok(new)) = renamed.map(|rx| rx.recv()) {
+            Span::current().record("nick", field::display(&new.name));
+            self.user = new;
+        }
+        Ok(())
+    }
+}
+
+/// Tells a flooding connection what's happening to what it sends, straight to its `outbound` rather than
+/// through the broadcaster it's flooding, and when it can `retry_after` if it can.
+fn warn_flooder(outbound: &Outbound, text: &str, retry_after: Option<Duration>) {
+    let retry_after_ms = retry_after.map(|wait| wait.as_millis() as u64);
+    if let Some(frame) = encode(&ServerMessage::Notice { text: text.to_string(), retry_after_ms }) {
+        let _ = outbound.send(frame);
+    }
+}
+
+/// Cuts `text` down to `max` grapheme clusters so nobody's message gets split mid-character.
+pub(crate) fn fit_message(text: String, max: usize, from: &User) -> String {
+    let mut message = ServerFriendlyString::from(text);
+    let len = message.len();
+    if message.truncate(max) {
+        info!("<{}> Message is {len} characters, cutting it to {max}", from.name);
+    }
+    message.to_string()
+}
+
+fn broadcast_messages(
+    users: SharedRegistry,
+    receiver: Receiver<ChatLine>,
+    metrics: &Metrics,
+    cluster: Option<&Cluster>,
+    history: &Mutex<History>,
+    gates: (&Roles, &Maintenance, &ServerInfo, Option<&Accounts>, &Bans),
+) {
+    for line in receiver {
+        let received = line.received;
+        if let Some(done) = line.flushed {
+            let _ = done.send(());
+            continue;
+        }
+        if let Some(change) = line.presence {
+            broadcast_presence(&users, line.from, change, metrics);
+            continue;
+        }
+        match Command::parse(&line.text) {
+            None => {
+                let channel = users.channels().current(&line.from).map(str::to_string);
+                broadcast_chat(&users, &line, channel, (metrics, history), cluster);
+            }
+            Some(Ok(Command::Say { channel, text })) => {
+                if users.channels().hears(&line.from, channel.as_deref()) {
+                    broadcast_chat(&users, &ChatLine { text, ..line }, channel, (metrics, history), cluster);
+                } else {
+                    let text = match channel {
+                        Some(channel) => format!("You're not in {channel}"),
+                        None => "You're in a channel, so you're not in the lobby".to_string(),
+                    };
+                    notify(&users, &line.from, text);
+                }
+            }
+            Some(Ok(Command::Join { channel })) => join(&users, &line.from, channel, &history.lock()),
+            Some(Ok(Command::Nick { nick })) => rename(&users, line, nick, cluster, (gates.3, gates.4)),
+            Some(Ok(command)) => run_command(&users, &line.from, command, cluster, gates),
+            Some(Err(e)) => notify(&users, &line.from, e.to_string()),
+        }
+
+        if let Some(summary) = metrics.broadcast.record(received.elapsed()) {
+            info!("[BROADCAST] Latency: {summary}");
+        }
+    }
+}
+
+/// Tells everyone but `user` that they've connected or disconnected.
+fn broadcast_presence(users: &Registry<Outbound>, user: User, change: PresenceChange, metrics: &Metrics) {
+    let Some(frame) = encode(&ServerMessage::Presence { user: user.clone(), change }) else {
+        return;
+    };
+    for u in users.send_to_all(&frame, Some(&user)) {
+        let dropped = metrics.dropped_frames.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("[BROADCAST] {u} isn't keeping up, disconnecting them ({dropped} dropped so far)");
+    }
+}
+
+/// Sends `line` to everyone else in `channel`, or the lobby if `None`, and keeps it in the `history`.
+fn broadcast_chat(
+    users: &Registry<Outbound>,
+    line: &ChatLine,
+    channel: Option<String>,
+    (metrics, history): (&Metrics, &Mutex<History>),
+    cluster: Option<&Cluster>,
+) {
+    // Each message is its own frame so clients can tell where one ends and the next begins
+    let message = line.to_message(channel.clone());
+    let Some(full_msg) = encode(&message) else {
+        return;
+    };
+    history.lock().push(&message);
+    tail::chat(&message);
+    metrics.messages_broadcast.fetch_add(1, Ordering::Relaxed);
+
+    for u in users.send_to_channel(&full_msg, channel.as_deref(), Some(&line.from)) {
+        let dropped = metrics.dropped_frames.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("[BROADCAST] {u} isn't keeping up, disconnecting them ({dropped} dropped so far)");
+    }
+
+    // Only after letting go of the registry, the cluster takes it while holding its own lock
+    if let Some(cluster) = cluster {
+        cluster.publish(&message);
+    }
+}
+
+/// Carries out a command from `from`, if they're allowed to.
+fn run_command(
+    users: &Registry<Outbound>,
+    from: &User,
+    command: Command,
+    cluster: Option<&Cluster>,
+    (roles, maintenance, info, accounts, bans): (&Roles, &Maintenance, &ServerInfo, Option<&Accounts>, &Bans),
+) {
+    // Only looked up for operator commands, since it takes the accounts database
+    let registered = || accounts.is_some_and(|accounts| accounts.is_registered(&from.name).unwrap_or(false));
+    let opered = || users.get(from).is_some_and(|outbound| outbound.is_opered());
+    if command.operators_only() && roles.of(from, (registered(), opered())) != Role::Operator {
+        notify(users, from, "Permission denied, you're not an operator");
+        if let Some(diagnostics) = users.get(from).as_ref().and_then(Outbound::diagnostics) {
+            diagnostics.violation("Tried an operator command without being an operator");
+        }
+        return;
+    }
+
+    match command {
+        Command::Kill { nick, reason } => {
+            let target = User::new(nick);
+            let message = ServerMessage::Disconnected { reason: format!("Killed by {from}: {reason}") };
+            let Some(frame) = encode(&message) else {
+                return;
+            };
+
+            let remote = cluster.is_some_and(|cluster| cluster.is_taken_remotely(&target));
+            let local = users.kill(&target, frame);
+            if !local && !remote {
+                notify(users, from, format!("No such nick: {target}"));
+                return;
+            }
+
+            if let Some(cluster) = cluster {
+                cluster.kill(&target, &message);
+            }
+            info!("[OPER] {from} killed {target}: {reason}");
+        }
+        Command::Kick { nick, reason } => {
+            let target = User::new(nick);
+            if !kick(users, &target, &format!("kicked by {from}"), reason.as_deref()) {
+                notify(users, from, format!("No such nick: {target}"));
+                return;
+            }
+            info!("[OPER] {from} kicked {target}: {reason:?}");
+        }
+        Command::Ban { mask, reason } => {
+            let mut caught = Vec::new();
+            users.for_each(|user, outbound| {
+                if mask.catches(user, outbound.diagnostics().map(Diagnostics::peer)) {
+                    caught.push(user.clone());
+                }
+            });
+            caught.sort();
+            if caught.contains(from) {
+                notify(users, from, format!("{mask} would ban you too"));
+                return;
+            }
+
+            let why = match &reason {
+                Some(reason) => format!("Banned by {from}: {reason}"),
+                None => format!("Banned by {from}"),
+            };
+            if !bans.ban(mask.clone(), why) {
+                notify(users, from, format!("{mask} is already banned"));
+                return;
+            }
+            info!("[OPER] {from} banned {mask}: {reason:?}");
+            if caught.is_empty() {
+                notify(users, from, format!("Banned {mask}, nobody connected here matches it"));
+            }
+            for target in caught {
+                kick(users, &target, &format!("banned by {from}"), reason.as_deref());
+            }
+        }
+        Command::Unban { mask } => {
+            if bans.unban(&mask) {
+                info!("[OPER] {from} unbanned {mask}");
+                notify(users, from, format!("Unbanned {mask}"));
+            } else {
+                notify(users, from, format!("{mask} isn't banned"));
+            }
+        }
+        Command::Bans => {
+            let banned = bans.list();
+            let mut text = match banned.len() {
+                0 => "Nobody's banned".to_string(),
+                n => format!("{n} ban(s):"),
+            };
+            for (mask, why) in banned {
+                let _ = write!(text, "\n  {mask}: {why}");
+            }
+            notify(users, from, text);
+        }
+        Command::Wallops { text } => {
+            let message = ServerMessage::notice(format!("[wallops] {from}: {text}"));
+            let Some(frame) = encode(&message) else {
+                return;
+            };
+
+            users.send_to_all(&frame, None);
+            if let Some(cluster) = cluster {
+                cluster.publish(&message);
+            }
+            info!("[OPER] Wallops from {from}: {text:?}");
+        }
+        Command::Maintenance { on: true, message } => {
+            let message = maintenance.start(message);
+            info!("[OPER] {from} started maintenance: {message:?}");
+            announce(users, info, &format!("*** Maintenance: {message}"));
+        }
+        Command::Maintenance { on: false, .. } => {
+            if maintenance.end() {
+                info!("[OPER] {from} ended maintenance");
+                announce(users, info, "*** Maintenance is over");
+            } else {
+                notify(users, from, "Not in maintenance");
+            }
+        }
+        Command::Part { channel } => {
+            let mut channels = users.channels();
+            let text = if channels.part(from, &channel) {
+                format!("Left {channel}, talking in {} now", channels.current(from).unwrap_or("the lobby"))
+            } else {
+                format!("You're not in {channel}")
+            };
+            // Has to be let go before notifying, which takes the users lock
+            drop(channels);
+            notify(users, from, text);
+        }
+        Command::Msg { nick, text } => {
+            let to = User::new(nick);
+            let message = ServerMessage::Private { from: from.clone(), to: to.clone(), text };
+            let Some(frame) = encode(&message) else {
+                return;
+            };
+
+            match (users.send_to(&to, frame), cluster) {
+                (Ok(()), _) => {}
+                // Whoever sent it doesn't need to know what happens to them
+                (Err(OutboundError::Full), _) => {
+                    warn!("[MSG] {to} isn't keeping up, disconnecting them");
+                    notify(users, from, format!("Couldn't deliver that to {to}"));
+                }
+                (Err(OutboundError::Closed), Some(cluster)) if cluster.is_taken_remotely(&to) => cluster.publish(&message),
+                (Err(OutboundError::Closed), _) => notify(users, from, format!("No such nick: {to}")),
+            }
+        }
+        // Hashes the password right here, holding up everyone's chat a little, but it's a one-off per nick
+        Command::Oper { password } => {
+            let text = if !roles.operators.contains(from) {
+                format!("{from} isn't an operator")
+            } else if roles.password.is_none() {
+                "This server has no operator password".to_string()
+            } else if !roles.is_password(&password) {
+                warn!("[BROADCAST] {from} gave the wrong operator password");
+                if let Some(diagnostics) = users.get(from).as_ref().and_then(Outbound::diagnostics) {
+                    diagnostics.violation("Gave the wrong operator password");
+                }
+                "Wrong operator password".to_string()
+            } else {
+                info!("[BROADCAST] {from} is an operator now");
+                if let Some(outbound) = users.get(from) {
+                    outbound.oper();
+                }
+                "You're an operator now".to_string()
+            };
+            notify(users, from, text);
+        }
+        Command::Register { password } => {
+            let text = match accounts.map(|accounts| accounts.register(&from.name, &password)) {
+                None => "This server doesn't keep accounts".to_string(),
+                Some(Ok(())) => {
+                    info!("[ACCOUNT] {from} registered");
+                    format!("Registered {from}, connecting as it needs that password from now on")
+                }
+                Some(Err(e @ StorageError::Taken(_))) => e.to_string(),
+                Some(Err(e)) => {
+                    warn!("[ACCOUNT] Couldn't register {from}: {e}");
+                    "Couldn't register, try again later".to_string()
+                }
+            };
+            notify(users, from, text);
+        }
+        Command::Names { channel } => names(users, from, channel),
+        Command::List => list(users, from),
+        // Needs the whole line it came in on, so the broadcaster handles it before it gets here
+        Command::Say { .. } => unreachable!("Say is broadcast like chat"),
+        Command::Nick { .. } => unreachable!("Nick answers the connection it came in on"),
+        Command::Join { .. } => unreachable!("Join needs the history"),
+        Command::Pong { .. } => unreachable!("Pong only has to be read, it never leaves handle_chat"),
+    }
+}
+
+/// Disconnects `target` from this server, telling them and everyone else they were `how`, like `kicked by
+/// alice`, and why if there's a `reason`. Returns whether they were here to kick.
+pub(crate) fn kick(users: &Registry<Outbound>, target: &User, how: &str, reason: Option<&str>) -> bool {
+    let text = match reason {
+        Some(reason) => format!("{target} was {how}: {reason}"),
+        None => format!("{target} was {how}"),
+    };
+    // They're told they're being thrown off in a way their client can't mistake for anything else said
+    let told = encode(&ServerMessage::Disconnected { reason: text.clone() });
+    let Some((told, frame)) = told.zip(encode(&ServerMessage::notice(text))) else {
+        return false;
+    };
+    if !users.kill(target, told) {
+        return false;
+    }
+    users.send_to_all(&frame, Some(target));
+    true
+}
+
+/// `/join`, catching `from` up on what was said in `channel` lately if they weren't in it already.
+fn join(users: &Registry<Outbound>, from: &User, channel: String, history: &History) {
+    if !users.channels().join(from, &channel) {
+        notify(users, from, format!("Talking in {channel} again"));
+        return;
+    }
+    notify(users, from, format!("Joined {channel}, talking there now"));
+    replay(users, from, history, Some(&channel));
+}
+
+/// Sends `user` what was said lately in `channel`, or the lobby if `None`. Whatever gets said between them
+/// getting in and this might show up twice, once live and once here.
+fn replay(users: &Registry<Outbound>, user: &User, history: &History, channel: Option<&str>) {
+    let recent: Vec<_> = history.recent(channel).filter_map(encode).collect();
+    if recent.is_empty() {
+        return;
+    }
+
+    notify(users, user, format!("Last {} messages in {}:", recent.len(), channel.unwrap_or(channel::LOBBY)));
+    for frame in recent {
+        if users.send_to(user, frame).is_err() {
+            return;
+        }
+    }
+}
+
+/// `/names`, telling `from` who's connected, or who's in `channel` if given.
+fn names(users: &Registry<Outbound>, from: &User, channel: Option<String>) {
+    let now = SystemTime::now();
+    let mut members = Vec::new();
+    let add = |user: &User, outbound: &Outbound| {
+        members.push(Member { user: user.clone(), idle_secs: outbound.diagnostics().map(|d| d.idle(now).as_secs()) });
+    };
+    match channel.as_deref() {
+        None => users.for_each(add),
+        Some(channel) => users.for_each_in(Some(channel).filter(|c| *c != channel::LOBBY), add),
+    }
+    // Shards don't keep everyone in order between them
+    members.sort_by(|a, b| a.user.cmp(&b.user));
+
+    if let Some(frame) = encode(&ServerMessage::Names { channel, users: members }) {
+        if let Err(e) = users.send_to(from, frame) {
+            warn!("[BROADCAST] Couldn't send {from} who's here: {e}");
+        }
+    }
+}
+
+fn list(users: &Registry<Outbound>, from: &User) {
+    let channels = users.channels().sizes().map(|(channel, members)| (channel.to_string(), members)).collect();
+    if let Some(frame) = encode(&ServerMessage::Channels { channels }) {
+        if let Err(e) = users.send_to(from, frame) {
+            warn!("[BROADCAST] Couldn't send {from} the channels: {e}");
+        }
+    }
+}
+
+/// `/nick`, moving whoever sent `line` over to `nick` and telling everyone, or telling them why not. Nicks
+/// registered in `accounts` are only for connecting as, and nicks in `bans` aren't for anyone.
+fn rename(
+    users: &Registry<Outbound>,
+    line: ChatLine,
+    nick: String,
+    cluster: Option<&Cluster>,
+    (accounts, bans): (Option<&Accounts>, &Bans),
+) {
+    let (from, to) = (&line.from, User { name: nick, ..line.from.clone() });
+    let registered = || accounts.map_or(Ok(false), |accounts| accounts.is_registered(&to.name));
+    let renamed = to.validate().map_err(ServerError::from).and_then(|()| match cluster {
+        _ if bans.reason(&to).is_some() => Err(ServerError::Banned(format!("{to} is banned"))),
+        _ if registered()? => Err(ServerError::Unidentified(to.name.clone())),
+        Some(cluster) if cluster.is_taken_remotely(&to) => Err(ServerError::AlreadyConnected(to.name.clone())),
+        _ => users.rename(from, &to),
+    });
+    if let Err(e) = renamed {
+        notify(users, from, e.to_string());
+        return;
+    }
+
+    let message = ServerMessage::renamed(from, &to);
+    if let Some(frame) = encode(&message) {
+        users.send_to_all(&frame, None);
+    }
+    if let Some(cluster) = cluster {
+        cluster.signed_on(&to, SystemTime::now());
+        cluster.publish(&message);
+    }
+    info!("[NICK] {from} is now {to}");
+
+    if let Some(renamed) = &line.renamed {
+        // Their connection is waiting on this, so it's only gone if it's gone wrong
+        let _ = renamed.send(to);
+    }
+}
+
+/// Sends every local user a notice from the server, rendered just for them.
+fn announce(users: &Registry<Outbound>, info: &ServerInfo, template: &str) {
+    let online = users.len();
+    users.for_each(|user, outbound| {
+        if let Some(frame) = encode(&ServerMessage::notice(info.render(template, user, online))) {
+            let _ = outbound.send(frame);
+        }
+    });
+}
+
+/// Sends `user` a notice from the server.
+fn notify(users: &Registry<Outbound>, user: &User, text: impl Into<String>) {
+    if let Some(frame) = encode(&ServerMessage::notice(text)) {
+        if let Err(e) = users.send_to(user, frame) {
+            warn!("[BROADCAST] Couldn't send {user} a notice: {e}");
+        }
+    }
+}
+
+fn encode(message: &ServerMessage) -> Option<Frame> {
+    match encode_message(message) {
+        Ok(frame) => Some(frame.into()),
+        Err(e) => {
+            warn!("[BROADCAST] Failed encoding {message:?}: {e:?}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicBool;
+    use crate::frame::read_message;
+    use crate::outbound::Queue;
+    use crate::response::OLDEST_SUPPORTED;
+    use crate::testing::{accepted, hello, Trickle};
+    use super::*;
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        crate::frame::encode_frame(payload).unwrap()
+    }
+
+    fn diagnostics() -> Diagnostics {
+        Diagnostics::new(IpAddr::from([127, 0, 0, 1]), Arc::new(Mutex::new(IoMeter::new(IoLimits::default(), Instant::now()))))
+    }
+
+    fn outbound() -> Outbound {
+        Outbound::new(CHANNEL_SIZE).0
+    }
+
+    /// An `Outbound` for someone connecting from `peer`, and its queue.
+    fn from_address(peer: [u8; 4]) -> (Outbound, Queue) {
+        let meter = Arc::new(Mutex::new(IoMeter::new(IoLimits::default(), Instant::now())));
+        let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+        (outbound.with_diagnostics(Arc::new(Diagnostics::new(IpAddr::from(peer), meter))), queue)
+    }
+
+    /// `do_auth_flow` for a server that isn't clustered, in maintenance or anything else.
+    fn auth<R: Read, W: Write>(reader: R, output: &mut W, users: &Registry<Outbound>) -> Result<User, ServerError> {
+        auth_with(&mut Framed::new(reader), output, users, &Options::default())
+    }
+
+    /// `auth`, for a server with a password or accounts in its `options`.
+    fn auth_with<R: Read, W: Write>(
+        reader: &mut Framed<R>,
+        output: &mut W,
+        users: &Registry<Outbound>,
+        options: &Options,
+    ) -> Result<User, ServerError> {
+        let gates = (&Default::default(), &Default::default(), &Default::default());
+        do_auth_flow(reader, output, users, outbound(), None, gates, options)
+    }
+
+    fn broadcast(users: SharedRegistry, receiver: Receiver<ChatLine>, metrics: &Metrics, roles: &Roles) {
+        let gates = (roles, &Default::default(), &Default::default(), None, &Default::default());
+        broadcast_messages(users, receiver, metrics, None, &Default::default(), gates);
+    }
+
+    /// `output` with the time taken back out of the `Capabilities` it starts with, so it can be compared.
+    fn unstamped(output: &[u8]) -> Vec<u8> {
+        let mut output = Cursor::new(output);
+        let capabilities = match read_message(&mut output, &FrameLimits::default()).unwrap() {
+            Capabilities::Accepted { version, features, time_ms: Some(_) } => {
+                Capabilities::Accepted { version, features, time_ms: None }
+            }
+            other => panic!("Didn't expect {other:?}"),
+        };
+        let rest = &output.get_ref()[output.position() as usize..];
+        [encode_message(&capabilities).unwrap(), rest.to_vec()].concat()
+    }
+
+    /// Everything queued up for a connection so far, as one buffer.
+    fn drain(queue: &Receiver<Arc<[u8]>>) -> Vec<u8> {
+        queue.try_iter().flat_map(|frame| frame.to_vec()).collect()
+    }
+
+    #[test]
+    fn do_auth_flow_valid_json() {
+        let user = User::new("hello");
+        let mut input = Cursor::new(hello(&user));
+        let mut output = Cursor::new(Vec::new());
+
+        let success_resp = serde_json::to_vec(&AuthResponse::Success).unwrap();
+
+        assert_eq!(user, auth(&mut input, &mut output, &Default::default()).unwrap());
+        assert_eq!(&[accepted(), framed(&success_resp)].concat(), &unstamped(output.get_ref()));
+    }
+
+    #[test]
+    fn do_auth_flow_byte_at_a_time() {
+        let user = User::new("hello");
+        let mut input = Trickle(Cursor::new(hello(&user)));
+        let mut output = Cursor::new(Vec::new());
+
+        assert_eq!(user, auth(&mut input, &mut output, &Default::default()).unwrap());
+    }
+
+    #[test]
+    fn do_auth_flow_coalesced_with_chat() {
+        let mut user = User::new("hello");
+        let mut input = hello(&user);
+        input.extend(b"first!\nsecond!\n");
+        // Big enough that the hello and both lines land in the buffer in one go
+        let mut reader = Framed::new(Cursor::new(input));
+        let mut output = Cursor::new(Vec::new());
+
+        let authed = auth_with(&mut reader, &mut output, &Default::default(), &Options::default());
+        assert_eq!(user, authed.unwrap());
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        handle_chat(reader, &mut user, tx, &MemoryBudget::new(1024), &Default::default(), (&diagnostics(), &outbound()), &Options::default());
+        for text in ["first!", "second!"] {
+            let line = rx.recv().unwrap();
+            assert_eq!((&user, text), (&line.from, line.text.as_str()));
+        }
+    }
+
+    // Only necessary because of VALIDATE_BUFFER_SIZE
+    #[test]
+    fn do_auth_flow_buffer_length_failure() {
+        let mut long_str = String::with_capacity(VALIDATE_BUFFER_SIZE);
+        (0..VALIDATE_BUFFER_SIZE).for_each(|_| long_str.push('a'));
+        let user = User::new(long_str.clone());
+        let mut input = Cursor::new(hello(&user));
+        let mut output = Cursor::new(Vec::new());
+
+        let res = auth(&mut input, &mut output, &Default::default()).err().unwrap();
+        assert!(matches!(res, ServerError::Frame(FrameError::TooLarge { max: VALIDATE_BUFFER_SIZE, .. })));
+        assert_eq!(&accepted(), &unstamped(output.get_ref()));
+    }
+
+    #[test]
+    fn do_auth_flow_without_a_handshake() {
+        // How clients from before handshakes open
+        let mut input = Cursor::new(framed(&serde_json::to_vec(&User::new("hello")).unwrap()));
+        let mut output = Cursor::new(Vec::new());
+
+        let res = auth(&mut input, &mut output, &Default::default());
+        assert!(matches!(res, Err(ServerError::Incompatible(_))));
+        assert_eq!(&framed(&serde_json::to_vec(&AuthResponse::needs_handshake()).unwrap()), output.get_ref());
+    }
+
+    #[test]
+    fn do_auth_flow_needs_the_password() {
+        let (user, users) = (User::new("hello"), Registry::default());
+        let options = Options { password: Some("sesame".to_string()), ..Default::default() };
+        let log_in = |password: &str| {
+            let login = Login::new(user.clone(), Some(password.to_string()));
+            let handshake = encode_message(&Handshake::new(&[response::PASSWORD])).unwrap();
+            let mut input = Framed::new(Cursor::new([handshake, encode_message(&login).unwrap()].concat()));
+            let mut output = Cursor::new(Vec::new());
+            let res = auth_with(&mut input, &mut output, &users, &options);
+            output.set_position(0);
+            let capabilities: Capabilities = read_message(&mut output, &FrameLimits::default()).unwrap();
+            assert!(capabilities.has(response::PASSWORD));
+            let resp: AuthResponse = read_message(&mut output, &FrameLimits::default()).unwrap();
+            (res, resp)
+        };
+
+        let (res, resp) = log_in("open up");
+        assert!(matches!(res, Err(ServerError::BadPassword)));
+        assert_eq!(AuthResponse::BadPassword, resp);
+        assert!(users.is_empty());
+
+        let (res, resp) = log_in("sesame");
+        assert_eq!(user, res.unwrap());
+        assert_eq!(AuthResponse::Success, resp);
+    }
+
+    #[cfg(feature = "accounts")]
+    #[test]
+    fn registered_nicks_need_their_password() {
+        let accounts = Arc::new(Accounts::in_memory().unwrap());
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let connected_users: SharedRegistry = Default::default();
+        let (alice_outbound, alice_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&alice, alice_outbound).unwrap();
+        let (bob_outbound, bob_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&bob, bob_outbound).unwrap();
+
+        // Operators' nicks only count once they're registered
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        for line in ["/wallops early", "/register hunter2", "/register again", "/wallops hi"] {
+            tx.send(ChatLine::new(alice.clone(), line)).unwrap();
+        }
+        tx.send(ChatLine::new(bob.clone(), "/nick ALICE")).unwrap();
+        drop(tx);
+        let roles = Roles { operators: [alice.clone()].into(), password: None };
+        let gates = (&roles, &Default::default(), &Default::default(), Some(&*accounts), &Default::default());
+        broadcast_messages(connected_users, rx, &Default::default(), None, &Default::default(), gates);
+        let registered = notice("Registered alice, connecting as it needs that password from now on");
+        let (denied, wallops) = (notice("Permission denied, you're not an operator"), notice("[wallops] alice: hi"));
+        assert_eq!(vec![denied, registered, notice("alice is already registered"), wallops.clone()], messages(&alice_queue));
+        assert_eq!(vec![wallops, notice("ALICE is registered, connect with its password to use it")], messages(&bob_queue));
+
+        let options = Options { accounts: Some(accounts), ..Default::default() };
+        let log_in = |account_password: Option<&str>| {
+            let login = Login::new(alice.clone(), None).identified(account_password.map(str::to_string));
+            let input = [encode_message(&Handshake::new(&[])).unwrap(), encode_message(&login).unwrap()].concat();
+            auth_with(&mut Framed::new(Cursor::new(input)), &mut Vec::new(), &Registry::default(), &options)
+        };
+        assert!(matches!(log_in(None), Err(ServerError::Unidentified(nick)) if nick == "alice"));
+        assert!(matches!(log_in(Some("hunter3")), Err(ServerError::Unidentified(_))));
+        assert_eq!(alice, log_in(Some("hunter2")).unwrap());
+    }
+
+    #[test]
+    fn do_auth_flow_too_old() {
+        let handshake = Handshake { version: OLDEST_SUPPORTED - 1, features: Vec::new() };
+        let mut input = Cursor::new(framed(&serde_json::to_vec(&handshake).unwrap()));
+        let mut output = Cursor::new(Vec::new());
+
+        let res = auth(&mut input, &mut output, &Default::default());
+        assert!(matches!(res, Err(ServerError::Incompatible(_))));
+        let answer: Capabilities = read_message(&mut Cursor::new(output.into_inner()), &FrameLimits::default()).unwrap();
+        assert!(matches!(answer, Capabilities::Rejected(_)));
+    }
+
+    #[test]
+    fn do_auth_flow_invalid_nick() {
+        let mut input = Cursor::new(hello(&User::new("not ok")));
+        let mut output = Cursor::new(Vec::new());
+        let connected_users = Registry::default();
+
+        let res = auth(&mut input, &mut output, &connected_users);
+        assert!(matches!(res, Err(ServerError::InvalidUser(UserError::BadNick))));
+        assert!(connected_users.is_empty());
+    }
+
+    #[test]
+    fn do_auth_flow_keeps_display_name() {
+        let user = User::new("alice").with_display_name("Älice");
+        let mut input = Cursor::new(hello(&user));
+
+        let authed = auth(&mut input, &mut Cursor::new(Vec::new()), &Default::default()).unwrap();
+        assert_eq!(Some("Älice"), authed.display_name.as_deref());
+    }
+
+    #[test]
+    fn do_auth_flow_already_logged_in() {
+        let user = User::new("hello");
+        let mut input = Cursor::new(hello(&user));
+        let mut output = Cursor::new(Vec::new());
+
+        let connected_users = Registry::default();
+        connected_users.claim_nick(&user, outbound()).unwrap();
+
+        let failure_res = serde_json::to_vec(&AuthResponse::Error("Name is already taken: hello".to_string())).unwrap();
+
+        let res = auth(&mut input, &mut output, &connected_users).err().unwrap();
+        assert_eq!(
+            std::mem::discriminant(&res),
+            std::mem::discriminant(&ServerError::AlreadyConnected("".to_string()))
+        );
+        assert_eq!(&[accepted(), framed(&failure_res)].concat(), &unstamped(output.get_ref()));
+    }
+
+    #[test]
+    fn do_auth_flow_concurrent_duplicate_nick() {
+        const CLIENTS: usize = 16;
+        let connected_users: SharedRegistry = Default::default();
+        let barrier = std::sync::Barrier::new(CLIENTS);
+        let user_frame = hello(&User::new("hello"));
+
+        let logged_in = thread::scope(|scope| {
+            let handles: Vec<_> = (0..CLIENTS)
+                .map(|_| {
+                    let mut input = Cursor::new(user_frame.clone());
+                    let (users, barrier) = (&connected_users, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        auth(&mut input, &mut Cursor::new(Vec::new()), users).is_ok()
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).filter(|won| *won).count()
+        });
+
+        assert_eq!(1, logged_in);
+        assert_eq!(1, connected_users.len());
+    }
+
+    #[test]
+    fn broadcast_message() {
+        let user_1 = User::new("one");
+        let user_2 = User::new("two");
+
+        let connected_users: SharedRegistry = Default::default();
+        let (outbound_1, queue_1) = Outbound::new(CHANNEL_SIZE);
+        let (outbound_2, queue_2) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&user_1, outbound_1).unwrap();
+        connected_users.claim_nick(&user_2, outbound_2).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let (hello, waddup) = (ChatLine::new(user_1.clone(), "hello"), ChatLine::new(user_2.clone(), "yo waddup"));
+        let expected = |line: &ChatLine| framed(&serde_json::to_vec(&line.to_message(None)).unwrap());
+        let (expected_hello, expected_waddup) = (expected(&hello), expected(&waddup));
+        tx.send(hello).unwrap();
+        tx.send(waddup).unwrap();
+        drop(tx);
+
+        broadcast(connected_users.clone(), rx, &Default::default(), &Default::default());
+        assert_eq!(expected_waddup, drain(&queue_1));
+        assert_eq!(expected_hello, drain(&queue_2));
+    }
+
+    #[test]
+    fn broadcast_back_to_back_messages_stay_separate() {
+        let (sender, receiver) = (User::new("one"), User::new("two"));
+
+        let connected_users: SharedRegistry = Default::default();
+        let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&receiver, outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let lines = [ChatLine::new(sender.clone(), "hello"), ChatLine::new(sender, "hello again")];
+        let expected: Vec<_> = lines.iter().map(|line| line.to_message(None)).collect();
+        lines.into_iter().for_each(|line| tx.send(line).unwrap());
+        drop(tx);
+
+        broadcast(connected_users.clone(), rx, &Default::default(), &Default::default());
+
+        // Both messages end up in one buffer, like they would if they got coalesced into one TCP segment
+        let mut received = Cursor::new(drain(&queue));
+        for expected in expected {
+            let msg: ServerMessage = read_message(&mut received, &FrameLimits::default()).unwrap();
+            assert_eq!(expected, msg);
+        }
+    }
+
+    #[test]
+    fn handle_chat_drops_oversized_lines() {
+        let mut user = User::new("hello");
+        let input = format!("short\n{}\nafter\n", "a".repeat(100));
+        let metrics = Metrics::default();
+        let options = Options { max_line_len: 16, ..Default::default() };
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        handle_chat(Cursor::new(input), &mut user, tx, &MemoryBudget::new(1024), &metrics, (&diagnostics(), &outbound()), &options);
+
+        let texts: Vec<_> = rx.iter().map(|line| line.text).collect();
+        assert_eq!(vec!["short", "after"], texts);
+        assert_eq!(1, metrics.oversized_lines.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn handle_chat_cuts_long_messages_at_graphemes() {
+        let mut user = User::new("hello");
+        // Way over 4 in bytes, but only 4 characters too many
+        let input = "漢字かな交じり文\n👍🏽👍🏽👍🏽👍🏽\n";
+        let options = Options { max_message_len: 4, ..Default::default() };
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        handle_chat(Cursor::new(input), &mut user, tx, &MemoryBudget::new(1024), &Default::default(), (&diagnostics(), &outbound()), &options);
+
+        let texts: Vec<_> = rx.iter().map(|line| line.text).collect();
+        assert_eq!(vec!["漢字かな", "👍🏽👍🏽👍🏽👍🏽"], texts);
+    }
+
+    #[test]
+    fn line_reader_memory_stays_bounded() {
+        let line = format!("{}\n", "x".repeat(100));
+        let mut lines = LineReader::new(Cursor::new(line.repeat(10_000)), 1024);
+        let starting_capacity = lines.buffer.capacity();
+
+        let mut count = 0;
+        while let Some(line) = lines.next_line().unwrap() {
+            assert_eq!(Line::Text("x".repeat(100)), line);
+            count += 1;
+        }
+
+        assert_eq!(10_000, count);
+        assert_eq!(starting_capacity, lines.buffer.capacity());
+    }
+
+    #[test]
+    fn line_reader_long_line_doesnt_stick_around() {
+        let input = format!("{}\nshort\n", "x".repeat(2000));
+        let mut lines = LineReader::new(Cursor::new(input), 1024);
+
+        assert_eq!(Some(Line::TooLong), lines.next_line().unwrap());
+        assert_eq!(Some(Line::Text("short".to_string())), lines.next_line().unwrap());
+        assert_eq!(None, lines.next_line().unwrap());
+        assert!(lines.buffer.capacity() <= 2048);
+    }
+
+    #[test]
+    fn handle_chat_cuts_off_line_floods() {
+        let mut user = User::new("hello");
+        let metrics = Metrics::default();
+        let meter = IoMeter::new(IoLimits { lines_per_sec: 2, max_strikes: 0, ..Default::default() }, Instant::now());
+        let diagnostics = Diagnostics::new(IpAddr::from([127, 0, 0, 1]), Arc::new(Mutex::new(meter)));
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        handle_chat(Cursor::new("a\nb\nc\nd\n"), &mut user, tx, &MemoryBudget::new(1024), &metrics, (&diagnostics, &outbound()), &Options::default());
+
+        assert_eq!(2, rx.try_iter().count());
+        assert_eq!(1, metrics.io_disconnects.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn handle_chat_drops_then_cuts_off_floods() {
+        let metrics = Metrics::default();
+        let options = Options { flood: FloodLimits { messages_per_sec: 1, burst: 2, max_dropped: 2 }, ..Default::default() };
+        let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let (input, connection) = (Cursor::new("a\nb\nc\nd\ne\nf\n"), (&diagnostics(), &outbound));
+        let quit = handle_chat(input, &mut User::new("hello"), tx, &MemoryBudget::new(1024), &metrics, connection, &options);
+
+        assert_eq!(vec!["a", "b"], rx.try_iter().map(|line| line.text).collect::<Vec<_>>());
+        assert_eq!(PresenceChange::Quit { reason: Some("flooding".to_string()) }, quit);
+        let slow_down = "You're sending too fast, your messages are being dropped until you slow down";
+        let [ServerMessage::Notice { text, retry_after_ms: Some(retry_after_ms) }, cut_off] = &messages(&queue)[..] else {
+            panic!("Should've been warned, with when to try again, then cut off");
+        };
+        assert_eq!((slow_down, &notice("Disconnected for flooding")), (text.as_str(), cut_off));
+        // A token of debt and one more to get through, at a second each, give or take how long reading took
+        assert!((1900..=2000).contains(retry_after_ms), "{retry_after_ms}");
+        assert_eq!(2, metrics.flood_drops.load(Ordering::Relaxed));
+        assert_eq!(1, metrics.flood_disconnects.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn handle_chat_takes_pongs_and_ping_timeouts() {
+        let diagnostics = diagnostics();
+        diagnostics.ping(SystemTime::now());
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let (input, connection) = (Cursor::new("/pong 1\nhi\n"), (&diagnostics, &outbound()));
+        let quit = handle_chat(input, &mut User::new("hello"), tx, &MemoryBudget::new(1024), &Default::default(), connection, &Default::default());
+
+        assert_eq!(vec!["hi"], rx.try_iter().map(|line| line.text).collect::<Vec<_>>());
+        assert_eq!(None, diagnostics.ping(SystemTime::now()));
+        assert_eq!(PresenceChange::Quit { reason: None }, quit);
+
+        // Hung up on by the keepalive, which looks like them hanging up from here
+        diagnostics.time_out();
+        let connection = (&diagnostics, &outbound());
+        let (tx, _rx) = mpsc::sync_channel(1);
+        let quit = handle_chat(Cursor::new(""), &mut User::new("hello"), tx, &MemoryBudget::new(1024), &Default::default(), connection, &Default::default());
+        assert_eq!(PresenceChange::Quit { reason: Some("Ping timeout".to_string()) }, quit);
+    }
+
+    #[test]
+    fn handle_chat_respects_memory_budget() {
+        let mut user = User::new("hello");
+        let memory = MemoryBudget::new(10);
+
+        // Nothing drains the channel, so the first line's reservation is still held when the second shows up
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        handle_chat(Cursor::new("123456\n7890123\n"), &mut user, tx, &memory, &Default::default(), (&diagnostics(), &outbound()), &Options::default());
+
+        let texts: Vec<_> = rx.try_iter().map(|line| line.text).collect();
+        assert_eq!(vec!["123456"], texts);
+        assert_eq!(1, memory.rejected());
+        // Everything's been dropped by now, so it should all be back
+        assert_eq!(0, memory.used());
+    }
+
+    #[test]
+    fn broadcast_includes_receive_time() {
+        let line = ChatLine::new(User::new("one"), "hello");
+        let ServerMessage::Chat { received_ms, .. } = line.to_message(None) else {
+            panic!("Chat lines should turn into chat");
+        };
+
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert!(received_ms > 0 && received_ms <= now_ms);
+    }
+
+    #[test]
+    fn broadcast_cuts_off_slow_readers_only() {
+        let (sender, slow, fast) = (User::new("one"), User::new("slow"), User::new("fast"));
+
+        let connected_users: SharedRegistry = Default::default();
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let hanging_up = hung_up.clone();
+        let (slow_outbound, slow_queue) = Outbound::new(1);
+        let slow_outbound = slow_outbound.with_hang_up(move || hanging_up.store(true, Ordering::Relaxed));
+        let (fast_outbound, fast_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&slow, slow_outbound).unwrap();
+        connected_users.claim_nick(&fast, fast_outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        (0..3).for_each(|i| tx.send(ChatLine::new(sender.clone(), i.to_string())).unwrap());
+        drop(tx);
+
+        let metrics = Metrics::default();
+        broadcast(connected_users, rx, &metrics, &Default::default());
+
+        assert_eq!(1, slow_queue.try_iter().count());
+        assert_eq!(3, fast_queue.try_iter().count());
+        assert_eq!(2, metrics.dropped_frames.load(Ordering::Relaxed));
+        assert!(hung_up.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn turns_away_clients_past_the_max() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (address, listener) = (listener.local_addr().unwrap(), Listener::from(listener));
+        let (shutdown, clients, accepts) = (Shutdown::default(), ClientLimit::new(Some(1)), AcceptRate::new(0, 0));
+        shutdown.watch(&listener).unwrap();
+        let (done, serving) = mpsc::channel::<()>();
+        let serving = Mutex::new(serving);
+        let pool = Pool::new(2, 2, move |_: Incoming| {
+            let _ = serving.lock().recv();
+        });
+
+        let bans = Bans::default();
+        let gates = (&bans, &shutdown, &clients, &accepts);
+        thread::scope(|scope| {
+            scope.spawn(|| accept_connections((listener, Transport::Tcp), &pool, (2, 2, false), gates));
+            let _first = TcpStream::connect(address).unwrap();
+            let mut second = TcpStream::connect(address).unwrap();
+            assert_eq!(AuthResponse::ServerFull, read_message(&mut second, &FrameLimits::default()).unwrap());
+            assert_eq!(1, clients.turned_away());
+
+            // Its seat's given up once the first one's done with
+            done.send(()).unwrap();
+            while clients.connected() > 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+            shutdown.begin();
+        });
+    }
+
+    #[test]
+    fn turns_away_clients_coming_too_fast() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (address, listener) = (listener.local_addr().unwrap(), Listener::from(listener));
+        let (shutdown, clients, accepts) = (Shutdown::default(), ClientLimit::new(None), AcceptRate::new(1, 1));
+        shutdown.watch(&listener).unwrap();
+        let pool = Pool::new(1, 1, |_: Incoming| {});
+
+        let bans = Bans::default();
+        let gates = (&bans, &shutdown, &clients, &accepts);
+        thread::scope(|scope| {
+            scope.spawn(|| accept_connections((listener, Transport::Tcp), &pool, (1, 1, false), gates));
+            let _first = TcpStream::connect(address).unwrap();
+            let mut second = TcpStream::connect(address).unwrap();
+            let Ok(AuthResponse::Busy { retry_after_ms }) = read_message(&mut second, &FrameLimits::default()) else {
+                panic!("The second one in the same second wasn't told to come back later");
+            };
+            assert!((900..=2000).contains(&retry_after_ms), "{retry_after_ms}");
+            assert_eq!((1, 0), (accepts.turned_away(), clients.turned_away()));
+            shutdown.begin();
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn takes_unix_connections_through_the_same_gates() {
+        use std::os::unix::net::UnixStream;
+        let path = std::env::temp_dir().join(format!("basic-irc-server-{}.sock", std::process::id()));
+        let listener = transport::bind("127.0.0.1:0".parse().unwrap(), 1, (None, Some(&path))).unwrap().remove(0);
+        let _socket = SocketFile(path.clone());
+        let (shutdown, clients, accepts) = (Shutdown::default(), ClientLimit::new(None), AcceptRate::new(1, 1));
+        shutdown.watch(&listener.0).unwrap();
+        let (served, peers) = mpsc::channel();
+        let pool = Pool::new(1, 1, move |(_, peer, _): Incoming| served.send(peer).unwrap());
+
+        let bans = Bans::default();
+        let gates = (&bans, &shutdown, &clients, &accepts);
+        thread::scope(|scope| {
+            scope.spawn(|| accept_connections(listener, &pool, (1, 1, true), gates));
+            let _first = UnixStream::connect(&path).unwrap();
+            assert_eq!(IpAddr::from([127, 0, 0, 1]), peers.recv().unwrap());
+            // Nothing to unwrap on a Unix socket, so it's told why it's turned away even with TLS on
+            let mut second = UnixStream::connect(&path).unwrap();
+            assert!(matches!(read_message(&mut second, &FrameLimits::default()), Ok(AuthResponse::Busy { .. })));
+            shutdown.begin();
+        });
+    }
+
+    #[test]
+    fn goodbye_comes_after_everything_already_said() {
+        let connected_users: SharedRegistry = Default::default();
+        let hung_up = Arc::new(AtomicBool::new(false));
+        let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+        let hanging_up = hung_up.clone();
+        let outbound = outbound.with_hang_up(move || hanging_up.store(true, Ordering::Relaxed));
+        connected_users.claim_nick(&User::new("two"), outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let users = connected_users.clone();
+        let broadcaster = thread::spawn(move || broadcast(users, rx, &Default::default(), &Default::default()));
+        let hello = ChatLine::new(User::new("one"), "hello");
+        let expected_hello = hello.to_message(None);
+        tx.send(hello).unwrap();
+        say_goodbye(&connected_users, &tx, Instant::now() + Duration::from_secs(5));
+        drop(tx);
+        broadcaster.join().unwrap();
+
+        assert_eq!(vec![expected_hello, notice("Server shutting down")], messages(&queue));
+        assert!(hung_up.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn handle_chat_read_only() {
+        let mirror = MirrorOptions { upstream: ([127, 0, 0, 1], 6667).into(), name: "mirror".to_string() };
+        let options = Options { mirror: Some(mirror), ..Default::default() };
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let input = Cursor::new("a\n/join #rust\n/nick b\n/names #rust\n/part #rust\n");
+        let connection = (&diagnostics(), &outbound());
+        handle_chat(input, &mut User::new("hello"), tx, &MemoryBudget::new(1024), &Default::default(), connection, &options);
+        // Only picking channels to follow gets through
+        let kept: Vec<_> = rx.try_iter().map(|line| line.text).collect();
+        assert_eq!(vec!["/join #rust", "/names #rust", "/part #rust"], kept);
+    }
+
+    fn messages(queue: &Receiver<Arc<[u8]>>) -> Vec<ServerMessage> {
+        let mut received = Cursor::new(drain(queue));
+        let mut messages = Vec::new();
+        while received.position() < received.get_ref().len() as u64 {
+            messages.push(read_message(&mut received, &FrameLimits::default()).unwrap());
+        }
+        messages
+    }
+
+    fn notice(text: &str) -> ServerMessage {
+        ServerMessage::notice(text.to_string())
+    }
+
+    #[test]
+    fn operator_commands() {
+        let (oper, bob) = (User::new("oper"), User::new("bob"));
+        let roles = Roles { operators: [oper.clone()].into(), password: Some("hunter2".to_string()) };
+
+        let connected_users: SharedRegistry = Default::default();
+        let (oper_outbound, oper_queue) = Outbound::new(CHANNEL_SIZE);
+        let (bob_outbound, bob_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&oper, oper_outbound).unwrap();
+        connected_users.claim_nick(&bob, bob_outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        tx.send(ChatLine::new(bob.clone(), "/oper hunter2")).unwrap();
+        let lines = ["/wallops too soon", "/oper hunter3", "/oper hunter2", "/wallops heads up"];
+        for line in lines.into_iter().chain(["/kill nobody bye", "/kill bob bye"]) {
+            tx.send(ChatLine::new(oper.clone(), line)).unwrap();
+        }
+        drop(tx);
+
+        broadcast(connected_users.clone(), rx, &Default::default(), &roles);
+        let expected = [
+            "Permission denied, you're not an operator",
+            "Wrong operator password",
+            "You're an operator now",
+            "[wallops] oper: heads up",
+            "No such nick: nobody",
+        ];
+        assert_eq!(expected.map(notice).to_vec(), messages(&oper_queue));
+        let killed = ServerMessage::Disconnected { reason: "Killed by oper: bye".to_string() };
+        assert_eq!(vec![notice("bob isn't an operator"), notice("[wallops] oper: heads up"), killed], messages(&bob_queue));
+    }
+
+    #[test]
+    fn kicks_and_bans() {
+        let (oper, bob, carol, erin) = (User::new("oper"), User::new("bob"), User::new("carol"), User::new("erin"));
+        let roles = Roles { operators: [oper.clone()].into(), password: None };
+        let (maintenance, info, bans) = (Maintenance::default(), ServerInfo::default(), Bans::default());
+
+        let connected_users: SharedRegistry = Default::default();
+        let [oper_queue, bob_queue, carol_queue, erin_queue] = [(&oper, 1), (&bob, 2), (&carol, 3), (&erin, 4)].map(|(user, host)| {
+            let (outbound, queue) = from_address([10, 0, 0, host]);
+            connected_users.claim_nick(user, outbound).unwrap();
+            queue
+        });
+        connected_users.get(&oper).unwrap().oper();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let lines = [
+            "/kick bob flooding", "/kick nobody", "/ban carol", "/ban Carol", "/ban dave spam", "/nick dave", "/ban 10.0.0.0/24",
+            "/ban 10.0.0.4 office", "/unban dave", "/unban dave", "/bans",
+        ];
+        for line in lines {
+            tx.send(ChatLine::new(oper.clone(), line)).unwrap();
+        }
+        drop(tx);
+        let gates = (&roles, &maintenance, &info, None, &bans);
+        broadcast_messages(connected_users.clone(), rx, &Default::default(), None, &Default::default(), gates);
+
+        let expected = [
+            "bob was kicked by oper: flooding",
+            "No such nick: nobody",
+            "carol was banned by oper",
+            "carol is already banned",
+            "Banned dave, nobody connected here matches it",
+            "dave is banned",
+            "10.0.0.0/24 would ban you too",
+            "erin was banned by oper: office",
+            "Unbanned dave",
+            "dave isn't banned",
+            "2 ban(s):\n  carol: Banned by oper\n  10.0.0.4: Banned by oper: office",
+        ];
+        assert_eq!(expected.map(notice).to_vec(), messages(&oper_queue));
+        // Kicked, but still around until their connection notices. Everyone else hears it as a notice
+        let kicked = ["bob was kicked by oper: flooding", "carol was banned by oper", "erin was banned by oper: office"];
+        for (i, queue) in [&bob_queue, &carol_queue, &erin_queue].into_iter().enumerate() {
+            let mut told = kicked.map(notice);
+            told[i] = ServerMessage::Disconnected { reason: kicked[i].to_string() };
+            assert_eq!(told.to_vec(), messages(queue));
+        }
+
+        connected_users.release(&carol);
+        let (gates, options) = ((&maintenance, &info, &bans), Options::default());
+        for (nick, host, why) in [("carol", 5, "Banned by oper"), ("frank", 4, "Banned by oper: office")] {
+            let input = Cursor::new(hello(&User::new(nick)));
+            let mut output = Cursor::new(Vec::new());
+            let outbound = from_address([10, 0, 0, host]).0;
+            let res = do_auth_flow(&mut Framed::new(input), &mut output, &connected_users, outbound, None, gates, &options);
+            assert!(matches!(res, Err(ServerError::Banned(message)) if message == why));
+            let refusal = framed(&serde_json::to_vec(&AuthResponse::Error(why.to_string())).unwrap());
+            assert_eq!(&[accepted(), refusal].concat(), &unstamped(output.get_ref()));
+        }
+        let input = Cursor::new(hello(&User::new("dave")));
+        let res = do_auth_flow(&mut Framed::new(input), &mut Vec::new(), &connected_users, outbound(), None, gates, &options);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn commands_need_an_operator() {
+        let bob = User::new("bob");
+        let connected_users: SharedRegistry = Default::default();
+        let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&bob, outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        for line in ["/kill bob bye", "/nope"] {
+            tx.send(ChatLine::new(bob.clone(), line)).unwrap();
+        }
+        drop(tx);
+
+        broadcast(connected_users.clone(), rx, &Default::default(), &Default::default());
+        assert_eq!(
+            vec![notice("Permission denied, you're not an operator"), notice("Unknown command: /nope")],
+            messages(&queue)
+        );
+    }
+
+    #[test]
+    fn channels_scope_chat() {
+        let users = [User::new("alice"), User::new("bob"), User::new("carol")];
+        let connected_users: SharedRegistry = Default::default();
+        let queues: Vec<_> = users
+            .iter()
+            .map(|user| {
+                let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+                connected_users.claim_nick(user, outbound).unwrap();
+                queue
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let lines = [(0, "/join #rust"), (1, "/join #rust"), (0, "hi rust"), (2, "hi lobby"), (1, "/part #rust"), (1, "back")];
+        for (i, line) in lines {
+            tx.send(ChatLine::new(users[i].clone(), line)).unwrap();
+        }
+        drop(tx);
+        broadcast(connected_users.clone(), rx, &Default::default(), &Default::default());
+
+        let shown = |queue| -> Vec<String> {
+            messages(queue)
+                .into_iter()
+                .map(|message| match message {
+                    ServerMessage::Chat { from, text, channel, .. } => format!("{channel:?} <{from}> {text}"),
+                    ServerMessage::Notice { text, .. } => text,
+                    other => panic!("Didn't expect {other:?}"),
+                })
+                .collect()
+        };
+        assert_eq!(vec!["Joined #rust, talking there now"], shown(&queues[0]));
+        let bob = ["Joined #rust, talking there now", "Some(\"#rust\") <alice> hi rust", "Left #rust, talking in the lobby now"];
+        assert_eq!(Vec::from(bob), shown(&queues[1]));
+        assert_eq!(vec!["None <bob> back"], shown(&queues[2]));
+    }
+
+    #[test]
+    fn history_catches_people_up() {
+        let (alice, bob, carol) = (User::new("alice"), User::new("bob"), User::new("carol"));
+        let connected_users: SharedRegistry = Default::default();
+        let (outbound, _alice_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&alice, outbound).unwrap();
+        let (outbound, bob_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&bob, outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        for line in ["in the lobby", "/join #rust", "one", "two", "three"] {
+            tx.send(ChatLine::new(alice.clone(), line)).unwrap();
+        }
+        tx.send(ChatLine::new(bob.clone(), "/join #rust")).unwrap();
+        drop(tx);
+        let history = Mutex::new(History::new(2));
+        let gates = (&Default::default(), &Default::default(), &Default::default(), None, &Default::default());
+        broadcast_messages(connected_users.clone(), rx, &Default::default(), None, &history, gates);
+
+        let texts = |queue| -> Vec<String> {
+            messages(queue)
+                .into_iter()
+                .map(|message| match message {
+                    ServerMessage::Chat { text, .. } | ServerMessage::Notice { text, .. } => text,
+                    other => panic!("Didn't expect {other:?}"),
+                })
+                .collect()
+        };
+        let caught_up = ["in the lobby", "Joined #rust, talking there now", "Last 2 messages in #rust:", "two", "three"];
+        assert_eq!(Vec::from(caught_up), texts(&bob_queue));
+
+        // And the lobby for whoever connects next
+        let (outbound, carol_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&carol, outbound).unwrap();
+        replay(&connected_users, &carol, &history.lock(), None);
+        assert_eq!(vec!["Last 1 messages in &lobby:", "in the lobby"], texts(&carol_queue));
+    }
+
+    #[test]
+    fn everyone_else_hears_who_comes_and_goes() {
+        let (alice, bob) = (User::new("alice"), User::new("bob"));
+        let connected_users: SharedRegistry = Default::default();
+        let (sending, alice_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&alice, sending).unwrap();
+        let (sending, bob_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&bob, sending).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        presence(&tx, &alice, PresenceChange::Joined);
+        tx.send(ChatLine::new(alice.clone(), "hi")).unwrap();
+        let reset = PresenceChange::Quit { reason: Some("connection reset".to_string()) };
+        presence(&tx, &alice, reset.clone());
+        drop(tx);
+        let gates = (&Default::default(), &Default::default(), &Default::default(), None, &Default::default());
+        broadcast_messages(connected_users, rx, &Default::default(), None, &Mutex::new(History::new(0)), gates);
+
+        let heard = messages(&bob_queue);
+        assert_eq!(ServerMessage::Presence { user: alice.clone(), change: PresenceChange::Joined }, heard[0]);
+        assert!(matches!(&heard[1], ServerMessage::Chat { text, .. } if text == "hi"));
+        assert_eq!(ServerMessage::Presence { user: alice, change: reset }, heard[2]);
+        assert!(messages(&alice_queue).is_empty());
+
+        // Hanging up cleanly has no more to it than that
+        let (tx, _rx) = mpsc::sync_channel(CHANNEL_SIZE);
+        let connection = (&diagnostics(), &outbound());
+        let quit = handle_chat(Cursor::new("bye\n"), &mut bob.clone(), tx, &MemoryBudget::new(1024), &Default::default(), connection, &Options::default());
+        assert_eq!(PresenceChange::Quit { reason: None }, quit);
+    }
+
+    #[test]
+    fn names_and_lists_for_whoever_asked() {
+        let (alice, bob, carol) = (User::new("alice"), User::new("bob"), User::new("carol"));
+        let connected_users: SharedRegistry = Default::default();
+        let (watched, alice_queue) = Outbound::new(CHANNEL_SIZE);
+        connected_users.claim_nick(&alice, watched.with_diagnostics(Arc::new(diagnostics()))).unwrap();
+        connected_users.claim_nick(&bob, outbound()).unwrap();
+        connected_users.claim_nick(&carol, outbound()).unwrap();
+        connected_users.channels().join(&bob, "#rust");
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        for line in ["/who", "/names #rust", "/names &lobby", "/names #nobody", "/list"] {
+            tx.send(ChatLine::new(alice.clone(), line)).unwrap();
+        }
+        drop(tx);
+        let gates = (&Default::default(), &Default::default(), &Default::default(), None, &Default::default());
+        broadcast_messages(connected_users, rx, &Default::default(), None, &Mutex::new(History::new(0)), gates);
+
+        let member = |user: &User, idle_secs| Member { user: user.clone(), idle_secs };
+        let names = |channel: Option<&str>, users| ServerMessage::Names { channel: channel.map(str::to_string), users };
+        let everyone = vec![member(&alice, Some(0)), member(&bob, None), member(&carol, None)];
+        let expected = vec![
+            names(None, everyone),
+            names(Some("#rust"), vec![member(&bob, None)]),
+            names(Some("&lobby"), vec![member(&alice, Some(0)), member(&carol, None)]),
+            names(Some("#nobody"), vec![]),
+            ServerMessage::Channels { channels: [("#rust".to_string(), 1)].into() },
+        ];
+        assert_eq!(expected, messages(&alice_queue));
+    }
+
+    #[test]
+    fn nick_follows_the_connection() {
+        let (mut alice, bob) = (User::new("alice"), User::new("bob"));
+        let connected_users: SharedRegistry = Default::default();
+        let [alice_queue, bob_queue] = [&alice, &bob].map(|user| {
+            let (outbound, queue) = Outbound::new(CHANNEL_SIZE);
+            connected_users.claim_nick(user, outbound).unwrap();
+            queue
+        });
+        connected_users.channels().join(&alice, "#rust");
+        connected_users.channels().join(&bob, "#rust");
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        let broadcaster = {
+            let users = connected_users.clone();
+            thread::spawn(move || broadcast(users, rx, &Default::default(), &Default::default()))
+        };
+        let input = Cursor::new("/nick bob\n/nick 9lives\n/nick alicia\nhi\n");
+        handle_chat(input, &mut alice, tx, &MemoryBudget::new(1024), &Default::default(), (&diagnostics(), &outbound()), &Options::default());
+        broadcaster.join().unwrap();
+
+        assert_eq!(User::new("alicia"), alice);
+        assert!(connected_users.channels().hears(&alice, Some("#rust")));
+        let renamed = ServerMessage::renamed(&User::new("alice"), &alice);
+        let alice_got = messages(&alice_queue);
+        assert_eq!(3, alice_got.len());
+        assert_eq!(notice("A user is already connected with that name: `bob`"), alice_got[0]);
+        assert_eq!(renamed, alice_got[2]);
+        let bob_got = messages(&bob_queue);
+        assert_eq!(renamed, bob_got[0]);
+        assert!(matches!(&bob_got[1], ServerMessage::Chat { from, .. } if *from == alice));
+    }
+
+    #[test]
+    fn private_messages() {
+        let (alice, bob, carol) = (User::new("alice"), User::new("bob"), User::new("carol"));
+        let connected_users: SharedRegistry = Default::default();
+        // Carol's only got room for one
+        let queues = [(&alice, CHANNEL_SIZE), (&bob, CHANNEL_SIZE), (&carol, 1)].map(|(user, queue_len)| {
+            let (outbound, queue) = Outbound::new(queue_len);
+            connected_users.claim_nick(user, outbound).unwrap();
+            queue
+        });
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        for line in ["/msg bob just between us", "/msg nobody hello?", "/msg carol one", "/msg carol two"] {
+            tx.send(ChatLine::new(alice.clone(), line)).unwrap();
+        }
+        drop(tx);
+        broadcast(connected_users.clone(), rx, &Default::default(), &Default::default());
+
+        // Without saying what's happening to carol for not keeping up
+        assert_eq!(vec![notice("No such nick: nobody"), notice("Couldn't deliver that to carol")], messages(&queues[0]));
+        let private = ServerMessage::Private { from: alice.clone(), to: bob, text: "just between us".to_string() };
+        assert_eq!(vec![private], messages(&queues[1]));
+        let private = ServerMessage::Private { from: alice, to: carol, text: "one".to_string() };
+        assert_eq!(vec![private], messages(&queues[2]));
+    }
+
+    #[test]
+    fn maintenance_turns_new_connections_away() {
+        let (oper, bob) = (User::new("oper"), User::new("bob"));
+        let roles = Roles { operators: [oper.clone()].into(), password: None };
+        let maintenance = Maintenance::new("back soon, {user}");
+        let info = ServerInfo::default();
+
+        let connected_users: SharedRegistry = Default::default();
+        let (oper_outbound, queue) = Outbound::new(CHANNEL_SIZE);
+        oper_outbound.oper();
+        connected_users.claim_nick(&oper, oper_outbound).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel::<ChatLine>(CHANNEL_SIZE);
+        tx.send(ChatLine::new(oper.clone(), "/maintenance on")).unwrap();
+        drop(tx);
+        let gates = (&roles, &maintenance, &info, None, &Default::default());
+        broadcast_messages(connected_users.clone(), rx, &Default::default(), None, &Default::default(), gates);
+        assert_eq!(vec![notice("*** Maintenance: back soon, oper")], messages(&queue));
+
+        let (gates, options) = ((&maintenance, &info, &Default::default()), Options::default());
+        let input = Cursor::new(hello(&bob));
+        let mut output = Cursor::new(Vec::new());
+        let res = do_auth_flow(&mut Framed::new(input), &mut output, &connected_users, outbound(), None, gates, &options);
+        assert!(matches!(res, Err(ServerError::Maintenance(message)) if message == "back soon, bob"));
+        let refusal = framed(&serde_json::to_vec(&AuthResponse::Error("back soon, bob".to_string())).unwrap());
+        assert_eq!(&[accepted(), refusal].concat(), &unstamped(output.get_ref()));
+
+        maintenance.end();
+        let input = Cursor::new(hello(&bob));
+        let res = do_auth_flow(&mut Framed::new(input), &mut Cursor::new(Vec::new()), &connected_users, outbound(), None, gates, &options);
+        assert!(res.is_ok());
+    }
+}
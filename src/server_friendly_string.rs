@@ -1,4 +1,5 @@
 use std::fmt::{Display, Formatter};
+use unicode_segmentation::UnicodeSegmentation;
 
 /// A String that's guaranteed to end with a line feed (LF, '\n', 0xA)
 /// and trims whitespace from the end of the string
@@ -7,13 +8,33 @@ use std::fmt::{Display, Formatter};
 /// The various methods on it (`len` etc.) are meant to remove the line feed from its calculations,
 /// e.g. `ServerFriendlyString.len()` returns the length of the String _without_ the line feed.
 /// If one wants the String _with_ a line feed, access the underlying data with `self.0`.
+///
+/// Lengths are in grapheme clusters, i.e. characters as people see them, not bytes. Otherwise CJK text
+/// counts triple and an emoji with a skin tone counts for 8.
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
 pub struct ServerFriendlyString(pub String);
 
 impl ServerFriendlyString {
-    /// Returns the length of the String without the line feed character.
+    /// Returns the length of the String without the line feed character, in grapheme clusters.
     pub fn len(&self) -> usize {
-        self.0.len() - 1
+        self.text().graphemes(true).count()
+    }
+
+    /// Cuts the String down to at most `max` grapheme clusters without splitting any of them.
+    /// Returns whether anything was cut.
+    pub fn truncate(&mut self, max: usize) -> bool {
+        let Some((end, _)) = self.text().grapheme_indices(true).nth(max) else {
+            return false;
+        };
+
+        let end = self.0[..end].trim_end().len();
+        self.0.truncate(end);
+        self.0.push('\n');
+        true
+    }
+
+    fn text(&self) -> &str {
+        &self.0[..self.0.len() - 1]
     }
 }
 
@@ -27,7 +48,7 @@ impl<T: Into<String>> From<T> for ServerFriendlyString {
 
 impl Display for ServerFriendlyString {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", &self.0[..self.len()])
+        write!(f, "{}", self.text())
     }
 }
 
@@ -65,4 +86,25 @@ mod tests {
         assert_eq!(input.len() - 3, sfs.len());
         assert_eq!("hello world", format!("{sfs}"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_server_friendly_string_counts_graphemes() {
+        assert_eq!(5, ServerFriendlyString::from("こんにちは").len());
+        // Family emoji is 7 code points, "é" as e + combining acute is 2
+        assert_eq!(3, ServerFriendlyString::from("👨‍👩‍👧‍👦e\u{301}!").len());
+    }
+
+    #[test]
+    fn test_server_friendly_string_truncate() {
+        let mut sfs = ServerFriendlyString::from("👍🏽👍🏽👍🏽");
+        assert!(sfs.truncate(2));
+        assert_eq!("👍🏽👍🏽\n", sfs.0);
+
+        let mut sfs = ServerFriendlyString::from("hi there");
+        assert!(sfs.truncate(3));
+        assert_eq!("hi", format!("{sfs}"));
+
+        assert!(!sfs.truncate(2));
+        assert_eq!("hi\n", sfs.0);
+    }
+}
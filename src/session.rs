@@ -0,0 +1,102 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::away::AwayLog;
+use crate::backfill::Seen;
+use crate::transcript::Transcript;
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Couldn't read/write session {0}: `{1}`")]
+    IO(String, std::io::Error),
+    #[error("Session {0} is corrupt: `{1}`")]
+    Json(String, serde_json::Error),
+}
+
+/// What the client remembers between runs: what it's shown, what's waiting in `/awaylog`, the channels it's
+/// in so it can join them again, and for a bouncer, what it's been sent so far.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    /// When it was last saved, `None` for a brand new session.
+    pub(crate) saved_at: Option<SystemTime>,
+    /// Oldest joined first, so joining them in order leaves the client talking where it was.
+    pub(crate) channels: Vec<String>,
+    pub(crate) transcript: Transcript,
+    pub(crate) away: AwayLog,
+    /// Where it got up to in each buffer, if it was attached to a bouncer.
+    #[serde(default)]
+    pub(crate) seen: Seen,
+}
+
+impl Session {
+    /// The session saved at `path`, or a new one if nothing's been saved there yet.
+    pub fn load(path: &Path) -> Result<Self, SessionError> {
+        let name = || path.display().to_string();
+        match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| SessionError::Json(name(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(SessionError::IO(name(), e)),
+        }
+    }
+
+    /// Writes the session next to `path` first and then moves it over, so dying halfway through a save
+    /// doesn't lose the last one.
+    pub fn save(&mut self, path: &Path) -> Result<(), SessionError> {
+        let name = || path.display().to_string();
+        self.saved_at = Some(SystemTime::now());
+        let json = serde_json::to_vec(self).map_err(|e| SessionError::Json(name(), e))?;
+
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, json).map_err(|e| SessionError::IO(name(), e))?;
+        std::fs::rename(&partial, path).map_err(|e| SessionError::IO(name(), e))
+    }
+
+    /// Notes joining `channel`, which moves it to the end if it's already been joined.
+    pub(crate) fn joined(&mut self, channel: &str) {
+        self.parted(channel);
+        self.channels.push(channel.to_string());
+    }
+
+    pub(crate) fn parted(&mut self, channel: &str) {
+        self.channels.retain(|c| c != channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::user::User;
+    use super::*;
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!("basic-irc-session-{}.json", std::process::id()));
+        assert!(Session::load(&path).unwrap().saved_at.is_none());
+
+        let mut session = Session::default();
+        session.joined("#rust");
+        session.joined("#general");
+        session.joined("#rust");
+        session.parted("#nope");
+        session.transcript.chat(&User::new("alice"), "hi");
+        session.away.away("lunch");
+        session.save(&path).unwrap();
+
+        let loaded = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.saved_at.is_some());
+        assert_eq!(vec!["#general", "#rust"], loaded.channels);
+        assert_eq!(1, loaded.transcript.find("<alice> hi").len());
+        assert_eq!(Some("You're still away (lunch)".to_string()), loaded.away.status());
+    }
+
+    #[test]
+    fn corrupt_sessions() {
+        let path = std::env::temp_dir().join(format!("basic-irc-session-corrupt-{}.json", std::process::id()));
+        std::fs::write(&path, "{ not json").unwrap();
+        let loaded = Session::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(SessionError::Json(..))));
+    }
+}
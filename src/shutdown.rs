@@ -0,0 +1,159 @@
+//! Stopping the server without leaving anyone hanging. SIGINT or SIGTERM stops the acceptors, or wakes up the mio
+//! or tokio loop, and the server then says goodbye to everyone and waits a little while for it to go out before
+//! returning. A second signal doesn't wait for any of that.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use socket2::SockRef;
+use tracing::{info, warn};
+use crate::transport::Listener;
+
+/// How long to wait between checking on the writers.
+const POLL: Duration = Duration::from_millis(20);
+
+/// Whether the server's shutting down, with what it needs to wake the acceptors up and see writers finish.
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    stopping: AtomicBool,
+    /// Clones of the listeners the acceptors are waiting on.
+    listeners: Mutex<Vec<Listener>>,
+    /// Every connection's writer that might still have something to send.
+    writers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Shutdown {
+    /// Keeps a way to wake up whoever accepts from `listener` once it's time to stop.
+    pub(crate) fn watch(&self, listener: &Listener) -> std::io::Result<()> {
+        self.listeners.lock().push(listener.try_clone()?);
+        Ok(())
+    }
+
+    pub(crate) fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Stops taking connections, waking up the acceptors so they see that.
+    pub(crate) fn begin(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        for listener in self.listeners.lock().drain(..) {
+            wake(&listener);
+        }
+    }
+
+    /// Keeps track of a connection's `writer`, so shutting down can wait for it to get everything out.
+    pub(crate) fn writing(&self, writer: JoinHandle<()>) {
+        let mut writers = self.writers.lock();
+        writers.retain(|writer| !writer.is_finished());
+        writers.push(writer);
+    }
+
+    /// Waits until every writer's done or it's `deadline`, returning how many still weren't.
+    pub(crate) fn wait_for_writers(&self, deadline: Instant) -> usize {
+        loop {
+            let mut writers = self.writers.lock();
+            writers.retain(|writer| !writer.is_finished());
+            if writers.is_empty() || Instant::now() >= deadline {
+                return writers.len();
+            }
+            drop(writers);
+            thread::sleep(POLL);
+        }
+    }
+}
+
+/// Wakes up whoever's waiting in `accept` on `listener`. Shutting it down does it on Linux, connecting to it
+/// does it everywhere else.
+fn wake(listener: &Listener) {
+    match listener {
+        Listener::Tcp(listener) => {
+            let _ = SockRef::from(listener).shutdown(std::net::Shutdown::Read);
+            if let Ok(addr) = listener.local_addr() {
+                let _ = TcpStream::connect_timeout(&reachable(addr), Duration::from_secs(1));
+            }
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener) => {
+            let _ = SockRef::from(listener).shutdown(std::net::Shutdown::Read);
+            if let Some(path) = listener.local_addr().ok().as_ref().and_then(|addr| addr.as_pathname()) {
+                let _ = std::os::unix::net::UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+/// Where to connect to reach a listener on `addr`, which might be listening on every address.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
+
+/// Starts a thread that begins shutting down on SIGINT or SIGTERM, and exits right away on a second one.
+#[cfg(unix)]
+pub(crate) fn on_signals(shutdown: Arc<Shutdown>) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+    thread::Builder::new().name("signals".to_string()).spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Got signal {signal}, shutting down");
+            shutdown.begin();
+        }
+        if signals.next().is_some() {
+            warn!("Got another signal, not waiting for connections to finish");
+            std::process::exit(1);
+        }
+    })?;
+    Ok(())
+}
+
+/// Without Unix signals, Ctrl-C just ends the process like it always did.
+#[cfg(not(unix))]
+pub(crate) fn on_signals(_: Arc<Shutdown>) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wakes_up(listener: Listener) {
+        let shutdown = Arc::new(Shutdown::default());
+        shutdown.watch(&listener).unwrap();
+
+        let watching = shutdown.clone();
+        let accepted = move || std::iter::from_fn(|| Some(listener.accept())).take_while(|_| !watching.stopping()).count();
+        let acceptor = thread::spawn(accepted);
+        thread::sleep(Duration::from_millis(50));
+        shutdown.begin();
+        assert_eq!(0, acceptor.join().unwrap());
+    }
+
+    #[test]
+    fn wakes_up_acceptors() {
+        wakes_up(std::net::TcpListener::bind("127.0.0.1:0").unwrap().into());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wakes_up_unix_acceptors() {
+        let path = std::env::temp_dir().join(format!("basic-irc-shutdown-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        wakes_up(Listener::Unix(std::os::unix::net::UnixListener::bind(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn waits_for_writers() {
+        let shutdown = Shutdown::default();
+        shutdown.writing(thread::spawn(|| thread::sleep(Duration::from_millis(50))));
+        shutdown.writing(thread::spawn(|| thread::sleep(Duration::from_secs(5))));
+        assert_eq!(1, shutdown.wait_for_writers(Instant::now() + Duration::from_millis(500)));
+    }
+}
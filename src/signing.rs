@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use ed25519_dalek::{SigningKey, VerifyingKey, SECRET_KEY_LENGTH};
+use thiserror::Error;
+use tracing::info;
+use crate::secret::{self, SecretError};
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Failed reading/writing the key file: `{0}`")]
+    IO(#[from] std::io::Error),
+    #[error("Couldn't get randomness for a new key: `{0}`")]
+    Random(getrandom::Error),
+    #[error("Key isn't {0} bytes of hex")]
+    Malformed(usize),
+    #[error("Not a valid ed25519 public key: `{0}`")]
+    Invalid(#[from] ed25519_dalek::SignatureError),
+    #[error("{0}")]
+    Secret(#[from] SecretError),
+}
+
+/// Which node's messages each key vouches for.
+pub type TrustedKeys = BTreeMap<String, VerifyingKey>;
+
+pub fn generate() -> Result<SigningKey, KeyError> {
+    let mut seed = [0; SECRET_KEY_LENGTH];
+    getrandom::getrandom(&mut seed).map_err(KeyError::Random)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Reads the hex-encoded key at `path`, making a new one there first if there isn't one yet. It's a private
+/// key, so it's refused if anyone can read it, and made so nobody else can.
+pub fn load_or_create(path: &Path) -> Result<SigningKey, KeyError> {
+    match secret::read(path) {
+        Ok(hex) => Ok(SigningKey::from_bytes(&decode(hex.trim())?)),
+        Err(SecretError::Read(_, e)) if e.kind() == ErrorKind::NotFound => {
+            let key = generate()?;
+            secret::create(path, hex::encode(key.to_bytes()).as_bytes())?;
+            info!("[CLUSTER] Made a new node key at {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn encode_public(key: &VerifyingKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+/// Parses `node=<hex public key>`, for `--trust`.
+pub fn parse_trusted(value: &str) -> Result<(String, VerifyingKey), String> {
+    let (node, hex) = value.split_once('=').ok_or("Expected node=<hex public key>")?;
+    let key = decode(hex).and_then(|bytes| Ok(VerifyingKey::from_bytes(&bytes)?)).map_err(|e| e.to_string())?;
+    Ok((node.to_string(), key))
+}
+
+fn decode(hex: &str) -> Result<[u8; 32], KeyError> {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(hex, &mut bytes).map_err(|_| KeyError::Malformed(bytes.len()))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+
+    #[test]
+    fn key_file_round_trips() {
+        let path = std::env::temp_dir().join(format!("basic-irc-key-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let made = load_or_create(&path).unwrap();
+        let loaded = load_or_create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(made.to_bytes(), loaded.to_bytes());
+    }
+
+    #[test]
+    fn trusted_keys_parse() {
+        let key = generate().unwrap().verifying_key();
+        let parsed = parse_trusted(&format!("two={}", encode_public(&key))).unwrap();
+        assert_eq!(("two".to_string(), key), parsed);
+
+        assert!(parse_trusted("two").is_err());
+        assert!(parse_trusted("two=abcd").is_err());
+    }
+}
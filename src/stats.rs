@@ -0,0 +1,108 @@
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::metrics::{Histogram, LATENCY_BUCKETS};
+use crate::response::ServerMessage;
+
+/// What the client keeps count of with `--stats`, for telling a flaky network from a quiet one. Shown with
+/// `/stats` and when the client exits.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    sent: u64,
+    bytes_sent: u64,
+    received: u64,
+    /// From the server reading a message to it getting here. Only means much if both clocks are right.
+    latency: Histogram,
+    /// Messages stamped later than they got here, so left out of `latency`.
+    skewed: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            sent: 0,
+            bytes_sent: 0,
+            received: 0,
+            latency: Histogram::new(LATENCY_BUCKETS),
+            skewed: 0,
+        }
+    }
+}
+
+impl Stats {
+    pub fn sent(&mut self, line: &str) {
+        self.sent += 1;
+        self.bytes_sent += line.len() as u64;
+    }
+
+    /// Counts `msg`, which got here at `now`. Anything through a bouncer is left out of the latency, since
+    /// what it replays was read upstream however long ago.
+    pub fn received(&mut self, msg: &ServerMessage, now: SystemTime) {
+        self.received += 1;
+        let ServerMessage::Chat { received_ms, .. } = msg else {
+            return;
+        };
+        if *received_ms == 0 {
+            return;
+        }
+
+        let sent_at = UNIX_EPOCH + Duration::from_millis(*received_ms);
+        match now.duration_since(sent_at) {
+            Ok(took) => self.latency.observe(took),
+            Err(_) => self.skewed += 1,
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Stats for the last {:.1?}:", self.started.elapsed())?;
+        writeln!(f, "  Sent {} lines ({} bytes)", self.sent, self.bytes_sent)?;
+        write!(f, "  Received {} messages", self.received)?;
+
+        // The quantiles are bucket bounds, so they're "at most"
+        let show = |d: Option<Duration>| d.map_or(">10s".to_string(), |d| format!("<={d:?}"));
+        if let Some(mean) = self.latency.mean() {
+            let mean = Duration::from_micros((mean.as_secs_f64() * 1e6).round() as u64);
+            let (p50, p99) = (show(self.latency.quantile(0.5)), show(self.latency.quantile(0.99)));
+            write!(f, "\n  Latency from the server: avg {mean:?}, p50 {p50}, p99 {p99} over {} messages", self.latency.count())?;
+        }
+        if self.skewed > 0 {
+            write!(f, "\n  {} messages were stamped in the future, is this machine's clock behind?", self.skewed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::user::User;
+    use super::*;
+
+    fn chat(received_ms: u64) -> ServerMessage {
+        ServerMessage::Chat { from: User::new("alice"), text: "hi".to_string(), received_ms, channel: None }
+    }
+
+    #[test]
+    fn counts_and_latency() {
+        let mut stats = Stats::default();
+        assert!(!stats.to_string().contains("Latency"));
+
+        stats.sent("hello\n");
+        stats.sent("/join #rust\n");
+        let now = UNIX_EPOCH + Duration::from_millis(10_000);
+        for received_ms in [9_990, 9_970, 0, 10_500] {
+            stats.received(&chat(received_ms), now);
+        }
+        stats.received(&ServerMessage::notice("hi"), now);
+        let replayed = ServerMessage::Sequenced { buffer: "&lobby".to_string(), seq: 1, message: Box::new(chat(1_000)) };
+        stats.received(&replayed, now);
+
+        let shown = stats.to_string();
+        assert!(shown.contains("Sent 2 lines (18 bytes)"), "{shown}");
+        assert!(shown.contains("Received 6 messages"), "{shown}");
+        assert!(shown.contains("avg 20ms, p50 <=10ms, p99 <=50ms over 2 messages"), "{shown}");
+        assert!(shown.contains("1 messages were stamped in the future"), "{shown}");
+    }
+}
@@ -0,0 +1,181 @@
+//! Registered nicks, kept in SQLite so they survive restarts. Anyone can `/register` the nick they're on with
+//! a password, and from then on connecting as it needs that password too. Only the argon2 hash is stored.
+//! Built without the `accounts` feature, there's no database and no way to open one.
+
+use std::path::Path;
+#[cfg(feature = "accounts")]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "accounts")]
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+#[cfg(feature = "accounts")]
+use argon2::password_hash::SaltString;
+#[cfg(feature = "accounts")]
+use parking_lot::Mutex;
+#[cfg(feature = "accounts")]
+use rusqlite::{Connection, OptionalExtension, params};
+use thiserror::Error;
+
+// Nicks are ASCII, so ignoring ASCII case is enough to stop anyone taking `Alice` while `alice` is away
+#[cfg(feature = "accounts")]
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS accounts (
+    nick TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+    hash TEXT NOT NULL,
+    registered_ms INTEGER NOT NULL
+)";
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[cfg(feature = "accounts")]
+    #[error("Database trouble: `{0}`")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(not(feature = "accounts"))]
+    #[error("This was built without the accounts feature, so there's no database to open")]
+    NotBuilt,
+    #[error("Couldn't hash the password: `{0}`")]
+    Hash(String),
+    #[error("{0} is already registered")]
+    Taken(String),
+}
+
+#[cfg(feature = "accounts")]
+impl From<argon2::password_hash::Error> for StorageError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        StorageError::Hash(e.to_string())
+    }
+}
+
+/// The accounts database. Hashing is slow on purpose, so it's never done holding the connection.
+#[cfg(feature = "accounts")]
+#[derive(Debug)]
+pub struct Accounts {
+    db: Mutex<Connection>,
+}
+
+#[cfg(feature = "accounts")]
+impl Accounts {
+    /// Opens the database at `path`, creating it if it isn't there.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        Self::with(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Result<Self, StorageError> {
+        Self::with(Connection::open_in_memory()?)
+    }
+
+    fn with(db: Connection) -> Result<Self, StorageError> {
+        db.execute(SCHEMA, [])?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// How many nicks are registered.
+    pub fn count(&self) -> Result<u64, StorageError> {
+        Ok(self.db.lock().query_row("SELECT COUNT(*) FROM accounts", [], |row| row.get(0))?)
+    }
+
+    pub fn is_registered(&self, nick: &str) -> Result<bool, StorageError> {
+        Ok(self.hash_of(nick)?.is_some())
+    }
+
+    /// Registers `nick` with `password`, unless someone got there first.
+    pub fn register(&self, nick: &str, password: &str) -> Result<(), StorageError> {
+        // Not worth hashing for, but the insert below is what actually decides it
+        if self.is_registered(nick)? {
+            return Err(StorageError::Taken(nick.to_string()));
+        }
+
+        let mut salt = [0; 16];
+        getrandom::getrandom(&mut salt).map_err(|e| StorageError::Hash(e.to_string()))?;
+        let salt = SaltString::encode_b64(&salt)?;
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string();
+
+        let registered_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+        let inserted = self.db.lock().execute(
+            "INSERT OR IGNORE INTO accounts (nick, hash, registered_ms) VALUES (?1, ?2, ?3)",
+            params![nick, hash, registered_ms],
+        )?;
+        match inserted {
+            0 => Err(StorageError::Taken(nick.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `password` gets someone in as `nick`. Anyone gets in as a nick that isn't registered.
+    pub fn lets_in(&self, nick: &str, password: Option<&str>) -> Result<bool, StorageError> {
+        let Some(hash) = self.hash_of(nick)? else {
+            return Ok(true);
+        };
+        let Some(password) = password else {
+            return Ok(false);
+        };
+        Ok(Argon2::default().verify_password(password.as_bytes(), &PasswordHash::new(&hash)?).is_ok())
+    }
+
+    fn hash_of(&self, nick: &str) -> Result<Option<String>, StorageError> {
+        let db = self.db.lock();
+        Ok(db.query_row("SELECT hash FROM accounts WHERE nick = ?1", [nick], |row| row.get(0)).optional()?)
+    }
+}
+
+/// Without the `accounts` feature there's never a database, so nothing's ever registered.
+#[cfg(not(feature = "accounts"))]
+#[derive(Debug)]
+pub enum Accounts {}
+
+#[cfg(not(feature = "accounts"))]
+impl Accounts {
+    pub fn open(_: &Path) -> Result<Self, StorageError> {
+        Err(StorageError::NotBuilt)
+    }
+
+    pub fn count(&self) -> Result<u64, StorageError> {
+        match *self {}
+    }
+
+    pub fn is_registered(&self, _: &str) -> Result<bool, StorageError> {
+        match *self {}
+    }
+
+    pub fn register(&self, _: &str, _: &str) -> Result<(), StorageError> {
+        match *self {}
+    }
+
+    pub fn lets_in(&self, _: &str, _: Option<&str>) -> Result<bool, StorageError> {
+        match *self {}
+    }
+}
+
+#[cfg(all(test, feature = "accounts"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_nicks_need_their_password() {
+        let accounts = Accounts::in_memory().unwrap();
+        assert!(accounts.lets_in("alice", None).unwrap());
+
+        accounts.register("alice", "hunter2").unwrap();
+        assert!(matches!(accounts.register("alice", "again"), Err(StorageError::Taken(_))));
+        assert_eq!(1, accounts.count().unwrap());
+        assert!(accounts.lets_in("alice", Some("hunter2")).unwrap());
+        assert!(!accounts.lets_in("alice", Some("hunter3")).unwrap());
+        assert!(!accounts.lets_in("alice", None).unwrap());
+        assert!(!accounts.lets_in("ALICE", Some("hunter3")).unwrap());
+        assert!(matches!(accounts.register("Alice", "again"), Err(StorageError::Taken(_))));
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("basic-irc-accounts-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        Accounts::open(&path).unwrap().register("bob", "sesame").unwrap();
+        let reopened = Accounts::open(&path).unwrap();
+        assert!(reopened.is_registered("bob").unwrap());
+        assert!(reopened.lets_in("bob", Some("sesame")).unwrap());
+        assert!(!reopened.lets_in("bob", Some("open")).unwrap());
+
+        drop(reopened);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+type TransactionId = [u8; 12];
+
+#[derive(Error, Debug)]
+pub enum StunError {
+    #[error("Failed talking to the STUN server: `{0}`")]
+    IO(#[from] std::io::Error),
+    #[error("STUN server `{0}` didn't resolve to anything")]
+    Unresolved(String),
+    #[error("STUN server sent back something that isn't an answer to our request")]
+    BadResponse,
+    #[error("STUN server didn't say what our address is")]
+    NoAddress,
+}
+
+/// Asks a STUN server (`host:port`) what address our packets show up from, i.e. our public IP if we're
+/// behind a NAT. Note the port is whatever the NAT picked for this UDP socket, not anything TCP would get.
+pub fn public_address(server: &str, timeout: Duration) -> Result<SocketAddr, StunError> {
+    let server_addr = server
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| StunError::Unresolved(server.to_string()))?;
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server_addr)?;
+
+    let transaction = transaction_id();
+    socket.send(&binding_request(&transaction))?;
+
+    let mut response = [0; 512];
+    let n = socket.recv(&mut response)?;
+    parse_binding_response(&response[..n], &transaction)
+}
+
+/// Doesn't need to be cryptographically random, just unlikely to collide with anyone else's.
+fn transaction_id() -> TransactionId {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    let (high, low) = (hasher.finish(), RandomState::new().build_hasher().finish());
+
+    let mut id = [0; 12];
+    id[..8].copy_from_slice(&high.to_be_bytes());
+    id[8..].copy_from_slice(&low.to_be_bytes()[..4]);
+    id
+}
+
+fn binding_request(transaction: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut request = [0; HEADER_LEN];
+    request[..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // Bytes 2..4 are the attribute length, and there aren't any
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(transaction);
+    request
+}
+
+fn parse_binding_response(response: &[u8], transaction: &TransactionId) -> Result<SocketAddr, StunError> {
+    if response.len() < HEADER_LEN
+        || response[..2] != BINDING_RESPONSE.to_be_bytes()
+        || response[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || &response[8..HEADER_LEN] != transaction
+    {
+        return Err(StunError::BadResponse);
+    }
+
+    let len = u16::from_be_bytes([response[2], response[3]]) as usize;
+    let mut attributes = response.get(HEADER_LEN..HEADER_LEN + len).ok_or(StunError::BadResponse)?;
+
+    // Old servers only send the plain MAPPED-ADDRESS, so fall back to it
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len).ok_or(StunError::BadResponse)?;
+
+        match kind {
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+
+        // Attributes are padded out to a multiple of 4 bytes
+        let padded = (4 + len).next_multiple_of(4);
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+
+    mapped.ok_or(StunError::NoAddress)
+}
+
+/// Reads a (XOR-)MAPPED-ADDRESS value. XORed ones come with the transaction they were XORed with.
+fn parse_address(value: &[u8], xored_with: Option<&TransactionId>) -> Result<SocketAddr, StunError> {
+    if value.len() < 4 {
+        return Err(StunError::BadResponse);
+    }
+
+    let mut mask = [0; 16];
+    if let Some(transaction) = xored_with {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+
+    let port = u16::from_be_bytes([value[2] ^ mask[0], value[3] ^ mask[1]]);
+    let address = &value[4..];
+    let ip = match (value[1], address.len()) {
+        (0x01, 4) => {
+            let octets: [u8; 4] = std::array::from_fn(|i| address[i] ^ mask[i]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (0x02, 16) => {
+            let octets: [u8; 16] = std::array::from_fn(|i| address[i] ^ mask[i]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(StunError::BadResponse),
+    };
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION: TransactionId = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+
+    fn response(attributes: &[u8]) -> Vec<u8> {
+        let mut response = Vec::from(BINDING_RESPONSE.to_be_bytes());
+        response.extend((attributes.len() as u16).to_be_bytes());
+        response.extend(MAGIC_COOKIE.to_be_bytes());
+        response.extend(TRANSACTION);
+        response.extend(attributes);
+        response
+    }
+
+    #[test]
+    fn request_is_well_formed() {
+        let request = binding_request(&TRANSACTION);
+        assert_eq!([0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42], request[..8]);
+        assert_eq!(TRANSACTION, request[8..]);
+    }
+
+    #[test]
+    fn xor_mapped_ipv4() {
+        // 192.0.2.1:32853 from RFC 5769's sample IPv4 response
+        let attribute = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let address = parse_binding_response(&response(&attribute), &TRANSACTION).unwrap();
+        assert_eq!("192.0.2.1:32853".parse::<SocketAddr>().unwrap(), address);
+    }
+
+    #[test]
+    fn plain_mapped_address_fallback() {
+        // Some other attribute with padding first, then the plain address
+        let mut attributes = vec![0x80, 0x22, 0x00, 0x03, b'h', b'i', b'!', 0x00];
+        attributes.extend([0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x1a, 0x0b, 203, 0, 113, 7]);
+
+        let address = parse_binding_response(&response(&attributes), &TRANSACTION).unwrap();
+        assert_eq!("203.0.113.7:6667".parse::<SocketAddr>().unwrap(), address);
+    }
+
+    #[test]
+    fn wrong_transaction_is_rejected() {
+        let attribute = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        let other = [0; 12];
+        assert!(matches!(parse_binding_response(&response(&attribute), &other), Err(StunError::BadResponse)));
+    }
+
+    #[test]
+    fn truncated_attribute_is_rejected() {
+        let attribute = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01];
+        assert!(matches!(parse_binding_response(&response(&attribute), &TRANSACTION), Err(StunError::BadResponse)));
+        assert!(matches!(parse_binding_response(&response(&[]), &TRANSACTION), Err(StunError::NoAddress)));
+    }
+}
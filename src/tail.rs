@@ -0,0 +1,230 @@
+//! Server logs, which go to stderr and to anyone following them with `tail` on the admin socket, along with
+//! live chat for anyone following that instead. Log with `tracing`'s `info!` and `warn!` rather than
+//! `eprintln!` so they show up there too, with whatever span they're in, like the connection's.
+
+use std::fmt::{self, Debug, Write as _};
+use std::io::{stderr, IsTerminal};
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use parking_lot::{const_mutex, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use crate::channel::LOBBY;
+use crate::response::ServerMessage;
+
+/// Lines that may wait for someone following before newer ones get dropped for them.
+const FOLLOW_QUEUE_LEN: usize = 1024;
+
+/// Who's following what, dropped once they stop.
+static FOLLOWING: Mutex<Vec<(Follow, SyncSender<String>)>> = const_mutex(Vec::new());
+
+/// How logs are written to stderr.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object a line, for shipping them off to a collector.
+    Json,
+}
+
+/// How much a log line matters to anyone tailing. Anything less than info never gets to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info,
+    /// Something went wrong or got dropped.
+    Warn,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            _ => Err(format!("No such level `{s}`, it's info or warn")),
+        }
+    }
+}
+
+/// What someone's tailing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Follow {
+    /// Chat said in `channel`, or anywhere if `None`.
+    Chat { channel: Option<String> },
+    /// Log lines at this level or above.
+    Logs(Level),
+}
+
+impl Follow {
+    fn wants_chat(&self, said_in: &str) -> bool {
+        matches!(self, Follow::Chat { channel } if channel.as_deref().is_none_or(|c| c == said_in))
+    }
+
+    fn wants_log(&self, level: Level) -> bool {
+        matches!(self, Follow::Logs(at_least) if level >= *at_least)
+    }
+}
+
+/// Starts following `what`, until the receiver's dropped.
+pub fn follow(what: Follow) -> Receiver<String> {
+    let (sender, receiver) = sync_channel(FOLLOW_QUEUE_LEN);
+    FOLLOWING.lock().push((what, sender));
+    receiver
+}
+
+/// Logs everything at `level` or above to stderr as `format` from now on, and everything at info or above to
+/// whoever's following logs.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let logs = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(stderr).with_ansi(stderr().is_terminal()).boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_writer(stderr).boxed(),
+    };
+    tracing_subscriber::registry().with(logs.with_filter(level)).with(Tail.with_filter(LevelFilter::INFO)).init();
+}
+
+/// Passes `message` on to whoever's following chat where it was said, if it's chat.
+pub fn chat(message: &ServerMessage) {
+    let ServerMessage::Chat { from, text, channel, .. } = message else {
+        return;
+    };
+    let said_in = channel.as_deref().unwrap_or(LOBBY);
+    send(|what| what.wants_chat(said_in), || format!("[{said_in}] <{from}> {text}"));
+}
+
+/// Sends the line from `line`, made only if someone wants it, to everyone `wanted` says yes to. Anyone too far
+/// behind misses it, and anyone who's stopped following is forgotten.
+fn send(wanted: impl Fn(&Follow) -> bool, line: impl FnOnce() -> String) {
+    let mut following = FOLLOWING.lock();
+    if !following.iter().any(|(what, _)| wanted(what)) {
+        return;
+    }
+
+    let line = line();
+    following.retain(|(what, sender)| {
+        !wanted(what) || !matches!(sender.try_send(line.clone()), Err(TrySendError::Disconnected(_)))
+    });
+}
+
+/// Passes logs on to whoever's following them, as plain lines like `conn{peer=10.0.0.7 nick=alice}: hello`.
+pub struct Tail;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Tail {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let level = match *event.metadata().level() {
+            tracing::Level::ERROR | tracing::Level::WARN => Level::Warn,
+            tracing::Level::INFO => Level::Info,
+            _ => return,
+        };
+        send(|what| what.wants_log(level), || {
+            let mut line = String::new();
+            for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+                let extensions = span.extensions();
+                let fields = extensions.get::<Fields>().map(|fields| fields.to_string()).unwrap_or_default();
+                let _ = write!(line, "{}{{{}}}: ", span.name(), fields.trim_start());
+            }
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            line + &fields.message + &fields.to_string()
+        });
+    }
+}
+
+/// What a span or event was given, as text. Fields given again replace what they were.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    others: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, format!("{value:?}"));
+    }
+}
+
+impl Fields {
+    fn record(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else if let Some((_, old)) = self.others.iter_mut().find(|(name, _)| *name == field.name()) {
+            *old = value;
+        } else {
+            self.others.push((field.name(), value));
+        }
+    }
+}
+
+/// Everything but the message, each with a space before it.
+impl fmt::Display for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.others.iter().try_for_each(|(name, value)| write!(f, " {name}={value}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{debug, info, info_span, warn};
+    use crate::user::User;
+    use super::*;
+
+    fn said(channel: Option<&str>, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new("alice"), text: text.to_string(), received_ms: 1, channel: channel.map(str::to_string) }
+    }
+
+    #[test]
+    fn follows_what_was_asked_for() {
+        let _logging = tracing::subscriber::set_default(tracing_subscriber::registry().with(Tail));
+        // Other tests log and follow warnings too, so only lines from this one count
+        let mine = |receiver: &Receiver<String>| receiver.try_iter().filter(|l| l.contains("tail test")).collect::<Vec<_>>();
+        let everywhere = follow(Follow::Chat { channel: None });
+        let rust = follow(Follow::Chat { channel: Some("#rust".to_string()) });
+        let warnings = follow(Follow::Logs(Level::Warn));
+        let logs = follow(Follow::Logs(Level::Info));
+
+        chat(&said(None, "tail test 1"));
+        chat(&said(Some("#rust"), "tail test 2"));
+        info!("[TEST] tail test {}", 3);
+        warn!("[TEST] tail test {}", 4);
+        debug!("[TEST] tail test, too quiet to follow");
+        let span = info_span!("conn", peer = %"10.0.0.7", nick = tracing::field::Empty);
+        span.record("nick", "alice");
+        span.in_scope(|| info!(lines = 2, "[TEST] tail test {}", 5));
+
+        assert_eq!(vec!["[&lobby] <alice> tail test 1", "[#rust] <alice> tail test 2"], mine(&everywhere));
+        assert_eq!(vec!["[#rust] <alice> tail test 2"], mine(&rust));
+        assert_eq!(vec!["[TEST] tail test 4"], mine(&warnings));
+        let spanned = "conn{peer=10.0.0.7 nick=alice}: [TEST] tail test 5 lines=2";
+        assert_eq!(vec!["[TEST] tail test 3", "[TEST] tail test 4", spanned], mine(&logs));
+
+        drop(logs);
+        warn!("[TEST] tail test 6");
+        assert!(!FOLLOWING.lock().iter().any(|(what, _)| *what == Follow::Logs(Level::Info)));
+        assert_eq!(Ok(Level::Warn), "warn".parse());
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
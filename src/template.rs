@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+use crate::user::User;
+
+/// What templates get to say about the server they're sent from.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub started: Instant,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            name: "basic-irc".to_string(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl ServerInfo {
+    /// Fills in `{user}`, `{server_name}`, `{online_count}` and `{uptime}` in `template` as of right now.
+    pub fn render(&self, template: &str, user: &User, online_count: usize) -> String {
+        render(template, &[
+            ("user", user.name.clone()),
+            ("server_name", self.name.clone()),
+            ("online_count", online_count.to_string()),
+            ("uptime", format_uptime(self.started.elapsed())),
+        ])
+    }
+}
+
+/// Swaps every `{name}` in `template` for its value. Anything in braces that isn't a known name is left
+/// alone, so a typo shows up in the output instead of silently disappearing.
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after
+            .find('}')
+            .and_then(|close| vars.iter().find(|(name, _)| *name == &after[..close]).map(|(_, value)| (close, value)));
+
+        match value {
+            Some((close, value)) => {
+                rendered.push_str(value);
+                rest = &after[close + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Like `3d 4h 5m`, dropping anything smaller once it gets to days.
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, _) => format!("{mins}m {}s", secs % 60),
+        (0, _, _) => format!("{hours}h {mins}m"),
+        _ => format!("{days}d {hours}h {mins}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_known_placeholders() {
+        let vars = [("user", "alice".to_string()), ("online_count", "3".to_string())];
+        assert_eq!("Hi alice, 3 online", render("Hi {user}, {online_count} online", &vars));
+        assert_eq!("{nope} {user {alice}", render("{nope} {user {{user}}", &vars));
+        assert_eq!("", render("", &vars));
+    }
+
+    #[test]
+    fn server_info_fills_everything_in() {
+        let info = ServerInfo { name: "irc.example".to_string(), started: Instant::now() };
+        let rendered = info.render("{user}@{server_name} ({online_count}) up {uptime}", &User::new("bob"), 2);
+        assert_eq!("bob@irc.example (2) up 0s", rendered);
+    }
+
+    #[test]
+    fn uptime_formats() {
+        assert_eq!("59s", format_uptime(Duration::from_secs(59)));
+        assert_eq!("1m 5s", format_uptime(Duration::from_secs(65)));
+        assert_eq!("2h 0m", format_uptime(Duration::from_secs(7200)));
+        assert_eq!("1d 1h 1m", format_uptime(Duration::from_secs(90061)));
+    }
+}
@@ -0,0 +1,88 @@
+//! Streams and logins the tests all over the crate share.
+
+use std::io::{Cursor, Read, Write};
+use protocol::client::Connection;
+pub use protocol::testing::Trickle;
+use crate::frame::{encode_message, read_message, write_message, FrameLimits};
+use crate::response::{AuthResponse, Capabilities, Handshake, Login, PROTOCOL_VERSION};
+use crate::scuffed_clone::ScuffedClone;
+use crate::user::User;
+
+/// So it can stand in for a client's connection.
+impl ScuffedClone for Trickle {
+    fn scuffed_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// A stream with separate read and write sides, so writes don't clobber unread input like they would
+/// with a single `Cursor`. Clones get their own copy of both.
+#[derive(Debug, Clone, Default)]
+pub struct Duplex {
+    pub input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+}
+
+impl Duplex {
+    pub fn new(input: impl Into<Vec<u8>>) -> Self {
+        Self {
+            input: Cursor::new(input.into()),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ScuffedClone for Duplex {
+    fn scuffed_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+/// What a client opens with: the handshake, then `user`, with no password.
+pub fn hello(user: &User) -> Vec<u8> {
+    [encode_message(&Handshake::new(&[])).unwrap(), encode_message(user).unwrap()].concat()
+}
+
+/// What a server with no features answers a handshake with.
+pub fn accepted() -> Vec<u8> {
+    encode_message(&Capabilities::Accepted { version: PROTOCOL_VERSION, features: Vec::new(), time_ms: None }).unwrap()
+}
+
+/// Logs in as `user` over `stream` like a client would, returning how it went.
+// Only the other runtimes' tests need it
+#[cfg_attr(not(any(feature = "mio", feature = "tokio")), allow(dead_code))]
+pub fn log_in<S: Read + Write>(stream: &mut S, user: &User) -> AuthResponse {
+    write_message(stream, &Handshake::new(&[])).unwrap();
+    match read_message(stream, &FrameLimits::default()).unwrap() {
+        Capabilities::Accepted { .. } => {}
+        Capabilities::Rejected(reason) => return AuthResponse::Error(reason),
+    }
+    write_message(stream, user).unwrap();
+    read_message(stream, &FrameLimits::default()).unwrap()
+}
+
+/// A client's `Connection` that's already logged in as `user`.
+pub fn logged_in(user: &User) -> Connection {
+    let mut connection = Connection::new(&[]);
+    connection.receive(&accepted()).unwrap();
+    connection.log_in(&Login::new(user.clone(), None)).unwrap();
+    connection.receive(&encode_message(&AuthResponse::Success).unwrap()).unwrap();
+    connection.take_outgoing();
+    connection
+}
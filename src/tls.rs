@@ -0,0 +1,249 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use thiserror::Error;
+use crate::scuffed_clone::{HangUp, ScuffedClone};
+use crate::secret::{self, SecretError};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Couldn't read PEM file: `{0}`")]
+    Pem(#[from] rustls::pki_types::pem::Error),
+    #[error("No certificates in {0}")]
+    NoCerts(String),
+    #[error("Not a valid host name: `{0}`")]
+    Host(String),
+    #[error("TLS error: `{0}`")]
+    Rustls(#[from] rustls::Error),
+    #[error("{0}")]
+    Secret(#[from] SecretError),
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// What the server hands to clients: the certificate chain in `cert` and its private key in `key`, both PEM.
+/// The key has to be kept private.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = certs(cert)?;
+    secret::check_private(key)?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// What the client trusts: the CAs in `ca` (PEM) if given, the usual public ones if not.
+pub fn client_config(ca: Option<&Path>) -> Result<Arc<ClientConfig>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in certs(ca)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCerts(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// A TLS connection over a `TcpStream`, usable anywhere a plain `TcpStream` is. Clones share the one TLS
+/// session, so the reader and writer threads can both have one like they would a `TcpStream`.
+///
+/// Reads wait on the socket _without_ holding the session, so a blocked reader never holds up a writer.
+/// The handshake happens on the first read or write.
+#[derive(Debug)]
+pub struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    tcp: TcpStream,
+}
+
+impl TlsStream {
+    pub fn accept(tcp: TcpStream, config: Arc<ServerConfig>) -> Result<Self, TlsError> {
+        let conn = ServerConnection::new(config)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn.into())), tcp })
+    }
+
+    /// Connects as a client to the server at `host`, an IP or host name its certificate has to be for.
+    pub fn connect(tcp: TcpStream, config: Arc<ClientConfig>, host: &str) -> Result<Self, TlsError> {
+        let name = ServerName::try_from(host.to_string()).map_err(|_| TlsError::Host(host.to_string()))?;
+        let conn = ClientConnection::new(config, name)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn.into())), tcp })
+    }
+
+    /// Sends whatever TLS records the session has waiting, which could be a handshake message or an alert.
+    fn flush_tls(&self, conn: &mut Connection) -> std::io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut records = [0; 16 * 1024];
+        loop {
+            match self.conn.lock().reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            let n = self.tcp.read(&mut records)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.conn.lock();
+            let mut received = &records[..n];
+            while !received.is_empty() {
+                conn.read_tls(&mut received)?;
+                if let Err(e) = conn.process_new_packets() {
+                    // Tell them what went wrong before giving up on them
+                    let _ = self.flush_tls(&mut conn);
+                    return Err(std::io::Error::new(ErrorKind::InvalidData, e));
+                }
+            }
+            self.flush_tls(&mut conn)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut conn = self.conn.lock();
+        let n = conn.writer().write(buf)?;
+        self.flush_tls(&mut conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut conn = self.conn.lock();
+        conn.writer().flush()?;
+        self.flush_tls(&mut conn)
+    }
+}
+
+impl ScuffedClone for TlsStream {
+    fn scuffed_clone(&self) -> Self {
+        Self { conn: self.conn.clone(), tcp: self.tcp.scuffed_clone() }
+    }
+}
+
+impl HangUp for TlsStream {
+    fn hang_up(&self) {
+        let _ = self.tcp.shutdown(Shutdown::Read);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+    use super::*;
+
+    /// A self-signed certificate for 127.0.0.1, written to `dir` as `cert.pem` and `key.pem`.
+    fn self_signed(dir: &Path) {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        #[cfg(unix)]
+        std::fs::set_permissions(dir.join("key.pem"), std::os::unix::fs::PermissionsExt::from_mode(0o600)).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("tls-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn talks_both_ways() {
+        let dir = temp_dir("both-ways");
+        self_signed(&dir);
+        let server_config = server_config(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+        let client_config = client_config(Some(&dir.join("cert.pem"))).unwrap();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = TlsStream::accept(listener.accept().unwrap().0, server_config).unwrap();
+            // Write from a clone while the original's reading, like the writer thread does
+            let mut writer = stream.scuffed_clone();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(format!("you said {line}").as_bytes()).unwrap();
+        });
+
+        let mut client = TlsStream::connect(TcpStream::connect(addr).unwrap(), client_config, "127.0.0.1").unwrap();
+        client.write_all(b"hello\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        assert_eq!("you said hello\n", line);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn untrusted_servers_get_refused() {
+        let dir = temp_dir("untrusted");
+        self_signed(&dir);
+        let server_config = server_config(&dir.join("cert.pem"), &dir.join("key.pem")).unwrap();
+        // Only trusts the usual public CAs, which never signed our self-signed cert
+        let client_config = client_config(None).unwrap();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = TlsStream::accept(listener.accept().unwrap().0, server_config).unwrap();
+            assert!(stream.read(&mut [0; 16]).is_err());
+        });
+
+        let mut client = TlsStream::connect(TcpStream::connect(addr).unwrap(), client_config, "127.0.0.1").unwrap();
+        client.write_all(b"hello\n").unwrap();
+        let err = client.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn missing_certs() {
+        let dir = temp_dir("missing");
+        std::fs::write(dir.join("empty.pem"), "").unwrap();
+        assert!(matches!(client_config(Some(&dir.join("empty.pem"))), Err(TlsError::NoCerts(_))));
+        assert!(matches!(server_config(&dir.join("nope.pem"), &dir.join("nope.pem")), Err(TlsError::Pem(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_readable_keys_get_refused() {
+        let dir = temp_dir("world-readable");
+        self_signed(&dir);
+        std::fs::set_permissions(dir.join("key.pem"), std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+        let refused = server_config(&dir.join("cert.pem"), &dir.join("key.pem"));
+        assert!(matches!(refused, Err(TlsError::Secret(SecretError::WorldReadable(_)))));
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/// Classic token bucket: `rate` tokens trickle in per second up to `burst`, and taking more than what's
+/// there puts the bucket in debt that has to be waited off.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full.
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: now,
+        }
+    }
+
+    /// Takes `n` tokens, returning how long the caller should wait before acting on them. Zero means go
+    /// right ahead.
+    pub fn take(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n as f64;
+
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Takes `n` tokens if they're there. If they aren't, takes nothing and returns how long until they will be.
+    pub fn try_take(&mut self, n: u64, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        let short = n as f64 - self.tokens;
+        if short <= 0.0 || self.rate <= 0.0 {
+            self.tokens -= n as f64;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(short / self.rate))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_free() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 100, now);
+        assert_eq!(Duration::ZERO, bucket.take(60, now));
+        assert_eq!(Duration::ZERO, bucket.take(40, now));
+    }
+
+    #[test]
+    fn debt_has_to_be_waited_off() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 100, now);
+        bucket.take(100, now);
+
+        assert_eq!(Duration::from_millis(500), bucket.take(50, now));
+        // Waiting it off clears the debt, and then some
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Duration::ZERO, bucket.take(50, later));
+    }
+
+    #[test]
+    fn try_take_leaves_it_alone() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+        assert_eq!(Ok(()), bucket.try_take(2, now));
+        assert_eq!(Err(Duration::from_millis(100)), bucket.try_take(1, now));
+        assert_eq!(Err(Duration::from_millis(100)), bucket.try_take(1, now));
+        assert_eq!(Ok(()), bucket.try_take(1, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn refill_caps_at_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(100, 100, now);
+
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(Duration::ZERO, bucket.take(100, much_later));
+        assert_eq!(Duration::from_millis(10), bucket.take(1, much_later));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::user::User;
+
+/// Oldest entries get forgotten past this, so a client left running for weeks doesn't grow forever.
+const MAX_ENTRIES: usize = 10_000;
+
+/// What an export gets written as, picked from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    /// `.md`/`.markdown` and `.html`/`.htm` get those, anything else is plain text.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("md" | "markdown") => Format::Markdown,
+            Some("html" | "htm") => Format::Html,
+            _ => Format::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Entry {
+    Chat { at: SystemTime, from: String, text: String },
+    Event { at: SystemTime, text: String },
+}
+
+impl Entry {
+    fn as_text(&self) -> String {
+        match self {
+            Entry::Chat { at, from, text } => format!("[{}] <{from}> {text}", timestamp(*at)),
+            Entry::Event { at, text } => format!("[{}] * {text}", timestamp(*at)),
+        }
+    }
+
+    fn at(&self) -> SystemTime {
+        match self {
+            Entry::Chat { at, .. } | Entry::Event { at, .. } => *at,
+        }
+    }
+}
+
+/// Everything the client has shown this session, for `/export` and `/find`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Transcript {
+    entries: VecDeque<Entry>,
+}
+
+impl Transcript {
+    pub fn chat(&mut self, from: &User, text: &str) {
+        self.chat_at(SystemTime::now(), from, text);
+    }
+
+    /// Something said at `at`, which might be a while back if it's history being replayed.
+    pub fn chat_at(&mut self, at: SystemTime, from: &User, text: &str) {
+        self.push(Entry::Chat { at, from: from.shown().to_string(), text: text.to_string() });
+    }
+
+    /// Things that happened that aren't anyone talking, like connecting.
+    pub fn event(&mut self, text: &str) {
+        self.event_at(SystemTime::now(), text);
+    }
+
+    pub fn event_at(&mut self, at: SystemTime, text: &str) {
+        self.push(Entry::Event { at, text: text.to_string() });
+    }
+
+    /// Keeps entries in the order they happened. Nearly everything goes on the end, so that's where to look.
+    fn push(&mut self, entry: Entry) {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        let after = self.entries.iter().rposition(|e| e.at() <= entry.at()).map_or(0, |i| i + 1);
+        self.entries.insert(after, entry);
+    }
+
+    /// The last `n` lines, oldest first.
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        self.entries.iter().skip(self.entries.len().saturating_sub(n)).map(Entry::as_text).collect()
+    }
+
+    /// Every line with `needle` in it (ignoring ASCII case), oldest first, with the matches in reverse video.
+    pub fn find(&self, needle: &str) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| highlight(&entry.as_text(), needle))
+            .collect()
+    }
+
+    /// Writes the whole transcript to `path` in whichever format its extension asks for.
+    pub fn export(&self, path: &Path) -> std::io::Result<Format> {
+        let format = Format::for_path(path);
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write(format, &mut file)?;
+        file.flush()?;
+        Ok(format)
+    }
+
+    fn write<W: Write>(&self, format: Format, mut out: W) -> std::io::Result<()> {
+        if format == Format::Html {
+            writeln!(out, "<!DOCTYPE html>\n<meta charset=\"utf-8\">\n<title>basic-irc transcript</title>\n<ul>")?;
+        }
+
+        for entry in &self.entries {
+            match (format, entry) {
+                (Format::Text, entry) => writeln!(out, "{}", entry.as_text())?,
+                (Format::Markdown, Entry::Chat { at, from, text }) => {
+                    writeln!(out, "- `{}` **{}**: {}", timestamp(*at), escape_markdown(from), escape_markdown(text))?
+                }
+                (Format::Markdown, Entry::Event { at, text }) => {
+                    writeln!(out, "- `{}` _{}_", timestamp(*at), escape_markdown(text))?
+                }
+                (Format::Html, Entry::Chat { at, from, text }) => writeln!(
+                    out,
+                    "<li><time>{}</time> <b>{}</b>: {}</li>",
+                    timestamp(*at), escape_html(from), escape_html(text)
+                )?,
+                (Format::Html, Entry::Event { at, text }) => {
+                    writeln!(out, "<li><time>{}</time> <i>{}</i></li>", timestamp(*at), escape_html(text))?
+                }
+            }
+        }
+
+        if format == Format::Html {
+            writeln!(out, "</ul>")?;
+        }
+        Ok(())
+    }
+}
+
+/// `line` with each `needle` in it highlighted, or `None` if there aren't any.
+fn highlight(line: &str, needle: &str) -> Option<String> {
+    // ASCII lowercasing keeps every byte where it was, so offsets into one work for the other
+    let (haystack, needle) = (line.to_ascii_lowercase(), needle.to_ascii_lowercase());
+    let mut matches = haystack.match_indices(&needle).peekable();
+    matches.peek()?;
+
+    let mut highlighted = String::with_capacity(line.len() + 16);
+    let mut last = 0;
+    for (start, found) in matches {
+        let end = start + found.len();
+        highlighted.push_str(&line[last..start]);
+        highlighted.push_str(&format!("\x1b[7m{}\x1b[27m", &line[start..end]));
+        last = end;
+    }
+    highlighted.push_str(&line[last..]);
+    Some(highlighted)
+}
+
+/// Like `2024-03-01 13:37:00 UTC`.
+pub(crate) fn timestamp(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_date(secs / 86400);
+    let (hours, mins, secs) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    format!("{year:04}-{month:02}-{day:02} {hours:02}:{mins:02}:{secs:02} UTC")
+}
+
+/// Days since 1970-01-01 to a calendar date, going by Howard Hinnant's `civil_from_days`.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>#|~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    fn transcript() -> Transcript {
+        let at = UNIX_EPOCH + Duration::from_secs(1_709_300_220);
+        Transcript {
+            entries: VecDeque::from([
+                Entry::Event { at, text: "Connected as alice".to_string() },
+                Entry::Chat { at, from: "alice".to_string(), text: "<b>*hi*</b> & bye".to_string() },
+            ]),
+        }
+    }
+
+    fn written(format: Format) -> String {
+        let mut out = Vec::new();
+        transcript().write(format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(Format::Markdown, Format::for_path(Path::new("log.MD")));
+        assert_eq!(Format::Html, Format::for_path(Path::new("/tmp/log.htm")));
+        assert_eq!(Format::Text, Format::for_path(Path::new("log.txt")));
+        assert_eq!(Format::Text, Format::for_path(Path::new("log")));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!("1970-01-01 00:00:00 UTC", timestamp(UNIX_EPOCH));
+        // Leap day, and the end of a leap year
+        assert_eq!("2024-02-29 23:59:59 UTC", timestamp(UNIX_EPOCH + Duration::from_secs(1_709_251_199)));
+        assert_eq!("2000-12-31 12:00:00 UTC", timestamp(UNIX_EPOCH + Duration::from_secs(978_264_000)));
+    }
+
+    #[test]
+    fn writes_text() {
+        let expected = "[2024-03-01 13:37:00 UTC] * Connected as alice\n\
+                        [2024-03-01 13:37:00 UTC] <alice> <b>*hi*</b> & bye\n";
+        assert_eq!(expected, written(Format::Text));
+    }
+
+    #[test]
+    fn writes_markdown_escaped() {
+        let expected = "- `2024-03-01 13:37:00 UTC` _Connected as alice_\n\
+                        - `2024-03-01 13:37:00 UTC` **alice**: \\<b\\>\\*hi\\*\\</b\\> & bye\n";
+        assert_eq!(expected, written(Format::Markdown));
+    }
+
+    #[test]
+    fn writes_html_escaped() {
+        let html = written(Format::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<li><time>2024-03-01 13:37:00 UTC</time> <b>alice</b>: &lt;b&gt;*hi*&lt;/b&gt; &amp; bye</li>"));
+        assert!(html.ends_with("</ul>\n"));
+    }
+
+    #[test]
+    fn finds_and_highlights_ignoring_case() {
+        let found = transcript().find("BYE");
+        assert_eq!(vec!["[2024-03-01 13:37:00 UTC] <alice> <b>*hi*</b> & \x1b[7mbye\x1b[27m"], found);
+
+        assert_eq!(2, transcript().find("alice").len());
+        assert!(transcript().find("nope").is_empty());
+    }
+
+    #[test]
+    fn replayed_history_goes_where_it_happened() {
+        let mut transcript = transcript();
+        let alice = User::new("alice");
+        transcript.chat_at(UNIX_EPOCH + Duration::from_secs(1_709_300_100), &alice, "earlier");
+        transcript.event_at(UNIX_EPOCH + Duration::from_secs(1_709_300_220), "same time, so after");
+
+        let texts: Vec<_> = transcript.tail(4).into_iter().map(|line| line.split("] ").nth(1).unwrap().to_string()).collect();
+        assert_eq!(vec!["<alice> earlier", "* Connected as alice", "<alice> <b>*hi*</b> & bye", "* same time, so after"], texts);
+    }
+
+    #[test]
+    fn forgets_oldest_past_the_limit() {
+        let mut transcript = Transcript::default();
+        for i in 0..MAX_ENTRIES + 5 {
+            transcript.event(&i.to_string());
+        }
+
+        assert_eq!(MAX_ENTRIES, transcript.entries.len());
+        assert!(matches!(&transcript.entries[0], Entry::Event { text, .. } if text == "5"));
+    }
+}
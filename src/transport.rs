@@ -0,0 +1,267 @@
+//! The ways clients can reach the server. Each has its own listeners, but they all feed the same acceptor
+//! gates and worker pool, and once a worker's unwrapped a connection, TLS first and then whatever the
+//! transport wraps it in, the rest of the server can't tell them apart.
+
+use std::fmt::{self, Display};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use crate::listener;
+use crate::scuffed_clone::{HangUp, ScuffedClone};
+
+/// How a connection got here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Straight over TCP, like the native and IRC clients.
+    Tcp,
+    /// Upgraded to a WebSocket first, like browsers.
+    WebSocket,
+    /// Over a Unix socket, from somewhere on this machine, with `--unix`.
+    Unix,
+}
+
+impl Transport {
+    /// Whether a client can be told something before it's been unwrapped, like why it's being turned away.
+    /// Anything else would need a handshake first, which is just the work there's no room for.
+    pub fn speaks_first(self, tls: bool) -> bool {
+        match self {
+            Transport::Tcp => !tls,
+            // Never leaves the machine, so it's never wrapped in TLS either
+            Transport::Unix => true,
+            Transport::WebSocket => false,
+        }
+    }
+}
+
+/// Where connections are accepted from, whichever kind of socket it is.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Waits for the next connection, with who it's from. Anyone on a Unix socket is on this machine, so as far
+    /// as bans and the like go they're localhost.
+    pub(crate) fn accept(&self) -> io::Result<(Stream, IpAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                // IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d, which should count the same as a.b.c.d
+                Ok((Stream::Tcp(stream), peer.ip().to_canonical()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok((Stream::Unix(listener.accept()?.0), std::net::Ipv4Addr::LOCALHOST.into())),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+
+    /// The port it's on, if it's on one.
+    pub(crate) fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "a TCP port"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr().ok().as_ref().and_then(|addr| addr.as_pathname()) {
+                Some(path) => write!(f, "{}", path.display()),
+                None => write!(f, "a Unix socket"),
+            },
+        }
+    }
+}
+
+/// A connection from a `Listener`.
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl ScuffedClone for Stream {
+    fn scuffed_clone(&self) -> Self {
+        match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.scuffed_clone()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Stream::Unix(stream.scuffed_clone()),
+        }
+    }
+}
+
+impl HangUp for Stream {
+    fn hang_up(&self) {
+        match self {
+            Stream::Tcp(stream) => stream.hang_up(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.hang_up(),
+        }
+    }
+}
+
+/// Binds `acceptors` TCP listeners to `address`, or a Unix socket at `unix` instead if there is one, and one
+/// WebSocket listener on `ws_port` on the same IP if there is one.
+pub(crate) fn bind(
+    address: SocketAddr,
+    acceptors: usize,
+    (ws_port, unix): (Option<u16>, Option<&Path>),
+) -> io::Result<Vec<(Listener, Transport)>> {
+    let mut listeners = match unix {
+        Some(path) => vec![(bind_unix(path)?, Transport::Unix)],
+        None => listener::bind(address, acceptors)?.into_iter().map(|tcp| (tcp.into(), Transport::Tcp)).collect(),
+    };
+    if let Some(port) = ws_port {
+        listeners.push((listener::bind_one(SocketAddr::new(address.ip(), port))?.into(), Transport::WebSocket));
+    }
+    Ok(listeners)
+}
+
+/// Listens on `path`, cleaning up after a server that's gone if there was one. Anything there that isn't a
+/// socket is left alone, it's more likely a typo than something to delete.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<Listener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(found) if !found.file_type().is_socket() => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "There's something other than a socket there"));
+        }
+        Ok(_) if UnixStream::connect(path).is_ok() => {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "There's already a server on that socket"));
+        }
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path).map(Listener::Unix)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &Path) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--unix needs Unix sockets, which there aren't here"))
+}
+
+/// The file behind a Unix socket the server's listening on, which is removed again once this is dropped.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct SocketFile(pub(crate) PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn binds_every_transport() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 1, (Some(0), None)).unwrap();
+        let transports: Vec<_> = listeners.iter().map(|(_, transport)| *transport).collect();
+        assert_eq!(vec![Transport::Tcp, Transport::WebSocket], transports);
+        assert!(!Transport::WebSocket.speaks_first(false) && !Transport::Tcp.speaks_first(true));
+        assert!(Transport::Tcp.speaks_first(false) && Transport::Unix.speaks_first(true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_instead_of_tcp() {
+        let path = std::env::temp_dir().join(format!("basic-irc-unix-{}.sock", std::process::id()));
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), 4, (None, Some(&path))).unwrap();
+        let [(listener, Transport::Unix)] = &listeners[..] else {
+            panic!("Should've only been the Unix socket, got {listeners:?}");
+        };
+        assert_eq!((path.display().to_string(), None), (listener.to_string(), listener.port()));
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        assert_eq!(IpAddr::from(Ipv4Addr::LOCALHOST), peer);
+        client.write_all(b"hi\n").unwrap();
+        let mut hi = [0; 3];
+        stream.scuffed_clone().read_exact(&mut hi).unwrap();
+        assert_eq!(b"hi\n", &hi);
+        // Somebody's still on it, so it isn't cleaned up
+        assert_eq!(io::ErrorKind::AddrInUse, bind_unix(&path).unwrap_err().kind());
+
+        // Left behind by a server that's gone, which is fine to take over
+        drop((listeners, client, stream));
+        assert!(path.exists());
+        let again = bind_unix(&path).unwrap();
+        drop((again, SocketFile(path.clone())));
+        assert!(!path.exists());
+
+        // Nor is anything that isn't a socket
+        std::fs::write(&path, "not a socket").unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, bind_unix(&path).unwrap_err().kind());
+        assert_eq!("not a socket", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! Rules for the client that act on what comes in without anyone at the keyboard, like answering private
+//! messages while `/away` or running a command when a keyword comes up. Each is one `--trigger`:
+//!
+//! ```text
+//! [away] <private|mention|message> [text] => <action>
+//! ```
+//!
+//! `private` is any private message, `mention` is chat with our nick in it, and `message` is any chat or
+//! private message. `away` only fires while we're `/away`, and `text` only for messages with it in them,
+//! ignoring case. The action is a line to send, with `{from}`, `{channel}` and `{text}` filled in, or `!`
+//! and a shell command. Commands get those in `CHAT_FROM`, `CHAT_CHANNEL` and `CHAT_TEXT` rather than
+//! pasted into them, so nobody can slip their own in.
+//!
+//! Each rule fires at most once per cooldown for each sender, and never for anything we said, so two
+//! clients answering each other can't keep it up.
+
+use std::collections::HashMap;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use crate::away::mentions;
+use crate::response::ServerMessage;
+use crate::user::User;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TriggerError {
+    #[error("Trigger {0:?} should look like `[away] <private|mention|message> [text] => <action>`")]
+    Malformed(String),
+    #[error("Trigger {0:?} doesn't say what to do after the `=>`")]
+    NoAction(String),
+}
+
+/// What sets a trigger off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum On {
+    Private,
+    Mention,
+    Message,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    /// A line to send, like `/msg {from} Back soon`.
+    Send(String),
+    /// A shell command to run.
+    Run(String),
+}
+
+#[derive(Debug, Clone)]
+struct Trigger {
+    away_only: bool,
+    on: On,
+    /// Lowercase, and empty for anything.
+    text: String,
+    action: Action,
+}
+
+/// What a trigger firing comes to, with whatever's filled in filled in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fired {
+    Send(String),
+    Run { command: String, env: Vec<(&'static str, String)> },
+}
+
+/// Every `--trigger`, and when each last fired for whom.
+#[derive(Debug, Default)]
+pub struct Triggers {
+    triggers: Vec<Trigger>,
+    cooldown: Duration,
+    fired: HashMap<(usize, String), Instant>,
+}
+
+impl Triggers {
+    /// Reads `rules`, each firing at most once per `cooldown` for any one sender.
+    pub fn parse(rules: &[String], cooldown: Duration) -> Result<Self, TriggerError> {
+        let triggers = rules.iter().map(|rule| parse(rule)).collect::<Result<_, _>>()?;
+        Ok(Self { triggers, cooldown, fired: HashMap::new() })
+    }
+
+    /// What `msg` sets off, coming in at `now` for `me`, who might be `away`.
+    pub fn fire(&mut self, msg: &ServerMessage, me: &User, away: bool, now: Instant) -> Vec<Fired> {
+        let (from, channel, text, private) = match msg {
+            ServerMessage::Sequenced { message, .. } => return self.fire(message, me, away, now),
+            ServerMessage::Chat { from, channel, text, .. } => (from, channel.as_deref(), text, false),
+            ServerMessage::Private { from, text, .. } => (from, None, text, true),
+            _ => return Vec::new(),
+        };
+        if from.name.eq_ignore_ascii_case(&me.name) {
+            return Vec::new();
+        }
+
+        let cooldown = self.cooldown;
+        self.fired.retain(|_, at| now.saturating_duration_since(*at) < cooldown);
+        let lowercase = text.to_lowercase();
+        let mut fired = Vec::new();
+        for (i, trigger) in self.triggers.iter().enumerate() {
+            let on = match trigger.on {
+                On::Private => private,
+                On::Mention => !private && mentions(text, &me.name),
+                On::Message => true,
+            };
+            if !on || (trigger.away_only && !away) || !lowercase.contains(&trigger.text) {
+                continue;
+            }
+            // Once per sender per cooldown, so nobody gets answered more than that, whoever they are
+            let key = (i, from.name.to_lowercase());
+            if self.fired.contains_key(&key) {
+                continue;
+            }
+
+            // Whatever's filled into a line stays on it
+            let text = text.replace(['\r', '\n'], " ");
+            let channel = channel.unwrap_or_default();
+            let action = match &trigger.action {
+                Action::Send(line) => {
+                    let filled = line.replace("{from}", &from.name).replace("{channel}", channel).replace("{text}", &text);
+                    // A line that wasn't a command doesn't get to be made one by whoever it's answering
+                    if filled.starts_with('/') && !line.starts_with('/') {
+                        continue;
+                    }
+                    Fired::Send(filled)
+                }
+                Action::Run(command) => Fired::Run {
+                    command: command.clone(),
+                    env: vec![("CHAT_FROM", from.name.clone()), ("CHAT_CHANNEL", channel.to_string()), ("CHAT_TEXT", text)],
+                },
+            };
+            // Only what actually fires starts the cooldown
+            self.fired.insert(key, now);
+            fired.push(action);
+        }
+        fired
+    }
+}
+
+fn parse(rule: &str) -> Result<Trigger, TriggerError> {
+    let malformed = || TriggerError::Malformed(rule.to_string());
+    let (when, action) = rule.split_once("=>").ok_or_else(malformed)?;
+    let action = match action.trim() {
+        "" | "!" => return Err(TriggerError::NoAction(rule.to_string())),
+        action => match action.strip_prefix('!') {
+            Some(command) => Action::Run(command.trim().to_string()),
+            None => Action::Send(action.to_string()),
+        },
+    };
+
+    let when = when.trim();
+    let (away_only, when) = match when.strip_prefix("away ") {
+        Some(rest) => (true, rest.trim_start()),
+        None => (false, when),
+    };
+    let (on, text) = when.split_once(' ').unwrap_or((when, ""));
+    let on = match on {
+        "private" => On::Private,
+        "mention" => On::Mention,
+        "message" => On::Message,
+        _ => return Err(malformed()),
+    };
+    Ok(Trigger { away_only, on, text: text.trim().to_lowercase(), action })
+}
+
+/// Runs `command` in the shell with `env`, without waiting for it. Whatever it prints goes nowhere, it'd only
+/// mess up the terminal.
+pub fn run(command: &str, env: Vec<(&'static str, String)>) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+
+    let mut child = shell.arg(command).envs(env).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+    // Someone has to wait on it, or it hangs around once it's done
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(from: &str, text: &str) -> ServerMessage {
+        ServerMessage::Chat { from: User::new(from), text: text.to_string(), received_ms: 0, channel: Some("#ops".to_string()) }
+    }
+
+    fn private(from: &str, text: &str) -> ServerMessage {
+        ServerMessage::Private { from: User::new(from), to: User::new("bob"), text: text.to_string() }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = parse("away private => /msg {from} Away right now").unwrap();
+        assert_eq!((true, On::Private, ""), (rule.away_only, rule.on, rule.text.as_str()));
+        assert_eq!(Action::Send("/msg {from} Away right now".to_string()), rule.action);
+
+        let rule = parse("message Deploy failed => !notify-send \"$CHAT_TEXT\"").unwrap();
+        assert_eq!((false, On::Message, "deploy failed"), (rule.away_only, rule.on, rule.text.as_str()));
+        assert_eq!(Action::Run("notify-send \"$CHAT_TEXT\"".to_string()), rule.action);
+
+        assert_eq!(Err(TriggerError::Malformed("dm => hi".to_string())), parse("dm => hi").map(|_| ()));
+        assert_eq!(Err(TriggerError::Malformed("private hi".to_string())), parse("private hi").map(|_| ()));
+        assert_eq!(Err(TriggerError::NoAction("mention => !".to_string())), parse("mention => !").map(|_| ()));
+    }
+
+    #[test]
+    fn fires_on_what_matches() {
+        let rules = [
+            "away private => /msg {from} Away, back later".to_string(),
+            "mention => !echo".to_string(),
+            "message deploy => [{channel}] {from} said {text}".to_string(),
+        ];
+        let (mut triggers, bob, now) = (Triggers::parse(&rules, Duration::from_secs(30)).unwrap(), User::new("bob"), Instant::now());
+
+        // Only while away
+        assert_eq!(Vec::<Fired>::new(), triggers.fire(&private("alice", "hi"), &bob, false, now));
+        let answer = Fired::Send("/msg alice Away, back later".to_string());
+        assert_eq!(vec![answer], triggers.fire(&private("alice", "hi"), &bob, true, now));
+
+        let fired = triggers.fire(&chat("carol", "bob: the DEPLOY\nbroke"), &bob, false, now);
+        let env = vec![("CHAT_FROM", "carol".to_string()), ("CHAT_CHANNEL", "#ops".to_string()), ("CHAT_TEXT", "bob: the DEPLOY broke".to_string())];
+        let said = "[#ops] carol said bob: the DEPLOY broke".to_string();
+        assert_eq!(vec![Fired::Run { command: "echo".to_string(), env }, Fired::Send(said)], fired);
+
+        // Nor for making someone else's words into a command
+        let echo = ["message => {text}".to_string()];
+        let mut echoing = Triggers::parse(&echo, Duration::ZERO).unwrap();
+        assert!(echoing.fire(&chat("mallory", "/kill alice"), &bob, false, now).is_empty());
+
+        // Never for what we said ourselves
+        assert!(triggers.fire(&chat("Bob", "bob deploy"), &bob, true, now).is_empty());
+    }
+
+    #[test]
+    fn cools_down_per_sender() {
+        let rules = ["private => /msg {from} Not now".to_string()];
+        let (mut triggers, bob, now) = (Triggers::parse(&rules, Duration::from_secs(30)).unwrap(), User::new("bob"), Instant::now());
+        assert_eq!(1, triggers.fire(&private("alice", "hi"), &bob, false, now).len());
+        // Another client answering that right back doesn't get answered again
+        assert!(triggers.fire(&private("ALICE", "Not now"), &bob, false, now + Duration::from_secs(1)).is_empty());
+        assert_eq!(1, triggers.fire(&private("carol", "hi"), &bob, false, now + Duration::from_secs(1)).len());
+        assert_eq!(1, triggers.fire(&private("alice", "hi"), &bob, false, now + Duration::from_secs(30)).len());
+
+        // Something that got turned away doesn't count
+        let echo = ["message => {text}".to_string()];
+        let mut echoing = Triggers::parse(&echo, Duration::from_secs(30)).unwrap();
+        assert!(echoing.fire(&chat("mallory", "/kill alice"), &bob, false, now).is_empty());
+        assert_eq!(vec![Fired::Send("hi".to_string())], echoing.fire(&chat("mallory", "hi"), &bob, false, now));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_commands_with_the_message_in_the_environment() {
+        let path = std::env::temp_dir().join(format!("basic-irc-trigger-{}.txt", std::process::id()));
+        let command = format!("printf %s \"$CHAT_TEXT\" > {}", path.display());
+        run(&command, vec![("CHAT_TEXT", "$(echo nope) `echo nope`".to_string())]).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&path).map_or(true, |ran| ran.is_empty()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!("$(echo nope) `echo nope`", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
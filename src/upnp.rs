@@ -0,0 +1,88 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use igd_next::{AddPortError, GetExternalIpError, PortMappingProtocol, SearchError, SearchOptions};
+use thiserror::Error;
+use tracing::warn;
+
+/// Mappings get leased instead of made permanent, so a server that dies without cleaning up doesn't leave
+/// the router forwarding to nothing forever. They get renewed well before they run out.
+const LEASE: Duration = Duration::from_secs(60 * 60);
+const RENEW_EVERY: Duration = Duration::from_secs(20 * 60);
+const DESCRIPTION: &str = "basic-irc server";
+
+#[derive(Error, Debug)]
+pub enum UpnpError {
+    #[error("Couldn't find a UPnP router: `{0}`")]
+    Search(#[from] SearchError),
+    #[error("Router wouldn't say what its external IP is: `{0}`")]
+    ExternalIp(#[from] GetExternalIpError),
+    #[error("Router wouldn't map the port: `{0}`")]
+    AddPort(#[from] AddPortError),
+    #[error("Couldn't work out our LAN address: `{0}`")]
+    IO(#[from] std::io::Error),
+}
+
+/// A port forwarded to us by the router. The mapping is kept alive until this is dropped, then removed.
+pub struct PortMapping {
+    pub external: SocketAddr,
+    // Dropping this stops the renewer, which removes the mapping on its way out
+    _stop: Sender<()>,
+}
+
+/// Asks the router to forward its `port` to the same port on this machine.
+pub fn map_port(port: u16) -> Result<PortMapping, UpnpError> {
+    let gateway = igd_next::search_gateway(SearchOptions {
+        timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    })?;
+
+    let local = SocketAddr::new(local_ip_towards(gateway.addr)?, port);
+    gateway.add_port(PortMappingProtocol::TCP, port, local, LEASE.as_secs() as u32, DESCRIPTION)?;
+    let external = SocketAddr::new(gateway.get_external_ip()?, port);
+
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::Builder::new()
+        .name("upnp".to_string())
+        .spawn(move || {
+            // Nothing ever gets sent, so this only stops waiting once the mapping is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(RENEW_EVERY) {
+                let renewed = gateway.add_port(PortMappingProtocol::TCP, port, local, LEASE.as_secs() as u32, DESCRIPTION);
+                if let Err(e) = renewed {
+                    warn!("[UPNP] Failed renewing port mapping: {e:?}");
+                }
+            }
+
+            if let Err(e) = gateway.remove_port(PortMappingProtocol::TCP, port) {
+                warn!("[UPNP] Failed removing port mapping: {e:?}");
+            }
+        })?;
+
+    Ok(PortMapping { external, _stop: stop })
+}
+
+/// Which of our addresses the router would see traffic come from. Connecting a UDP socket doesn't send
+/// anything, it just makes the OS pick a route.
+fn local_ip_towards(gateway: SocketAddr) -> std::io::Result<IpAddr> {
+    let bind: SocketAddr = match gateway {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(gateway)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn local_ip_towards_loopback() {
+        let ip = local_ip_towards((Ipv4Addr::LOCALHOST, 1900).into()).unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), ip);
+    }
+}
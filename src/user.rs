@@ -1,21 +0,0 @@
-use std::fmt::{Display, Formatter};
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
-pub struct User {
-    pub name: String,
-}
-
-impl User {
-    pub fn new(name: impl Into<String>) -> Self {
-        Self {
-            name: name.into()
-        }
-    }
-}
-
-impl Display for User {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.name)
-    }
-}
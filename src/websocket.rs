@@ -0,0 +1,406 @@
+//! Just enough of WebSockets (RFC 6455) for browsers to connect on `--ws-port`, like the `web` crate's
+//! client. Once upgraded, a `WsStream` is a byte stream like any other, so everything past it is the same as
+//! for TCP. Binary messages carry the same bytes that'd go over TCP, however they're split up, and text
+//! messages are chat lines, for a frontend that'd rather not frame what it says. Everything the server
+//! sends goes out as binary messages.
+
+use std::io::{ErrorKind, IoSlice, Read, Write};
+use std::sync::Arc;
+use parking_lot::Mutex;
+use thiserror::Error;
+use crate::http::{self, Status};
+use crate::scuffed_clone::{HangUp, ScuffedClone};
+
+/// What the key in the upgrade request is hashed with to prove we speak WebSocket.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Biggest message a client may send, however many frames it's in. Way over what a line or frame can be.
+const MAX_MESSAGE: usize = 256 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+#[derive(Error, Debug)]
+pub enum WsError {
+    #[error("Failed to read/write from stream: `{0}`")]
+    IO(#[from] std::io::Error),
+    #[error("Not a WebSocket upgrade: {0}")]
+    NotAnUpgrade(&'static str),
+}
+
+/// A connection upgraded to WebSocket, read and written as the bytes its messages carry. Clones share what's
+/// been read but not handed out yet, and take turns writing so their frames don't interleave.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    inner: S,
+    reading: Arc<Mutex<Reading>>,
+    writing: Arc<Mutex<()>>,
+}
+
+/// Whatever's been read and not handed out yet.
+#[derive(Debug, Default)]
+struct Reading {
+    ready: Vec<u8>,
+    /// The message being put together from fragments, and whether it's text.
+    partial: Option<(bool, Vec<u8>)>,
+    closed: bool,
+}
+
+impl<S: Read + Write> WsStream<S> {
+    /// Reads the upgrade request off `inner` and agrees to it, or says what's wrong with it and hangs up.
+    pub fn accept(mut inner: S) -> Result<Self, WsError> {
+        let request = http::read_request(&mut inner)?;
+        let key = match upgrade_key(&request) {
+            Ok(key) => key,
+            Err(why) => {
+                http::respond(&mut inner, Status::BadRequest, "text/plain", &format!("{why}\n"))?;
+                return Err(WsError::NotAnUpgrade(why));
+            }
+        };
+
+        write!(
+            inner,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        )?;
+        inner.flush()?;
+        Ok(Self { inner, reading: Default::default(), writing: Default::default() })
+    }
+
+    /// Reads frames until there's something to hand out, answering pings and closes on the way. False once
+    /// the other end's closed.
+    fn fill(&mut self, reading: &mut Reading) -> std::io::Result<bool> {
+        while reading.ready.is_empty() {
+            if reading.closed {
+                return Ok(false);
+            }
+            let Some((fin, opcode, payload)) = read_frame(&mut self.inner)? else {
+                reading.closed = true;
+                return Ok(false);
+            };
+
+            match opcode {
+                PING => self.send(PONG, &payload)?,
+                PONG => {}
+                CLOSE => {
+                    // Saying the same status back is all closing takes
+                    let _ = self.send(CLOSE, &payload[..payload.len().min(2)]);
+                    reading.closed = true;
+                }
+                TEXT | BINARY if reading.partial.is_some() => return Err(invalid("New message before the last one ended")),
+                TEXT | BINARY => reading.partial = Some((opcode == TEXT, payload)),
+                CONTINUATION => match &mut reading.partial {
+                    Some((_, message)) if message.len() + payload.len() <= MAX_MESSAGE => message.extend(payload),
+                    Some(_) => return Err(invalid("Message is too big")),
+                    None => return Err(invalid("Continuation without a message to continue")),
+                },
+                _ => return Err(invalid("Unknown opcode")),
+            }
+
+            if fin && opcode & 0x8 == 0 {
+                let Some((text, mut message)) = reading.partial.take() else {
+                    continue;
+                };
+                // Text's a line of chat, which needs its newline to be one
+                if text {
+                    std::str::from_utf8(&message).map_err(|_| invalid("Text message isn't UTF-8"))?;
+                    if !message.ends_with(b"\n") {
+                        message.push(b'\n');
+                    }
+                }
+                reading.ready = message;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Sends one unmasked frame, which is how servers send them.
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        let _turn = self.writing.lock();
+        self.inner.write_all(&frame)?;
+        self.inner.flush()
+    }
+}
+
+impl<S: Read + Write> Read for WsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let reading = self.reading.clone();
+        let mut reading = reading.lock();
+        if !self.fill(&mut reading)? {
+            return Ok(0);
+        }
+        let n = buf.len().min(reading.ready.len());
+        buf[..n].copy_from_slice(&reading.ready[..n]);
+        reading.ready.drain(..n);
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for WsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.send(BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    /// All of it in the one message, so batched frames stay batched.
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let all = bufs.iter().map(|buf| &**buf).collect::<Vec<_>>().concat();
+        self.write(&all)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: ScuffedClone> ScuffedClone for WsStream<S> {
+    fn scuffed_clone(&self) -> Self {
+        Self { inner: self.inner.scuffed_clone(), reading: self.reading.clone(), writing: self.writing.clone() }
+    }
+}
+
+impl<S: HangUp> HangUp for WsStream<S> {
+    fn hang_up(&self) {
+        self.inner.hang_up();
+    }
+}
+
+/// The key from an upgrade `request`, or why it isn't one.
+fn upgrade_key(request: &http::Request) -> Result<&str, &'static str> {
+    let has = |header: &str, token: &str| {
+        request.header(header).is_some_and(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    };
+    if request.method != "GET" {
+        return Err("Upgrades have to be GETs");
+    }
+    if !has("upgrade", "websocket") || !has("connection", "upgrade") {
+        return Err("Only WebSocket connections are taken here");
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err("Only WebSocket version 13 is spoken here");
+    }
+    request.header("sec-websocket-key").ok_or("No Sec-WebSocket-Key")
+}
+
+/// What the server answers `key` with to prove it read the request.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// The next frame's `(fin, opcode, payload)`, unmasked, or `None` if the connection ended before one started.
+fn read_frame(inner: &mut impl Read) -> std::io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let mut head = [0; 2];
+    match inner.read_exact(&mut head) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let (fin, opcode, masked) = (head[0] & 0x80 != 0, head[0] & 0x0F, head[1] & 0x80 != 0);
+    if !masked {
+        return Err(invalid("Clients have to mask what they send"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            inner.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            inner.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err(invalid("Frame is too big"));
+    }
+
+    let mut mask = [0; 4];
+    inner.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    inner.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+fn invalid(why: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, why.to_string())
+}
+
+/// SHA-1, which the handshake's stuck with. Only ever hashes the key, so slow and simple is fine.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64, padded.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | ((*byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use super::*;
+
+    /// A frame as a client sends it, masked.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// A client that's upgraded, and the server's end of it.
+    fn upgraded() -> (TcpStream, WsStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || WsStream::accept(listener.accept().unwrap().0).unwrap());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let request = "GET /chat HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = BufReader::new(client.try_clone().unwrap());
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            response.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line.trim_end().to_string());
+        }
+        assert_eq!("HTTP/1.1 101 Switching Protocols", head[0]);
+        assert!(head.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()), "{head:?}");
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!("qZk+NkcGgWq6PiVxeFDCbJzQ2J0=", base64(&sha1(b"abc")));
+        assert_eq!("2jmj7l5rSw0yVb/vlWAYkK/YBwk=", base64(&sha1(b"")));
+        assert_eq!(("", "Zg==", "Zm8=", "Zm9v"), (&*base64(b""), &*base64(b"f"), &*base64(b"fo"), &*base64(b"foo")));
+    }
+
+    #[test]
+    fn messages_are_the_stream() {
+        let (mut client, mut server) = upgraded();
+        client.write_all(&client_frame(true, BINARY, b"\x00\x00")).unwrap();
+        client.write_all(&client_frame(true, PING, b"hi")).unwrap();
+        client.write_all(&client_frame(false, TEXT, b"hello, ")).unwrap();
+        client.write_all(&client_frame(true, CONTINUATION, "wörld".as_bytes())).unwrap();
+        client.write_all(&client_frame(true, TEXT, &[b'x'; 300])).unwrap();
+        client.write_all(&client_frame(true, CLOSE, &1000u16.to_be_bytes())).unwrap();
+
+        let mut read = Vec::new();
+        server.read_to_end(&mut read).unwrap();
+        let expected = [b"\x00\x00".as_slice(), "hello, wörld\n".as_bytes(), &[b'x'; 300], b"\n"].concat();
+        assert_eq!(expected, read);
+
+        server.write_all(b"frame").unwrap();
+        let mut answers = [0; 4 + 4 + 7];
+        client.read_exact(&mut answers).unwrap();
+        assert_eq!(b"\x8A\x02hi\x88\x02\x03\xE8\x82\x05frame", &answers);
+    }
+
+    #[test]
+    fn rejects_unmasked_frames() {
+        let (mut client, mut server) = upgraded();
+        client.write_all(b"\x82\x01x").unwrap();
+        assert_eq!(ErrorKind::InvalidData, server.read(&mut [0; 8]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn rejects_other_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || WsStream::accept(listener.accept().unwrap().0).map(|_| ()));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{response}");
+        assert!(matches!(server.join().unwrap(), Err(WsError::NotAnUpgrade(_))));
+    }
+}
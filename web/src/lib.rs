@@ -0,0 +1,155 @@
+//! The chat client for browsers, so the web UI talks to the server exactly like the native client does: it's
+//! the same `protocol::client::Connection` underneath, driven over a WebSocket to the server's `--ws-port`
+//! instead of TCP. Build it with `wasm-pack build web --target web`.
+//!
+//! ```js
+//! const client = Client.connect("ws://localhost:7878", "alice");
+//! client.onMessage(event => console.log(event));
+//! client.send("hello");
+//! ```
+//!
+//! Both ways, binary WebSocket messages carry the same bytes that'd go over TCP, however they're split up.
+//! What comes in is handed to `onMessage` as the `Event` it turned out to be, as a plain JS object like
+//! `{ Message: { Chat: { from: ..., text: ... } } }` or `"LoggedIn"`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use js_sys::{Function, Uint8Array, JSON};
+use protocol::client::{Connection, ConnectionError, Event};
+use protocol::response::{self, Login};
+use protocol::user::User;
+use wasm_bindgen::prelude::*;
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+/// Optional features the client asks for in the handshake.
+const FEATURES: &[&str] = &[response::PASSWORD];
+
+#[wasm_bindgen]
+pub struct Client {
+    shared: Rc<Shared>,
+    // Kept around for as long as the socket might call them
+    _on_open: Closure<dyn FnMut()>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+/// What the client and the socket's callbacks share. JS is single-threaded, so it's all just `RefCell`s.
+struct Shared {
+    socket: WebSocket,
+    connection: RefCell<Connection>,
+    login: Login,
+    on_message: RefCell<Option<Function>>,
+    on_close: RefCell<Option<Function>>,
+}
+
+#[wasm_bindgen]
+impl Client {
+    /// Opens a WebSocket to `url` and logs in as `nick` once it's open, with the server's `password` if it
+    /// needs one.
+    pub fn connect(url: &str, nick: &str, password: Option<String>) -> Result<Client, JsError> {
+        let user = User::new(nick);
+        user.validate()?;
+        let socket = WebSocket::new(url).map_err(|e| JsError::new(&format!("Couldn't open {url}: {e:?}")))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let shared = Rc::new(Shared {
+            socket,
+            connection: RefCell::new(Connection::new(FEATURES)),
+            login: Login::new(user, password),
+            on_message: RefCell::new(None),
+            on_close: RefCell::new(None),
+        });
+
+        let opened = shared.clone();
+        let on_open = Closure::<dyn FnMut()>::new(move || opened.flush());
+        let receiving = shared.clone();
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            // Text messages aren't ours, the server only sends frames
+            if event.data().is_instance_of::<js_sys::ArrayBuffer>() {
+                receiving.receive(&Uint8Array::new(&event.data()).to_vec());
+            }
+        });
+        let closing = shared.clone();
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+            let callback = closing.on_close.borrow().clone();
+            if let Some(callback) = callback {
+                let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(&event.reason()));
+            }
+        });
+
+        shared.socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        shared.socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        shared.socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        Ok(Client { shared, _on_open: on_open, _on_message: on_message, _on_close: on_close })
+    }
+
+    /// Sends a line of chat, or a command like `/join #rust`.
+    pub fn send(&self, text: &str) -> Result<(), JsError> {
+        self.shared.connection.borrow_mut().send(text)?;
+        self.shared.flush();
+        Ok(())
+    }
+
+    /// Calls `callback` with every `Event` from the server from now on.
+    #[wasm_bindgen(js_name = onMessage)]
+    pub fn on_message(&self, callback: Function) {
+        *self.shared.on_message.borrow_mut() = Some(callback);
+    }
+
+    /// Calls `callback` with the reason the connection closed, once it has.
+    #[wasm_bindgen(js_name = onClose)]
+    pub fn on_close(&self, callback: Function) {
+        *self.shared.on_close.borrow_mut() = Some(callback);
+    }
+
+    pub fn close(&self) {
+        let _ = self.shared.socket.close();
+    }
+}
+
+impl Shared {
+    fn receive(&self, bytes: &[u8]) {
+        let events = self.connection.borrow_mut().receive(bytes);
+        let events = match events {
+            Ok(events) => events,
+            Err(e) => return self.give_up(e),
+        };
+
+        for event in events {
+            if let Event::Accepted(_) = event {
+                if let Err(e) = self.connection.borrow_mut().log_in(&self.login) {
+                    return self.give_up(e);
+                }
+            }
+            self.tell(&event);
+        }
+        // Logging in, or answering pings
+        self.flush();
+    }
+
+    /// Hands `event` to whatever `onMessage` was given, without holding on to anything it might want to use.
+    fn tell(&self, event: &Event) {
+        let callback = self.on_message.borrow().clone();
+        let (Some(callback), Ok(json)) = (callback, serde_json::to_string(event)) else {
+            return;
+        };
+        if let Ok(event) = JSON::parse(&json) {
+            let _ = callback.call1(&JsValue::NULL, &event);
+        }
+    }
+
+    /// Sends whatever the connection has waiting to go out.
+    fn flush(&self) {
+        let outgoing = self.connection.borrow_mut().take_outgoing();
+        if !outgoing.is_empty() {
+            let _ = self.socket.send_with_u8_array(&outgoing);
+        }
+    }
+
+    /// Closes the socket as a protocol error, saying why.
+    fn give_up(&self, e: ConnectionError) {
+        // Browsers won't close at all with a reason over 123 bytes, which 30 characters can never be
+        let reason: String = e.to_string().chars().take(30).collect();
+        let _ = self.socket.close_with_code_and_reason(1002, &reason);
+    }
+}